@@ -71,9 +71,14 @@ static size_t input_frame_sizes[] = {
 
 static const struct v4l2r_video_frame_provider *capture_provider = NULL;
 static bool drain_completed = false;
+static bool decoder_died = false;
 
 const char *device_path = "/dev/video1";
 
+// Use MMAP instead to let the decoder allocate the CAPTURE frames.
+static const enum v4l2r_memory_type capture_memory_type =
+    V4L2R_MEMORY_TYPE_DMABUF;
+
 static void on_input_done(void *ptr, const struct v4l2_buffer *buffer) {
   printf("Input buffer %d done\n", buffer->index);
 }
@@ -110,6 +115,10 @@ on_format_change(void *ptr,
     v4l2r_video_frame_provider_drop(capture_provider);
   capture_provider = event->new_provider;
 
+  // MMAP frames are allocated by the decoder and already available to it.
+  if (capture_memory_type == V4L2R_MEMORY_TYPE_MMAP)
+    return;
+
   dmabufs = allocate_dmabufs(format, event->min_num_frames);
   printf("Got %zu CAPTURE frames\n", dmabufs.nb_buffers);
   for (i = 0; i < dmabufs.nb_buffers; i++)
@@ -129,6 +138,15 @@ void on_event(void *ptr, struct v4l2r_decoder_event *event) {
     printf("Drain completed!\n");
     drain_completed = true;
     break;
+  case CorruptedFrame:
+    printf("Frame %d reported as corrupted\n",
+           event->corrupted_frame.buffer->index);
+    on_frame_decoded(ptr, &event->corrupted_frame);
+    break;
+  case Died:
+    fprintf(stderr, "Decoder has died!\n");
+    decoder_died = true;
+    break;
   }
 }
 
@@ -137,9 +155,9 @@ int main() {
   struct v4l2_format output_format;
   size_t output_buffer_size;
   int output_dmabuf;
+  enum v4l2r_status status;
   char fmt[4];
   int i;
-  int ret;
 
   FILE *input_file = fopen(input_file_path, "r");
   if (!input_file) {
@@ -149,13 +167,18 @@ int main() {
 
   v4l2r_init();
 
-  struct v4l2r_decoder *decoder =
-      v4l2r_decoder_new(device_path, V4L2_PIX_FMT_FWHT, 1, 0, 0, on_input_done,
-                        on_event, (void *)0xdeadbeef);
+  struct v4l2r_decoder *decoder;
+  status = v4l2r_decoder_new(device_path, V4L2_PIX_FMT_FWHT, 1, 0, 0,
+                             capture_memory_type, on_input_done, on_event,
+                             (void *)0xdeadbeef, &decoder);
+  if (status != V4L2R_STATUS_OK) {
+    fprintf(stderr, "Cannot create decoder: %s\n", v4l2r_last_error());
+    return 1;
+  }
 
-  ret = v4l2r_decoder_get_input_format(decoder, &output_format);
-  if (ret < 0)
-    return ret;
+  status = v4l2r_decoder_get_input_format(decoder, &output_format);
+  if (status != V4L2R_STATUS_OK)
+    return 1;
   *((uint32_t*)&fmt) = output_format.fmt.pix_mp.pixelformat;
   printf("Reported OUTPUT format: %c%c%c%c, %d bytes per frame\n",
          fmt[0], fmt[1], fmt[2], fmt[3],
@@ -183,13 +206,16 @@ int main() {
     }
     munmap(mapping, output_buffer_size);
 
-    ret = v4l2r_decoder_decode(decoder, i, output_dmabuf, frame_bytes_used);
-    if (ret < 0)
+    status = v4l2r_decoder_decode(decoder, i, output_dmabuf, frame_bytes_used,
+                                  NULL);
+    if (status != V4L2R_STATUS_OK) {
+      fprintf(stderr, "Error while decoding: %s\n", v4l2r_last_error());
       return 1;
+    }
   }
 
-  v4l2r_decoder_drain(decoder, false);
-  while (!drain_completed)
+  v4l2r_decoder_drain(decoder, false, NULL);
+  while (!drain_completed && !decoder_died)
     usleep(10000);
 
   v4l2r_decoder_destroy(decoder);
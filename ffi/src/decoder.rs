@@ -10,12 +10,15 @@
 //! frame being decoded, or a change in the output format (due to e.g. a dynamic
 //! resolution change). The output format is initially undefined and a format
 //! change event will be produced before any frame can be decoded.
+//!
+//! The memory type of decoded frames is chosen when creating the decoder. With
+//! DMABUF and USERPTR memory the client allocates the frames to decode into,
+//! while with MMAP memory they are allocated by the device.
 #![allow(non_camel_case_types)]
 
 use log::{debug, error, info, warn};
 use nix::sys::time::{TimeVal, TimeValLike};
 use std::{
-    ffi::CStr,
     mem::MaybeUninit,
     os::raw::{c_char, c_int, c_uint, c_void},
     path::Path,
@@ -24,33 +27,66 @@ use std::{
 use v4l2r::{
     bindings,
     decoder::{
-        stateful::{Decoder, Decoding, DrainError},
+        stateful::{Decoder, DecoderOpenError, Decoding, DrainError, ReadyToDecode},
         CompletedInputBuffer, DecoderEvent, DecoderEventCallback, FormatChangedCallback,
         FormatChangedReply, InputDoneCallback,
     },
-    device::queue::{direction::Capture, dqbuf::DqBuffer, qbuf::OutputQueueable, FormatBuilder},
-    memory::DmaBufHandle,
+    device::queue::{
+        direction::Capture,
+        dqbuf::DqBuffer,
+        qbuf::OutputQueueable,
+        qbuf::{get_free::GetFreeCaptureBuffer, get_indexed::GetCaptureBufferByIndex},
+        BuffersAllocated, FormatBuilder, Queue,
+    },
+    memory::{DmaBufHandle, MemoryType, MmapHandle, PrimitiveBufferHandles, UserPtrHandle},
     PixelFormat, PlaneLayout, Rect,
 };
 
-use crate::memory::{
-    v4l2r_video_frame, v4l2r_video_frame_provider, v4l2r_video_frame_provider_queue_frame,
-    DmaBufFd, VideoFrameMemoryType,
+use crate::{
+    memory::{
+        v4l2r_memory_type, v4l2r_video_frame, v4l2r_video_frame_provider, DmaBufFd, VideoFrame,
+        VideoFrameMemory, VideoFrameProvider,
+    },
+    status::{fail, path_from_c_str, v4l2r_status},
 };
 
-type DynCbDecoder = Decoder<
+type DynCbDecoder<H> = Decoder<
     Decoding<
         Vec<DmaBufHandle<DmaBufFd>>,
-        Arc<v4l2r_video_frame_provider>,
+        VideoFrameProvider<H>,
         Box<dyn InputDoneCallback<Vec<DmaBufHandle<DmaBufFd>>>>,
-        Box<dyn DecoderEventCallback<Arc<v4l2r_video_frame_provider>>>,
-        Box<dyn FormatChangedCallback<Arc<v4l2r_video_frame_provider>>>,
+        Box<dyn DecoderEventCallback<VideoFrameProvider<H>>>,
+        Box<dyn FormatChangedCallback<VideoFrameProvider<H>>>,
     >,
 >;
 
+/// Decoder for each of the memory types supported for decoded frames.
+enum DecoderInstance {
+    DmaBuf(DynCbDecoder<DmaBufHandle<DmaBufFd>>),
+    Mmap(DynCbDecoder<MmapHandle>),
+    UserPtr(DynCbDecoder<UserPtrHandle<Vec<u8>>>),
+}
+
+/// Evaluate `$body` with `$d` bound to the decoder of `$instance`, whatever the
+/// memory type of its frames.
+macro_rules! with_decoder {
+    ($instance:expr, $d:ident => $body:expr) => {
+        match $instance {
+            DecoderInstance::DmaBuf($d) => $body,
+            DecoderInstance::Mmap($d) => $body,
+            DecoderInstance::UserPtr($d) => $body,
+        }
+    };
+}
+
+/// Number of frames allocated in addition to the minimum required by the
+/// decoder when using MMAP memory, so the client can hold a few frames without
+/// starving it.
+const NUM_EXTRA_MMAP_FRAMES: usize = 4;
+
 /// A V4L2 decoder instance.
 pub struct v4l2r_decoder {
-    decoder: DynCbDecoder,
+    decoder: DecoderInstance,
     // Reference to the video frame provider for our callbacks.
     provider: Option<Arc<v4l2r_video_frame_provider>>,
     // Keep the size of input buffers at hand.
@@ -105,7 +141,10 @@ pub struct v4l2r_decoder_format_changed_event {
     ///
     /// The client is responsible for allocating video frames in the new format
     /// and start giving them to the new provider using
-    /// [`v4l2r_video_frame_provider_queue_frame`].
+    /// [`v4l2r_video_frame_provider_queue_frame`]. If the decoder uses MMAP
+    /// memory, the provider already holds the frames allocated by the device
+    /// and the client must only give back the frames it receives in
+    /// `FrameDecoded` events.
     ///
     /// [`v4l2r_video_frame_provider_queue_frame`]:
     /// crate::memory::v4l2r_video_frame_provider_queue_frame
@@ -128,13 +167,20 @@ pub enum v4l2r_decoder_event {
     FrameDecoded(v4l2r_decoder_frame_decoded_event),
     FormatChanged(v4l2r_decoder_format_changed_event),
     EndOfStream,
+    /// Emitted instead of `FrameDecoded` when the driver reported an error
+    /// while decoding the frame. Its content may be corrupted, but the frame
+    /// must still be returned to the provider once the client is done with it.
+    CorruptedFrame(v4l2r_decoder_frame_decoded_event),
+    /// Emitted when the decoder encountered an unrecoverable error. No further
+    /// event will be emitted, and the decoder should be destroyed.
+    Died,
 }
 
 /// Events callback. This callback is guaranteed to always be called from the
 /// same thread, i.e. events are completely sequential.
 pub type v4l2r_decoder_event_cb = extern "C" fn(*mut c_void, *mut v4l2r_decoder_event);
 
-fn set_capture_format_cb(
+fn set_capture_format_cb<H: VideoFrameMemory>(
     f: FormatBuilder,
     desired_pixel_format: Option<PixelFormat>,
     visible_rect: Rect,
@@ -142,7 +188,7 @@ fn set_capture_format_cb(
     decoder: *mut v4l2r_decoder,
     event_cb: v4l2r_decoder_event_cb,
     cb_data: *mut c_void,
-) -> anyhow::Result<FormatChangedReply<Arc<v4l2r_video_frame_provider>>> {
+) -> anyhow::Result<FormatChangedReply<VideoFrameProvider<H>>> {
     // Safe unless the C part did something funny with the decoder returned by
     // `v4l2r_decoder_new`.
     let decoder = unsafe { decoder.as_mut().unwrap() };
@@ -151,8 +197,33 @@ fn set_capture_format_cb(
         None => f.apply()?,
     };
 
+    let mem_type = VideoFrame::<H>::MEMORY_TYPE;
+    let num_buffers = match mem_type {
+        // MMAP buffers are allocated by the device, so only allocate what we
+        // need.
+        MemoryType::Mmap => min_num_buffers + NUM_EXTRA_MMAP_FRAMES,
+        // Since we are using DMABUF or USERPTR, always allocate the maximum
+        // number of V4L2 buffers (32) since they are virtually free. This gives
+        // more flexibility for the client as to how many frames it can
+        // allocate.
+        _ => bindings::VIDEO_MAX_FRAME as usize,
+    };
+
     // Create new memory provider on the heap and update our internal pointer.
     let new_provider = Arc::new(v4l2r_video_frame_provider::new());
+    // MMAP frames are not allocated by the client, so make them available
+    // right away, with the index of their V4L2 buffer as identifier.
+    if let MemoryType::Mmap = mem_type {
+        // Safe because the decoder uses the multi-planar API.
+        let num_planes = unsafe { v4l2_format.fmt.pix_mp.num_planes } as usize;
+        for id in 0..num_buffers {
+            new_provider.queue_frame(v4l2r_video_frame {
+                id: id as u32,
+                num_planes,
+                ..Default::default()
+            });
+        }
+    }
     // Reference for our own callbacks.
     decoder.provider = Some(Arc::clone(&new_provider));
 
@@ -172,24 +243,28 @@ fn set_capture_format_cb(
     );
 
     Ok(FormatChangedReply {
-        provider: new_provider,
-        // TODO: can't the provider report the memory type that it is
-        // actually serving itself?
-        mem_type: VideoFrameMemoryType,
-        // Since we are using DMABUF, always allocate the maximum number of
-        // V4L2 buffers (32) since they are virtually free. This gives more
-        // flexibility for the client as to how many frames it can allocate.
-        num_buffers: bindings::VIDEO_MAX_FRAME as usize,
+        provider: VideoFrameProvider::new(new_provider),
+        mem_type,
+        num_buffers,
     })
 }
 
-fn frame_decoded_cb(
+fn frame_decoded_cb<H: VideoFrameMemory>(
     decoder: &mut v4l2r_decoder,
-    mut dqbuf: DqBuffer<Capture, v4l2r_video_frame>,
+    mut dqbuf: DqBuffer<Capture, VideoFrame<H>>,
+    corrupted: bool,
     event_cb: v4l2r_decoder_event_cb,
     cb_data: *mut c_void,
 ) {
-    let frame = dqbuf.take_handles().unwrap();
+    let mut frame = dqbuf.take_handles().unwrap().into_frame();
+    let provider = match &decoder.provider {
+        Some(provider) => provider,
+        None => {
+            error!("Frame decoded callback called while no provider set!");
+            return;
+        }
+    };
+    H::frame_decoded(provider, &dqbuf, &mut frame);
     debug!(
         "Video frame {} ({}) decoded from V4L2 buffer {} (flags: {:?})",
         frame.id,
@@ -204,30 +279,26 @@ fn frame_decoded_cb(
 
     // Immediately recycle empty frames. We will pass the corresponding
     // event to the client.
-    if v4l2_data.get_first_plane().bytesused() == 0 {
+    if v4l2_data.get_first_plane().payload_size() == 0 {
         debug!(
             "Immediately recycling zero-sized frame {} {}",
             frame.id,
             v4l2_data.is_last()
         );
-        // Should be safe as `provider` is initialized in the format
-        // change callback and is thus valid, as well as `frame`.
-        match &decoder.provider {
-            Some(provider) => unsafe {
-                v4l2r_video_frame_provider_queue_frame(provider.as_ref(), frame);
-            },
-            None => {
-                error!("Frame decoded callback called while no provider set!");
-            }
-        }
+        provider.queue_frame(frame);
     } else {
+        let event = v4l2r_decoder_frame_decoded_event {
+            buffer: v4l2_data.as_raw_v4l2_buffer(),
+            frame,
+        };
         // TODO check return value?
         event_cb(
             cb_data,
-            &mut v4l2r_decoder_event::FrameDecoded(v4l2r_decoder_frame_decoded_event {
-                buffer: v4l2_data.as_raw_v4l2_buffer(),
-                frame,
-            }),
+            &mut if corrupted {
+                v4l2r_decoder_event::CorruptedFrame(event)
+            } else {
+                v4l2r_decoder_event::FrameDecoded(event)
+            },
         );
     }
 }
@@ -244,6 +315,81 @@ impl<T> Copy for SendablePtr<T> {}
 unsafe impl<T> Send for SendablePtr<T> {}
 unsafe impl<T> Sync for SendablePtr<T> {}
 
+// Start `decoder` with frames backed by memory of type `H`.
+fn start_decoder<H: VideoFrameMemory>(
+    decoder: Decoder<ReadyToDecode<Vec<DmaBufHandle<DmaBufFd>>>>,
+    decoder_ptr: SendablePtr<v4l2r_decoder>,
+    output_format: Option<PixelFormat>,
+    input_done_cb: v4l2r_decoder_input_done_cb,
+    event_cb: v4l2r_decoder_event_cb,
+    cb_data: SendablePtr<c_void>,
+) -> Result<DynCbDecoder<H>, v4l2r_status>
+where
+    for<'a> Queue<Capture, BuffersAllocated<VideoFrame<H>>>:
+        GetFreeCaptureBuffer<'a, VideoFrame<H>> + GetCaptureBufferByIndex<'a, VideoFrame<H>>,
+{
+    decoder
+        .start(
+            Box::new(
+                move |buf: CompletedInputBuffer<Vec<DmaBufHandle<DmaBufFd>>>| {
+                    match buf {
+                        CompletedInputBuffer::Dequeued(dqbuf) => {
+                            debug!("Input buffer {} done", dqbuf.data.index());
+                            // TODO check return value?
+                            input_done_cb(cb_data.0, dqbuf.data.as_raw_v4l2_buffer());
+                        }
+                        // Just drop canceled buffers for now - the client will remove
+                        // them on its side as well.
+                        // TODO add a status parameter to the callback and invoke it?
+                        // that way the client does not need to clear its own list...
+                        CompletedInputBuffer::Canceled(_) => (),
+                    }
+                },
+            ) as Box<dyn InputDoneCallback<Vec<DmaBufHandle<DmaBufFd>>>>,
+            Box::new(move |event: DecoderEvent<VideoFrameProvider<H>>| {
+                let decoder = unsafe { decoder_ptr.0.as_mut().unwrap() };
+
+                match event {
+                    DecoderEvent::FrameDecoded(frame) => {
+                        frame_decoded_cb(decoder, frame.into_buffer(), false, event_cb, cb_data.0)
+                    }
+                    DecoderEvent::EndOfStream => {
+                        event_cb(cb_data.0, &mut v4l2r_decoder_event::EndOfStream)
+                    }
+                    DecoderEvent::CorruptedFrame(frame) => {
+                        frame_decoded_cb(decoder, frame.into_buffer(), true, event_cb, cb_data.0)
+                    }
+                    DecoderEvent::Died(e) => {
+                        error!("Decoder {:p} died: {}", decoder, e);
+                        event_cb(cb_data.0, &mut v4l2r_decoder_event::Died)
+                    }
+                };
+            }) as Box<dyn DecoderEventCallback<VideoFrameProvider<H>>>,
+            Box::new(
+                move |f: FormatBuilder,
+                      visible_rect: Rect,
+                      min_num_buffers: usize|
+                      -> anyhow::Result<FormatChangedReply<VideoFrameProvider<H>>> {
+                    set_capture_format_cb(
+                        f,
+                        output_format,
+                        visible_rect,
+                        min_num_buffers,
+                        decoder_ptr.0,
+                        event_cb,
+                        cb_data.0,
+                    )
+                },
+            ) as Box<dyn FormatChangedCallback<VideoFrameProvider<H>>>,
+        )
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Cannot start decoder: {}", e),
+            )
+        })
+}
+
 #[allow(clippy::too_many_arguments)]
 fn v4l2r_decoder_new_safe(
     path: &Path,
@@ -251,17 +397,21 @@ fn v4l2r_decoder_new_safe(
     num_input_buffers: usize,
     input_buffer_size: usize,
     output_format_fourcc: u32,
+    capture_memory_type: v4l2r_memory_type,
     input_done_cb: v4l2r_decoder_input_done_cb,
     event_cb: v4l2r_decoder_event_cb,
     cb_data: *mut c_void,
-) -> *mut v4l2r_decoder {
-    let decoder = match Decoder::open(path) {
-        Ok(decoder) => decoder,
-        Err(e) => {
-            error!("failed to open decoder {}: {:#?}", path.display(), e);
-            return std::ptr::null_mut();
-        }
-    };
+) -> Result<*mut v4l2r_decoder, v4l2r_status> {
+    let decoder = Decoder::open(path).map_err(|e| {
+        let status = match e {
+            DecoderOpenError::NotAStatefulDecoder => v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+            _ => v4l2r_status::V4L2R_STATUS_OPEN_FAILED,
+        };
+        fail(
+            status,
+            format!("failed to open decoder {}: {}", path.display(), e),
+        )
+    })?;
 
     info!(
         "Opened decoder {} with format {}, {} input buffers of size {}",
@@ -271,37 +421,38 @@ fn v4l2r_decoder_new_safe(
         input_buffer_size
     );
 
-    let decoder = match decoder.set_output_format(|f| {
-        let pixel_format = input_format_fourcc.into();
-        let format = match f
-            .set_pixelformat(pixel_format)
-            .set_planes_layout(vec![PlaneLayout {
-                sizeimage: input_buffer_size as u32,
-                ..Default::default()
-            }])
-            .apply::<v4l2r::FormatConversionError, v4l2r::Format>()
-        {
-            Ok(format) if format.pixelformat == pixel_format => format,
-            Ok(_) => {
-                return Err(anyhow::anyhow!(
-                    "Unrecognized OUTPUT format {:?}",
-                    pixel_format
-                ))
-            }
-            Err(e) => return Err(e.into()),
-        };
-        debug!(
-            "Decoder requires input buffer size of: {}",
-            format.plane_fmt[0].sizeimage
-        );
-        Ok(())
-    }) {
-        Ok(decoder) => decoder,
-        Err(e) => {
-            error!("Error while setting output format: {}", e);
-            return std::ptr::null_mut();
-        }
-    };
+    let decoder = decoder
+        .set_output_format(|f| {
+            let pixel_format = input_format_fourcc.into();
+            let format = match f
+                .set_pixelformat(pixel_format)
+                .set_planes_layout(vec![PlaneLayout {
+                    sizeimage: input_buffer_size as u32,
+                    ..Default::default()
+                }])
+                .apply::<v4l2r::FormatConversionError, v4l2r::Format>()
+            {
+                Ok(format) if format.pixelformat == pixel_format => format,
+                Ok(_) => {
+                    return Err(anyhow::anyhow!(
+                        "Unrecognized OUTPUT format {:?}",
+                        pixel_format
+                    ))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            debug!(
+                "Decoder requires input buffer size of: {}",
+                format.plane_fmt[0].sizeimage
+            );
+            Ok(())
+        })
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+                format!("Error while setting output format: {}", e),
+            )
+        })?;
 
     let output_format = match output_format_fourcc {
         0 => None,
@@ -310,72 +461,54 @@ fn v4l2r_decoder_new_safe(
 
     let cb_data = SendablePtr(cb_data);
 
-    let decoder =
-        match decoder.allocate_output_buffers::<Vec<DmaBufHandle<DmaBufFd>>>(num_input_buffers) {
-            Ok(decoder) => decoder,
-            Err(e) => {
-                error!("Error while allocating OUTPUT buffers: {}", e);
-                return std::ptr::null_mut();
-            }
-        };
+    let decoder = decoder
+        .allocate_output_buffers::<Vec<DmaBufHandle<DmaBufFd>>>(num_input_buffers)
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Error while allocating OUTPUT buffers: {}", e),
+            )
+        })?;
 
     // Reserve memory on the heap for our decoder and take a pointer that we
     // can use in our callbacks.
     let mut decoder_box = Box::new(MaybeUninit::<v4l2r_decoder>::uninit());
     let decoder_ptr = SendablePtr(decoder_box.as_mut_ptr());
 
-    let decoder = match decoder.start(
-        Box::new(
-            move |buf: CompletedInputBuffer<Vec<DmaBufHandle<DmaBufFd>>>| {
-                match buf {
-                    CompletedInputBuffer::Dequeued(dqbuf) => {
-                        debug!("Input buffer {} done", dqbuf.data.index());
-                        // TODO check return value?
-                        input_done_cb(cb_data.0, dqbuf.data.as_raw_v4l2_buffer());
-                    }
-                    // Just drop canceled buffers for now - the client will remove
-                    // them on its side as well.
-                    // TODO add a status parameter to the callback and invoke it?
-                    // that way the client does not need to clear its own list...
-                    CompletedInputBuffer::Canceled(_) => (),
-                }
-            },
-        ) as Box<dyn InputDoneCallback<Vec<DmaBufHandle<DmaBufFd>>>>,
-        Box::new(move |event: DecoderEvent<Arc<v4l2r_video_frame_provider>>| {
-            let decoder = unsafe { decoder_ptr.0.as_mut().unwrap() };
-
-            match event {
-                DecoderEvent::FrameDecoded(dqbuf) => frame_decoded_cb(decoder, dqbuf, event_cb, cb_data.0),
-                DecoderEvent::EndOfStream =>
-                    event_cb(cb_data.0,
-                        &mut v4l2r_decoder_event::EndOfStream)
-            };
-        }) as Box<dyn DecoderEventCallback<Arc<v4l2r_video_frame_provider>>>,
-        Box::new(
-            move |f: FormatBuilder,
-                  visible_rect: Rect,
-                  min_num_buffers: usize|
-                  -> anyhow::Result<FormatChangedReply<Arc<v4l2r_video_frame_provider>>> {
-                set_capture_format_cb(
-                    f,
-                    output_format,
-                    visible_rect,
-                    min_num_buffers,
-                    decoder_ptr.0,
-                    event_cb,
-                    cb_data.0,
-                )
-            },
-        ) as Box<dyn FormatChangedCallback<Arc<v4l2r_video_frame_provider>>>,
-    ) {
-        Ok(decoder) => decoder,
-        Err(e) => {
-            error!("Cannot start decoder: {}", e);
-            return std::ptr::null_mut();
-        }
+    let decoder = match capture_memory_type {
+        v4l2r_memory_type::V4L2R_MEMORY_TYPE_DMABUF => DecoderInstance::DmaBuf(start_decoder(
+            decoder,
+            decoder_ptr,
+            output_format,
+            input_done_cb,
+            event_cb,
+            cb_data,
+        )?),
+        v4l2r_memory_type::V4L2R_MEMORY_TYPE_MMAP => DecoderInstance::Mmap(start_decoder(
+            decoder,
+            decoder_ptr,
+            output_format,
+            input_done_cb,
+            event_cb,
+            cb_data,
+        )?),
+        v4l2r_memory_type::V4L2R_MEMORY_TYPE_USERPTR => DecoderInstance::UserPtr(start_decoder(
+            decoder,
+            decoder_ptr,
+            output_format,
+            input_done_cb,
+            event_cb,
+            cb_data,
+        )?),
     };
 
-    let input_format: v4l2r::Format = decoder.get_output_format().unwrap();
+    let input_format: v4l2r::Format =
+        with_decoder!(&decoder, d => d.get_output_format()).map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Error while getting OUTPUT format: {}", e),
+            )
+        })?;
 
     let decoder = v4l2r_decoder {
         decoder,
@@ -395,7 +528,7 @@ fn v4l2r_decoder_new_safe(
 
     info!("Decoder {:p}: successfully started", decoder_box.as_ref());
 
-    Box::into_raw(decoder_box)
+    Ok(Box::into_raw(decoder_box))
 }
 
 fn v4l2r_decoder_decode_safe(
@@ -403,33 +536,32 @@ fn v4l2r_decoder_decode_safe(
     bitstream_id: i32,
     fd: c_int,
     bytes_used: usize,
-) -> c_int {
-    let v4l2_buffer = match decoder.decoder.get_buffer() {
-        Ok(buffer) => buffer,
-        Err(e) => {
-            error!("Error obtaining V4L2 buffer: {}", e);
-            return -1;
-        }
-    };
-    let v4l2_buffer_id = v4l2_buffer.index();
-
-    match v4l2_buffer
-        .set_timestamp(TimeVal::seconds(bitstream_id as i64))
-        .queue_with_handles(
-            vec![DmaBufHandle::from(DmaBufFd::new(
-                fd,
-                decoder.input_buf_size,
-            ))],
-            &[bytes_used],
-        ) {
-        Ok(()) => (),
-        Err(e) => {
-            error!("Error while queueing buffer: {}", e);
-            return -1;
-        }
-    };
+) -> Result<c_int, v4l2r_status> {
+    let input_buf_size = decoder.input_buf_size;
+    with_decoder!(&mut decoder.decoder, d => {
+        let v4l2_buffer = d.get_buffer().map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Error obtaining V4L2 buffer: {}", e),
+            )
+        })?;
+        let v4l2_buffer_id = v4l2_buffer.index();
+
+        v4l2_buffer
+            .set_timestamp(TimeVal::seconds(bitstream_id as i64))
+            .queue_with_handles(
+                vec![DmaBufHandle::from(DmaBufFd::new(fd, input_buf_size))],
+                &[bytes_used],
+            )
+            .map_err(|e| {
+                fail(
+                    v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                    format!("Error while queueing buffer: {}", e),
+                )
+            })?;
 
-    v4l2_buffer_id as c_int
+        Ok(v4l2_buffer_id as c_int)
+    })
 }
 
 /// Create a new decoder for a given encoded format.
@@ -446,6 +578,8 @@ fn v4l2r_decoder_decode_safe(
 /// * `output_format_fourcc` is the FOURCC code of the desired pixel format for
 ///   output frames (e.g. "NV12"). It can also be 0, in which case the decoder
 ///   will use whichever pixel format is active by default.
+/// * `capture_memory_type` is the type of memory backing the output frames.
+///   See [`v4l2r_memory_type`] for how frames are provided in each case.
 /// * `input_done_cb` is a pointer to a callback function to be called whenever
 ///   an encoded input buffer is done being processed. This callback is
 ///   guaranteed to be invoked during calls to [`v4l2r_decoder_decode`] or
@@ -459,10 +593,12 @@ fn v4l2r_decoder_decode_safe(
 ///   different threads).
 /// * `cb_data` is a pointer that will always be passed as the first parameter
 ///   of the `input_done_cb` and `events_cb`.
+/// * `decoder` receives the created decoder upon success.
 ///
 /// # Safety
 /// The passed `path` must be a valid, zero-terminated C string containining the
-/// path to the device. Expect a crash if passing an invalid string.
+/// path to the device. Expect a crash if passing an invalid string. `decoder`
+/// must point to valid memory that can receive a pointer.
 #[no_mangle]
 pub unsafe extern "C" fn v4l2r_decoder_new(
     path: *const c_char,
@@ -470,30 +606,47 @@ pub unsafe extern "C" fn v4l2r_decoder_new(
     num_input_buffers: usize,
     input_buffer_size: usize,
     output_format_fourcc: u32,
+    capture_memory_type: v4l2r_memory_type,
     input_done_cb: v4l2r_decoder_input_done_cb,
     event_cb: v4l2r_decoder_event_cb,
     cb_data: *mut c_void,
-) -> *mut v4l2r_decoder {
-    let cstr = CStr::from_ptr(path);
-    let rstr = cstr.to_str().unwrap();
-    let path = Path::new(&rstr);
+    decoder: *mut *mut v4l2r_decoder,
+) -> v4l2r_status {
+    if decoder.is_null() {
+        return fail(
+            v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT,
+            "NULL decoder pointer",
+        );
+    }
+    let path = match path_from_c_str(path) {
+        Ok(path) => path,
+        Err(status) => return status,
+    };
 
-    v4l2r_decoder_new_safe(
+    match v4l2r_decoder_new_safe(
         path,
         input_format_fourcc,
         num_input_buffers,
         input_buffer_size,
         output_format_fourcc,
+        capture_memory_type,
         input_done_cb,
         event_cb,
         cb_data,
-    )
+    ) {
+        Ok(new_decoder) => {
+            *decoder = new_decoder;
+            v4l2r_status::V4L2R_STATUS_OK
+        }
+        Err(status) => status,
+    }
 }
 
 /// Stop and destroy a decoder.
 ///
 /// Stop `decoder` and destroy it. This function DOES take ownership of
-/// `decoder`, which must absolutely not be used after this call.
+/// `decoder`, which must absolutely not be used after this call, even if an
+/// error is returned.
 ///
 /// It is guaranteed that none of the callbacks passed to [`v4l2r_decoder_new`]
 /// will be called after this function has returned.
@@ -501,21 +654,24 @@ pub unsafe extern "C" fn v4l2r_decoder_new(
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// `v4l2r_decoder_new`. Passing a NULL or invalid pointer will cause a crash.
+/// `v4l2r_decoder_new`. Passing an invalid pointer will cause a crash.
 /// `decoder` must not be used again after this function is called.
 #[no_mangle]
-pub unsafe extern "C" fn v4l2r_decoder_destroy(decoder: *mut v4l2r_decoder) {
+pub unsafe extern "C" fn v4l2r_decoder_destroy(decoder: *mut v4l2r_decoder) -> v4l2r_status {
     info!("Decoder {:p}: destroying", decoder);
 
     if decoder.is_null() {
         warn!("Trying to destroy a NULL decoder");
-        return;
+        return v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT;
     }
 
     let decoder = Box::from_raw(decoder);
-    match decoder.decoder.stop() {
-        Ok(_) => (),
-        Err(e) => error!("Error while stopping decoder: {}", e),
+    match with_decoder!(decoder.decoder, d => d.stop().map(|_| ())) {
+        Ok(_) => v4l2r_status::V4L2R_STATUS_OK,
+        Err(e) => fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error while stopping decoder: {}", e),
+        ),
     }
 }
 
@@ -525,8 +681,7 @@ pub unsafe extern "C" fn v4l2r_decoder_destroy(decoder: *mut v4l2r_decoder) {
 /// This function can be called at any time since a decoder always have a valid
 /// input format.
 ///
-/// Returns 0 in case of success, -1 if an error occured, in which case `format`
-/// is not overwritten.
+/// `format` is not overwritten if an error occurs.
 ///
 /// # Safety
 ///
@@ -536,22 +691,28 @@ pub unsafe extern "C" fn v4l2r_decoder_destroy(decoder: *mut v4l2r_decoder) {
 pub unsafe extern "C" fn v4l2r_decoder_get_input_format(
     decoder: *const v4l2r_decoder,
     format: *mut bindings::v4l2_format,
-) -> c_int {
-    assert!(!decoder.is_null());
-    assert!(!format.is_null());
-
-    let decoder = &*decoder;
-    let format = &mut *format;
+) -> v4l2r_status {
+    let (decoder, format) = match (decoder.as_ref(), format.as_mut()) {
+        (Some(decoder), Some(format)) => (decoder, format),
+        _ => {
+            return fail(
+                v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT,
+                "NULL decoder or format",
+            )
+        }
+    };
 
-    *format = match decoder.decoder.get_output_format() {
+    *format = match with_decoder!(&decoder.decoder, d => d.get_output_format()) {
         Ok(format) => format,
         Err(e) => {
-            error!("Error while getting output format: {}", e);
-            return -1;
+            return fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Error while getting output format: {}", e),
+            )
         }
     };
 
-    0
+    v4l2r_status::V4L2R_STATUS_OK
 }
 
 /// Decode the encoded data referenced by `fd`.
@@ -563,35 +724,46 @@ pub unsafe extern "C" fn v4l2r_decoder_get_input_format(
 ///
 /// `bytes_used` is amount of encoded data within that buffer.
 ///
-/// The value returned is the index of the V4L2 buffer `fd` has been queued with.
-/// It can be used to know when `fd` is done being decoded as a `v4l2_buffer` of
-/// the same index will be passed as argument to the *input done callback* when
-/// this is the case.
-///
-/// In case of error, -1 is returned.
+/// Upon success, `buffer_index` receives the index of the V4L2 buffer `fd` has
+/// been queued with. It can be used to know when `fd` is done being decoded as
+/// a `v4l2_buffer` of the same index will be passed as argument to the *input
+/// done callback* when this is the case. `buffer_index` can be NULL if the
+/// client does not need this information.
 ///
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// [`v4l2r_decoder_new`]. Passing a NULL or invalid pointer will cause a crash.
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
 /// `fd` is expected to be a valid DMABUF FD backed by enough memory for the
 /// expected input buffer size. Failure to provide a valid FD will return in an
-/// ioctl error (but no crash).
+/// ioctl error (but no crash). `buffer_index` must be NULL or point to valid
+/// memory that can receive an `int`.
 #[no_mangle]
 pub unsafe extern "C" fn v4l2r_decoder_decode(
     decoder: *mut v4l2r_decoder,
     bitstream_id: i32,
     fd: c_int,
     bytes_used: usize,
-) -> c_int {
+    buffer_index: *mut c_int,
+) -> v4l2r_status {
     debug!(
         "Decoder {:p}: decoding bitstream id {}",
         decoder, bitstream_id
     );
-    assert!(!decoder.is_null());
-    let decoder = &mut *decoder;
+    let decoder = match decoder.as_mut() {
+        Some(decoder) => decoder,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL decoder"),
+    };
 
-    v4l2r_decoder_decode_safe(decoder, bitstream_id, fd, bytes_used)
+    match v4l2r_decoder_decode_safe(decoder, bitstream_id, fd, bytes_used) {
+        Ok(index) => {
+            if let Some(buffer_index) = buffer_index.as_mut() {
+                *buffer_index = index;
+            }
+            v4l2r_status::V4L2R_STATUS_OK
+        }
+        Err(status) => status,
+    }
 }
 
 /// Kick the decoder and see if some input buffers fall as a result.
@@ -614,72 +786,87 @@ pub unsafe extern "C" fn v4l2r_decoder_decode(
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// [`v4l2r_decoder_new`]. Passing a NULL or invalid pointer will cause a crash.
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
 #[no_mangle]
-pub unsafe extern "C" fn v4l2r_decoder_kick(decoder: *const v4l2r_decoder) {
-    assert!(!decoder.is_null());
-    let decoder = &*decoder;
+pub unsafe extern "C" fn v4l2r_decoder_kick(decoder: *const v4l2r_decoder) -> v4l2r_status {
+    let decoder = match decoder.as_ref() {
+        Some(decoder) => decoder,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL decoder"),
+    };
 
-    match decoder.decoder.kick() {
-        Ok(()) => (),
-        Err(e) => {
-            error!("Error while kicking decoder: {}", e);
-        }
+    match with_decoder!(&decoder.decoder, d => d.kick()) {
+        Ok(()) => v4l2r_status::V4L2R_STATUS_OK,
+        Err(e) => fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error while kicking decoder: {}", e),
+        ),
     }
 }
 
-/// Possible responses for the [`v4l2r_decoder_drain`] commmand.
-#[repr(C)]
-#[allow(clippy::upper_case_acronyms)]
-pub enum v4l2r_decoder_drain_response {
-    /// The drain has already completed as [`v4l2r_decoder_drain`] returned.
-    DRAIN_COMPLETED,
-    /// The drain has started but will be completed when we receive a
-    /// [`v4l2r_decoder_event::EndOfStream`] event.
-    DRAIN_STARTED,
-    /// Drain cannot be done at the moment because not enough input buffers
-    /// have been processed to know the output format.
-    TRY_AGAIN,
-    /// An error has occurred.
-    ERROR,
-}
-
+/// Drain the decoder, i.e. make sure all the encoded data queued so far is
+/// decoded and the corresponding frames emitted.
+///
+/// Upon success, `completed` is set to `true` if the drain has already
+/// completed as this function returned, or to `false` if it will be completed
+/// when we receive a [`v4l2r_decoder_event::EndOfStream`] event. `completed`
+/// can be NULL if `blocking` is `true`, since the drain is then always
+/// completed upon success.
+///
+/// `V4L2R_STATUS_TRY_AGAIN` is returned if the drain cannot be done at the
+/// moment because not enough input buffers have been processed to know the
+/// output format.
+///
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// [`v4l2r_decoder_new`]. Passing a NULL or invalid pointer will cause a crash.
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
+/// `completed` must be NULL or point to valid memory that can receive a
+/// `bool`.
 #[no_mangle]
 pub unsafe extern "C" fn v4l2r_decoder_drain(
     decoder: *const v4l2r_decoder,
     blocking: bool,
-) -> v4l2r_decoder_drain_response {
-    assert!(!decoder.is_null());
-    let decoder = &*decoder;
-
-    match decoder.decoder.drain(blocking) {
-        Ok(true) => v4l2r_decoder_drain_response::DRAIN_COMPLETED,
-        Ok(false) => v4l2r_decoder_drain_response::DRAIN_STARTED,
-        Err(DrainError::TryAgain) => v4l2r_decoder_drain_response::TRY_AGAIN,
-        Err(e) => {
-            error!("Error while draining decoder: {}", e);
-            v4l2r_decoder_drain_response::ERROR
+    completed: *mut bool,
+) -> v4l2r_status {
+    let decoder = match decoder.as_ref() {
+        Some(decoder) => decoder,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL decoder"),
+    };
+
+    match with_decoder!(&decoder.decoder, d => d.drain(blocking)) {
+        Ok(drain_completed) => {
+            if let Some(completed) = completed.as_mut() {
+                *completed = drain_completed;
+            }
+            v4l2r_status::V4L2R_STATUS_OK
         }
+        Err(DrainError::TryAgain) => fail(
+            v4l2r_status::V4L2R_STATUS_TRY_AGAIN,
+            "Cannot drain decoder before the output format is known",
+        ),
+        Err(e) => fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error while draining decoder: {}", e),
+        ),
     }
 }
 
 /// # Safety
 ///
 /// `decoder` must be a valid pointer to a decoder returned by
-/// [`v4l2r_decoder_new`]. Passing a NULL or invalid pointer will cause a crash.
+/// [`v4l2r_decoder_new`]. Passing an invalid pointer will cause a crash.
 #[no_mangle]
-pub unsafe extern "C" fn v4l2r_decoder_flush(decoder: *const v4l2r_decoder) {
-    assert!(!decoder.is_null());
-    let decoder = &*decoder;
+pub unsafe extern "C" fn v4l2r_decoder_flush(decoder: *const v4l2r_decoder) -> v4l2r_status {
+    let decoder = match decoder.as_ref() {
+        Some(decoder) => decoder,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL decoder"),
+    };
 
-    match decoder.decoder.flush() {
-        Ok(()) => (),
-        Err(e) => {
-            error!("Error while flushing decoder: {:#?}", e);
-        }
+    match with_decoder!(&decoder.decoder, d => d.flush()) {
+        Ok(()) => v4l2r_status::V4L2R_STATUS_OK,
+        Err(e) => fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error while flushing decoder: {:#?}", e),
+        ),
     }
 }
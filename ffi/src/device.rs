@@ -0,0 +1,249 @@
+//! Module for querying the capabilities of a V4L2 device.
+//!
+//! [`v4l2r_device_query_caps`] lists the formats supported by a memory-to-memory
+//! device, along with their frame sizes and codec profiles. This lets clients
+//! decide whether a device is suitable before creating a decoder or encoder
+//! from it. The returned capabilities must be released using
+//! [`v4l2r_device_caps_free`].
+#![allow(non_camel_case_types)]
+
+use std::{
+    os::raw::c_char,
+    ptr::{self, slice_from_raw_parts_mut},
+};
+use v4l2r::{
+    bindings,
+    device::{Device, DeviceConfig},
+    encoder::params::Codec,
+    ioctl::{self, Capabilities, FormatIterator, FrameSizeIterator},
+    PixelFormat, QueueType,
+};
+
+use crate::status::{fail, path_from_c_str, v4l2r_status};
+
+/// Range of frame sizes supported for a format.
+///
+/// Discrete frame sizes are reported as a range with identical minimum and
+/// maximum values.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct v4l2r_frame_size_range {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub step_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub step_height: u32,
+}
+
+impl From<ioctl::FrameSizeRange> for v4l2r_frame_size_range {
+    fn from(range: ioctl::FrameSizeRange) -> Self {
+        v4l2r_frame_size_range {
+            min_width: range.min_width,
+            max_width: range.max_width,
+            step_width: range.step_width,
+            min_height: range.min_height,
+            max_height: range.max_height,
+            step_height: range.step_height,
+        }
+    }
+}
+
+/// A format supported by one of the queues of a device.
+#[repr(C)]
+pub struct v4l2r_format_caps {
+    /// FOURCC code of the format.
+    pub pixelformat: u32,
+    /// `V4L2_FMT_FLAG_*` flags of the format.
+    pub flags: u32,
+    /// Number of entries in `frame_sizes`.
+    pub num_frame_sizes: usize,
+    /// Frame sizes supported for this format, or NULL if the device does not
+    /// report them.
+    pub frame_sizes: *mut v4l2r_frame_size_range,
+    /// Number of entries in `profiles`.
+    pub num_profiles: usize,
+    /// Codec profiles supported for this format, as values of the
+    /// `V4L2_CID_MPEG_VIDEO_*_PROFILE` control of the codec, or NULL if the
+    /// format is not a codec we know the profiles of.
+    pub profiles: *mut u32,
+}
+
+/// Capabilities of a memory-to-memory device.
+///
+/// Input formats are the formats of the *OUTPUT* queue, i.e. the encoded
+/// formats for a decoder and the raw formats for an encoder. Output formats
+/// are the formats of the *CAPTURE* queue.
+#[repr(C)]
+pub struct v4l2r_device_caps {
+    /// Number of entries in `input_formats`.
+    pub num_input_formats: usize,
+    pub input_formats: *mut v4l2r_format_caps,
+    /// Number of entries in `output_formats`.
+    pub num_output_formats: usize,
+    pub output_formats: *mut v4l2r_format_caps,
+}
+
+// Leak `v` into a raw array suitable for the C side, to be released with
+// `free_array`. Empty arrays are represented by NULL.
+fn into_array<T>(v: Vec<T>) -> (usize, *mut T) {
+    if v.is_empty() {
+        return (0, ptr::null_mut());
+    }
+
+    let len = v.len();
+    (len, Box::into_raw(v.into_boxed_slice()) as *mut T)
+}
+
+// Release an array created by `into_array`.
+//
+// Safe if `array` and `len` have been returned by `into_array`.
+unsafe fn free_array<T>(len: usize, array: *mut T) {
+    if !array.is_null() {
+        drop(Box::from_raw(slice_from_raw_parts_mut(array, len)));
+    }
+}
+
+// Returns the values of the profile control of `pixelformat` that are
+// supported by `device`.
+fn query_profiles(device: &Device, pixelformat: PixelFormat) -> Vec<u32> {
+    let id = match Codec::from_pixelformat(pixelformat) {
+        Some(Codec::H264) => bindings::V4L2_CID_MPEG_VIDEO_H264_PROFILE,
+        Some(Codec::Hevc) => bindings::V4L2_CID_MPEG_VIDEO_HEVC_PROFILE,
+        Some(Codec::Vp8) => bindings::V4L2_CID_MPEG_VIDEO_VP8_PROFILE,
+        Some(Codec::Vp9) => bindings::V4L2_CID_MPEG_VIDEO_VP9_PROFILE,
+        None => return Vec::new(),
+    };
+    let qctrl = match ioctl::query_ext_ctrl(device, id) {
+        Ok(qctrl) => qctrl,
+        Err(_) => return Vec::new(),
+    };
+
+    (qctrl.minimum..=qctrl.maximum)
+        .map(|value| value as u32)
+        .filter(|&value| ioctl::querymenu(device, id, value).is_ok())
+        .collect()
+}
+
+fn query_formats(device: &Device, queue: QueueType) -> Vec<v4l2r_format_caps> {
+    FormatIterator::new(device, queue)
+        .map(|fmtdesc| {
+            let (num_frame_sizes, frame_sizes) = into_array(
+                FrameSizeIterator::new(device, fmtdesc.pixelformat)
+                    .map(|frame_size| frame_size.range().into())
+                    .collect(),
+            );
+            let (num_profiles, profiles) = into_array(query_profiles(device, fmtdesc.pixelformat));
+
+            v4l2r_format_caps {
+                pixelformat: fmtdesc.pixelformat.into(),
+                flags: fmtdesc.flags.bits(),
+                num_frame_sizes,
+                frame_sizes,
+                num_profiles,
+                profiles,
+            }
+        })
+        .collect()
+}
+
+/// Query the formats supported by the device at `path`, along with their frame
+/// sizes and codec profiles, and write them into `caps`.
+///
+/// The arrays of `caps` are allocated by this function and must be released
+/// using [`v4l2r_device_caps_free`].
+///
+/// # Safety
+///
+/// `path` must be a valid, zero-terminated C string containing the path to the
+/// device. `caps` must point to valid memory that can receive a
+/// [`v4l2r_device_caps`].
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_device_query_caps(
+    path: *const c_char,
+    caps: *mut v4l2r_device_caps,
+) -> v4l2r_status {
+    let path = match path_from_c_str(path) {
+        Ok(path) => path,
+        Err(status) => return status,
+    };
+    let caps = match caps.as_mut() {
+        Some(caps) => caps,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL caps"),
+    };
+
+    let device = match Device::open(path, DeviceConfig::new()) {
+        Ok(device) => device,
+        Err(e) => {
+            return fail(
+                v4l2r_status::V4L2R_STATUS_OPEN_FAILED,
+                format!("failed to open device {}: {}", path.display(), e),
+            )
+        }
+    };
+
+    let capabilities = device
+        .capability
+        .device_caps
+        .unwrap_or(device.capability.capabilities);
+    let (output_queue, capture_queue) = if capabilities.contains(Capabilities::VIDEO_M2M_MPLANE) {
+        (QueueType::VideoOutputMplane, QueueType::VideoCaptureMplane)
+    } else if capabilities.contains(Capabilities::VIDEO_M2M) {
+        (QueueType::VideoOutput, QueueType::VideoCapture)
+    } else {
+        return fail(
+            v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+            format!("{} is not a memory-to-memory device", path.display()),
+        );
+    };
+
+    let (num_input_formats, input_formats) = into_array(query_formats(&device, output_queue));
+    let (num_output_formats, output_formats) = into_array(query_formats(&device, capture_queue));
+
+    *caps = v4l2r_device_caps {
+        num_input_formats,
+        input_formats,
+        num_output_formats,
+        output_formats,
+    };
+
+    v4l2r_status::V4L2R_STATUS_OK
+}
+
+/// Release the arrays of `caps`, which is then reset to an empty list of
+/// formats.
+///
+/// # Safety
+///
+/// `caps` must have been filled by a successful call to
+/// [`v4l2r_device_query_caps`], and not have been modified since.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_device_caps_free(caps: *mut v4l2r_device_caps) -> v4l2r_status {
+    let caps = match caps.as_mut() {
+        Some(caps) => caps,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL caps"),
+    };
+
+    for (len, formats) in [
+        (caps.num_input_formats, caps.input_formats),
+        (caps.num_output_formats, caps.output_formats),
+    ] {
+        if formats.is_null() {
+            continue;
+        }
+        for format in std::slice::from_raw_parts(formats, len) {
+            free_array(format.num_frame_sizes, format.frame_sizes);
+            free_array(format.num_profiles, format.profiles);
+        }
+        free_array(len, formats);
+    }
+
+    *caps = v4l2r_device_caps {
+        num_input_formats: 0,
+        input_formats: ptr::null_mut(),
+        num_output_formats: 0,
+        output_formats: ptr::null_mut(),
+    };
+
+    v4l2r_status::V4L2R_STATUS_OK
+}
@@ -0,0 +1,890 @@
+//! Module for creating and controlling V4L2 encoders.
+//!
+//! Encoders are created using [`v4l2r_encoder_new`] and remain active until
+//! being given to [`v4l2r_encoder_destroy`]. They expect to be fed raw frames
+//! in the format specified at creation time using [`v4l2r_encoder_encode`].
+//!
+//! Encoders communicate with the client using an event callback that is invoked
+//! on a dedicated thread. This callback signals events of interest, like an
+//! encoded packet being available, or the end of a drain sequence.
+#![allow(non_camel_case_types)]
+
+use log::{debug, error, info, warn};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::{
+    os::raw::{c_char, c_int, c_void},
+    path::Path,
+};
+use v4l2r::{
+    bindings,
+    device::queue::{handles_provider::MmapProvider, qbuf::OutputQueueable},
+    encoder::{
+        packet::EncodedPacket,
+        params::{
+            BitrateMode, DynamicParams, EncoderParams, EncoderParamsError, HeaderMode, QpRange,
+        },
+        CompletedOutputBuffer, DrainError, Encoder, EncoderEvent, EncoderEventCallback,
+        EncoderOpenError, Encoding,
+    },
+    memory::DmaBufHandle,
+    Format, PixelFormat, PlaneLayout,
+};
+
+use crate::{
+    memory::{v4l2r_video_frame, DmaBufFd},
+    status::{fail, path_from_c_str, v4l2r_status},
+};
+
+type InputDoneCb = Box<dyn Fn(CompletedOutputBuffer<Vec<DmaBufHandle<DmaBufFd>>>)>;
+
+type DynCbEncoder = Encoder<
+    Encoding<
+        Vec<DmaBufHandle<DmaBufFd>>,
+        MmapProvider,
+        InputDoneCb,
+        Box<dyn EncoderEventCallback<MmapProvider>>,
+    >,
+>;
+
+/// A V4L2 encoder instance.
+pub struct v4l2r_encoder {
+    encoder: DynCbEncoder,
+    // Size of each plane of the input frames.
+    input_plane_sizes: Vec<u64>,
+}
+
+/// Callback called when the encoder is done with a frame submitted using
+/// [`v4l2r_encoder_encode`].
+///
+/// The first argument is the `cb_data` pointer given to [`v4l2r_encoder_new`].
+/// The second argument is the dequeued V4L2 buffer. The client can use the
+/// `timestamp.tv_sec` member of `buffer` to match this buffer with the
+/// `bitstream_id` parameter of [`v4l2r_encoder_encode`] and understand which
+/// frame can be reused.
+///
+/// This callback is only called during calls to [`v4l2r_encoder_encode`] and
+/// [`v4l2r_encoder_kick`].
+pub type v4l2r_encoder_input_done_cb = extern "C" fn(*mut c_void, *const bindings::v4l2_buffer);
+
+/// Bitrate control modes that can be selected using [`v4l2r_encoder_params`].
+#[repr(C)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy)]
+pub enum v4l2r_encoder_bitrate_mode {
+    /// Keep the mode currently selected by the driver.
+    BITRATE_MODE_DEFAULT,
+    BITRATE_MODE_VBR,
+    BITRATE_MODE_CBR,
+    BITRATE_MODE_CONSTANT_QUALITY,
+}
+
+/// Ways of producing the stream headers that can be selected using
+/// [`v4l2r_encoder_params`].
+#[repr(C)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy)]
+pub enum v4l2r_encoder_header_mode {
+    /// Keep the mode currently selected by the driver.
+    HEADER_MODE_DEFAULT,
+    /// Headers are returned in their own packet.
+    HEADER_MODE_SEPARATE,
+    /// Headers are returned together with the first encoded frame.
+    HEADER_MODE_JOINED_WITH_FIRST_FRAME,
+}
+
+/// Encoding parameters applied when creating the encoder.
+///
+/// Integer members set to a negative value, as well as enum members set to
+/// their `DEFAULT` value, leave the current setting of the driver unchanged.
+/// Use [`v4l2r_encoder_default_params`] to obtain a structure with all members
+/// unset.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct v4l2r_encoder_params {
+    /// Average bitrate, in bits per second.
+    pub bitrate: i32,
+    /// Peak bitrate in bits per second, used in VBR mode.
+    pub peak_bitrate: i32,
+    pub bitrate_mode: v4l2r_encoder_bitrate_mode,
+    /// Quality to target in constant quality mode, from 1 (smallest output) to
+    /// 100 (best quality).
+    pub constant_quality: i32,
+    /// Distance between two key frames.
+    pub gop_size: i32,
+    /// Number of B-frames between two reference frames.
+    pub b_frames: i32,
+    /// Range of quantization parameters the encoder is allowed to use. Both
+    /// members must be set for the range to be applied.
+    pub min_qp: i32,
+    pub max_qp: i32,
+    pub header_mode: v4l2r_encoder_header_mode,
+    /// Frame rate of the stream, as `framerate_numerator / framerate_denominator`
+    /// frames per second. Both members must be set for the frame rate to be
+    /// applied.
+    pub framerate_numerator: i32,
+    pub framerate_denominator: i32,
+}
+
+impl Default for v4l2r_encoder_params {
+    fn default() -> Self {
+        v4l2r_encoder_params {
+            bitrate: -1,
+            peak_bitrate: -1,
+            bitrate_mode: v4l2r_encoder_bitrate_mode::BITRATE_MODE_DEFAULT,
+            constant_quality: -1,
+            gop_size: -1,
+            b_frames: -1,
+            min_qp: -1,
+            max_qp: -1,
+            header_mode: v4l2r_encoder_header_mode::HEADER_MODE_DEFAULT,
+            framerate_numerator: -1,
+            framerate_denominator: -1,
+        }
+    }
+}
+
+// Returns `value` if it is set, i.e. not negative.
+fn opt_param(value: i32) -> Option<u32> {
+    if value < 0 {
+        None
+    } else {
+        Some(value as u32)
+    }
+}
+
+impl From<&v4l2r_encoder_params> for EncoderParams {
+    fn from(params: &v4l2r_encoder_params) -> Self {
+        EncoderParams {
+            bitrate: opt_param(params.bitrate),
+            peak_bitrate: opt_param(params.peak_bitrate),
+            bitrate_mode: match params.bitrate_mode {
+                v4l2r_encoder_bitrate_mode::BITRATE_MODE_DEFAULT => None,
+                v4l2r_encoder_bitrate_mode::BITRATE_MODE_VBR => Some(BitrateMode::Vbr),
+                v4l2r_encoder_bitrate_mode::BITRATE_MODE_CBR => Some(BitrateMode::Cbr),
+                v4l2r_encoder_bitrate_mode::BITRATE_MODE_CONSTANT_QUALITY => {
+                    Some(BitrateMode::ConstantQuality)
+                }
+            },
+            constant_quality: opt_param(params.constant_quality),
+            gop_size: opt_param(params.gop_size),
+            b_frames: opt_param(params.b_frames),
+            qp_range: opt_param(params.min_qp)
+                .zip(opt_param(params.max_qp))
+                .map(|(min, max)| QpRange { min, max }),
+            header_mode: match params.header_mode {
+                v4l2r_encoder_header_mode::HEADER_MODE_DEFAULT => None,
+                v4l2r_encoder_header_mode::HEADER_MODE_SEPARATE => Some(HeaderMode::Separate),
+                v4l2r_encoder_header_mode::HEADER_MODE_JOINED_WITH_FIRST_FRAME => {
+                    Some(HeaderMode::JoinedWithFirstFrame)
+                }
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// Parameters that can be changed while encoding using
+/// [`v4l2r_encoder_set_dynamic_params`].
+///
+/// Integer members set to a negative value leave the current setting unchanged.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct v4l2r_encoder_dynamic_params {
+    /// Average bitrate, in bits per second.
+    pub bitrate: i32,
+    /// Peak bitrate in bits per second, used in VBR mode.
+    pub peak_bitrate: i32,
+    /// Encode the next frame passed to [`v4l2r_encoder_encode`] as a key frame.
+    pub force_key_frame: bool,
+}
+
+impl From<&v4l2r_encoder_dynamic_params> for DynamicParams {
+    fn from(params: &v4l2r_encoder_dynamic_params) -> Self {
+        DynamicParams {
+            bitrate: opt_param(params.bitrate),
+            peak_bitrate: opt_param(params.peak_bitrate),
+            force_key_frame: params.force_key_frame,
+        }
+    }
+}
+
+#[repr(C)]
+pub struct v4l2r_encoder_frame_encoded_event {
+    /// Dequeued V4L2 buffer containing the encoded data. Useful to check for
+    /// flags and errors.
+    buffer: *const bindings::v4l2_buffer,
+    /// `bitstream_id` of the frame this packet has been encoded from, as
+    /// passed to [`v4l2r_encoder_encode`].
+    bitstream_id: i32,
+    /// Encoded data. Only valid until the event callback returns, so the
+    /// client must copy it if it needs to keep it around.
+    data: *const u8,
+    /// Size in bytes of the encoded data pointed to by `data`.
+    size: usize,
+    /// Whether this packet is a key frame, i.e. decoding can start from it.
+    is_keyframe: bool,
+    /// Whether the driver reported an error while encoding this packet. The
+    /// data may be corrupted.
+    has_error: bool,
+}
+
+/// Encoding-related events. These events can be produced at any time between
+/// calls to [`v4l2r_encoder_new`] and [`v4l2r_encoder_destroy`] and are passed
+/// to the events callback.
+///
+/// cbindgen:prefix-with-name
+#[repr(C)]
+pub enum v4l2r_encoder_event {
+    FrameEncoded(v4l2r_encoder_frame_encoded_event),
+    /// Emitted after the last packet of a drain sequence started with
+    /// [`v4l2r_encoder_drain`].
+    EndOfStream,
+    /// Emitted when the encoder encountered an unrecoverable error. No further
+    /// event will be emitted, and the encoder should be destroyed.
+    Died,
+}
+
+/// Events callback. This callback is guaranteed to always be called from the
+/// same thread, i.e. events are completely sequential.
+pub type v4l2r_encoder_event_cb = extern "C" fn(*mut c_void, *mut v4l2r_encoder_event);
+
+// A void pointer that can be sent across threads, so the callbacks can pass it
+// to the client.
+struct SendablePtr<T>(*mut T);
+impl<T> Clone for SendablePtr<T> {
+    fn clone(&self) -> Self {
+        SendablePtr(self.0)
+    }
+}
+impl<T> Copy for SendablePtr<T> {}
+unsafe impl<T> Send for SendablePtr<T> {}
+unsafe impl<T> Sync for SendablePtr<T> {}
+
+fn frame_encoded_cb(
+    packet: EncodedPacket<MmapProvider>,
+    event_cb: v4l2r_encoder_event_cb,
+    cb_data: *mut c_void,
+) {
+    let buffer = packet.buffer();
+    let bytes_used = packet.bytes_used();
+    debug!(
+        "Packet of {} bytes encoded from frame {} into V4L2 buffer {} (flags: {:?})",
+        bytes_used,
+        buffer.data.timestamp().tv_sec,
+        buffer.data.index(),
+        buffer.data.flags(),
+    );
+
+    // Empty buffers carry no data and are just returned to the encoder.
+    if bytes_used == 0 {
+        return;
+    }
+
+    let mapping = match packet.get_mapping() {
+        Some(mapping) => mapping,
+        None => {
+            error!(
+                "Failed to map encoded buffer {}, dropping it",
+                buffer.data.index()
+            );
+            return;
+        }
+    };
+    let data = &mapping.as_ref()[..std::cmp::min(bytes_used, mapping.len())];
+
+    // TODO check return value?
+    event_cb(
+        cb_data,
+        &mut v4l2r_encoder_event::FrameEncoded(v4l2r_encoder_frame_encoded_event {
+            buffer: buffer.data.as_raw_v4l2_buffer(),
+            bitstream_id: buffer.data.timestamp().tv_sec as i32,
+            data: data.as_ptr(),
+            size: data.len(),
+            is_keyframe: packet.is_keyframe(),
+            has_error: packet.has_error(),
+        }),
+    );
+}
+
+// Returns the status matching a failure to apply encoder parameters.
+fn params_error_status(e: &EncoderParamsError) -> v4l2r_status {
+    match e {
+        EncoderParamsError::UnsupportedControl(_)
+        | EncoderParamsError::OutOfRange { .. }
+        | EncoderParamsError::UnsupportedValue { .. }
+        | EncoderParamsError::CodecMismatch { .. } => v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+        _ => v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn v4l2r_encoder_new_safe(
+    path: &Path,
+    input_format_fourcc: u32,
+    output_format_fourcc: u32,
+    width: usize,
+    height: usize,
+    num_input_buffers: usize,
+    num_output_buffers: usize,
+    output_buffer_size: usize,
+    params: Option<&v4l2r_encoder_params>,
+    input_done_cb: v4l2r_encoder_input_done_cb,
+    event_cb: v4l2r_encoder_event_cb,
+    cb_data: *mut c_void,
+) -> Result<*mut v4l2r_encoder, v4l2r_status> {
+    let encoder = Encoder::open(path).map_err(|e| {
+        let status = match e {
+            EncoderOpenError::NotAnEncoder => v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+            _ => v4l2r_status::V4L2R_STATUS_OPEN_FAILED,
+        };
+        fail(
+            status,
+            format!("failed to open encoder {}: {}", path.display(), e),
+        )
+    })?;
+
+    let input_format = PixelFormat::from(input_format_fourcc);
+    let output_format = PixelFormat::from(output_format_fourcc);
+    info!(
+        "Opened encoder {} for {}x{} {} frames into {}",
+        path.display(),
+        width,
+        height,
+        input_format,
+        output_format,
+    );
+
+    let encoder = encoder
+        .set_capture_format(|f| {
+            let format: Format = f
+                .set_pixelformat(output_format)
+                .set_size(width, height)
+                .set_planes_layout(vec![PlaneLayout {
+                    sizeimage: output_buffer_size as u32,
+                    ..Default::default()
+                }])
+                .apply()?;
+            if format.pixelformat != output_format {
+                return Err(anyhow::anyhow!(
+                    "Unrecognized CAPTURE format {:?}",
+                    output_format
+                ));
+            }
+            Ok(())
+        })
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+                format!("Error while setting capture format: {}", e),
+            )
+        })?;
+
+    let encoder = encoder
+        .set_output_format(|f| {
+            let format: Format = f
+                .set_pixelformat(input_format)
+                .set_size(width, height)
+                .apply()?;
+            if format.pixelformat != input_format {
+                return Err(anyhow::anyhow!(
+                    "Unrecognized OUTPUT format {:?}",
+                    input_format
+                ));
+            }
+            if format.width as usize != width || format.height as usize != height {
+                return Err(anyhow::anyhow!(
+                    "Unsupported frame size {}x{}",
+                    width,
+                    height
+                ));
+            }
+            Ok(())
+        })
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+                format!("Error while setting output format: {}", e),
+            )
+        })?;
+
+    if let Some(params) = params {
+        encoder
+            .set_params(&EncoderParams::from(params))
+            .map_err(|e| {
+                fail(
+                    params_error_status(&e),
+                    format!("Error while setting encoder parameters: {}", e),
+                )
+            })?;
+        if let (Some(numerator), Some(denominator)) = (
+            opt_param(params.framerate_numerator),
+            opt_param(params.framerate_denominator),
+        ) {
+            let (numerator, denominator) =
+                encoder
+                    .set_frame_rate(numerator, denominator)
+                    .map_err(|e| {
+                        fail(
+                            v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+                            format!("Error while setting frame rate: {}", e),
+                        )
+                    })?;
+            debug!("Frame rate set to {}/{}", numerator, denominator);
+        }
+    }
+
+    let (input_format, capture_format) =
+        match (encoder.get_output_format(), encoder.get_capture_format()) {
+            (Ok(input_format), Ok(capture_format)) => (input_format, capture_format),
+            (Err(e), _) | (_, Err(e)) => {
+                return Err(fail(
+                    v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                    format!("Error while getting encoder formats: {}", e),
+                ))
+            }
+        };
+    debug!(
+        "Encoder requires input planes of sizes {:?}, output buffer size of {}",
+        input_format
+            .plane_fmt
+            .iter()
+            .map(|p| p.sizeimage)
+            .collect::<Vec<_>>(),
+        capture_format.plane_fmt[0].sizeimage
+    );
+
+    let cb_data = SendablePtr(cb_data);
+
+    let encoder = encoder
+        .allocate_output_buffers::<Vec<DmaBufHandle<DmaBufFd>>>(num_input_buffers)
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Error while allocating OUTPUT buffers: {}", e),
+            )
+        })?;
+
+    let encoder = encoder
+        .allocate_capture_buffers(num_output_buffers, MmapProvider::new(&capture_format))
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Error while allocating CAPTURE buffers: {}", e),
+            )
+        })?;
+
+    let encoder = encoder
+        .start(
+            Box::new(
+                move |buf: CompletedOutputBuffer<Vec<DmaBufHandle<DmaBufFd>>>| {
+                    match buf {
+                        CompletedOutputBuffer::Dequeued(dqbuf) => {
+                            debug!("Input frame {} done", dqbuf.data.index());
+                            // TODO check return value?
+                            input_done_cb(cb_data.0, dqbuf.data.as_raw_v4l2_buffer());
+                        }
+                        // Just drop canceled buffers for now - the client will remove
+                        // them on its side as well.
+                        CompletedOutputBuffer::Canceled(_) => (),
+                    }
+                },
+            ) as InputDoneCb,
+            Box::new(move |event: EncoderEvent<MmapProvider>| match event {
+                EncoderEvent::FrameEncoded(packet) => frame_encoded_cb(packet, event_cb, cb_data.0),
+                EncoderEvent::EndOfStream => {
+                    event_cb(cb_data.0, &mut v4l2r_encoder_event::EndOfStream)
+                }
+                EncoderEvent::Died(e) => {
+                    error!("Encoder has died: {}", e);
+                    event_cb(cb_data.0, &mut v4l2r_encoder_event::Died)
+                }
+            }) as Box<dyn EncoderEventCallback<MmapProvider>>,
+        )
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Cannot start encoder: {}", e),
+            )
+        })?;
+
+    let encoder = Box::new(v4l2r_encoder {
+        encoder,
+        input_plane_sizes: input_format
+            .plane_fmt
+            .iter()
+            .map(|p| p.sizeimage as u64)
+            .collect(),
+    });
+
+    info!("Encoder {:p}: successfully started", encoder.as_ref());
+
+    Ok(Box::into_raw(encoder))
+}
+
+fn v4l2r_encoder_encode_safe(
+    encoder: &mut v4l2r_encoder,
+    bitstream_id: i32,
+    frame: &v4l2r_video_frame,
+) -> Result<c_int, v4l2r_status> {
+    if frame.num_planes != encoder.input_plane_sizes.len() {
+        return Err(fail(
+            v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT,
+            format!(
+                "Frame has {} planes, but the input format requires {}",
+                frame.num_planes,
+                encoder.input_plane_sizes.len()
+            ),
+        ));
+    }
+
+    let handles = frame.planes[..frame.num_planes]
+        .iter()
+        .zip(encoder.input_plane_sizes.iter())
+        .map(|(&fd, &len)| DmaBufHandle::from(DmaBufFd::new(fd, len)))
+        .collect::<Vec<_>>();
+    let bytes_used = encoder
+        .input_plane_sizes
+        .iter()
+        .map(|&len| len as usize)
+        .collect::<Vec<_>>();
+
+    let v4l2_buffer = encoder.encoder.get_buffer().map_err(|e| {
+        fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error obtaining V4L2 buffer: {}", e),
+        )
+    })?;
+    let v4l2_buffer_id = v4l2_buffer.index();
+
+    v4l2_buffer
+        .set_timestamp(TimeVal::seconds(bitstream_id as i64))
+        .queue_with_handles(handles, &bytes_used)
+        .map_err(|e| {
+            fail(
+                v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+                format!("Error while queueing buffer: {}", e),
+            )
+        })?;
+
+    Ok(v4l2_buffer_id as c_int)
+}
+
+/// Returns a [`v4l2r_encoder_params`] structure with all the parameters unset.
+#[no_mangle]
+pub extern "C" fn v4l2r_encoder_default_params() -> v4l2r_encoder_params {
+    Default::default()
+}
+
+/// Create a new encoder producing a given encoded format.
+///
+/// * `path` is the path to the V4L2 device that will be used for encoding.
+/// * `input_format_fourcc` is the FOURCC code of the pixel format of the
+///   frames to encode, e.g. "NV12".
+/// * `output_format_fourcc` is the FOURCC code of the encoded format to
+///   produce, e.g. "H264" or "VP80".
+/// * `width` and `height` are the dimensions of the frames to encode.
+/// * `num_input_buffers` is the number of V4L2 buffers to use for input
+///   frames, i.e. the maximum number of frames that can be queued at the same
+///   time.
+/// * `num_output_buffers` is the number of V4L2 buffers to use for encoded
+///   packets.
+/// * `output_buffer_size` is the desired size of the buffers receiving the
+///   encoded packets. The encoder may adjust this value.
+/// * `params` points to the encoding parameters to apply before starting the
+///   encoder. It can be NULL, in which case the driver's defaults are used.
+/// * `input_done_cb` is a pointer to a callback function to be called whenever
+///   an input frame is done being processed. This callback is guaranteed to be
+///   invoked during calls to [`v4l2r_encoder_encode`] or
+///   [`v4l2r_encoder_kick`], i.e. it will always be called in the current
+///   thread.
+/// * `event_cb` is a pointer to a function to be called for handling the
+///   various events produced by the encoder. See [`v4l2r_encoder_event`] for
+///   more details on events. This callback is guaranteed to be called from a
+///   separate, unique thread, therefore the events can be assumed to be
+///   sequential.
+/// * `cb_data` is a pointer that will always be passed as the first parameter
+///   of the `input_done_cb` and `events_cb`.
+/// * `encoder` receives the created encoder upon success.
+///
+/// # Safety
+/// The passed `path` must be a valid, zero-terminated C string containining the
+/// path to the device. Expect a crash if passing an invalid string. `params`
+/// must be NULL or point to a valid [`v4l2r_encoder_params`] structure.
+/// `encoder` must point to valid memory that can receive a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_new(
+    path: *const c_char,
+    input_format_fourcc: u32,
+    output_format_fourcc: u32,
+    width: usize,
+    height: usize,
+    num_input_buffers: usize,
+    num_output_buffers: usize,
+    output_buffer_size: usize,
+    params: *const v4l2r_encoder_params,
+    input_done_cb: v4l2r_encoder_input_done_cb,
+    event_cb: v4l2r_encoder_event_cb,
+    cb_data: *mut c_void,
+    encoder: *mut *mut v4l2r_encoder,
+) -> v4l2r_status {
+    if encoder.is_null() {
+        return fail(
+            v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT,
+            "NULL encoder pointer",
+        );
+    }
+    let path = match path_from_c_str(path) {
+        Ok(path) => path,
+        Err(status) => return status,
+    };
+
+    match v4l2r_encoder_new_safe(
+        path,
+        input_format_fourcc,
+        output_format_fourcc,
+        width,
+        height,
+        num_input_buffers,
+        num_output_buffers,
+        output_buffer_size,
+        params.as_ref(),
+        input_done_cb,
+        event_cb,
+        cb_data,
+    ) {
+        Ok(new_encoder) => {
+            *encoder = new_encoder;
+            v4l2r_status::V4L2R_STATUS_OK
+        }
+        Err(status) => status,
+    }
+}
+
+/// Stop and destroy an encoder.
+///
+/// Stop `encoder` and destroy it. Frames still being encoded are discarded; to
+/// obtain the packets of all the frames queued so far, call
+/// [`v4l2r_encoder_drain`] and wait for the
+/// [`v4l2r_encoder_event::EndOfStream`] event first. This function DOES take
+/// ownership of `encoder`, which must absolutely not be used after this call,
+/// even if an error is returned.
+///
+/// It is guaranteed that none of the callbacks passed to [`v4l2r_encoder_new`]
+/// will be called after this function has returned.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// `v4l2r_encoder_new`. Passing an invalid pointer will cause a crash.
+/// `encoder` must not be used again after this function is called.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_destroy(encoder: *mut v4l2r_encoder) -> v4l2r_status {
+    info!("Encoder {:p}: destroying", encoder);
+
+    if encoder.is_null() {
+        warn!("Trying to destroy a NULL encoder");
+        return v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT;
+    }
+
+    let encoder = Box::from_raw(encoder);
+    match encoder.encoder.stop() {
+        Ok(_) => v4l2r_status::V4L2R_STATUS_OK,
+        Err(e) => fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error while stopping encoder: {}", e),
+        ),
+    }
+}
+
+/// Change encoding parameters while encoding.
+///
+/// The new parameters apply from the next frame passed to
+/// [`v4l2r_encoder_encode`]. If one of the parameters is not supported by the
+/// encoder, `V4L2R_STATUS_UNSUPPORTED` is returned and none of the parameters
+/// is applied.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. `params` must point to a valid
+/// [`v4l2r_encoder_dynamic_params`] structure.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_set_dynamic_params(
+    encoder: *const v4l2r_encoder,
+    params: *const v4l2r_encoder_dynamic_params,
+) -> v4l2r_status {
+    let (encoder, params) = match (encoder.as_ref(), params.as_ref()) {
+        (Some(encoder), Some(params)) => (encoder, params),
+        _ => {
+            return fail(
+                v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT,
+                "NULL encoder or parameters",
+            )
+        }
+    };
+
+    match encoder
+        .encoder
+        .set_dynamic_params(&DynamicParams::from(params), None)
+    {
+        Ok(()) => v4l2r_status::V4L2R_STATUS_OK,
+        Err(e) => fail(
+            params_error_status(&e),
+            format!("Error while setting encoder parameters: {}", e),
+        ),
+    }
+}
+
+/// Encode the frame whose planes are referenced by `frame`.
+///
+/// The encoder does NOT take ownership of the FDs of `frame` and won't close
+/// them. The frame must be in the input format passed to
+/// [`v4l2r_encoder_new`], and have as many planes as that format. The `id`
+/// member of `frame` is ignored.
+///
+/// `bitstream_id` is the identifier of this frame. The packets encoded from it
+/// will carry this identifier in the `bitstream_id` member of their event.
+///
+/// Upon success, `buffer_index` receives the index of the V4L2 buffer `frame`
+/// has been queued with. It can be used to know when `frame` can be reused as
+/// a `v4l2_buffer` of the same index will be passed as argument to the *input
+/// done callback* when this is the case. `buffer_index` can be NULL if the
+/// client does not need this information.
+///
+/// If all the input buffers are currently queued, this function blocks until
+/// one of them is done being processed.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+/// `frame` must point to a valid [`v4l2r_video_frame`] which planes are valid
+/// DMABUF FDs backed by enough memory for the input format. Failure to provide
+/// valid FDs will result in an ioctl error (but no crash). `buffer_index` must
+/// be NULL or point to valid memory that can receive an `int`.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_encode(
+    encoder: *mut v4l2r_encoder,
+    bitstream_id: i32,
+    frame: *const v4l2r_video_frame,
+    buffer_index: *mut c_int,
+) -> v4l2r_status {
+    debug!("Encoder {:p}: encoding frame id {}", encoder, bitstream_id);
+    let (encoder, frame) = match (encoder.as_mut(), frame.as_ref()) {
+        (Some(encoder), Some(frame)) => (encoder, frame),
+        _ => {
+            return fail(
+                v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT,
+                "NULL encoder or frame",
+            )
+        }
+    };
+
+    match v4l2r_encoder_encode_safe(encoder, bitstream_id, frame) {
+        Ok(index) => {
+            if let Some(buffer_index) = buffer_index.as_mut() {
+                *buffer_index = index;
+            }
+            v4l2r_status::V4L2R_STATUS_OK
+        }
+        Err(status) => status,
+    }
+}
+
+/// Check for input frames that the encoder is done with, and call the input
+/// done callback for each of them.
+///
+/// Completed input frames are otherwise only checked when calling
+/// [`v4l2r_encoder_encode`]. A client which has queued all its frames can call
+/// this function upon receiving a [`v4l2r_encoder_frame_encoded_event`] to
+/// recycle them without having to queue a new frame.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_kick(encoder: *const v4l2r_encoder) -> v4l2r_status {
+    let encoder = match encoder.as_ref() {
+        Some(encoder) => encoder,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL encoder"),
+    };
+
+    match encoder.encoder.kick() {
+        Ok(()) => v4l2r_status::V4L2R_STATUS_OK,
+        Err(e) => fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error while kicking encoder: {}", e),
+        ),
+    }
+}
+
+/// Drain the encoder, i.e. make sure packets for all the frames queued so far
+/// are emitted. The end of the drain is signaled by a
+/// [`v4l2r_encoder_event::EndOfStream`] event.
+///
+/// Upon success, `completed` is set to `true` if the drain has already
+/// completed as this function returned, or to `false` if it will be completed
+/// when we receive the [`v4l2r_encoder_event::EndOfStream`] event. `completed`
+/// can be NULL if `blocking` is `true`, since the drain is then always
+/// completed upon success.
+///
+/// Once drained, the encoder does not process new frames until
+/// [`v4l2r_encoder_resume`] is called.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+/// `completed` must be NULL or point to valid memory that can receive a
+/// `bool`.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_drain(
+    encoder: *const v4l2r_encoder,
+    blocking: bool,
+    completed: *mut bool,
+) -> v4l2r_status {
+    let encoder = match encoder.as_ref() {
+        Some(encoder) => encoder,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL encoder"),
+    };
+
+    match encoder.encoder.drain(blocking) {
+        Ok(drain_completed) => {
+            if let Some(completed) = completed.as_mut() {
+                *completed = drain_completed;
+            }
+            v4l2r_status::V4L2R_STATUS_OK
+        }
+        Err(e @ DrainError::SendCommandError(_)) => fail(
+            v4l2r_status::V4L2R_STATUS_INTERNAL_ERROR,
+            format!("Error while draining encoder: {}", e),
+        ),
+        Err(e) => fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error while draining encoder: {}", e),
+        ),
+    }
+}
+
+/// Resume encoding after a drain has completed.
+///
+/// # Safety
+///
+/// `encoder` must be a valid pointer to an encoder returned by
+/// [`v4l2r_encoder_new`]. Passing an invalid pointer will cause a crash.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_encoder_resume(encoder: *const v4l2r_encoder) -> v4l2r_status {
+    let encoder = match encoder.as_ref() {
+        Some(encoder) => encoder,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL encoder"),
+    };
+
+    match encoder.encoder.resume() {
+        Ok(()) => v4l2r_status::V4L2R_STATUS_OK,
+        Err(e) => fail(
+            v4l2r_status::V4L2R_STATUS_DEVICE_ERROR,
+            format!("Error while resuming encoder: {}", e),
+        ),
+    }
+}
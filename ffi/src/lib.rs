@@ -1,24 +1,38 @@
 //! C FFI of the V4L2R crate.
 //!
 //! This crate provides a C API that can be used by client programs to make use
-//! of the features exported by this crate. For now it strictly focuses on
-//! stateful decoders.
+//! of the features exported by this crate. It covers stateful decoders and
+//! encoders, as well as querying the capabilities of a device.
+//!
+//! Fallible functions return a [`status::v4l2r_status`], and record a message
+//! describing the failure that can be retrieved using
+//! [`status::v4l2r_last_error`].
+//!
+//! Logs are sent to the platform's default destination once [`v4l2r_init`] is
+//! called, or can be routed to the client using
+//! [`logging::v4l2r_set_log_callback`].
 
 use log::debug;
 
 pub mod decoder;
+pub mod device;
+pub mod encoder;
+pub mod logging;
 pub mod memory;
+pub mod status;
 
 static INIT: std::sync::Once = std::sync::Once::new();
 
 /// Initialize the V4L2R library. This only sets up the proper hooks for
 /// logging, so although it is not a hard requirement to call this function,
-/// failure to do so will result in no logs being printed.
+/// failure to do so will result in no logs being printed. It does nothing if
+/// logs are already routed to a callback set using
+/// [`logging::v4l2r_set_log_callback`].
 #[no_mangle]
 pub extern "C" fn v4l2r_init() {
     INIT.call_once(|| {
         #[cfg(feature = "env_logger")]
-        env_logger::builder().format_timestamp(None).init();
+        let _ = env_logger::builder().format_timestamp(None).try_init();
 
         #[cfg(feature = "android")]
         android_logger::init_once(
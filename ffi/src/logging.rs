@@ -0,0 +1,172 @@
+//! Routing of the logs of the library to the client.
+//!
+//! By default, [`crate::v4l2r_init`] sends logs to the platform's usual
+//! destination (stderr or the Android log). Clients that have their own logging
+//! framework can instead receive every log message through a callback
+//! registered using [`v4l2r_set_log_callback`].
+#![allow(non_camel_case_types)]
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    ffi::CString,
+    os::raw::{c_char, c_void},
+    sync::RwLock,
+};
+
+use crate::status::{fail, v4l2r_status};
+
+/// Severity of a log message, or maximum severity of the messages to receive
+/// when passed to [`v4l2r_set_log_callback`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum v4l2r_log_level {
+    /// Do not log anything. Never passed to the log callback.
+    V4L2R_LOG_LEVEL_OFF = 0,
+    V4L2R_LOG_LEVEL_ERROR,
+    V4L2R_LOG_LEVEL_WARN,
+    V4L2R_LOG_LEVEL_INFO,
+    V4L2R_LOG_LEVEL_DEBUG,
+    V4L2R_LOG_LEVEL_TRACE,
+}
+
+impl From<Level> for v4l2r_log_level {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Error => v4l2r_log_level::V4L2R_LOG_LEVEL_ERROR,
+            Level::Warn => v4l2r_log_level::V4L2R_LOG_LEVEL_WARN,
+            Level::Info => v4l2r_log_level::V4L2R_LOG_LEVEL_INFO,
+            Level::Debug => v4l2r_log_level::V4L2R_LOG_LEVEL_DEBUG,
+            Level::Trace => v4l2r_log_level::V4L2R_LOG_LEVEL_TRACE,
+        }
+    }
+}
+
+impl From<v4l2r_log_level> for LevelFilter {
+    fn from(level: v4l2r_log_level) -> Self {
+        match level {
+            v4l2r_log_level::V4L2R_LOG_LEVEL_OFF => LevelFilter::Off,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_ERROR => LevelFilter::Error,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_WARN => LevelFilter::Warn,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_INFO => LevelFilter::Info,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_DEBUG => LevelFilter::Debug,
+            v4l2r_log_level::V4L2R_LOG_LEVEL_TRACE => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Log callback.
+///
+/// The first argument is the `cb_data` pointer given to
+/// [`v4l2r_set_log_callback`]. The other arguments are the level of the
+/// message, its target (usually the Rust module that produced it) and the
+/// message itself. The strings are only valid for the duration of the call.
+///
+/// This callback can be invoked from any thread, including concurrently.
+pub type v4l2r_log_cb =
+    Option<extern "C" fn(*mut c_void, v4l2r_log_level, *const c_char, *const c_char)>;
+
+#[derive(Clone, Copy)]
+struct LogCallback {
+    cb: extern "C" fn(*mut c_void, v4l2r_log_level, *const c_char, *const c_char),
+    cb_data: *mut c_void,
+}
+
+// The client guarantees that `cb_data` can be used from any thread when
+// registering the callback.
+unsafe impl Send for LogCallback {}
+unsafe impl Sync for LogCallback {}
+
+struct CallbackLoggerState {
+    // Whether `LOGGER` has been installed as the logger of the `log` crate.
+    installed: bool,
+    callback: Option<LogCallback>,
+}
+
+/// Logger forwarding all the enabled records to the registered callback.
+struct CallbackLogger {
+    state: RwLock<CallbackLoggerState>,
+}
+
+static LOGGER: CallbackLogger = CallbackLogger {
+    state: RwLock::new(CallbackLoggerState {
+        installed: false,
+        callback: None,
+    }),
+};
+
+// Interior nul characters cannot be represented, so drop them.
+fn to_c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+impl Log for CallbackLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Do not hold the lock while calling the client, so the callback can
+        // register a new one without deadlocking.
+        let callback = match self.state.read().unwrap().callback {
+            Some(callback) => callback,
+            None => return,
+        };
+        let target = to_c_string(record.target());
+        let message = to_c_string(&record.args().to_string());
+        (callback.cb)(
+            callback.cb_data,
+            record.level().into(),
+            target.as_ptr(),
+            message.as_ptr(),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Send the logs of the library to `cb` instead of the default destination.
+///
+/// Only messages of severity `level` or higher are passed to `cb`. This
+/// function can be called again at any time to change the level or the
+/// callback. Passing a NULL `cb` disables logging altogether.
+///
+/// This function must be called before [`crate::v4l2r_init`], which does not
+/// need to be called at all if logs are routed to a callback.
+/// `V4L2R_STATUS_UNSUPPORTED` is returned if another logger has already been
+/// installed, e.g. by a previous call to [`crate::v4l2r_init`].
+///
+/// # Safety
+///
+/// `cb_data` will be passed to `cb` from any thread, and must thus be safe to
+/// use from any of them. Calls to a previously registered callback that are in
+/// progress on other threads can still complete after this function returns,
+/// so its `cb_data` must remain valid until they do.
+#[no_mangle]
+pub unsafe extern "C" fn v4l2r_set_log_callback(
+    level: v4l2r_log_level,
+    cb: v4l2r_log_cb,
+    cb_data: *mut c_void,
+) -> v4l2r_status {
+    let mut state = LOGGER.state.write().unwrap();
+    if !state.installed {
+        if log::set_logger(&LOGGER).is_err() {
+            return fail(
+                v4l2r_status::V4L2R_STATUS_UNSUPPORTED,
+                "another logger is already installed",
+            );
+        }
+        state.installed = true;
+    }
+
+    state.callback = cb.map(|cb| LogCallback { cb, cb_data });
+    log::set_max_level(match state.callback {
+        Some(_) => level.into(),
+        None => LevelFilter::Off,
+    });
+
+    v4l2r_status::V4L2R_STATUS_OK
+}
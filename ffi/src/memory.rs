@@ -2,11 +2,14 @@
 
 use log::{error, trace};
 use std::{
-    collections::VecDeque,
+    cmp::min,
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
     os::{
-        raw::c_int,
+        raw::{c_int, c_void},
         unix::io::{AsRawFd, RawFd},
     },
+    ptr,
     sync::{Arc, Mutex},
     task::Wake,
 };
@@ -15,6 +18,8 @@ use v4l2r::{
     device::{
         poller::Waker,
         queue::{
+            direction::Capture,
+            dqbuf::DqBuffer,
             handles_provider::{GetSuitableBufferError, HandlesProvider},
             qbuf::{
                 get_free::GetFreeCaptureBuffer, get_indexed::GetCaptureBufferByIndex,
@@ -22,9 +27,15 @@ use v4l2r::{
             },
         },
     },
-    memory::{BufferHandles, DmaBufHandle, DmaBufSource, MemoryType, PrimitiveBufferHandles},
+    ioctl::MmapRegion,
+    memory::{
+        BufferHandles, DmaBufHandle, DmaBufSource, Memory, MemoryType, MmapHandle, PlaneHandle,
+        PrimitiveBufferHandles, UserPtrHandle,
+    },
 };
 
+use crate::status::{fail, v4l2r_status};
+
 /// The simplest type used to represent a DMABUF fd. It does not take ownership
 /// of the FD at any time and does not close it ; thus the using code is
 /// responsible for managing the given FD's lifetime.
@@ -60,9 +71,26 @@ impl DmaBufSource for DmaBufFd {
     }
 }
 
+/// Type of memory backing the planes of the frames decoded into.
+#[repr(C)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum v4l2r_memory_type {
+    /// Planes are DMABUFs allocated by the client and passed using the `planes`
+    /// member of [`v4l2r_video_frame`].
+    V4L2R_MEMORY_TYPE_DMABUF = 0,
+    /// Planes are allocated by the device. The client does not allocate any
+    /// frame and accesses the decoded data through the `plane_addrs` member of
+    /// [`v4l2r_video_frame`].
+    V4L2R_MEMORY_TYPE_MMAP,
+    /// Planes are memory areas allocated by the client and passed using the
+    /// `plane_addrs` and `plane_sizes` members of [`v4l2r_video_frame`].
+    V4L2R_MEMORY_TYPE_USERPTR,
+}
+
 /// A struct representing a set of buffers to which decoded frames will be
 /// output.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 #[repr(C)]
 pub struct v4l2r_video_frame {
     /// Identifier of the frame. Each frame of the provider must have a unique
@@ -71,43 +99,141 @@ pub struct v4l2r_video_frame {
     pub id: u32,
     /// Number of entries in `fds`, e.g. the number of planes in this frame.
     pub num_planes: usize,
-    /// DMABUF FDs of the planes for this frame.
+    /// DMABUF FDs of the planes for this frame, if it uses DMABUF memory.
     pub planes: [c_int; 4],
+    /// Addresses of the planes for this frame.
+    ///
+    /// For USERPTR memory, they are set by the client to the memory areas to
+    /// decode into. For MMAP memory, they are set by the decoder to the start
+    /// of the decoded data and remain valid until the provider of the frame is
+    /// dropped. They are unused for DMABUF memory.
+    pub plane_addrs: [*mut c_void; 4],
+    /// Size in bytes of the memory areas pointed to by `plane_addrs`. For MMAP
+    /// memory, this is the size of the decoded data.
+    pub plane_sizes: [usize; 4],
+}
+
+impl Default for v4l2r_video_frame {
+    fn default() -> Self {
+        v4l2r_video_frame {
+            id: 0,
+            num_planes: 0,
+            planes: [0; 4],
+            plane_addrs: [ptr::null_mut(); 4],
+            plane_sizes: [0; 4],
+        }
+    }
+}
+
+// The memory behind `plane_addrs` is managed by either the client or the
+// provider, and is only accessed by the device while the frame is queued.
+unsafe impl Send for v4l2r_video_frame {}
+
+/// A [`v4l2r_video_frame`] which planes are backed by memory of type `H`.
+#[derive(Debug)]
+pub struct VideoFrame<H: VideoFrameMemory> {
+    frame: v4l2r_video_frame,
+    _memory: PhantomData<H>,
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-pub struct VideoFrameMemoryType;
+impl<H: VideoFrameMemory> VideoFrame<H> {
+    pub fn new(frame: v4l2r_video_frame) -> Self {
+        VideoFrame {
+            frame,
+            _memory: PhantomData,
+        }
+    }
 
-impl From<VideoFrameMemoryType> for MemoryType {
-    fn from(_: VideoFrameMemoryType) -> Self {
-        MemoryType::DmaBuf
+    pub fn into_frame(self) -> v4l2r_video_frame {
+        self.frame
     }
 }
 
-impl BufferHandles for v4l2r_video_frame {
-    type SupportedMemoryType = VideoFrameMemoryType;
+impl<H: VideoFrameMemory> BufferHandles for VideoFrame<H> {
+    type SupportedMemoryType = MemoryType;
 
     fn len(&self) -> usize {
-        self.num_planes
+        self.frame.num_planes
     }
 
     fn fill_v4l2_plane(&self, index: usize, plane: &mut bindings::v4l2_plane) {
-        plane.m.fd = self.planes[index];
-        // We don't need to set plane.m.length as these buffers are meant for
-        // the CAPTURE queue.
+        // We don't need to set plane.length for DMABUF as these buffers are
+        // meant for the CAPTURE queue, and MMAP planes are managed by the
+        // device.
+        match H::Memory::MEMORY_TYPE {
+            MemoryType::DmaBuf => plane.m.fd = self.frame.planes[index],
+            MemoryType::UserPtr => {
+                plane.m.userptr = self.frame.plane_addrs[index] as _;
+                plane.length = self.frame.plane_sizes[index] as u32;
+            }
+            MemoryType::Mmap => (),
+        }
     }
 }
 
-impl PrimitiveBufferHandles for v4l2r_video_frame {
-    // TODO: Uh? This is bullocks but somehow it compiles??
-    type HandleType = DmaBufHandle<DmaBufFd>;
+impl<H: VideoFrameMemory> PrimitiveBufferHandles for VideoFrame<H> {
+    // The frame does not hold actual handles of type `H`, but this lets the
+    // queue know which memory type to use.
+    type HandleType = H;
 
-    const MEMORY_TYPE: Self::SupportedMemoryType = VideoFrameMemoryType;
+    const MEMORY_TYPE: Self::SupportedMemoryType = H::Memory::MEMORY_TYPE;
+}
+
+/// Plane handle types that can back the planes of a [`v4l2r_video_frame`].
+pub trait VideoFrameMemory: PlaneHandle + Sized {
+    /// Called once `frame` has been decoded into from `dqbuf`, so the memory
+    /// type can expose the decoded planes to the client.
+    fn frame_decoded(
+        _provider: &v4l2r_video_frame_provider,
+        _dqbuf: &DqBuffer<Capture, VideoFrame<Self>>,
+        _frame: &mut v4l2r_video_frame,
+    ) {
+    }
+}
+
+impl VideoFrameMemory for DmaBufHandle<DmaBufFd> {}
+
+impl VideoFrameMemory for UserPtrHandle<Vec<u8>> {}
+
+impl VideoFrameMemory for MmapHandle {
+    fn frame_decoded(
+        provider: &v4l2r_video_frame_provider,
+        dqbuf: &DqBuffer<Capture, VideoFrame<Self>>,
+        frame: &mut v4l2r_video_frame,
+    ) {
+        // MMAP frames are identified by the V4L2 buffer backing them.
+        let index = dqbuf.data.index() as u32;
+        frame.id = index;
+
+        // The planes are mapped by the queue the first time they are dequeued. Keep a reference
+        // to the mapped regions so they remain valid for as long as the provider lives, even if
+        // the buffers are freed.
+        let mut regions = Vec::with_capacity(frame.num_planes);
+        for i in 0..frame.num_planes {
+            let (plane, mapping) = match (dqbuf.data.get_plane(i), dqbuf.get_plane_mapping(i)) {
+                (Some(plane), Some(mapping)) => (plane, mapping),
+                _ => {
+                    error!("Failed to map the planes of CAPTURE buffer {}", index);
+                    return;
+                }
+            };
+            let region = Arc::clone(mapping.region());
+            let end = min(plane.bytesused() as usize, region.len());
+            let start = min(plane.data_offset() as usize, end);
+            frame.plane_addrs[i] = region.as_mut_ptr().wrapping_add(start) as *mut c_void;
+            frame.plane_sizes[i] = end - start;
+            regions.push(region);
+        }
+
+        provider.d.lock().unwrap().mappings.insert(index, regions);
+    }
 }
 
 struct VideoFrameProviderInternal {
     frames: VecDeque<v4l2r_video_frame>,
     waker: Option<Arc<Waker>>,
+    // Mappings of the planes of MMAP buffers, indexed by buffer.
+    mappings: HashMap<u32, Vec<Arc<MmapRegion>>>,
 }
 
 /// A way for the client-side to provide frames to be decoded into in the form
@@ -127,13 +253,39 @@ impl v4l2r_video_frame_provider {
             d: Mutex::new(VideoFrameProviderInternal {
                 frames: VecDeque::new(),
                 waker: None,
+                mappings: HashMap::new(),
             }),
         }
     }
+
+    /// Make `frame` available for being decoded into.
+    pub fn queue_frame(&self, frame: v4l2r_video_frame) {
+        let mut d = self.d.lock().unwrap();
+        d.frames.push_back(frame);
+        if let Some(waker) = d.waker.take() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+/// [`HandlesProvider`] serving the frames of a [`v4l2r_video_frame_provider`]
+/// as frames backed by memory of type `H`.
+pub struct VideoFrameProvider<H: VideoFrameMemory> {
+    provider: Arc<v4l2r_video_frame_provider>,
+    _memory: PhantomData<fn() -> H>,
+}
+
+impl<H: VideoFrameMemory> VideoFrameProvider<H> {
+    pub fn new(provider: Arc<v4l2r_video_frame_provider>) -> Self {
+        VideoFrameProvider {
+            provider,
+            _memory: PhantomData,
+        }
+    }
 }
 
-impl HandlesProvider for v4l2r_video_frame_provider {
-    type HandleType = v4l2r_video_frame;
+impl<H: VideoFrameMemory> HandlesProvider for VideoFrameProvider<H> {
+    type HandleType = VideoFrame<H>;
 
     // TODO BUG: if the V4L2 buffer queue fails for some reason, there
     // is no guarantee that the handles will return to the provider, and
@@ -143,9 +295,9 @@ impl HandlesProvider for v4l2r_video_frame_provider {
     // Ideally the handles would be a C++ object that we just pass around,
     // and which destructor would be called even if the Rust side drops it.
     fn get_handles(&self, waker: &Arc<Waker>) -> Option<Self::HandleType> {
-        let mut d = self.d.lock().unwrap();
+        let mut d = self.provider.d.lock().unwrap();
         match d.frames.pop_front() {
-            Some(handles) => Some(handles),
+            Some(frame) => Some(VideoFrame::new(frame)),
             None => {
                 d.waker = Some(Arc::clone(waker));
                 None
@@ -165,15 +317,13 @@ impl HandlesProvider for v4l2r_video_frame_provider {
         Q: GetCaptureBufferByIndex<'a, Self::HandleType>
             + GetFreeCaptureBuffer<'a, Self::HandleType>,
     {
-        trace!("Getting suitable buffer for frame {}", handles.id);
+        let id = handles.frame.id;
+        trace!("Getting suitable buffer for frame {}", id);
 
         // Try to get the buffer with the same id as our frame. If that is not
         // possible, fall back to returning any free buffer.
-        let buffer = queue.try_get_buffer(handles.id as usize).or_else(|e| {
-            error!(
-                "failed to obtain CAPTURE buffer {} by index: {}",
-                handles.id, e
-            );
+        let buffer = queue.try_get_buffer(id as usize).or_else(|e| {
+            error!("failed to obtain CAPTURE buffer {} by index: {}", id, e);
             error!("falling back to getting the first available buffer.");
             queue.try_get_free_buffer()
         })?;
@@ -190,8 +340,8 @@ impl HandlesProvider for v4l2r_video_frame_provider {
 /// will remain untouched by the decoder until the client passes it to this
 /// function again.
 ///
-/// Returns `true` upon success, `false` if the provided frame had an invalid
-/// index or the decoder thread could not be awakened.
+/// Returns `V4L2R_STATUS_INVALID_ARGUMENT` if the provided frame had an invalid
+/// index, in which case it is not queued.
 ///
 /// This function can safely be called from any thread.
 ///
@@ -204,22 +354,22 @@ impl HandlesProvider for v4l2r_video_frame_provider {
 pub unsafe extern "C" fn v4l2r_video_frame_provider_queue_frame(
     provider: *const v4l2r_video_frame_provider,
     frame: v4l2r_video_frame,
-) -> bool {
+) -> v4l2r_status {
     trace!("Queueing output frame: {:?}", frame);
-    assert!(!provider.is_null());
-    let provider = &*provider;
+    let provider = match provider.as_ref() {
+        Some(provider) => provider,
+        None => return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL provider"),
+    };
 
     if frame.id >= bindings::VIDEO_MAX_FRAME {
-        error!("Invalid frame id {}, aborting queue.", frame.id);
-        return false;
+        return fail(
+            v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT,
+            format!("Invalid frame id {}, aborting queue.", frame.id),
+        );
     }
 
-    let mut provider = provider.d.lock().unwrap();
-    provider.frames.push_back(frame);
-    if let Some(waker) = provider.waker.take() {
-        waker.wake_by_ref();
-    }
-    true
+    provider.queue_frame(frame);
+    v4l2r_status::V4L2R_STATUS_OK
 }
 
 /// Delete a video frame provider.
@@ -238,9 +388,12 @@ pub unsafe extern "C" fn v4l2r_video_frame_provider_queue_frame(
 #[no_mangle]
 pub unsafe extern "C" fn v4l2r_video_frame_provider_drop(
     provider: *const v4l2r_video_frame_provider,
-) {
+) -> v4l2r_status {
     trace!("Destroying video frame provider: {:p}", provider);
-    assert!(!provider.is_null());
+    if provider.is_null() {
+        return fail(v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT, "NULL provider");
+    }
 
     Arc::from_raw(provider);
+    v4l2r_status::V4L2R_STATUS_OK
 }
@@ -0,0 +1,88 @@
+//! Status codes returned by the functions of the C API.
+//!
+//! Every fallible function returns a [`v4l2r_status`]. When a function fails,
+//! a message describing the error is also recorded for the calling thread and
+//! can be retrieved using [`v4l2r_last_error`].
+#![allow(non_camel_case_types)]
+
+use log::error;
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, OsStr},
+    fmt::Display,
+    os::{raw::c_char, unix::ffi::OsStrExt},
+    path::Path,
+    ptr,
+};
+
+/// Result of a call to the C API.
+#[repr(C)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum v4l2r_status {
+    /// The call has succeeded.
+    V4L2R_STATUS_OK = 0,
+    /// One of the arguments is invalid, e.g. a NULL pointer.
+    V4L2R_STATUS_INVALID_ARGUMENT,
+    /// The device could not be opened.
+    V4L2R_STATUS_OPEN_FAILED,
+    /// The device does not support the requested operation, format or
+    /// parameter.
+    V4L2R_STATUS_UNSUPPORTED,
+    /// The device has returned an error.
+    V4L2R_STATUS_DEVICE_ERROR,
+    /// The operation cannot be performed at the moment and should be attempted
+    /// again later.
+    V4L2R_STATUS_TRY_AGAIN,
+    /// An internal error occurred, e.g. a worker thread has died.
+    V4L2R_STATUS_INTERNAL_ERROR,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// Log `message` and record it as the last error of the current thread, then
+/// return `status` so callers can write `return fail(status, message)`.
+pub(crate) fn fail(status: v4l2r_status, message: impl Display) -> v4l2r_status {
+    let message = message.to_string();
+    error!("{}", message);
+    // Interior nul characters cannot be represented, so drop them.
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+
+    status
+}
+
+/// Convert the C string `path` into a `Path`, without requiring it to be
+/// valid UTF-8.
+///
+/// # Safety
+///
+/// `path` must be NULL or a valid, zero-terminated C string.
+pub(crate) unsafe fn path_from_c_str<'a>(path: *const c_char) -> Result<&'a Path, v4l2r_status> {
+    if path.is_null() {
+        return Err(fail(
+            v4l2r_status::V4L2R_STATUS_INVALID_ARGUMENT,
+            "NULL device path",
+        ));
+    }
+
+    Ok(Path::new(OsStr::from_bytes(
+        CStr::from_ptr(path).to_bytes(),
+    )))
+}
+
+/// Return a message describing the last error that occurred on the calling
+/// thread, or NULL if no error occurred yet.
+///
+/// The message is not reset by successful calls, so it is only meaningful
+/// right after a call that did not return `V4L2R_STATUS_OK`. The returned
+/// string remains valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn v4l2r_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| match &*last_error.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
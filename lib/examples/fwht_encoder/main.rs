@@ -19,7 +19,7 @@ use v4l2r::{
         },
     },
     encoder::*,
-    memory::{MmapHandle, UserPtrHandle},
+    memory::{DmaBufHandle, DmaHeap, MmapHandle, UserPtrHandle},
     Format,
 };
 
@@ -178,12 +178,25 @@ fn main() {
 
     let dmabufs: Option<VecDeque<_>> = match output_mem {
         GenericSupportedMemoryType::Mmap | GenericSupportedMemoryType::UserPtr => None,
-        GenericSupportedMemoryType::DmaBuf => Some(
-            utils::dmabuf_exporter::export_dmabufs(&output_format, NUM_BUFFERS)
-                .unwrap()
-                .into_iter()
-                .collect(),
-        ),
+        GenericSupportedMemoryType::DmaBuf => {
+            let heap = DmaHeap::open("system").expect("Failed to open DMA heap");
+            Some(
+                (0..NUM_BUFFERS)
+                    .map(|_| {
+                        output_format
+                            .plane_fmt
+                            .iter()
+                            .map(|plane| {
+                                DmaBufHandle::from(
+                                    heap.allocate(plane.sizeimage as usize)
+                                        .expect("Failed to allocate DMA-BUF"),
+                                )
+                            })
+                            .collect()
+                    })
+                    .collect(),
+            )
+        }
     };
     let dmabufs = RefCell::new(dmabufs);
 
@@ -214,7 +227,7 @@ fn main() {
     let poll_count_reader = Arc::new(AtomicUsize::new(0));
     let poll_count_writer = Arc::clone(&poll_count_reader);
     let mut frame_counter = 0usize;
-    let output_ready_cb = move |cap_dqbuf: DqBuffer<Capture, Vec<MmapHandle>>| {
+    let mut output_ready_cb = move |cap_dqbuf: DqBuffer<Capture, Vec<MmapHandle>>| {
         let bytes_used = cap_dqbuf.data.get_first_plane().bytesused() as usize;
         // Ignore zero-sized buffers.
         if bytes_used == 0 {
@@ -246,6 +259,11 @@ fn main() {
                 .expect("Error while writing output data");
         }
     };
+    let encoder_event_cb = move |event: EncoderEvent<MmapProvider>| match event {
+        EncoderEvent::FrameEncoded(packet) => output_ready_cb(packet.into_buffer()),
+        EncoderEvent::EndOfStream => (),
+        EncoderEvent::Died(e) => panic!("Encoder error: {}", e),
+    };
 
     let mut encoder = encoder
         .allocate_output_buffers_generic::<GenericBufferHandles>(output_mem, NUM_BUFFERS)
@@ -253,7 +271,7 @@ fn main() {
         .allocate_capture_buffers(NUM_BUFFERS, MmapProvider::new(&capture_format))
         .expect("Failed to allocate CAPTURE buffers")
         .set_poll_counter(poll_count_writer)
-        .start(input_done_cb, output_ready_cb)
+        .start(input_done_cb, encoder_event_cb)
         .expect("Failed to start encoder");
 
     while !lets_quit.load(Ordering::SeqCst) {
@@ -276,7 +294,7 @@ fn main() {
         };
         let bytes_used = frame_gen.frame_size();
         match v4l2_buffer {
-            GenericQBuffer::Mmap(buf) => {
+            GenericQBuffer::Mmap(mut buf) => {
                 let mut mapping = buf
                     .get_plane_mapping(0)
                     .expect("Failed to get MMAP mapping");
@@ -303,13 +321,13 @@ fn main() {
                 .expect("Failed to queue input frame");
             }
             GenericQBuffer::DmaBuf(buf) => {
-                let buffer = dmabufs
+                let mut buffer = dmabufs
                     .borrow_mut()
                     .as_mut()
                     .unwrap()
                     .pop_front()
                     .expect("No backing dmabuf to bind");
-                let mut mapping = buffer[0].map().unwrap();
+                let mut mapping = buffer[0].map_mut().unwrap();
                 frame_gen
                     .next_frame(&mut mapping)
                     .expect("Failed to generate frame");
@@ -319,6 +337,7 @@ fn main() {
         }
     }
 
+    encoder.drain(true).unwrap();
     encoder.stop().unwrap();
 
     // Insert new line since we were overwriting the same one
@@ -12,24 +12,21 @@ use std::{
 use anyhow::ensure;
 use nix::sys::time::{TimeVal, TimeValLike};
 use v4l2r::{
-    decoder::{format::fwht::FwhtFrameParser, FormatChangedReply},
+    decoder::{format::fwht::FwhtFrameParser, frame::DecodedFrame, FormatChangedReply},
     device::queue::{handles_provider::MmapProvider, FormatBuilder},
     memory::{MemoryType, MmapHandle},
     PlaneLayout,
 };
 use v4l2r::{
     decoder::{
-        format::{h264::H264FrameSplitter, StreamSplitter},
+        format::{h264::H264FrameSplitter, matroska::MatroskaDemuxer, mp4::Mp4Demuxer},
         stateful::GetBufferError,
     },
     PixelFormat,
 };
 use v4l2r::{
     decoder::{stateful::Decoder, DecoderEvent},
-    device::{
-        poller::PollError,
-        queue::{direction::Capture, dqbuf::DqBuffer},
-    },
+    device::poller::PollError,
     Format, Rect,
 };
 
@@ -38,6 +35,8 @@ use clap::{App, Arg};
 enum Codec {
     Fwht,
     H264,
+    Mp4,
+    Matroska,
 }
 
 fn main() {
@@ -47,7 +46,7 @@ fn main() {
         .arg(
             Arg::with_name("stream")
                 .required(true)
-                .help("Path to the FWHT stream to decode"),
+                .help("Path to the encoded stream to decode"),
         )
         .arg(
             Arg::with_name("device")
@@ -60,7 +59,7 @@ fn main() {
                 .required(false)
                 .takes_value(true)
                 .default_value("fwht")
-                .help("Format of the encoded stream (fwht or h264)"),
+                .help("Format of the encoded stream (fwht, h264, mp4 or webm)"),
         )
         .arg(
             Arg::with_name("output_file")
@@ -83,11 +82,66 @@ fn main() {
     {
         "fwht" => Codec::Fwht,
         "h264" => Codec::H264,
+        "mp4" => Codec::Mp4,
+        "webm" | "mkv" => Codec::Matroska,
         _ => panic!("Invalid input format specified"),
     };
 
     let stream = BufReader::new(File::open(stream_path).expect("Compressed stream not found"));
 
+    // Frames to decode, along with their timestamp.
+    // TODO setting the timestamp should not be necessary for raw streams. This is a requirement
+    // of the crosvm video device.
+    let (pixel_format, frames): (PixelFormat, Box<dyn Iterator<Item = (Vec<u8>, TimeVal)>>) =
+        match codec {
+            Codec::Fwht => (
+                b"FWHT".into(),
+                Box::new(
+                    FwhtFrameParser::new(stream)
+                        .unwrap_or_else(|| panic!("No FWHT stream detected in {}", stream_path))
+                        .enumerate()
+                        .map(|(i, frame)| (frame, TimeVal::seconds(i as i64))),
+                ),
+            ),
+            Codec::H264 => (
+                b"H264".into(),
+                Box::new(
+                    H264FrameSplitter::new(stream)
+                        .unwrap_or_else(|| panic!("No H.264 stream detected in {}", stream_path))
+                        .enumerate()
+                        .map(|(i, frame)| (frame, TimeVal::seconds(i as i64))),
+                ),
+            ),
+            Codec::Mp4 => {
+                let demuxer = Mp4Demuxer::new(stream)
+                    .unwrap_or_else(|e| panic!("Cannot open {}: {}", stream_path, e));
+                let pixel_format = demuxer
+                    .pixel_format()
+                    .unwrap_or_else(|| panic!("Unsupported codec {:?}", demuxer.codec()));
+                (
+                    pixel_format,
+                    Box::new(demuxer.map(|sample| {
+                        let timestamp = sample.timestamp();
+                        (sample.data, timestamp)
+                    })),
+                )
+            }
+            Codec::Matroska => {
+                let demuxer = MatroskaDemuxer::new(stream)
+                    .unwrap_or_else(|e| panic!("Cannot open {}: {}", stream_path, e));
+                let pixel_format = demuxer
+                    .pixel_format()
+                    .unwrap_or_else(|| panic!("Unsupported codec {}", demuxer.codec_id()));
+                (
+                    pixel_format,
+                    Box::new(demuxer.map(|frame| {
+                        let timestamp = frame.timestamp();
+                        (frame.data, timestamp)
+                    })),
+                )
+            }
+        };
+
     let mut output_file: Option<File> = matches
         .value_of("output_file")
         .map(|path| File::create(path).expect("Invalid output file specified."));
@@ -108,7 +162,8 @@ fn main() {
     let poll_count_writer = Arc::clone(&poll_count_reader);
     let start_time = std::time::Instant::now();
     let mut frame_counter = 0usize;
-    let mut output_ready_cb = move |cap_dqbuf: DqBuffer<Capture, Vec<MmapHandle>>| {
+    let mut output_ready_cb = move |frame: DecodedFrame<MmapProvider>| {
+        let cap_dqbuf = frame.buffer();
         let bytes_used = cap_dqbuf.data.get_first_plane().bytesused() as usize;
         // Ignore zero-sized buffers.
         if bytes_used == 0 {
@@ -129,20 +184,31 @@ fn main() {
         );
         io::stdout().flush().unwrap();
 
+        // Write the frame without the padding at the end of its lines.
         if let Some(ref mut output) = output_file {
-            for i in 0..cap_dqbuf.data.num_planes() {
-                let mapping = cap_dqbuf
-                    .get_plane_mapping(i)
+            for i in 0..frame.num_planes() {
+                let plane = frame
+                    .map_plane(i)
                     .expect("Failed to map capture buffer plane");
-                output
-                    .write_all(&mapping)
-                    .expect("Error while writing output data");
+                for row in plane.rows() {
+                    output
+                        .write_all(row)
+                        .expect("Error while writing output data");
+                }
             }
         }
     };
     let decoder_event_cb = move |event: DecoderEvent<MmapProvider>| match event {
-        DecoderEvent::FrameDecoded(dqbuf) => output_ready_cb(dqbuf),
+        DecoderEvent::FrameDecoded(frame) => output_ready_cb(frame),
         DecoderEvent::EndOfStream => (),
+        DecoderEvent::CorruptedFrame(frame) => {
+            eprintln!(
+                "\nFrame {} decoded with errors",
+                frame.buffer().data.sequence()
+            );
+            output_ready_cb(frame)
+        }
+        DecoderEvent::Died(e) => panic!("Decoder error: {}", e),
     };
     let set_capture_format_cb = move |f: FormatBuilder,
                                       visible_rect: Rect,
@@ -168,10 +234,6 @@ fn main() {
     let mut decoder = Decoder::open(Path::new(device_path))
         .expect("Failed to open device")
         .set_output_format(|f| {
-            let pixel_format: PixelFormat = match codec {
-                Codec::Fwht => b"FWHT".into(),
-                Codec::H264 => b"H264".into(),
-            };
             let format: Format = f
                 .set_pixelformat(pixel_format)
                 // 1 MB per decoding unit should be enough for most streams.
@@ -199,24 +261,25 @@ fn main() {
 
     println!("Allocated {} buffers", decoder.num_output_buffers());
 
-    let parser = match codec {
-        Codec::Fwht => Box::new(
-            FwhtFrameParser::new(stream)
-                .unwrap_or_else(|| panic!("No FWHT stream detected in {}", stream_path)),
-        ) as Box<dyn StreamSplitter>,
-        Codec::H264 => Box::new(
-            H264FrameSplitter::new(stream)
-                .unwrap_or_else(|| panic!("No H.264 stream detected in {}", stream_path)),
-        ) as Box<dyn StreamSplitter>,
-    };
+    let format: Format = decoder
+        .get_output_format()
+        .expect("Failed to get OUTPUT format");
+    let mut max_frame_size = format.plane_fmt[0].sizeimage as usize;
 
-    'mainloop: for (bitstream_id, frame) in parser.enumerate() {
+    'mainloop: for (frame, timestamp) in frames {
         // Ctrl-c ?
         if lets_quit.load(Ordering::SeqCst) {
             break;
         }
 
-        let v4l2_buffer = match decoder.get_buffer() {
+        // Allocate larger OUTPUT buffers if the frame does not fit into them.
+        if frame.len() > max_frame_size {
+            max_frame_size = decoder
+                .resize_output_buffers(frame.len(), NUM_OUTPUT_BUFFERS)
+                .expect("Failed to resize OUTPUT buffers");
+        }
+
+        let mut v4l2_buffer = match decoder.get_buffer_for_size(frame.len()) {
             Ok(buffer) => buffer,
             // If we got interrupted while waiting for a buffer, just exit normally.
             Err(GetBufferError::PollError(PollError::EPollWait(e)))
@@ -233,10 +296,8 @@ fn main() {
         mapping.as_mut()[0..frame.len()].copy_from_slice(&frame);
         drop(mapping);
 
-        // TODO setting the timestamp should not be necessary. This is a requirement of the crosvm
-        // video device.
         v4l2_buffer
-            .set_timestamp(TimeVal::seconds(bitstream_id as i64))
+            .set_timestamp(timestamp)
             .queue(&[frame.len()])
             .expect("Failed to queue input frame");
     }
@@ -188,7 +188,7 @@ pub fn run<F: FnMut(&[u8])>(
             .expect("Failed to obtain output buffer");
 
         match output_buffer {
-            GenericQBuffer::Mmap(buf) => {
+            GenericQBuffer::Mmap(mut buf) => {
                 let mut mapping = buf
                     .get_plane_mapping(0)
                     .expect("Failed to get MMAP mapping");
@@ -148,14 +148,14 @@ pub fn run<F: FnMut(&[u8])>(
             "Capture buffer {} at offset 0x{:0x}, length 0x{:0x}",
             i, query_buf.planes[0].mem_offset, query_buf.planes[0].length
         );
-        capture_mappings.push(
+        capture_mappings.push(Arc::new(
             mmap(
                 &fd,
                 query_buf.planes[0].mem_offset,
                 query_buf.planes[0].length,
             )
             .expect("Failed to map buffer"),
-        );
+        ));
     }
 
     let output_image_size = output_format.plane_fmt[0].sizeimage as usize;
@@ -200,8 +200,11 @@ pub fn run<F: FnMut(&[u8])>(
                     querybuf(&fd, output_queue_type, output_buffer_index)
                         .expect("Failed to query output buffer");
                 let plane = &buffer_info.planes[0];
-                let mut mapping =
+                let region =
                     mmap(&fd, plane.mem_offset, plane.length).expect("Failed to map output buffer");
+                // Safe because the buffer is not queued, so we are the only user of its memory.
+                let mut mapping =
+                    unsafe { PlaneWriteMapping::new(Arc::new(region), 0, plane.length as usize) };
 
                 frame_gen
                     .next_frame(&mut mapping)
@@ -262,7 +265,17 @@ pub fn run<F: FnMut(&[u8])>(
         );
         io::stdout().flush().unwrap();
 
-        save_output(&capture_mappings[cap_dqbuf.index() as usize].as_ref()[0..bytes_used]);
+        // Safe because the buffer has just been dequeued and is not queued again before we are
+        // done with the mapping.
+        let mapping = unsafe {
+            PlaneReadMapping::new(
+                Arc::clone(&capture_mappings[cap_dqbuf.index() as usize]),
+                0,
+                bytes_used,
+            )
+        };
+        save_output(&mapping);
+        drop(mapping);
 
         cpt = cpt.wrapping_add(1);
     }
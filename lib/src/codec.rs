@@ -0,0 +1,2 @@
+//! Codec-specific helpers shared by the decoder and the encoder.
+pub(crate) mod h264;
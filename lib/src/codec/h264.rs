@@ -0,0 +1,33 @@
+//! Helpers for H.264 Annex B byte streams.
+
+/// Returns the position of the first `00 00 01` start code in `data`, if any.
+pub(crate) fn find_start_code(data: &[u8]) -> Option<usize> {
+    let mut pos = 2;
+    while pos < data.len() {
+        match data[pos] {
+            // Possibly the end of a start code.
+            1 if data[pos - 1] == 0 && data[pos - 2] == 0 => return Some(pos - 2),
+            // A start code cannot end before pos + 3.
+            b if b > 1 => pos += 3,
+            _ => pos += 1,
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_start_code() {
+        assert_eq!(find_start_code(&[]), None);
+        assert_eq!(find_start_code(&[0, 0]), None);
+        assert_eq!(find_start_code(&[0, 0, 1]), Some(0));
+        assert_eq!(find_start_code(&[0, 0, 0, 1]), Some(1));
+        assert_eq!(find_start_code(&[0, 0, 0, 0, 0, 1]), Some(3));
+        assert_eq!(find_start_code(&[5, 0, 0, 2, 0, 0, 1, 0]), Some(4));
+        assert_eq!(find_start_code(&[1, 0, 1, 0, 0]), None);
+    }
+}
@@ -2,16 +2,17 @@
 //! [stateful interface](https://www.kernel.org/doc/html/latest/userspace-api/media/v4l/dev-encoder.html).
 use crate::{
     device::queue::{
-        direction::{Capture, Output},
-        dqbuf::DqBuffer,
-        handles_provider::HandlesProvider,
-        CanceledBuffer, FormatBuilder,
+        direction::Output, dqbuf::DqBuffer, handles_provider::HandlesProvider, CanceledBuffer,
+        FormatBuilder,
     },
     memory::BufferHandles,
     Rect,
 };
+use frame::DecodedFrame;
+use thiserror::Error;
 
 pub mod format;
+pub mod frame;
 pub mod stateful;
 
 pub enum CompletedInputBuffer<OP: BufferHandles> {
@@ -27,23 +28,44 @@ where
 {
 }
 
-// TODO: add errors?
+/// Unrecoverable errors reported by a `DecoderEvent::Died` event.
+#[derive(Debug, Error)]
+pub enum DecoderError {
+    #[error("Stream is not supported by the decoder")]
+    UnsupportedStream,
+    #[error("Failed to allocate CAPTURE buffers: {0}")]
+    CaptureAllocationFailed(anyhow::Error),
+    #[error("Device error: {0}")]
+    DeviceError(anyhow::Error),
+}
+
 pub enum DecoderEvent<P: HandlesProvider> {
     /// Emitted when a frame is decoded.
     ///
-    /// The parameter is the dequeued buffer, containing the plane handles of
-    /// the decoded frame as well as its V4L2 parameters such as flags. The
-    /// flags remain untouched, but the client should not take action on some
-    /// of them: for instance, when the `V4L2_BUF_FLAG_LAST` is set, the proper
+    /// The parameter is the decoded frame, containing the dequeued buffer
+    /// with the plane handles of the frame and its V4L2 parameters such as
+    /// flags, along with the format needed to read it. The flags remain
+    /// untouched, but the client should not take action on some of them: for
+    /// instance, when the `V4L2_BUF_FLAG_LAST` is set, the proper
     /// corresponding event (resolution change or end of stream) will be
     /// signaled appropriately.
-    FrameDecoded(DqBuffer<Capture, P::HandleType>),
+    FrameDecoded(DecodedFrame<P>),
     /// Emitted when a previously requested `drain` request completes.
     ///
     /// When this event is emitted, the client knows that all the frames
     /// corresponding to all the input buffers queued before the `drain` request
     /// have been emitted.
     EndOfStream,
+    /// Emitted when a frame is decoded, but the driver reported an error
+    /// (`V4L2_BUF_FLAG_ERROR`) while decoding it. Its content may be corrupted.
+    ///
+    /// The decoder keeps running and subsequent frames may be fine.
+    CorruptedFrame(DecodedFrame<P>),
+    /// Emitted when the decoder encountered an unrecoverable error. No further
+    /// event will be emitted, and the decoder should be stopped.
+    ///
+    /// After this event, drain and flush requests fail immediately.
+    Died(DecoderError),
 }
 
 pub trait DecoderEventCallback<P: HandlesProvider>:
@@ -1,5 +1,7 @@
 pub mod fwht;
 pub mod h264;
+pub mod matroska;
+pub mod mp4;
 
 use log::error;
 use std::io;
@@ -8,12 +10,82 @@ use std::io;
 /// frames).
 pub trait StreamSplitter: Iterator<Item = Vec<u8>> {}
 
+/// Size of the chunks we read from the stream at once.
+const READ_CHUNK_SIZE: usize = 0x10000;
+
+/// Append up to `READ_CHUNK_SIZE` bytes read from `stream` to `buf`, and return the number of
+/// bytes read. Read errors are logged and handled like the end of the stream.
+fn read_chunk<S: io::Read>(stream: &mut S, buf: &mut Vec<u8>) -> usize {
+    let len = buf.len();
+    buf.resize(len + READ_CHUNK_SIZE, 0);
+    let res = loop {
+        match stream.read(&mut buf[len..]) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            res => break res,
+        }
+    };
+    let read = match res {
+        Ok(read) => read,
+        Err(e) => {
+            error!("Error while reading stream: {}", e);
+            0
+        }
+    };
+    buf.truncate(len + read);
+
+    read
+}
+
+/// Returns the position of the first occurrence of `pattern` in `data`, if any.
+///
+/// This uses the Knuth-Morris-Pratt algorithm, so the search is done in
+/// `O(data.len() + pattern.len())` time whatever the contents of `data`.
+fn find_pattern(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    if pattern.is_empty() {
+        return None;
+    }
+
+    // `prefix[i]` is the length of the longest proper prefix of `pattern[..=i]` that is also a
+    // suffix of it, i.e. how much of the pattern is still matched after a mismatch at `i + 1`.
+    let mut prefix = vec![0; pattern.len()];
+    let mut len = 0;
+    for (i, &b) in pattern.iter().enumerate().skip(1) {
+        while len > 0 && b != pattern[len] {
+            len = prefix[len - 1];
+        }
+        if b == pattern[len] {
+            len += 1;
+        }
+        prefix[i] = len;
+    }
+
+    let mut matched = 0;
+    for (i, &b) in data.iter().enumerate() {
+        while matched > 0 && b != pattern[matched] {
+            matched = prefix[matched - 1];
+        }
+        if b == pattern[matched] {
+            matched += 1;
+        }
+        if matched == pattern.len() {
+            return Some(i + 1 - pattern.len());
+        }
+    }
+
+    None
+}
+
 /// Splits a stream at each encounter of a given pattern. Useful to extract decodable units (or
 /// frames from an encoded stream.
 struct PatternSplitter<S: io::Read> {
     /// The pattern to split at.
     pattern: Vec<u8>,
-    stream: io::Bytes<S>,
+    stream: S,
+    /// Data read from the stream and not returned yet. Always starts with `pattern`.
+    buf: Vec<u8>,
+    /// Position in `buf` from which to look for the next pattern.
+    search_pos: usize,
+    eos: bool,
 }
 
 impl<S: io::Read> PatternSplitter<S> {
@@ -22,21 +94,40 @@ impl<S: io::Read> PatternSplitter<S> {
     /// `stream` must start with `pattern`, otherwise the input is considered invalid and `None` is
     /// returned.
     fn new(pattern: impl Into<Vec<u8>>, stream: S) -> Option<Self> {
-        let mut stream = stream.bytes();
         let pattern = pattern.into();
+        let mut splitter = PatternSplitter {
+            search_pos: pattern.len(),
+            pattern,
+            stream,
+            buf: Vec::with_capacity(READ_CHUNK_SIZE),
+            eos: false,
+        };
 
         // The stream must begin by our header, or it is invalid.
-        let stream_start = (0..pattern.len())
-            .into_iter()
-            .map(|_| stream.next().unwrap_or(Ok(0)).unwrap_or(0))
-            .collect::<Vec<_>>();
+        while splitter.buf.len() < splitter.pattern.len() {
+            if !splitter.fill_buf() {
+                return None;
+            }
+        }
 
-        if stream_start == pattern {
-            Some(PatternSplitter { pattern, stream })
+        if splitter.buf.starts_with(&splitter.pattern) {
+            Some(splitter)
         } else {
             None
         }
     }
+
+    /// Read one more chunk of data from the stream. Returns `false` if the end of the stream
+    /// has been reached or an error occurred.
+    fn fill_buf(&mut self) -> bool {
+        if self.eos {
+            return false;
+        }
+
+        self.eos = read_chunk(&mut self.stream, &mut self.buf) == 0;
+
+        !self.eos
+    }
 }
 
 impl<S: io::Read> Iterator for PatternSplitter<S> {
@@ -44,52 +135,88 @@ impl<S: io::Read> Iterator for PatternSplitter<S> {
 
     /// Returns the next frame in the stream, header included.
     fn next(&mut self) -> Option<Self::Item> {
-        let mut frame_data: Vec<u8> = Vec::with_capacity(0x10000);
-        // Add the header of the frame since we are already past it.
-        frame_data.extend(&self.pattern);
-        // Window used to try and detect the next frame header.
-        let mut header_window: Vec<u8> = Vec::with_capacity(self.pattern.len());
-        // Iterator to the next character expected for a frame header.
-        let mut header_iter = self.pattern.iter().peekable();
-
-        loop {
-            let b = match self.stream.next() {
-                Some(Ok(b)) => b,
-                // End of stream, commit all read data and return.
-                None => {
-                    frame_data.extend(&header_window);
-                    // If the only data is our pre-filled header, then we haven't
-                    // read anything and thus there is no frame to return.
-                    return if frame_data.len() <= self.pattern.len() {
-                        None
-                    } else {
-                        Some(frame_data)
-                    };
-                }
-                Some(Err(e)) => {
-                    error!("Error while reading stream: {}", e);
-                    return None;
-                }
-            };
+        let frame_len = loop {
+            let search_area = self.buf.get(self.search_pos..).unwrap_or_default();
+            if let Some(pos) = find_pattern(search_area, &self.pattern) {
+                break self.search_pos + pos;
+            }
+            // The last bytes of the buffer may be the beginning of the next pattern.
+            self.search_pos = std::cmp::max(
+                self.pattern.len(),
+                (self.buf.len() + 1).saturating_sub(self.pattern.len()),
+            );
+            if !self.fill_buf() {
+                break self.buf.len();
+            }
+        };
 
-            // Add the read byte to the header candidate buffer.
-            header_window.push(b);
+        // If the only data is our header, then there is no frame to return.
+        if frame_len <= self.pattern.len() {
+            return None;
+        }
 
-            // Possibly a header?
-            if Some(&&b) == header_iter.peek() {
-                header_iter.next();
-                // Found next frame's header, return read data.
-                if header_iter.peek().is_none() {
-                    return Some(frame_data);
-                }
-            } else {
-                // Not a header, commit header window data to current frame and reset.
-                frame_data.extend(&header_window);
-                if header_window.len() > 1 {
-                    header_iter = self.pattern.iter().peekable();
+        let frame = self.buf[..frame_len].to_vec();
+        // Keep the header of the next frame and the data following it in our buffer.
+        self.buf.drain(..frame_len);
+        self.search_pos = self.pattern.len();
+
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader returning data one byte at a time, to exercise chunk boundaries.
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl<'a> io::Read for ByteReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((b, rest)) if !buf.is_empty() => {
+                    buf[0] = *b;
+                    self.0 = rest;
+                    Ok(1)
                 }
-                header_window.clear();
+                _ => Ok(0),
             }
         }
     }
+
+    #[test]
+    fn test_find_pattern() {
+        assert_eq!(find_pattern(&[0, 0, 0, 0, 1], &[0, 0, 0, 1]), Some(1));
+        assert_eq!(find_pattern(&[0, 0, 1], &[0, 0, 0, 1]), None);
+        assert_eq!(find_pattern(&[1, 2, 1, 2, 3], &[1, 2, 3]), Some(2));
+        assert_eq!(find_pattern(&[1, 2, 3], &[]), None);
+        assert_eq!(find_pattern(&[1, 2, 1, 2, 1, 3], &[1, 2, 1, 3]), Some(2));
+        assert_eq!(find_pattern(&[1, 1, 2, 1, 1, 1, 2], &[1, 1, 1, 2]), Some(3));
+    }
+
+    #[test]
+    fn test_pattern_splitter() {
+        let pattern = [0u8, 0, 0, 1];
+        let stream = [
+            0u8, 0, 0, 1, 0x65, 0xaa, 0, 0, 0, 0, 1, 0x41, 0, 0, 0, 1, 0x41, 0, 0,
+        ];
+        let expected: [&[u8]; 3] = [
+            &[0, 0, 0, 1, 0x65, 0xaa, 0],
+            &[0, 0, 0, 1, 0x41],
+            &[0, 0, 0, 1, 0x41, 0, 0],
+        ];
+
+        let frames = PatternSplitter::new(pattern, stream.as_ref())
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(frames, expected);
+
+        let frames = PatternSplitter::new(pattern, ByteReader(&stream))
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(frames, expected);
+
+        assert!(PatternSplitter::new(pattern, [0u8, 0, 1, 0].as_ref()).is_none());
+        assert!(PatternSplitter::new(pattern, [0u8, 0].as_ref()).is_none());
+    }
 }
@@ -1,44 +1,772 @@
-use super::{PatternSplitter, StreamSplitter};
+//! Splitting of H.264 annex B streams into access units.
+//!
+//! The splitter parses the NAL unit headers of the stream, as well as the parts of the sequence
+//! parameter sets, picture parameter sets and slice headers required to apply the rules of
+//! sections 7.4.1.2.3 (order of NAL units and association to access units) and 7.4.1.2.4
+//! (detection of the first VCL NAL unit of a primary coded picture) of the H.264 specification.
+use super::{read_chunk, StreamSplitter, READ_CHUNK_SIZE};
+use crate::codec::h264::find_start_code;
+use log::warn;
+use std::collections::BTreeMap;
 use std::io;
 
-static H264_START_CODE: [u8; 4] = [0x0, 0x0, 0x0, 0x1];
+/// Maximum number of slice bytes to unescape when parsing a slice header. This is larger than
+/// the worst-case size of the fields we are interested in.
+const MAX_SLICE_HEADER_SIZE: usize = 128;
 
-/// Splits a H.264 annex B stream into chunks that are all guaranteed to contain a full frame
-/// worth of data.
+/// NAL unit types we are interested in, from table 7-1 of the H.264 specification.
+mod nal_type {
+    pub const SLICE: u8 = 1;
+    pub const SLICE_DATA_PARTITION_A: u8 = 2;
+    pub const SLICE_IDR: u8 = 5;
+    pub const SEI: u8 = 6;
+    pub const SPS: u8 = 7;
+    pub const PPS: u8 = 8;
+    pub const AUD: u8 = 9;
+    pub const END_OF_SEQUENCE: u8 = 10;
+    pub const END_OF_STREAM: u8 = 11;
+    pub const PREFIX: u8 = 14;
+    pub const RESERVED_18: u8 = 18;
+}
+
+/// Converts a NAL unit payload into its RBSP by removing the emulation prevention bytes.
+fn nal_to_rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+
+    for &b in nal {
+        if zeros >= 2 && b == 0x3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+
+    rbsp
+}
+
+/// Error returned when trying to read past the end of a RBSP.
+#[derive(Debug)]
+struct EndOfData;
+
+/// Reads bits and Exp-Golomb codes from a RBSP.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, EndOfData> {
+        let byte = self.data.get(self.pos / 8).ok_or(EndOfData)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 0x1;
+        self.pos += 1;
+
+        Ok(bit == 1)
+    }
+
+    /// Read a `u(n)` value, with `n` up to 32.
+    fn read_bits(&mut self, n: u32) -> Result<u32, EndOfData> {
+        let mut value = 0u32;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u32;
+        }
+
+        Ok(value)
+    }
+
+    fn read_flag(&mut self) -> Result<bool, EndOfData> {
+        self.read_bit()
+    }
+
+    /// Read an unsigned Exp-Golomb `ue(v)` value.
+    fn read_ue(&mut self) -> Result<u32, EndOfData> {
+        let mut leading_zeros = 0;
+        while !self.read_bit()? {
+            leading_zeros += 1;
+            // Valid streams never use more than 32 bits for a ue(v) value.
+            if leading_zeros > 31 {
+                return Err(EndOfData);
+            }
+        }
+
+        Ok(((1u64 << leading_zeros) - 1 + self.read_bits(leading_zeros)? as u64) as u32)
+    }
+
+    /// Read a signed Exp-Golomb `se(v)` value.
+    fn read_se(&mut self) -> Result<i32, EndOfData> {
+        let value = self.read_ue()? as i64;
+
+        Ok(if value & 0x1 == 1 {
+            ((value + 1) / 2) as i32
+        } else {
+            -(value / 2) as i32
+        })
+    }
+}
+
+/// The fields of a sequence parameter set that are needed to parse slice headers.
+#[derive(Debug, Clone, Default)]
+struct Sps {
+    separate_colour_plane_flag: bool,
+    log2_max_frame_num: u32,
+    pic_order_cnt_type: u32,
+    log2_max_pic_order_cnt_lsb: u32,
+    delta_pic_order_always_zero_flag: bool,
+    frame_mbs_only_flag: bool,
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Result<(), EndOfData> {
+    let mut last_scale = 8i32;
+    let mut next_scale = 8i32;
+
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = r.read_se()?;
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a SPS RBSP, returning its id and the fields we are interested in.
+fn parse_sps(rbsp: &[u8]) -> Result<(u32, Sps), EndOfData> {
+    let mut r = BitReader::new(rbsp);
+    let mut sps = Sps::default();
+
+    let profile_idc = r.read_bits(8)?;
+    // constraint_set flags and reserved_zero_2bits.
+    r.read_bits(8)?;
+    // level_idc
+    r.read_bits(8)?;
+    let seq_parameter_set_id = r.read_ue()?;
+
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        let chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc == 3 {
+            sps.separate_colour_plane_flag = r.read_flag()?;
+        }
+        // bit_depth_luma_minus8
+        r.read_ue()?;
+        // bit_depth_chroma_minus8
+        r.read_ue()?;
+        // qpprime_y_zero_transform_bypass_flag
+        r.read_flag()?;
+        let seq_scaling_matrix_present_flag = r.read_flag()?;
+        if seq_scaling_matrix_present_flag {
+            let num_lists = if chroma_format_idc != 3 { 8 } else { 12 };
+            for i in 0..num_lists {
+                if r.read_flag()? {
+                    skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    sps.log2_max_frame_num = r.read_ue()? + 4;
+    sps.pic_order_cnt_type = r.read_ue()?;
+    match sps.pic_order_cnt_type {
+        0 => sps.log2_max_pic_order_cnt_lsb = r.read_ue()? + 4,
+        1 => {
+            sps.delta_pic_order_always_zero_flag = r.read_flag()?;
+            // offset_for_non_ref_pic
+            r.read_se()?;
+            // offset_for_top_to_bottom_field
+            r.read_se()?;
+            let num_ref_frames_in_pic_order_cnt_cycle = r.read_ue()?;
+            for _ in 0..num_ref_frames_in_pic_order_cnt_cycle {
+                r.read_se()?;
+            }
+        }
+        _ => (),
+    }
+    // max_num_ref_frames
+    r.read_ue()?;
+    // gaps_in_frame_num_value_allowed_flag
+    r.read_flag()?;
+    // pic_width_in_mbs_minus1
+    r.read_ue()?;
+    // pic_height_in_map_units_minus1
+    r.read_ue()?;
+    sps.frame_mbs_only_flag = r.read_flag()?;
+
+    Ok((seq_parameter_set_id, sps))
+}
+
+/// The fields of a picture parameter set that are needed to parse slice headers.
+#[derive(Debug, Clone, Default)]
+struct Pps {
+    seq_parameter_set_id: u32,
+    bottom_field_pic_order_in_frame_present_flag: bool,
+}
+
+/// Parse a PPS RBSP, returning its id and the fields we are interested in.
+fn parse_pps(rbsp: &[u8]) -> Result<(u32, Pps), EndOfData> {
+    let mut r = BitReader::new(rbsp);
+
+    let pic_parameter_set_id = r.read_ue()?;
+    let seq_parameter_set_id = r.read_ue()?;
+    // entropy_coding_mode_flag
+    r.read_flag()?;
+    let bottom_field_pic_order_in_frame_present_flag = r.read_flag()?;
+
+    Ok((
+        pic_parameter_set_id,
+        Pps {
+            seq_parameter_set_id,
+            bottom_field_pic_order_in_frame_present_flag,
+        },
+    ))
+}
+
+/// The slice header fields used to detect the first VCL NAL unit of a new primary coded
+/// picture.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct SliceHeader {
+    pic_parameter_set_id: u32,
+    frame_num: u32,
+    field_pic_flag: bool,
+    bottom_field_flag: bool,
+    idr_pic_id: Option<u32>,
+    pic_order_cnt_type: u32,
+    pic_order_cnt_lsb: u32,
+    delta_pic_order_cnt_bottom: i32,
+    delta_pic_order_cnt: [i32; 2],
+}
+
+/// Information about a slice NAL unit.
+#[derive(Debug, Clone)]
+struct SliceInfo {
+    nal_ref_idc: u8,
+    is_idr: bool,
+    first_mb_in_slice: u32,
+    /// The rest of the header, if the parameter sets it refers to are known.
+    header: Option<SliceHeader>,
+}
+
+impl SliceInfo {
+    /// Returns whether the slice `self` belongs to a different primary coded picture than the
+    /// previous slice `prev`, following section 7.4.1.2.4 of the H.264 specification.
+    ///
+    /// A slice with `first_mb_in_slice` equal to 0 is also considered to start a new picture,
+    /// which allows us to split pictures for which the parameter sets are not known. Streams
+    /// using arbitrary slice order or redundant pictures may not be split properly as a result.
+    fn starts_new_picture(&self, prev: &SliceInfo) -> bool {
+        if self.first_mb_in_slice == 0 {
+            return true;
+        }
+
+        if (self.nal_ref_idc == 0) != (prev.nal_ref_idc == 0) || self.is_idr != prev.is_idr {
+            return true;
+        }
+
+        let (cur, prev) = match (&self.header, &prev.header) {
+            (Some(cur), Some(prev)) => (cur, prev),
+            _ => return false,
+        };
+
+        cur.frame_num != prev.frame_num
+            || cur.pic_parameter_set_id != prev.pic_parameter_set_id
+            || cur.field_pic_flag != prev.field_pic_flag
+            || cur.bottom_field_flag != prev.bottom_field_flag
+            || (cur.pic_order_cnt_type == 0
+                && prev.pic_order_cnt_type == 0
+                && (cur.pic_order_cnt_lsb != prev.pic_order_cnt_lsb
+                    || cur.delta_pic_order_cnt_bottom != prev.delta_pic_order_cnt_bottom))
+            || (cur.pic_order_cnt_type == 1
+                && prev.pic_order_cnt_type == 1
+                && cur.delta_pic_order_cnt != prev.delta_pic_order_cnt)
+            || (self.is_idr && cur.idr_pic_id != prev.idr_pic_id)
+    }
+}
+
+/// Reads NAL units from an annex B byte stream.
 ///
-/// This is a pretty naive implementation that is only guaranteed to work with our examples.
-pub struct H264FrameSplitter<S: io::Read>(PatternSplitter<S>);
+/// Each NAL unit is returned with its start code and any zero byte preceding it, so that
+/// concatenating all the NAL units returns the original stream.
+struct NalReader<S: io::Read> {
+    stream: S,
+    /// Data read from the stream and not consumed yet. Always starts with a start code, unless
+    /// we reached the end of the stream.
+    buf: Vec<u8>,
+    /// Position of the first byte of the next NAL unit's header in `buf`, if located.
+    header_pos: usize,
+    /// Length of the next NAL unit in `buf`, if located.
+    nal_len: Option<usize>,
+    eos: bool,
+}
+
+impl<S: io::Read> NalReader<S> {
+    /// Create a new reader for `stream`, which must start with a start code, possibly preceded
+    /// by zero bytes. `None` is returned otherwise.
+    fn new(stream: S) -> Option<Self> {
+        let mut reader = NalReader {
+            stream,
+            buf: Vec::with_capacity(READ_CHUNK_SIZE),
+            header_pos: 0,
+            nal_len: None,
+            eos: false,
+        };
+
+        loop {
+            if let Some(pos) = find_start_code(&reader.buf) {
+                if reader.buf[..pos].iter().any(|&b| b != 0) {
+                    return None;
+                }
+                reader.header_pos = pos + 3;
+                return Some(reader);
+            }
+            if reader.buf.iter().any(|&b| b != 0) || !reader.fill_buf() {
+                return None;
+            }
+        }
+    }
+
+    /// Read one more chunk of data from the stream. Returns `false` if the end of the stream
+    /// has been reached.
+    fn fill_buf(&mut self) -> bool {
+        if self.eos {
+            return false;
+        }
+
+        self.eos = read_chunk(&mut self.stream, &mut self.buf) == 0;
+
+        !self.eos
+    }
+
+    /// Locate the next NAL unit and return its data, header included but without start code.
+    fn peek(&mut self) -> Option<&[u8]> {
+        if self.nal_len.is_none() {
+            let mut search_pos = self.header_pos;
+            self.nal_len = loop {
+                if let Some(pos) = find_start_code(&self.buf[search_pos..]) {
+                    // Trailing zero bytes are attributed to the next NAL unit.
+                    let mut end = search_pos + pos;
+                    while end > self.header_pos && self.buf[end - 1] == 0 {
+                        end -= 1;
+                    }
+                    break Some(end);
+                }
+                // Start looking again from the last bytes, as they can be the beginning of a
+                // start code.
+                search_pos = std::cmp::max(self.header_pos, self.buf.len().saturating_sub(2));
+                if !self.fill_buf() {
+                    break Some(self.buf.len());
+                }
+            };
+        }
+
+        match self.nal_len {
+            Some(len) if len > self.header_pos => Some(&self.buf[self.header_pos..len]),
+            _ => None,
+        }
+    }
+
+    /// Append the NAL unit last returned by `peek()`, start code included, to `out`.
+    fn consume_into(&mut self, out: &mut Vec<u8>) {
+        let len = match self.nal_len.take() {
+            Some(len) => len,
+            None => return,
+        };
+
+        out.extend_from_slice(&self.buf[..len]);
+        self.buf.drain(..len);
+        self.header_pos = match find_start_code(&self.buf) {
+            Some(pos) => pos + 3,
+            None => self.buf.len(),
+        };
+    }
+}
+
+/// A complete access unit, as returned by [`H264FrameSplitter::next_access_unit`].
+#[derive(Debug, Clone, Default)]
+pub struct AccessUnit {
+    /// Data of the access unit, start codes included.
+    pub data: Vec<u8>,
+    /// Whether the primary coded picture of this access unit is an IDR picture.
+    pub is_idr: bool,
+    /// Whether the primary coded picture of this access unit is used for reference.
+    pub is_reference: bool,
+    /// `frame_num` of the primary coded picture, if its parameter sets were available.
+    pub frame_num: Option<u32>,
+    /// Number of slices in this access unit.
+    pub num_slices: usize,
+}
+
+/// Splits a H.264 annex B stream into access units, i.e. chunks of data that contain exactly
+/// one primary coded picture along with its associated non-VCL NAL units.
+///
+/// Both 3 and 4 bytes start codes are supported, and the stream is returned unmodified, i.e.
+/// concatenating all the returned access units produces the original stream.
+pub struct H264FrameSplitter<S: io::Read> {
+    reader: NalReader<S>,
+    sps: BTreeMap<u32, Sps>,
+    pps: BTreeMap<u32, Pps>,
+}
 
 impl<S: io::Read> H264FrameSplitter<S> {
+    /// Create a new splitter for `stream`. `None` is returned if `stream` does not start with a
+    /// start code.
     pub fn new(stream: S) -> Option<Self> {
-        Some(Self(PatternSplitter::new(
-            H264_START_CODE.to_vec(),
-            stream,
-        )?))
+        Some(Self {
+            reader: NalReader::new(stream)?,
+            sps: Default::default(),
+            pps: Default::default(),
+        })
     }
 
-    fn contains_frame(data: &[u8]) -> bool {
-        data[4..].windows(4).any(|window| {
-            window[0..3] == [0x0, 0x0, 0x1] && (window[3] & 0x1f == 0x5 || window[3] & 0x1f == 0x1)
+    fn parse_slice(&self, nal: &[u8]) -> Option<SliceInfo> {
+        let nal_ref_idc = (nal[0] >> 5) & 0x3;
+        let is_idr = nal[0] & 0x1f == nal_type::SLICE_IDR;
+        let rbsp = nal_to_rbsp(&nal[1..std::cmp::min(nal.len(), MAX_SLICE_HEADER_SIZE)]);
+        let mut r = BitReader::new(&rbsp);
+
+        let first_mb_in_slice = r.read_ue().ok()?;
+        let header = self.parse_slice_header(&mut r, is_idr);
+        if header.is_none() {
+            warn!("Could not parse slice header, falling back to first_mb_in_slice");
+        }
+
+        Some(SliceInfo {
+            nal_ref_idc,
+            is_idr,
+            first_mb_in_slice,
+            header,
         })
     }
+
+    /// Parse the slice header after `first_mb_in_slice`.
+    fn parse_slice_header(&self, r: &mut BitReader, is_idr: bool) -> Option<SliceHeader> {
+        let mut header = SliceHeader::default();
+
+        // slice_type
+        r.read_ue().ok()?;
+        header.pic_parameter_set_id = r.read_ue().ok()?;
+        let pps = self.pps.get(&header.pic_parameter_set_id)?;
+        let sps = self.sps.get(&pps.seq_parameter_set_id)?;
+
+        if sps.separate_colour_plane_flag {
+            // colour_plane_id
+            r.read_bits(2).ok()?;
+        }
+        header.frame_num = r.read_bits(sps.log2_max_frame_num).ok()?;
+        if !sps.frame_mbs_only_flag {
+            header.field_pic_flag = r.read_flag().ok()?;
+            if header.field_pic_flag {
+                header.bottom_field_flag = r.read_flag().ok()?;
+            }
+        }
+        if is_idr {
+            header.idr_pic_id = Some(r.read_ue().ok()?);
+        }
+        header.pic_order_cnt_type = sps.pic_order_cnt_type;
+        let bottom_present =
+            pps.bottom_field_pic_order_in_frame_present_flag && !header.field_pic_flag;
+        if sps.pic_order_cnt_type == 0 {
+            header.pic_order_cnt_lsb = r.read_bits(sps.log2_max_pic_order_cnt_lsb).ok()?;
+            if bottom_present {
+                header.delta_pic_order_cnt_bottom = r.read_se().ok()?;
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
+            header.delta_pic_order_cnt[0] = r.read_se().ok()?;
+            if bottom_present {
+                header.delta_pic_order_cnt[1] = r.read_se().ok()?;
+            }
+        }
+
+        Some(header)
+    }
+
+    /// Record the parameter set contained in `nal`, if it is one.
+    fn record_parameter_set(&mut self, nal_type: u8, nal: &[u8]) {
+        match nal_type {
+            nal_type::SPS => match parse_sps(&nal_to_rbsp(&nal[1..])) {
+                Ok((id, sps)) => {
+                    self.sps.insert(id, sps);
+                }
+                Err(_) => warn!("Failed to parse SPS"),
+            },
+            nal_type::PPS => match parse_pps(&nal_to_rbsp(&nal[1..])) {
+                Ok((id, pps)) => {
+                    self.pps.insert(id, pps);
+                }
+                Err(_) => warn!("Failed to parse PPS"),
+            },
+            _ => (),
+        }
+    }
+
+    /// Returns the next access unit in the stream, along with its metadata.
+    pub fn next_access_unit(&mut self) -> Option<AccessUnit> {
+        let mut au = AccessUnit {
+            data: Vec::with_capacity(READ_CHUNK_SIZE),
+            ..Default::default()
+        };
+        let mut last_slice: Option<SliceInfo> = None;
+
+        while let Some(nal) = self.reader.peek() {
+            let nal_type = nal[0] & 0x1f;
+
+            match nal_type {
+                nal_type::SLICE | nal_type::SLICE_DATA_PARTITION_A | nal_type::SLICE_IDR => {
+                    let nal = nal.to_vec();
+                    let slice = self.parse_slice(&nal);
+                    match (&last_slice, &slice) {
+                        (Some(prev), Some(cur)) if cur.starts_new_picture(prev) => break,
+                        _ => (),
+                    }
+                    if let Some(slice) = slice {
+                        if last_slice.is_none() {
+                            au.is_idr = slice.is_idr;
+                            au.is_reference = slice.nal_ref_idc != 0;
+                            au.frame_num = slice.header.as_ref().map(|h| h.frame_num);
+                        }
+                        au.num_slices += 1;
+                        last_slice = Some(slice);
+                    }
+                }
+                // These NAL units start a new access unit if they come after a VCL NAL unit.
+                nal_type::SEI
+                | nal_type::SPS
+                | nal_type::PPS
+                | nal_type::AUD
+                | nal_type::PREFIX..=nal_type::RESERVED_18 => {
+                    if last_slice.is_some() {
+                        break;
+                    }
+                    let nal = nal.to_vec();
+                    self.record_parameter_set(nal_type, &nal);
+                }
+                // These NAL units terminate the current access unit.
+                nal_type::END_OF_SEQUENCE | nal_type::END_OF_STREAM => {
+                    self.reader.consume_into(&mut au.data);
+                    if last_slice.is_some() {
+                        break;
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            self.reader.consume_into(&mut au.data);
+        }
+
+        if au.data.is_empty() {
+            None
+        } else {
+            Some(au)
+        }
+    }
 }
 
 impl<S: io::Read> Iterator for H264FrameSplitter<S> {
     type Item = Vec<u8>;
 
-    /// Returns the next frame in the stream, header included.
+    /// Returns the next access unit in the stream, start codes included.
     fn next(&mut self) -> Option<Self::Item> {
-        let mut next_slice = self.0.next()?;
-        while !Self::contains_frame(&next_slice) {
-            match self.0.next() {
-                None => return Some(next_slice),
-                Some(data) => next_slice.extend(data),
+        self.next_access_unit().map(|au| au.data)
+    }
+}
+
+impl<S: io::Read> StreamSplitter for H264FrameSplitter<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes bits and Exp-Golomb codes, to craft test streams.
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        num_bits: usize,
+    }
+
+    impl BitWriter {
+        fn write_bits(&mut self, value: u32, n: u32) -> &mut Self {
+            for i in (0..n).rev() {
+                if self.num_bits % 8 == 0 {
+                    self.data.push(0);
+                }
+                let bit = ((value >> i) & 0x1) as u8;
+                *self.data.last_mut().unwrap() |= bit << (7 - self.num_bits % 8);
+                self.num_bits += 1;
+            }
+            self
+        }
+
+        fn write_ue(&mut self, value: u32) -> &mut Self {
+            let value = value as u64 + 1;
+            let len = 64 - value.leading_zeros();
+            self.write_bits(0, len - 1);
+            self.write_bits(value as u32, len)
+        }
+
+        /// Add the RBSP trailing bits and return the data with `header` prepended.
+        fn into_nal(mut self, header: u8) -> Vec<u8> {
+            self.write_bits(1, 1);
+            while self.num_bits % 8 != 0 {
+                self.write_bits(0, 1);
             }
+            let mut nal = vec![header];
+            nal.extend(self.data);
+            nal
         }
+    }
 
-        Some(next_slice)
+    fn sps() -> Vec<u8> {
+        let mut w = BitWriter::default();
+        // profile_idc (baseline), constraint flags, level_idc, sps_id.
+        w.write_bits(66, 8)
+            .write_bits(0, 8)
+            .write_bits(30, 8)
+            .write_ue(0);
+        // log2_max_frame_num_minus4, pic_order_cnt_type, log2_max_pic_order_cnt_lsb_minus4.
+        w.write_ue(0).write_ue(0).write_ue(2);
+        // max_num_ref_frames, gaps_in_frame_num_value_allowed_flag.
+        w.write_ue(1).write_bits(0, 1);
+        // pic_width_in_mbs_minus1, pic_height_in_map_units_minus1, frame_mbs_only_flag.
+        w.write_ue(19).write_ue(14).write_bits(1, 1);
+        w.into_nal(0x67)
     }
-}
 
-impl<S: io::Read> StreamSplitter for H264FrameSplitter<S> {}
+    fn pps() -> Vec<u8> {
+        let mut w = BitWriter::default();
+        // pps_id, sps_id, entropy_coding_mode_flag, bottom_field_pic_order_in_frame_present_flag.
+        w.write_ue(0).write_ue(0).write_bits(0, 1).write_bits(0, 1);
+        w.into_nal(0x68)
+    }
+
+    fn slice(idr: bool, first_mb: u32, frame_num: u32, poc_lsb: u32) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        // first_mb_in_slice, slice_type, pps_id, frame_num.
+        w.write_ue(first_mb)
+            .write_ue(if idr { 7 } else { 5 })
+            .write_ue(0);
+        w.write_bits(frame_num, 4);
+        if idr {
+            w.write_ue(0);
+        }
+        w.write_bits(poc_lsb, 6);
+        // Some dummy slice data.
+        w.write_bits(0xa5a5, 16);
+        w.into_nal(if idr { 0x65 } else { 0x41 })
+    }
+
+    fn stream_of(nals: &[(&[u8], Vec<u8>)]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|(start_code, nal)| start_code.iter().chain(nal.iter()).copied())
+            .collect()
+    }
+
+    const SC3: &[u8] = &[0, 0, 1];
+    const SC4: &[u8] = &[0, 0, 0, 1];
+
+    #[test]
+    fn test_nal_to_rbsp() {
+        assert_eq!(nal_to_rbsp(&[0, 0, 3, 1]), vec![0, 0, 1]);
+        assert_eq!(nal_to_rbsp(&[0, 0, 3, 0, 0, 3]), vec![0, 0, 0, 0]);
+        assert_eq!(nal_to_rbsp(&[0, 3, 0, 3]), vec![0, 3, 0, 3]);
+    }
+
+    #[test]
+    fn test_exp_golomb() {
+        let mut w = BitWriter::default();
+        for i in 0..100 {
+            w.write_ue(i);
+        }
+        let mut r = BitReader::new(&w.data);
+        for i in 0..100 {
+            assert_eq!(r.read_ue().unwrap(), i);
+        }
+    }
+
+    #[test]
+    fn test_split_access_units() {
+        let aud = vec![0x09, 0xf0];
+        let sei = vec![0x06, 0x05, 0x01, 0x00, 0x80];
+
+        let stream = stream_of(&[
+            // First AU: IDR picture with two slices and 3-byte start codes.
+            (SC4, aud.clone()),
+            (SC4, sps()),
+            (SC3, pps()),
+            (SC3, sei.clone()),
+            (SC3, slice(true, 0, 0, 0)),
+            (SC3, slice(true, 150, 0, 0)),
+            // Second AU: non-IDR picture with three slices and no AUD.
+            (SC4, sei.clone()),
+            (SC4, slice(false, 0, 1, 2)),
+            (SC3, slice(false, 100, 1, 2)),
+            (SC4, slice(false, 200, 1, 2)),
+            // Third AU: single slice with no preceding non-VCL NAL unit.
+            (SC4, slice(false, 0, 2, 4)),
+            // Fourth AU: a slice with first_mb_in_slice != 0 that still starts a new picture.
+            (SC3, slice(false, 10, 3, 6)),
+            (SC3, vec![0x0b]),
+        ]);
+
+        let mut splitter = H264FrameSplitter::new(stream.as_slice()).unwrap();
+
+        let au = splitter.next_access_unit().unwrap();
+        assert!(au.is_idr);
+        assert!(au.is_reference);
+        assert_eq!(au.num_slices, 2);
+        assert_eq!(au.frame_num, Some(0));
+        let mut reconstructed = au.data.clone();
+
+        let au = splitter.next_access_unit().unwrap();
+        assert!(!au.is_idr);
+        assert_eq!(au.num_slices, 3);
+        assert_eq!(au.frame_num, Some(1));
+        assert!(au.data.starts_with(&[0, 0, 0, 1, 0x06]));
+        reconstructed.extend(au.data);
+
+        let au = splitter.next_access_unit().unwrap();
+        assert_eq!(au.num_slices, 1);
+        assert_eq!(au.frame_num, Some(2));
+        reconstructed.extend(au.data);
+
+        let au = splitter.next_access_unit().unwrap();
+        assert_eq!(au.num_slices, 1);
+        assert_eq!(au.frame_num, Some(3));
+        assert!(au.data.ends_with(&[0, 0, 1, 0x0b]));
+        reconstructed.extend(au.data);
+
+        assert!(splitter.next_access_unit().is_none());
+        assert_eq!(reconstructed, stream);
+    }
+
+    #[test]
+    fn test_split_without_parameter_sets() {
+        // Without SPS and PPS we can only rely on first_mb_in_slice.
+        let stream = stream_of(&[
+            (SC4, slice(true, 0, 0, 0)),
+            (SC4, slice(true, 50, 0, 0)),
+            (SC4, slice(false, 0, 1, 2)),
+        ]);
+
+        let splitter = H264FrameSplitter::new(stream.as_slice()).unwrap();
+        let aus = splitter.collect::<Vec<_>>();
+        assert_eq!(aus.len(), 2);
+        assert_eq!(aus.concat(), stream);
+    }
+
+    #[test]
+    fn test_invalid_stream() {
+        assert!(H264FrameSplitter::new([0x12u8, 0, 0, 1, 0x65].as_ref()).is_none());
+        assert!(H264FrameSplitter::new([0u8, 0, 0].as_ref()).is_none());
+        assert!(H264FrameSplitter::new([0u8, 0, 0, 0, 1, 0x09, 0xf0].as_ref()).is_some());
+    }
+}
@@ -0,0 +1,648 @@
+//! Demuxing of video tracks from Matroska and WebM files.
+//!
+//! [`MatroskaDemuxer`] reads a stream sequentially, which makes it usable with non-seekable
+//! inputs, and returns the frames of the first video track along with their timestamps. Frames
+//! are returned as stored in the container, so this is mostly useful for codecs that do not need
+//! any conversion to be queued into a stateful decoder, like VP8, VP9 and AV1.
+use crate::PixelFormat;
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, Read};
+use thiserror::Error;
+
+/// Element IDs we are interested in, from the Matroska specification.
+mod id {
+    pub const EBML: u32 = 0x1a45_dfa3;
+    pub const SEGMENT: u32 = 0x1853_8067;
+    pub const INFO: u32 = 0x1549_a966;
+    pub const TIMESTAMP_SCALE: u32 = 0x2a_d7b1;
+    pub const TRACKS: u32 = 0x1654_ae6b;
+    pub const TRACK_ENTRY: u32 = 0xae;
+    pub const TRACK_NUMBER: u32 = 0xd7;
+    pub const TRACK_TYPE: u32 = 0x83;
+    pub const CODEC_ID: u32 = 0x86;
+    pub const CODEC_PRIVATE: u32 = 0x63a2;
+    pub const VIDEO: u32 = 0xe0;
+    pub const PIXEL_WIDTH: u32 = 0xb0;
+    pub const PIXEL_HEIGHT: u32 = 0xba;
+    pub const CLUSTER: u32 = 0x1f43_b675;
+    pub const TIMESTAMP: u32 = 0xe7;
+    pub const SIMPLE_BLOCK: u32 = 0xa3;
+    pub const BLOCK_GROUP: u32 = 0xa0;
+    pub const BLOCK: u32 = 0xa1;
+    pub const BLOCK_DURATION: u32 = 0x9b;
+    pub const REFERENCE_BLOCK: u32 = 0xfb;
+}
+
+/// Track type of video tracks.
+const TRACK_TYPE_VIDEO: u64 = 1;
+/// Default duration of a timestamp tick, in nanoseconds.
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+/// Size above which we consider an element we need to load in memory to be invalid.
+const MAX_ELEMENT_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum MatroskaError {
+    #[error("I/O error while reading stream: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Stream is not an EBML document")]
+    NotEbml,
+    #[error("Invalid element 0x{0:x}")]
+    InvalidElement(u32),
+    #[error("Invalid variable-size integer")]
+    InvalidVint,
+    #[error("No video track found in stream")]
+    NoVideoTrack,
+}
+
+/// Returns the length of a variable-size integer from its first byte.
+fn vint_len(first: u8) -> Result<usize, MatroskaError> {
+    match first.leading_zeros() {
+        8 => Err(MatroskaError::InvalidVint),
+        n => Ok(n as usize + 1),
+    }
+}
+
+/// Parse a variable-size integer at the beginning of `data`, returning its value without the
+/// length marker and its length.
+fn parse_vint(data: &[u8]) -> Result<(u64, usize), MatroskaError> {
+    let first = *data.first().ok_or(MatroskaError::InvalidVint)?;
+    let len = vint_len(first)?;
+    let bytes = data.get(1..len).ok_or(MatroskaError::InvalidVint)?;
+    let value = bytes
+        .iter()
+        .fold((first as u64) & (0xff >> len), |v, &b| (v << 8) | b as u64);
+
+    Ok((value, len))
+}
+
+/// Parse the value of an unsigned integer element.
+fn parse_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |v, &b| (v << 8) | b as u64)
+}
+
+/// Iterates over the elements contained in `data`, returning their ID and payload.
+fn elements(data: &[u8]) -> impl Iterator<Item = Result<(u32, &[u8]), MatroskaError>> {
+    let mut data = data;
+
+    std::iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+        let res = (|| {
+            let id_len = vint_len(data[0])?;
+            let id = parse_uint(data.get(..id_len).ok_or(MatroskaError::InvalidVint)?) as u32;
+            let (size, size_len) = parse_vint(&data[id_len..])?;
+            let start = id_len + size_len;
+            let end = start
+                .checked_add(size as usize)
+                .filter(|&end| end <= data.len())
+                .ok_or(MatroskaError::InvalidElement(id))?;
+            let payload = &data[start..end];
+            data = &data[end..];
+            Ok((id, payload))
+        })();
+        if res.is_err() {
+            data = &[];
+        }
+        Some(res)
+    })
+}
+
+/// Split the payload of a block after its header into its frames, according to `lacing`.
+fn split_laced_frames(data: &[u8], lacing: u8) -> Result<Vec<&[u8]>, MatroskaError> {
+    if lacing == 0 {
+        return Ok(vec![data]);
+    }
+
+    let (&num_frames_minus1, mut data) = data
+        .split_first()
+        .ok_or(MatroskaError::InvalidElement(id::SIMPLE_BLOCK))?;
+    let num_frames = num_frames_minus1 as usize + 1;
+    let mut sizes = Vec::with_capacity(num_frames);
+    match lacing {
+        // Xiph lacing.
+        1 => {
+            for _ in 0..num_frames - 1 {
+                let mut size = 0;
+                loop {
+                    let (&b, rest) = data
+                        .split_first()
+                        .ok_or(MatroskaError::InvalidElement(id::SIMPLE_BLOCK))?;
+                    data = rest;
+                    size += b as usize;
+                    if b != 0xff {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+        // Fixed-size lacing.
+        2 => {
+            if data.len() % num_frames != 0 {
+                return Err(MatroskaError::InvalidElement(id::SIMPLE_BLOCK));
+            }
+            sizes.resize(num_frames - 1, data.len() / num_frames);
+        }
+        // EBML lacing. A single frame takes the whole block and has no size stored.
+        _ if num_frames == 1 => (),
+        _ => {
+            let (first_size, len) = parse_vint(data)?;
+            data = &data[len..];
+            let mut size = first_size as i64;
+            sizes.push(size as usize);
+            for _ in 1..num_frames - 1 {
+                let (raw, len) = parse_vint(data)?;
+                data = &data[len..];
+                size += raw as i64 - ((1i64 << (7 * len - 1)) - 1);
+                if size < 0 {
+                    return Err(MatroskaError::InvalidElement(id::SIMPLE_BLOCK));
+                }
+                sizes.push(size as usize);
+            }
+        }
+    }
+
+    let mut frames = Vec::with_capacity(num_frames);
+    for size in sizes {
+        if size > data.len() {
+            return Err(MatroskaError::InvalidElement(id::SIMPLE_BLOCK));
+        }
+        let (frame, rest) = data.split_at(size);
+        frames.push(frame);
+        data = rest;
+    }
+    frames.push(data);
+
+    Ok(frames)
+}
+
+/// A video frame returned by [`MatroskaDemuxer`].
+#[derive(Debug, Clone)]
+pub struct MatroskaFrame {
+    pub data: Vec<u8>,
+    /// Presentation time of the frame, in nanoseconds.
+    pub pts_ns: i64,
+    /// Duration of the frame in nanoseconds, if specified by the container.
+    pub duration_ns: Option<u64>,
+    pub is_keyframe: bool,
+}
+
+impl MatroskaFrame {
+    /// Returns the presentation time of the frame, suitable for use with
+    /// [`QBuffer::set_timestamp`](crate::device::queue::qbuf::QBuffer::set_timestamp).
+    pub fn timestamp(&self) -> TimeVal {
+        TimeVal::nanoseconds(self.pts_ns)
+    }
+}
+
+/// Description of the video track being demuxed.
+#[derive(Debug, Clone, Default)]
+struct VideoTrack {
+    number: u64,
+    codec_id: String,
+    codec_private: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+/// Demuxer returning the frames of the first video track of a Matroska or WebM stream.
+pub struct MatroskaDemuxer<S: Read> {
+    stream: S,
+    track: VideoTrack,
+    /// Duration of a timestamp tick, in nanoseconds.
+    timestamp_scale: u64,
+    /// Timestamp of the current cluster, in ticks.
+    cluster_timestamp: u64,
+    /// Frames parsed but not returned yet.
+    pending_frames: VecDeque<MatroskaFrame>,
+}
+
+impl<S: Read> MatroskaDemuxer<S> {
+    /// Create a new demuxer for the first video track found in `stream`.
+    ///
+    /// The stream is read up to its `Tracks` element.
+    pub fn new(mut stream: S) -> Result<Self, MatroskaError> {
+        match read_element_header(&mut stream)? {
+            Some((id::EBML, Some(size))) => skip(&mut stream, size)?,
+            _ => return Err(MatroskaError::NotEbml),
+        }
+
+        let mut timestamp_scale = DEFAULT_TIMESTAMP_SCALE;
+        let track = loop {
+            let (id, size) =
+                read_element_header(&mut stream)?.ok_or(MatroskaError::NoVideoTrack)?;
+            match (id, size) {
+                (id::SEGMENT, _) => (),
+                (id::INFO, Some(size)) => {
+                    let info = read_payload(&mut stream, id, size)?;
+                    for e in elements(&info) {
+                        if let (id::TIMESTAMP_SCALE, payload) = e? {
+                            timestamp_scale = parse_uint(payload);
+                        }
+                    }
+                }
+                (id::TRACKS, Some(size)) => {
+                    let tracks = read_payload(&mut stream, id, size)?;
+                    break parse_tracks(&tracks)?.ok_or(MatroskaError::NoVideoTrack)?;
+                }
+                // Tracks must be specified before any cluster.
+                (id::CLUSTER, _) => return Err(MatroskaError::NoVideoTrack),
+                (_, Some(size)) => skip(&mut stream, size)?,
+                (id, None) => return Err(MatroskaError::InvalidElement(id)),
+            }
+        };
+
+        Ok(MatroskaDemuxer {
+            stream,
+            track,
+            timestamp_scale,
+            cluster_timestamp: 0,
+            pending_frames: VecDeque::new(),
+        })
+    }
+
+    /// Returns the codec ID of the video track, e.g. `V_VP9`.
+    pub fn codec_id(&self) -> &str {
+        &self.track.codec_id
+    }
+
+    /// Returns the codec private data of the video track, which is empty if not specified.
+    pub fn codec_private(&self) -> &[u8] {
+        &self.track.codec_private
+    }
+
+    /// Returns the pixel format to use for the OUTPUT queue of a decoder for this track, if the
+    /// codec is supported.
+    pub fn pixel_format(&self) -> Option<PixelFormat> {
+        let fourcc = match self.track.codec_id.as_str() {
+            "V_VP8" => b"VP80",
+            "V_VP9" => b"VP90",
+            "V_AV1" => b"AV1F",
+            _ => return None,
+        };
+
+        Some(fourcc.into())
+    }
+
+    /// Returns the size of the video track, as declared by its `Video` element.
+    pub fn size(&self) -> (u32, u32) {
+        (self.track.width, self.track.height)
+    }
+
+    /// Parse a `Block` or `SimpleBlock` and add the frames it contains to our pending frames,
+    /// if it belongs to our track.
+    fn parse_block(
+        &mut self,
+        block: &[u8],
+        simple_block: bool,
+        is_keyframe: bool,
+        duration: Option<u64>,
+    ) -> Result<(), MatroskaError> {
+        let (track_number, len) = parse_vint(block)?;
+        if track_number != self.track.number {
+            return Ok(());
+        }
+        let header = block
+            .get(len..len + 3)
+            .ok_or(MatroskaError::InvalidElement(id::BLOCK))?;
+        let relative_timestamp = i16::from_be_bytes([header[0], header[1]]) as i64;
+        let flags = header[2];
+        let is_keyframe = if simple_block {
+            flags & 0x80 != 0
+        } else {
+            is_keyframe
+        };
+        let pts_ns = i64::try_from(self.cluster_timestamp)
+            .ok()
+            .and_then(|timestamp| timestamp.checked_add(relative_timestamp))
+            .and_then(|timestamp| timestamp.checked_mul(i64::try_from(self.timestamp_scale).ok()?))
+            .ok_or(MatroskaError::InvalidElement(id::BLOCK))?;
+        let duration_ns = duration
+            .map(|d| {
+                d.checked_mul(self.timestamp_scale)
+                    .ok_or(MatroskaError::InvalidElement(id::BLOCK_DURATION))
+            })
+            .transpose()?;
+
+        for frame in split_laced_frames(&block[len + 3..], (flags >> 1) & 0x3)? {
+            self.pending_frames.push_back(MatroskaFrame {
+                data: frame.to_vec(),
+                pts_ns,
+                duration_ns,
+                is_keyframe,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next frame of the video track, or `None` if the end of the stream has been
+    /// reached.
+    pub fn next_frame(&mut self) -> Result<Option<MatroskaFrame>, MatroskaError> {
+        while self.pending_frames.is_empty() {
+            let (id, size) = match read_element_header(&mut self.stream)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+
+            match (id, size) {
+                // Enter these elements, which may also be of unknown size.
+                (id::SEGMENT, _) | (id::CLUSTER, _) => (),
+                (id::TIMESTAMP, Some(size)) => {
+                    self.cluster_timestamp = parse_uint(&read_payload(&mut self.stream, id, size)?);
+                }
+                (id::SIMPLE_BLOCK, Some(size)) => {
+                    let block = read_payload(&mut self.stream, id, size)?;
+                    self.parse_block(&block, true, false, None)?;
+                }
+                (id::BLOCK_GROUP, Some(size)) => {
+                    let group = read_payload(&mut self.stream, id, size)?;
+                    let mut block = None;
+                    let mut duration = None;
+                    let mut is_keyframe = true;
+                    for e in elements(&group) {
+                        match e? {
+                            (id::BLOCK, payload) => block = Some(payload),
+                            (id::BLOCK_DURATION, payload) => duration = Some(parse_uint(payload)),
+                            (id::REFERENCE_BLOCK, _) => is_keyframe = false,
+                            _ => (),
+                        }
+                    }
+                    if let Some(block) = block {
+                        self.parse_block(block, false, is_keyframe, duration)?;
+                    }
+                }
+                (_, Some(size)) => skip(&mut self.stream, size)?,
+                (id, None) => return Err(MatroskaError::InvalidElement(id)),
+            }
+        }
+
+        Ok(self.pending_frames.pop_front())
+    }
+}
+
+impl<S: Read> Iterator for MatroskaDemuxer<S> {
+    type Item = MatroskaFrame;
+
+    /// Returns the next frame of the video track. Errors are logged and end the iteration.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().unwrap_or_else(|e| {
+            log::error!("Error while demuxing Matroska stream: {}", e);
+            None
+        })
+    }
+}
+
+/// Parse a `Tracks` element and return its first video track, if any.
+fn parse_tracks(tracks: &[u8]) -> Result<Option<VideoTrack>, MatroskaError> {
+    for e in elements(tracks) {
+        let entry = match e? {
+            (id::TRACK_ENTRY, entry) => entry,
+            _ => continue,
+        };
+
+        let mut track = VideoTrack::default();
+        let mut track_type = 0;
+        for e in elements(entry) {
+            match e? {
+                (id::TRACK_NUMBER, payload) => track.number = parse_uint(payload),
+                (id::TRACK_TYPE, payload) => track_type = parse_uint(payload),
+                (id::CODEC_ID, payload) => {
+                    track.codec_id = String::from_utf8_lossy(payload)
+                        .trim_end_matches('\0')
+                        .to_string()
+                }
+                (id::CODEC_PRIVATE, payload) => track.codec_private = payload.to_vec(),
+                (id::VIDEO, video) => {
+                    for e in elements(video) {
+                        match e? {
+                            (id::PIXEL_WIDTH, payload) => track.width = parse_uint(payload) as u32,
+                            (id::PIXEL_HEIGHT, payload) => {
+                                track.height = parse_uint(payload) as u32
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if track_type == TRACK_TYPE_VIDEO {
+            return Ok(Some(track));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Read the ID and size of the next element of `stream`. The size is `None` if unknown.
+///
+/// Returns `None` if the end of the stream has been reached.
+fn read_element_header<S: Read>(
+    stream: &mut S,
+) -> Result<Option<(u32, Option<u64>)>, MatroskaError> {
+    let mut first = [0u8; 1];
+    loop {
+        match stream.read(&mut first) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let id_len = vint_len(first[0])?;
+    if id_len > 4 {
+        return Err(MatroskaError::InvalidVint);
+    }
+    let mut id_bytes = [0u8; 4];
+    id_bytes[0] = first[0];
+    stream.read_exact(&mut id_bytes[1..id_len])?;
+    let id = parse_uint(&id_bytes[..id_len]) as u32;
+
+    let mut size_bytes = [0u8; 8];
+    stream.read_exact(&mut size_bytes[..1])?;
+    let size_len = vint_len(size_bytes[0])?;
+    stream.read_exact(&mut size_bytes[1..size_len])?;
+    let (size, _) = parse_vint(&size_bytes[..size_len])?;
+    // A size with all its bits set means that the size is unknown.
+    let unknown = (1u64 << (7 * size_len)) - 1;
+
+    Ok(Some((id, if size == unknown { None } else { Some(size) })))
+}
+
+/// Read the payload of element `id` of size `size`.
+fn read_payload<S: Read>(stream: &mut S, id: u32, size: u64) -> Result<Vec<u8>, MatroskaError> {
+    if size > MAX_ELEMENT_SIZE {
+        return Err(MatroskaError::InvalidElement(id));
+    }
+    let mut payload = vec![0u8; size as usize];
+    stream.read_exact(&mut payload)?;
+
+    Ok(payload)
+}
+
+/// Skip `size` bytes of `stream`.
+fn skip<S: Read>(stream: &mut S, size: u64) -> Result<(), MatroskaError> {
+    let skipped = io::copy(&mut stream.take(size), &mut io::sink())?;
+    if skipped < size {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode element `id` with `payload`, using a 8-byte size.
+    fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut e = id
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|&b| b == 0)
+            .collect::<Vec<_>>();
+        e.push(0x01);
+        e.extend_from_slice(&(payload.len() as u64).to_be_bytes()[1..]);
+        e.extend_from_slice(payload);
+        e
+    }
+
+    /// Encode element `id` with an unknown size.
+    fn unknown_size_element(id: u32) -> Vec<u8> {
+        let mut e = id.to_be_bytes().to_vec();
+        e.push(0xff);
+        e
+    }
+
+    fn simple_block(track: u8, timestamp: i16, flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut b = vec![0x80 | track];
+        b.extend_from_slice(&timestamp.to_be_bytes());
+        b.push(flags);
+        b.extend_from_slice(data);
+        element(id::SIMPLE_BLOCK, &b)
+    }
+
+    #[test]
+    fn test_parse_vint() {
+        assert_eq!(parse_vint(&[0x81]).unwrap(), (1, 1));
+        assert_eq!(parse_vint(&[0x40, 0x02]).unwrap(), (2, 2));
+        assert_eq!(
+            parse_vint(&[0x1a, 0x45, 0xdf, 0xa3]).unwrap(),
+            (0x0a45dfa3, 4)
+        );
+        assert!(parse_vint(&[0x00]).is_err());
+        assert!(parse_vint(&[0x40]).is_err());
+    }
+
+    #[test]
+    fn test_demux() {
+        let mut stream = element(id::EBML, &element(0x4282, b"webm"));
+        stream.extend(unknown_size_element(id::SEGMENT));
+        stream.extend(element(
+            id::INFO,
+            &element(id::TIMESTAMP_SCALE, &[0x0f, 0x42, 0x40]),
+        ));
+
+        let audio_track = [
+            element(id::TRACK_NUMBER, &[1]),
+            element(id::TRACK_TYPE, &[2]),
+            element(id::CODEC_ID, b"A_OPUS"),
+        ]
+        .concat();
+        let video_track = [
+            element(id::TRACK_NUMBER, &[2]),
+            element(id::TRACK_TYPE, &[1]),
+            element(id::CODEC_ID, b"V_VP9"),
+            element(id::CODEC_PRIVATE, &[1, 1, 0]),
+            element(
+                id::VIDEO,
+                &[
+                    element(id::PIXEL_WIDTH, &[0x05, 0x00]),
+                    element(id::PIXEL_HEIGHT, &[0x02, 0xd0]),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        stream.extend(element(
+            id::TRACKS,
+            &[
+                element(id::TRACK_ENTRY, &audio_track),
+                element(id::TRACK_ENTRY, &video_track),
+            ]
+            .concat(),
+        ));
+
+        // First cluster, with known size.
+        let block_group = [
+            element(id::BLOCK, &[0x82, 0x00, 0x28, 0x00, 0xcc]),
+            element(id::BLOCK_DURATION, &[33]),
+            element(id::REFERENCE_BLOCK, &[0xdf]),
+        ]
+        .concat();
+        stream.extend(element(
+            id::CLUSTER,
+            &[
+                element(id::TIMESTAMP, &[0x03, 0xe8]),
+                simple_block(2, 0, 0x80, &[0xaa, 0xbb]),
+                simple_block(1, 0, 0x80, &[0x11]),
+                element(id::BLOCK_GROUP, &block_group),
+            ]
+            .concat(),
+        ));
+        // Second cluster, with unknown size and a fixed-size laced block.
+        stream.extend(unknown_size_element(id::CLUSTER));
+        stream.extend(element(id::TIMESTAMP, &[0x07, 0xd0]));
+        stream.extend(simple_block(2, -5, 0x04, &[0x01, 0xd1, 0xd2]));
+
+        let mut demuxer = MatroskaDemuxer::new(stream.as_slice()).unwrap();
+        assert_eq!(demuxer.codec_id(), "V_VP9");
+        assert_eq!(demuxer.codec_private(), &[1, 1, 0]);
+        assert_eq!(demuxer.pixel_format(), Some(b"VP90".into()));
+        assert_eq!(demuxer.size(), (1280, 720));
+
+        let f = demuxer.next_frame().unwrap().unwrap();
+        assert_eq!(f.data, vec![0xaa, 0xbb]);
+        assert!(f.is_keyframe);
+        assert_eq!(f.timestamp(), TimeVal::seconds(1));
+
+        let f = demuxer.next_frame().unwrap().unwrap();
+        assert_eq!(f.data, vec![0xcc]);
+        assert!(!f.is_keyframe);
+        assert_eq!(f.pts_ns, 1_040_000_000);
+        assert_eq!(f.duration_ns, Some(33_000_000));
+
+        let frames = demuxer.collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].data, vec![0xd1]);
+        assert_eq!(frames[1].data, vec![0xd2]);
+        assert_eq!(frames[1].pts_ns, 1_995_000_000);
+    }
+
+    #[test]
+    fn test_lacing() {
+        let data = [0x02, 0x02, 0x03, 0xa, 0xb, 0xb, 0xb, 0xc];
+        assert_eq!(
+            split_laced_frames(&data, 1).unwrap(),
+            vec![&[0xa, 0xb][..], &[0xb, 0xb, 0xc], &[]]
+        );
+        // Sizes 2, then 3 (signed difference of +1 on one byte).
+        let data = [0x02, 0x82, 0xc0, 0xa, 0xb, 0xb, 0xb, 0xc, 0xd];
+        assert_eq!(
+            split_laced_frames(&data, 3).unwrap(),
+            vec![&[0xa, 0xb][..], &[0xb, 0xb, 0xc], &[0xd]]
+        );
+        assert!(split_laced_frames(&[0x01, 0xa, 0xb, 0xc], 2).is_err());
+        assert_eq!(
+            split_laced_frames(&[0x00, 0xa, 0xb], 3).unwrap(),
+            vec![&[0xa, 0xb][..]]
+        );
+    }
+}
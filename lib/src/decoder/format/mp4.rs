@@ -0,0 +1,1137 @@
+//! Demuxing of video tracks from MP4 (ISO base media file format) and QuickTime files.
+//!
+//! [`Mp4Demuxer`] selects the first video track of a file and returns its samples in decoding
+//! order, using either the sample tables of the `moov` box or, for fragmented files, the `moof`
+//! boxes that follow it. H.264 and HEVC samples are converted from their length-prefixed form
+//! into Annex B, with the parameter sets of the sample description inserted before each sync
+//! sample, so they can be directly queued into a stateful decoder.
+use crate::PixelFormat;
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::io::{self, Read, Seek, SeekFrom};
+use thiserror::Error;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+/// `sample_is_non_sync_sample` bit of the sample flags used in fragments.
+const SAMPLE_IS_NON_SYNC_SAMPLE: u32 = 0x10000;
+/// Size above which we consider a box or sample we need to load in memory to be invalid.
+const MAX_BOX_SIZE: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum Mp4Error {
+    #[error("I/O error while reading stream: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Truncated or invalid {0} box")]
+    InvalidBox(&'static str),
+    #[error("No moov box found in stream")]
+    NoMoov,
+    #[error("No video track found in stream")]
+    NoVideoTrack,
+    #[error("Sample {0} of the video track is out of the stream bounds")]
+    InvalidSample(usize),
+}
+
+/// Reads big-endian values from the payload of a box.
+struct ByteReader<'a> {
+    data: &'a [u8],
+    /// Name of the box being read, for error reporting.
+    name: &'static str,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8], name: &'static str) -> Self {
+        ByteReader { data, name }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Mp4Error> {
+        if self.data.len() < len {
+            return Err(Mp4Error::InvalidBox(self.name));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), Mp4Error> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, Mp4Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Mp4Error> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Mp4Error> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, Mp4Error> {
+        Ok(((self.u32()? as u64) << 32) | self.u32()? as u64)
+    }
+
+    fn fourcc(&mut self) -> Result<[u8; 4], Mp4Error> {
+        let b = self.bytes(4)?;
+        Ok([b[0], b[1], b[2], b[3]])
+    }
+
+    /// Read the version and flags of a full box.
+    fn full_box_header(&mut self) -> Result<(u8, u32), Mp4Error> {
+        let v = self.u32()?;
+        Ok(((v >> 24) as u8, v & 0xff_ffff))
+    }
+
+    /// Read the number of entries of a table of `entry_size` bytes entries, making sure the
+    /// table fits in the box before anything gets allocated for it.
+    fn entry_count(&mut self, entry_size: usize) -> Result<usize, Mp4Error> {
+        let count = self.u32()? as usize;
+        match count.checked_mul(entry_size) {
+            Some(len) if len <= self.data.len() => Ok(count),
+            _ => Err(Mp4Error::InvalidBox(self.name)),
+        }
+    }
+
+    /// Read a 32-bit value if `version` is 0, or a 64-bit one otherwise.
+    fn versioned(&mut self, version: u8) -> Result<u64, Mp4Error> {
+        if version == 0 {
+            Ok(self.u32()? as u64)
+        } else {
+            self.u64()
+        }
+    }
+}
+
+/// Make sure `count` samples whose entries do not take any room in the `name` box, e.g. because
+/// they all have the same size, do not take more memory than the boxes we accept once expanded.
+fn check_sample_count(count: usize, name: &'static str) -> Result<(), Mp4Error> {
+    match count.checked_mul(std::mem::size_of::<SampleInfo>()) {
+        Some(len) if len as u64 <= MAX_BOX_SIZE => Ok(()),
+        _ => Err(Mp4Error::InvalidBox(name)),
+    }
+}
+
+/// Iterates over the boxes contained in `data`, returning their type and payload.
+fn boxes(data: &[u8]) -> impl Iterator<Item = Result<([u8; 4], &[u8]), Mp4Error>> {
+    let mut r = ByteReader::new(data, "child");
+
+    std::iter::from_fn(move || {
+        if r.data.is_empty() {
+            return None;
+        }
+        let res = (|| {
+            let size = r.u32()? as u64;
+            let box_type = r.fourcc()?;
+            let payload_size = match size {
+                0 => r.data.len() as u64,
+                1 => r
+                    .u64()?
+                    .checked_sub(16)
+                    .ok_or(Mp4Error::InvalidBox("child"))?,
+                _ => size.checked_sub(8).ok_or(Mp4Error::InvalidBox("child"))?,
+            };
+            Ok((box_type, r.bytes(payload_size as usize)?))
+        })();
+        if res.is_err() {
+            r.data = &[];
+        }
+        Some(res)
+    })
+}
+
+/// Returns the payload of the first box of type `box_type` in `data`, if any.
+fn find_box<'a>(data: &'a [u8], box_type: &[u8; 4]) -> Result<Option<&'a [u8]>, Mp4Error> {
+    for b in boxes(data) {
+        let (t, payload) = b?;
+        if &t == box_type {
+            return Ok(Some(payload));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Codec-specific configuration of the video track.
+#[derive(Debug, Clone, Default)]
+struct CodecConfig {
+    /// Size in bytes of the NAL unit length prefixes, for H.264 and HEVC.
+    nal_length_size: Option<usize>,
+    /// Parameter sets of the sample description, already in Annex B format.
+    parameter_sets: Vec<u8>,
+}
+
+fn push_nal(out: &mut Vec<u8>, nal: &[u8]) {
+    out.extend_from_slice(&START_CODE);
+    out.extend_from_slice(nal);
+}
+
+/// Parse an `AVCDecoderConfigurationRecord`.
+fn parse_avcc(data: &[u8]) -> Result<CodecConfig, Mp4Error> {
+    let mut r = ByteReader::new(data, "avcC");
+    let mut config = CodecConfig::default();
+
+    // configurationVersion, AVCProfileIndication, profile_compatibility, AVCLevelIndication.
+    r.skip(4)?;
+    config.nal_length_size = Some((r.u8()? & 0x3) as usize + 1);
+    let num_sps = r.u8()? & 0x1f;
+    for _ in 0..num_sps {
+        let len = r.u16()? as usize;
+        push_nal(&mut config.parameter_sets, r.bytes(len)?);
+    }
+    let num_pps = r.u8()?;
+    for _ in 0..num_pps {
+        let len = r.u16()? as usize;
+        push_nal(&mut config.parameter_sets, r.bytes(len)?);
+    }
+
+    Ok(config)
+}
+
+/// Parse a `HEVCDecoderConfigurationRecord`.
+fn parse_hvcc(data: &[u8]) -> Result<CodecConfig, Mp4Error> {
+    let mut r = ByteReader::new(data, "hvcC");
+    let mut config = CodecConfig::default();
+
+    // Everything up to and including avgFrameRate.
+    r.skip(21)?;
+    config.nal_length_size = Some((r.u8()? & 0x3) as usize + 1);
+    let num_arrays = r.u8()?;
+    for _ in 0..num_arrays {
+        // array_completeness and NAL_unit_type.
+        r.skip(1)?;
+        let num_nalus = r.u16()?;
+        for _ in 0..num_nalus {
+            let len = r.u16()? as usize;
+            push_nal(&mut config.parameter_sets, r.bytes(len)?);
+        }
+    }
+
+    Ok(config)
+}
+
+/// Location and timing of a sample in the stream.
+#[derive(Debug, Clone, Default)]
+struct SampleInfo {
+    offset: u64,
+    size: u32,
+    /// Decoding time, in track timescale units.
+    dts: u64,
+    /// Offset of the composition time relative to `dts`.
+    cts_offset: i64,
+    duration: u32,
+    is_sync: bool,
+}
+
+/// Default values for the samples of fragments, from the `trex` box.
+#[derive(Debug, Clone, Default)]
+struct TrackExtends {
+    default_sample_duration: u32,
+    default_sample_size: u32,
+    default_sample_flags: u32,
+}
+
+/// A video sample returned by [`Mp4Demuxer`].
+#[derive(Debug, Clone)]
+pub struct Mp4Sample {
+    /// Data of the sample, in Annex B format for H.264 and HEVC.
+    pub data: Vec<u8>,
+    /// Presentation time, in units of [`Mp4Demuxer::timescale`].
+    pub pts: i64,
+    /// Decoding time, in units of [`Mp4Demuxer::timescale`].
+    pub dts: u64,
+    /// Duration of the sample, in units of [`Mp4Demuxer::timescale`].
+    pub duration: u32,
+    /// Whether this sample can be decoded independently of the previous ones.
+    pub is_sync: bool,
+    timescale: u32,
+}
+
+impl Mp4Sample {
+    /// Returns the presentation time of the sample, suitable for use with
+    /// [`QBuffer::set_timestamp`](crate::device::queue::qbuf::QBuffer::set_timestamp).
+    pub fn timestamp(&self) -> TimeVal {
+        TimeVal::microseconds(
+            (self.pts as i128 * 1_000_000 / std::cmp::max(self.timescale, 1) as i128) as i64,
+        )
+    }
+}
+
+/// Demuxer returning the samples of the first video track of a MP4 or QuickTime stream.
+///
+/// Only the first sample description of the track is used, and edit lists are ignored.
+pub struct Mp4Demuxer<S: Read + Seek> {
+    stream: S,
+    track_id: u32,
+    timescale: u32,
+    codec: [u8; 4],
+    width: u16,
+    height: u16,
+    config: CodecConfig,
+    /// Samples left to return, in reverse decoding order.
+    samples: Vec<SampleInfo>,
+    /// Total number of samples seen so far, for error reporting.
+    sample_count: usize,
+    /// Fragment defaults, if the stream is fragmented.
+    trex: Option<TrackExtends>,
+    /// Position of the next top-level box to look for fragments in.
+    next_box_pos: u64,
+    /// Decoding time of the next fragment if it has no `tfdt` box.
+    next_fragment_dts: u64,
+    /// Whether we need to insert the parameter sets before the next sample.
+    needs_parameter_sets: bool,
+}
+
+impl<S: Read + Seek> Mp4Demuxer<S> {
+    /// Create a new demuxer for the first video track found in `stream`.
+    pub fn new(mut stream: S) -> Result<Self, Mp4Error> {
+        let mut pos = stream.seek(SeekFrom::Start(0))?;
+        let moov = loop {
+            let (box_type, payload_pos, next_pos) =
+                read_box_header(&mut stream, pos)?.ok_or(Mp4Error::NoMoov)?;
+            if &box_type == b"moov" {
+                let moov = read_payload(&mut stream, "moov", next_pos - payload_pos)?;
+                pos = next_pos;
+                break moov;
+            }
+            pos = next_pos;
+        };
+
+        let mut demuxer = Self::from_moov(stream, &moov)?;
+        demuxer.next_box_pos = pos;
+
+        Ok(demuxer)
+    }
+
+    fn from_moov(stream: S, moov: &[u8]) -> Result<Self, Mp4Error> {
+        for b in boxes(moov) {
+            let (box_type, trak) = b?;
+            if &box_type != b"trak" {
+                continue;
+            }
+            let mdia = find_box(trak, b"mdia")?.ok_or(Mp4Error::InvalidBox("trak"))?;
+            let hdlr = find_box(mdia, b"hdlr")?.ok_or(Mp4Error::InvalidBox("mdia"))?;
+            let mut r = ByteReader::new(hdlr, "hdlr");
+            r.full_box_header()?;
+            // pre_defined
+            r.skip(4)?;
+            if &r.fourcc()? != b"vide" {
+                continue;
+            }
+
+            let mut demuxer = Self::from_video_trak(stream, trak, mdia)?;
+            if let Some(mvex) = find_box(moov, b"mvex")? {
+                demuxer.trex = Some(TrackExtends::default());
+                for b in boxes(mvex) {
+                    let (box_type, trex) = b?;
+                    if &box_type != b"trex" {
+                        continue;
+                    }
+                    let mut r = ByteReader::new(trex, "trex");
+                    r.full_box_header()?;
+                    if r.u32()? != demuxer.track_id {
+                        continue;
+                    }
+                    // default_sample_description_index
+                    r.skip(4)?;
+                    demuxer.trex = Some(TrackExtends {
+                        default_sample_duration: r.u32()?,
+                        default_sample_size: r.u32()?,
+                        default_sample_flags: r.u32()?,
+                    });
+                }
+            }
+
+            return Ok(demuxer);
+        }
+
+        Err(Mp4Error::NoVideoTrack)
+    }
+
+    fn from_video_trak(stream: S, trak: &[u8], mdia: &[u8]) -> Result<Self, Mp4Error> {
+        let tkhd = find_box(trak, b"tkhd")?.ok_or(Mp4Error::InvalidBox("trak"))?;
+        let mut r = ByteReader::new(tkhd, "tkhd");
+        let (version, _) = r.full_box_header()?;
+        // creation_time, modification_time
+        r.versioned(version)?;
+        r.versioned(version)?;
+        let track_id = r.u32()?;
+
+        let mdhd = find_box(mdia, b"mdhd")?.ok_or(Mp4Error::InvalidBox("mdia"))?;
+        let mut r = ByteReader::new(mdhd, "mdhd");
+        let (version, _) = r.full_box_header()?;
+        r.versioned(version)?;
+        r.versioned(version)?;
+        let timescale = r.u32()?;
+
+        let stbl = find_box(mdia, b"minf")?
+            .and_then(|minf| find_box(minf, b"stbl").transpose())
+            .transpose()?
+            .ok_or(Mp4Error::InvalidBox("mdia"))?;
+
+        let stsd = find_box(stbl, b"stsd")?.ok_or(Mp4Error::InvalidBox("stbl"))?;
+        let mut r = ByteReader::new(stsd, "stsd");
+        r.full_box_header()?;
+        // entry_count
+        r.skip(4)?;
+        let (codec, entry) = boxes(r.data).next().ok_or(Mp4Error::InvalidBox("stsd"))??;
+        let mut r = ByteReader::new(entry, "stsd");
+        // SampleEntry and VisualSampleEntry fields up to width and height.
+        r.skip(24)?;
+        let width = r.u16()?;
+        let height = r.u16()?;
+        // The rest of VisualSampleEntry, up to the child boxes.
+        r.skip(50)?;
+        let config = if let Some(avcc) = find_box(r.data, b"avcC")? {
+            parse_avcc(avcc)?
+        } else if let Some(hvcc) = find_box(r.data, b"hvcC")? {
+            parse_hvcc(hvcc)?
+        } else {
+            CodecConfig::default()
+        };
+
+        let mut samples = parse_sample_tables(stbl)?;
+        samples.reverse();
+
+        Ok(Mp4Demuxer {
+            stream,
+            track_id,
+            timescale,
+            codec,
+            width,
+            height,
+            config,
+            samples,
+            sample_count: 0,
+            trex: None,
+            next_box_pos: 0,
+            next_fragment_dts: 0,
+            needs_parameter_sets: true,
+        })
+    }
+
+    /// Returns the coding name of the sample description of the track, e.g. `avc1` or `vp09`.
+    pub fn codec(&self) -> [u8; 4] {
+        self.codec
+    }
+
+    /// Returns the pixel format to use for the OUTPUT queue of a decoder for this track, if the
+    /// codec is known.
+    pub fn pixel_format(&self) -> Option<PixelFormat> {
+        let fourcc = match &self.codec {
+            b"avc1" | b"avc3" => b"H264",
+            b"hvc1" | b"hev1" => b"HEVC",
+            b"vp08" => b"VP80",
+            b"vp09" => b"VP90",
+            b"av01" => b"AV1F",
+            b"mp4v" => b"MPG4",
+            _ => return None,
+        };
+
+        Some(fourcc.into())
+    }
+
+    /// Returns the coded size of the track, as declared by its sample description.
+    pub fn size(&self) -> (u32, u32) {
+        (self.width as u32, self.height as u32)
+    }
+
+    /// Returns the number of time units per second used for the timestamps of this track.
+    pub fn timescale(&self) -> u32 {
+        self.timescale
+    }
+
+    /// Parse the next fragment of our track, if any. Returns `false` if the end of the stream
+    /// has been reached.
+    fn read_next_fragment(&mut self) -> Result<bool, Mp4Error> {
+        let trex = match &self.trex {
+            Some(trex) => trex.clone(),
+            None => return Ok(false),
+        };
+
+        loop {
+            let (box_type, payload_pos, next_pos) =
+                match read_box_header(&mut self.stream, self.next_box_pos)? {
+                    Some(header) => header,
+                    None => return Ok(false),
+                };
+            let moof_pos = self.next_box_pos;
+            self.next_box_pos = next_pos;
+            if &box_type != b"moof" {
+                continue;
+            }
+
+            let moof = read_payload(&mut self.stream, "moof", next_pos - payload_pos)?;
+            for b in boxes(&moof) {
+                let (box_type, traf) = b?;
+                if &box_type == b"traf" {
+                    self.parse_traf(traf, moof_pos, &trex)?;
+                }
+            }
+
+            if !self.samples.is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn parse_traf(
+        &mut self,
+        traf: &[u8],
+        moof_pos: u64,
+        trex: &TrackExtends,
+    ) -> Result<(), Mp4Error> {
+        let tfhd = find_box(traf, b"tfhd")?.ok_or(Mp4Error::InvalidBox("traf"))?;
+        let mut r = ByteReader::new(tfhd, "tfhd");
+        let (_, flags) = r.full_box_header()?;
+        if r.u32()? != self.track_id {
+            return Ok(());
+        }
+        let base_data_offset = if flags & 0x1 != 0 { r.u64()? } else { moof_pos };
+        if flags & 0x2 != 0 {
+            // sample_description_index
+            r.skip(4)?;
+        }
+        let mut defaults = trex.clone();
+        if flags & 0x8 != 0 {
+            defaults.default_sample_duration = r.u32()?;
+        }
+        if flags & 0x10 != 0 {
+            defaults.default_sample_size = r.u32()?;
+        }
+        if flags & 0x20 != 0 {
+            defaults.default_sample_flags = r.u32()?;
+        }
+
+        let mut dts = match find_box(traf, b"tfdt")? {
+            Some(tfdt) => {
+                let mut r = ByteReader::new(tfdt, "tfdt");
+                let (version, _) = r.full_box_header()?;
+                r.versioned(version)?
+            }
+            None => self.next_fragment_dts,
+        };
+
+        let mut samples = Vec::new();
+        let mut data_offset = base_data_offset;
+        for b in boxes(traf) {
+            let (box_type, trun) = b?;
+            if &box_type != b"trun" {
+                continue;
+            }
+            let mut r = ByteReader::new(trun, "trun");
+            let (version, flags) = r.full_box_header()?;
+            // Each of the duration, size, flags and composition time offset fields takes 4 bytes
+            // per sample if present.
+            let sample_count = r.entry_count(4 * (flags & 0xf00).count_ones() as usize)?;
+            check_sample_count(samples.len() + sample_count, "trun")?;
+            if flags & 0x1 != 0 {
+                data_offset = (base_data_offset as i64 + r.u32()? as i32 as i64) as u64;
+            }
+            let first_sample_flags = if flags & 0x4 != 0 {
+                Some(r.u32()?)
+            } else {
+                None
+            };
+
+            for i in 0..sample_count {
+                let duration = if flags & 0x100 != 0 {
+                    r.u32()?
+                } else {
+                    defaults.default_sample_duration
+                };
+                let size = if flags & 0x200 != 0 {
+                    r.u32()?
+                } else {
+                    defaults.default_sample_size
+                };
+                let sample_flags = if flags & 0x400 != 0 {
+                    r.u32()?
+                } else {
+                    match first_sample_flags {
+                        Some(flags) if i == 0 => flags,
+                        _ => defaults.default_sample_flags,
+                    }
+                };
+                let cts_offset = if flags & 0x800 != 0 {
+                    let offset = r.u32()?;
+                    if version == 0 {
+                        offset as i64
+                    } else {
+                        offset as i32 as i64
+                    }
+                } else {
+                    0
+                };
+
+                samples.push(SampleInfo {
+                    offset: data_offset,
+                    size,
+                    dts,
+                    cts_offset,
+                    duration,
+                    is_sync: sample_flags & SAMPLE_IS_NON_SYNC_SAMPLE == 0,
+                });
+                data_offset += size as u64;
+                dts += duration as u64;
+            }
+        }
+
+        self.next_fragment_dts = dts;
+        samples.reverse();
+        samples.append(&mut self.samples);
+        self.samples = samples;
+
+        Ok(())
+    }
+
+    /// Returns the next sample of the track in decoding order, or `None` if the end of the
+    /// stream has been reached.
+    pub fn next_sample(&mut self) -> Result<Option<Mp4Sample>, Mp4Error> {
+        if self.samples.is_empty() && !self.read_next_fragment()? {
+            return Ok(None);
+        }
+        let info = match self.samples.pop() {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let sample_index = self.sample_count;
+        self.sample_count += 1;
+
+        if u64::from(info.size) > MAX_BOX_SIZE {
+            return Err(Mp4Error::InvalidBox("sample"));
+        }
+        let mut raw = vec![0u8; info.size as usize];
+        self.stream.seek(SeekFrom::Start(info.offset))?;
+        self.stream
+            .read_exact(&mut raw)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => Mp4Error::InvalidSample(sample_index),
+                _ => Mp4Error::IoError(e),
+            })?;
+
+        let data = match self.config.nal_length_size {
+            Some(length_size) => {
+                let mut data = Vec::with_capacity(raw.len() + self.config.parameter_sets.len());
+                if info.is_sync || self.needs_parameter_sets {
+                    data.extend_from_slice(&self.config.parameter_sets);
+                    self.needs_parameter_sets = false;
+                }
+                let mut r = ByteReader::new(&raw, "sample");
+                while !r.data.is_empty() {
+                    let len = r
+                        .bytes(length_size)
+                        .map_err(|_| Mp4Error::InvalidSample(sample_index))?
+                        .iter()
+                        .fold(0usize, |len, &b| (len << 8) | b as usize);
+                    let nal = r
+                        .bytes(len)
+                        .map_err(|_| Mp4Error::InvalidSample(sample_index))?;
+                    push_nal(&mut data, nal);
+                }
+                data
+            }
+            None => raw,
+        };
+
+        Ok(Some(Mp4Sample {
+            data,
+            pts: info.dts as i64 + info.cts_offset,
+            dts: info.dts,
+            duration: info.duration,
+            is_sync: info.is_sync,
+            timescale: self.timescale,
+        }))
+    }
+}
+
+impl<S: Read + Seek> Iterator for Mp4Demuxer<S> {
+    type Item = Mp4Sample;
+
+    /// Returns the next sample of the track. Errors are logged and end the iteration.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_sample().unwrap_or_else(|e| {
+            log::error!("Error while demuxing MP4 stream: {}", e);
+            None
+        })
+    }
+}
+
+/// Read the header of the top-level box at `pos`, and return its type, the position of its
+/// payload and the position of the next box. The stream is left at the payload position.
+///
+/// Returns `None` if there is no box at `pos`.
+fn read_box_header<S: Read + Seek>(
+    stream: &mut S,
+    pos: u64,
+) -> Result<Option<([u8; 4], u64, u64)>, Mp4Error> {
+    stream.seek(SeekFrom::Start(pos))?;
+    let mut header = [0u8; 8];
+    match stream.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut r = ByteReader::new(&header, "top-level");
+    let size = r.u32()? as u64;
+    let box_type = r.fourcc()?;
+
+    let invalid = || Mp4Error::InvalidBox("top-level");
+    let (payload_pos, next_pos) = match size {
+        0 => {
+            let end = stream.seek(SeekFrom::End(0))?;
+            let payload_pos = pos.checked_add(8).ok_or_else(invalid)?;
+            stream.seek(SeekFrom::Start(payload_pos))?;
+            (payload_pos, end)
+        }
+        1 => {
+            let mut large_size = [0u8; 8];
+            stream.read_exact(&mut large_size)?;
+            (
+                pos.checked_add(16).ok_or_else(invalid)?,
+                pos.checked_add(u64::from_be_bytes(large_size))
+                    .ok_or_else(invalid)?,
+            )
+        }
+        _ => (
+            pos.checked_add(8).ok_or_else(invalid)?,
+            pos.checked_add(size).ok_or_else(invalid)?,
+        ),
+    };
+    if next_pos < payload_pos {
+        return Err(invalid());
+    }
+
+    Ok(Some((box_type, payload_pos, next_pos)))
+}
+
+/// Read the `size` bytes of payload of the `name` box the stream is positioned at.
+fn read_payload<S: Read>(
+    stream: &mut S,
+    name: &'static str,
+    size: u64,
+) -> Result<Vec<u8>, Mp4Error> {
+    if size > MAX_BOX_SIZE {
+        return Err(Mp4Error::InvalidBox(name));
+    }
+    let mut payload = vec![0u8; size as usize];
+    stream.read_exact(&mut payload)?;
+
+    Ok(payload)
+}
+
+/// Expand the sample tables of `stbl` into a list of samples in decoding order.
+fn parse_sample_tables(stbl: &[u8]) -> Result<Vec<SampleInfo>, Mp4Error> {
+    // Sample sizes.
+    let sizes: Vec<u32> = if let Some(stsz) = find_box(stbl, b"stsz")? {
+        let mut r = ByteReader::new(stsz, "stsz");
+        r.full_box_header()?;
+        let sample_size = r.u32()?;
+        if sample_size != 0 {
+            let sample_count = r.u32()? as usize;
+            check_sample_count(sample_count, "stsz")?;
+            vec![sample_size; sample_count]
+        } else {
+            let sample_count = r.entry_count(4)?;
+            (0..sample_count)
+                .map(|_| r.u32())
+                .collect::<Result<_, _>>()?
+        }
+    } else if let Some(stz2) = find_box(stbl, b"stz2")? {
+        let mut r = ByteReader::new(stz2, "stz2");
+        r.full_box_header()?;
+        r.skip(3)?;
+        let field_size = r.u8()?;
+        let sample_count = r.u32()? as usize;
+        let table_len = match field_size {
+            4 => Some(sample_count.div_ceil(2)),
+            8 => Some(sample_count),
+            16 => sample_count.checked_mul(2),
+            _ => return Err(Mp4Error::InvalidBox("stz2")),
+        };
+        if table_len.is_none_or(|len| len > r.data.len()) {
+            return Err(Mp4Error::InvalidBox("stz2"));
+        }
+        let mut sizes = Vec::with_capacity(sample_count);
+        let mut nibbles = 0u8;
+        for i in 0..sample_count {
+            sizes.push(match field_size {
+                // Two samples per byte, upper nibble first.
+                4 if i % 2 == 0 => {
+                    nibbles = r.u8()?;
+                    (nibbles >> 4) as u32
+                }
+                4 => (nibbles & 0xf) as u32,
+                8 => r.u8()? as u32,
+                _ => r.u16()? as u32,
+            });
+        }
+        sizes
+    } else {
+        // Fragmented files can have no sample size box.
+        return Ok(Vec::new());
+    };
+
+    // Chunk offsets.
+    let chunk_offsets: Vec<u64> = if let Some(stco) = find_box(stbl, b"stco")? {
+        let mut r = ByteReader::new(stco, "stco");
+        r.full_box_header()?;
+        let count = r.entry_count(4)?;
+        (0..count)
+            .map(|_| r.u32().map(|o| o as u64))
+            .collect::<Result<_, _>>()?
+    } else if let Some(co64) = find_box(stbl, b"co64")? {
+        let mut r = ByteReader::new(co64, "co64");
+        r.full_box_header()?;
+        let count = r.entry_count(8)?;
+        (0..count).map(|_| r.u64()).collect::<Result<_, _>>()?
+    } else {
+        return Err(Mp4Error::InvalidBox("stbl"));
+    };
+
+    // Sample to chunk mapping, as (first_chunk, samples_per_chunk) pairs.
+    let stsc = find_box(stbl, b"stsc")?.ok_or(Mp4Error::InvalidBox("stbl"))?;
+    let mut r = ByteReader::new(stsc, "stsc");
+    r.full_box_header()?;
+    let count = r.entry_count(12)?;
+    let mut stsc_entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let first_chunk = r.u32()?;
+        let samples_per_chunk = r.u32()?;
+        // sample_description_index
+        r.skip(4)?;
+        stsc_entries.push((first_chunk, samples_per_chunk));
+    }
+
+    let mut samples = Vec::with_capacity(sizes.len());
+    let mut sizes_iter = sizes.iter();
+    let mut stsc_iter = stsc_entries.iter().peekable();
+    let mut samples_per_chunk = 0;
+    'chunks: for (chunk_index, &chunk_offset) in chunk_offsets.iter().enumerate() {
+        let chunk_number = chunk_index as u32 + 1;
+        while let Some(&&(first_chunk, n)) = stsc_iter.peek() {
+            if first_chunk > chunk_number {
+                break;
+            }
+            samples_per_chunk = n;
+            stsc_iter.next();
+        }
+
+        let mut offset = chunk_offset;
+        for _ in 0..samples_per_chunk {
+            let size = match sizes_iter.next() {
+                Some(&size) => size,
+                None => break 'chunks,
+            };
+            samples.push(SampleInfo {
+                offset,
+                size,
+                is_sync: true,
+                ..Default::default()
+            });
+            offset += size as u64;
+        }
+    }
+
+    // Decoding times.
+    if let Some(stts) = find_box(stbl, b"stts")? {
+        let mut r = ByteReader::new(stts, "stts");
+        r.full_box_header()?;
+        let count = r.u32()?;
+        let mut samples_iter = samples.iter_mut();
+        let mut dts = 0u64;
+        for _ in 0..count {
+            let sample_count = r.u32()?;
+            let sample_delta = r.u32()?;
+            for sample in samples_iter.by_ref().take(sample_count as usize) {
+                sample.dts = dts;
+                sample.duration = sample_delta;
+                dts += sample_delta as u64;
+            }
+        }
+    }
+
+    // Composition time offsets.
+    if let Some(ctts) = find_box(stbl, b"ctts")? {
+        let mut r = ByteReader::new(ctts, "ctts");
+        r.full_box_header()?;
+        let count = r.u32()?;
+        let mut samples_iter = samples.iter_mut();
+        for _ in 0..count {
+            let sample_count = r.u32()?;
+            // Version 0 offsets are unsigned, but some muxers write negative values anyway.
+            let sample_offset = r.u32()? as i32 as i64;
+            for sample in samples_iter.by_ref().take(sample_count as usize) {
+                sample.cts_offset = sample_offset;
+            }
+        }
+    }
+
+    // Sync samples. If the box is absent, all samples are sync samples.
+    if let Some(stss) = find_box(stbl, b"stss")? {
+        let mut r = ByteReader::new(stss, "stss");
+        r.full_box_header()?;
+        let count = r.u32()?;
+        for sample in samples.iter_mut() {
+            sample.is_sync = false;
+        }
+        for _ in 0..count {
+            let sample_number = r.u32()? as usize;
+            if let Some(sample) = sample_number
+                .checked_sub(1)
+                .and_then(|i| samples.get_mut(i))
+            {
+                sample.is_sync = true;
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut b = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(box_type);
+        b.extend_from_slice(payload);
+        b
+    }
+
+    fn full_box(box_type: &[u8; 4], version_flags: u32, payload: &[u8]) -> Vec<u8> {
+        let mut p = version_flags.to_be_bytes().to_vec();
+        p.extend_from_slice(payload);
+        mp4_box(box_type, &p)
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    const SPS: [u8; 4] = [0x67, 0x42, 0x00, 0x1e];
+    const PPS: [u8; 2] = [0x68, 0xce];
+
+    /// Build a `moov` box with a single H.264 video track, and sample tables if `stbl_extra`
+    /// is not empty.
+    fn moov(stbl_extra: &[u8], mvex: bool) -> Vec<u8> {
+        let mut avcc = vec![1, 0x42, 0, 0x1e, 0xff, 0xe1];
+        avcc.extend_from_slice(&(SPS.len() as u16).to_be_bytes());
+        avcc.extend_from_slice(&SPS);
+        avcc.push(1);
+        avcc.extend_from_slice(&(PPS.len() as u16).to_be_bytes());
+        avcc.extend_from_slice(&PPS);
+
+        let mut avc1 = vec![0u8; 24];
+        avc1.extend_from_slice(&320u16.to_be_bytes());
+        avc1.extend_from_slice(&240u16.to_be_bytes());
+        avc1.extend_from_slice(&[0u8; 50]);
+        avc1.extend(mp4_box(b"avcC", &avcc));
+
+        let mut stsd_payload = u32s(&[1]);
+        stsd_payload.extend(mp4_box(b"avc1", &avc1));
+        let mut stbl = full_box(b"stsd", 0, &stsd_payload);
+        stbl.extend_from_slice(stbl_extra);
+
+        let mut mdia = full_box(b"mdhd", 0, &u32s(&[0, 0, 90000, 0, 0]));
+        mdia.extend(full_box(b"hdlr", 0, &[0, 0, 0, 0, b'v', b'i', b'd', b'e']));
+        mdia.extend(mp4_box(b"minf", &mp4_box(b"stbl", &stbl)));
+
+        let mut trak = full_box(b"tkhd", 0, &u32s(&[0, 0, 1]));
+        trak.extend(mp4_box(b"mdia", &mdia));
+
+        let mut moov = mp4_box(b"trak", &trak);
+        if mvex {
+            moov.extend(mp4_box(
+                b"mvex",
+                &full_box(b"trex", 0, &u32s(&[1, 1, 3000, 0, 0])),
+            ));
+        }
+
+        mp4_box(b"moov", &moov)
+    }
+
+    /// Length-prefixed sample data with a single NAL unit.
+    fn sample(nal: &[u8]) -> Vec<u8> {
+        let mut s = (nal.len() as u32).to_be_bytes().to_vec();
+        s.extend_from_slice(nal);
+        s
+    }
+
+    fn annexb(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|nal| START_CODE.iter().chain(nal.iter()).copied())
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_tables() {
+        let samples = [
+            sample(&[0x65, 1, 2]),
+            sample(&[0x41, 3]),
+            sample(&[0x41, 4]),
+        ];
+        let mdat_payload = samples.concat();
+        let ftyp = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        let mdat = mp4_box(b"mdat", &mdat_payload);
+        let mdat_data_offset = (ftyp.len() + 8) as u32;
+
+        let mut stbl = full_box(b"stts", 0, &u32s(&[1, 3, 3000]));
+        stbl.extend(full_box(b"ctts", 0, &u32s(&[3, 1, 3000, 1, 6000, 1, 0])));
+        stbl.extend(full_box(b"stsc", 0, &u32s(&[2, 1, 2, 1, 2, 1, 1])));
+        let sizes = samples.iter().map(|s| s.len() as u32).collect::<Vec<_>>();
+        stbl.extend(full_box(
+            b"stsz",
+            0,
+            &u32s(&[0, 3, sizes[0], sizes[1], sizes[2]]),
+        ));
+        stbl.extend(full_box(
+            b"stco",
+            0,
+            &u32s(&[2, mdat_data_offset, mdat_data_offset + sizes[0] + sizes[1]]),
+        ));
+        stbl.extend(full_box(b"stss", 0, &u32s(&[1, 1])));
+
+        // Put the moov box at the end of the stream.
+        let stream = [ftyp, mdat, moov(&stbl, false)].concat();
+        let mut demuxer = Mp4Demuxer::new(Cursor::new(stream)).unwrap();
+        assert_eq!(demuxer.pixel_format(), Some(b"H264".into()));
+        assert_eq!(demuxer.size(), (320, 240));
+        assert_eq!(demuxer.timescale(), 90000);
+
+        let s = demuxer.next_sample().unwrap().unwrap();
+        assert!(s.is_sync);
+        assert_eq!(s.data, annexb(&[&SPS, &PPS, &[0x65, 1, 2]]));
+        assert_eq!(s.pts, 3000);
+        assert_eq!(s.timestamp(), TimeVal::microseconds(33333));
+
+        let s = demuxer.next_sample().unwrap().unwrap();
+        assert!(!s.is_sync);
+        assert_eq!(s.data, annexb(&[&[0x41, 3]]));
+        assert_eq!((s.dts, s.pts), (3000, 9000));
+
+        let s = demuxer.next_sample().unwrap().unwrap();
+        assert_eq!(s.data, annexb(&[&[0x41, 4]]));
+        assert_eq!((s.dts, s.pts), (6000, 6000));
+
+        assert!(demuxer.next_sample().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_huge_counts() {
+        let stco = full_box(b"stco", 0, &u32s(&[1, 0]));
+        let stsc = full_box(b"stsc", 0, &u32s(&[1, 1, 1, 1]));
+        let stsz = full_box(b"stsz", 0, &u32s(&[0, 1, 4]));
+        let invalid_stbls = [
+            // Sample size tables much shorter than their entry count.
+            [
+                full_box(b"stsz", 0, &u32s(&[0, u32::MAX, 4])),
+                stsc.clone(),
+                stco.clone(),
+            ],
+            [
+                full_box(b"stz2", 0, &u32s(&[16, u32::MAX, 4])),
+                stsc.clone(),
+                stco.clone(),
+            ],
+            // Constant sample size, so the count is all there is.
+            [
+                full_box(b"stsz", 0, &u32s(&[4, u32::MAX])),
+                stsc.clone(),
+                stco.clone(),
+            ],
+            [
+                stsz.clone(),
+                stsc.clone(),
+                full_box(b"stco", 0, &u32s(&[u32::MAX, 0])),
+            ],
+            [
+                stsz.clone(),
+                stsc.clone(),
+                full_box(b"co64", 0, &u32s(&[u32::MAX, 0, 0])),
+            ],
+            [
+                stsz,
+                full_box(b"stsc", 0, &u32s(&[u32::MAX, 1, 1, 1])),
+                stco,
+            ],
+        ];
+        for stbl in invalid_stbls.iter() {
+            assert!(matches!(
+                Mp4Demuxer::new(Cursor::new(moov(&stbl.concat(), false))),
+                Err(Mp4Error::InvalidBox(_))
+            ));
+        }
+
+        // Track runs with sizes for fewer samples than announced, and with no per-sample field.
+        for trun in [
+            full_box(b"trun", 0x201, &u32s(&[u32::MAX, 0, 4])),
+            full_box(b"trun", 0x001, &u32s(&[u32::MAX, 0])),
+        ]
+        .iter()
+        {
+            let mut traf = full_box(b"tfhd", 0x20000, &u32s(&[1]));
+            traf.extend_from_slice(trun);
+            let stream = [moov(&[], true), mp4_box(b"moof", &mp4_box(b"traf", &traf))].concat();
+            let mut demuxer = Mp4Demuxer::new(Cursor::new(stream)).unwrap();
+            assert!(matches!(
+                demuxer.next_sample(),
+                Err(Mp4Error::InvalidBox("trun"))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut stream = mp4_box(b"ftyp", b"iso6\0\0\0\0");
+        stream.extend(moov(&[], true));
+
+        for (i, nals) in [[&[0x65u8, 1][..], &[0x41, 2]], [&[0x65, 3], &[0x41, 4]]]
+            .iter()
+            .enumerate()
+        {
+            let samples = nals.iter().map(|nal| sample(nal)).collect::<Vec<_>>();
+            let make_moof = |data_offset: u32| {
+                let mut traf = full_box(b"tfhd", 0x20000, &u32s(&[1]));
+                traf.extend(full_box(b"tfdt", 0, &u32s(&[i as u32 * 6000])));
+                traf.extend(full_box(
+                    b"trun",
+                    0x605,
+                    &u32s(&[
+                        2,
+                        data_offset,
+                        0,
+                        samples[0].len() as u32,
+                        0,
+                        samples[1].len() as u32,
+                        SAMPLE_IS_NON_SYNC_SAMPLE,
+                    ]),
+                ));
+                mp4_box(b"moof", &mp4_box(b"traf", &traf))
+            };
+            let moof_len = make_moof(0).len() as u32;
+            stream.extend(make_moof(moof_len + 8));
+            stream.extend(mp4_box(b"mdat", &samples.concat()));
+        }
+
+        let demuxer = Mp4Demuxer::new(Cursor::new(stream)).unwrap();
+        let samples = demuxer.collect::<Vec<_>>();
+        assert_eq!(samples.len(), 4);
+        assert_eq!(samples[0].data, annexb(&[&SPS, &PPS, &[0x65, 1]]));
+        assert_eq!(samples[1].data, annexb(&[&[0x41, 2]]));
+        assert_eq!(samples[2].data, annexb(&[&SPS, &PPS, &[0x65, 3]]));
+        assert_eq!(samples[3].data, annexb(&[&[0x41, 4]]));
+        assert_eq!(
+            samples.iter().map(|s| s.pts).collect::<Vec<_>>(),
+            vec![0, 3000, 6000, 9000]
+        );
+        assert_eq!(
+            samples.iter().map(|s| s.is_sync).collect::<Vec<_>>(),
+            vec![true, false, true, false]
+        );
+    }
+}
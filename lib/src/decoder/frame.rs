@@ -0,0 +1,379 @@
+//! Decoded frames emitted by the decoder, along with the format information needed to read
+//! them.
+use crate::{
+    device::queue::{direction::Capture, dqbuf::DqBuffer, handles_provider::HandlesProvider},
+    ioctl::{BufferFlags, PlaneReadMapping},
+    memory::{Mappable, PrimitiveBufferHandles},
+    Colorimetry, Format, PixelFormat, Rect,
+};
+use nix::sys::time::TimeVal;
+use std::sync::Arc;
+
+/// Format of the CAPTURE queue at the time a frame has been decoded. Shared by all the frames
+/// decoded with the same format.
+#[derive(Debug, Clone)]
+pub(crate) struct FrameFormat {
+    pub format: Format,
+    pub visible_rect: Rect,
+    pub colorimetry: Colorimetry,
+}
+
+/// Memory layout of one of the planes of a pixel format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PlaneDesc {
+    /// Horizontal subsampling factor of the plane relative to the frame width.
+    h_sub: u32,
+    /// Vertical subsampling factor of the plane relative to the frame height.
+    v_sub: u32,
+    /// Number of bytes used by a group of `h_sub` horizontal pixels.
+    bytes_per_group: u32,
+    /// Height of a row of tiles in lines, for tiled formats.
+    tile_height: Option<u32>,
+}
+
+impl PlaneDesc {
+    const fn new(h_sub: u32, v_sub: u32, bytes_per_group: u32) -> Self {
+        PlaneDesc {
+            h_sub,
+            v_sub,
+            bytes_per_group,
+            tile_height: None,
+        }
+    }
+
+    const fn tiled(self, tile_height: u32) -> Self {
+        PlaneDesc {
+            tile_height: Some(tile_height),
+            ..self
+        }
+    }
+}
+
+/// Memory layout of a pixel format.
+struct FormatDesc {
+    planes: &'static [PlaneDesc],
+    /// Whether all the planes are stored one after the other in the same buffer, as is the case
+    /// for e.g. `NV12`. Otherwise each plane has its own buffer, as for e.g. `NM12`.
+    contiguous: bool,
+}
+
+const PACKED_3: &[PlaneDesc] = &[PlaneDesc::new(1, 1, 3)];
+const PACKED_4: &[PlaneDesc] = &[PlaneDesc::new(1, 1, 4)];
+const YUYV: &[PlaneDesc] = &[PlaneDesc::new(2, 1, 4)];
+const NV12: &[PlaneDesc] = &[PlaneDesc::new(1, 1, 1), PlaneDesc::new(2, 2, 2)];
+const YUV420: &[PlaneDesc] = &[
+    PlaneDesc::new(1, 1, 1),
+    PlaneDesc::new(2, 2, 1),
+    PlaneDesc::new(2, 2, 1),
+];
+const P010: &[PlaneDesc] = &[PlaneDesc::new(1, 1, 2), PlaneDesc::new(2, 2, 4)];
+/// MediaTek's tiled NV12: 16x32 luma tiles and 16x16 chroma tiles.
+const MM21: &[PlaneDesc] = &[
+    PlaneDesc::new(1, 1, 1).tiled(32),
+    PlaneDesc::new(2, 2, 2).tiled(16),
+];
+
+fn format_desc(pixelformat: PixelFormat) -> Option<FormatDesc> {
+    let fourcc: [u8; 4] = pixelformat.into();
+    let (planes, contiguous) = match &fourcc {
+        b"RGB3" | b"BGR3" => (PACKED_3, true),
+        b"AR24" | b"XR24" | b"AB24" | b"XB24" | b"BA24" | b"BX24" | b"RA24" | b"RX24" => {
+            (PACKED_4, true)
+        }
+        b"YUYV" | b"YVYU" | b"UYVY" | b"VYUY" => (YUYV, true),
+        b"NV12" | b"NV21" => (NV12, true),
+        b"NM12" | b"NM21" => (NV12, false),
+        b"YU12" | b"YV12" => (YUV420, true),
+        b"YM12" | b"YM21" => (YUV420, false),
+        b"P010" => (P010, true),
+        b"MM21" => (MM21, false),
+        _ => return None,
+    };
+
+    Some(FormatDesc { planes, contiguous })
+}
+
+/// Location of a plane of a frame in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PlaneGeometry {
+    /// Index of the V4L2 buffer plane containing this plane.
+    buffer_plane: usize,
+    /// Offset of this plane from the start of the data of the buffer plane.
+    offset: usize,
+    /// Number of bytes between the start of two consecutive rows.
+    stride: usize,
+    /// Number of meaningful bytes in a row.
+    row_size: usize,
+    num_rows: usize,
+    /// Height of a row in lines, for tiled formats.
+    tile_height: Option<usize>,
+}
+
+/// Compute the geometry of all the planes of frames using `format`.
+///
+/// Formats unknown to us are considered to have one plane per buffer plane, each with as many
+/// rows as the frame has lines.
+fn plane_geometries(format: &Format) -> Vec<PlaneGeometry> {
+    let height = format.height as usize;
+
+    let desc = match format_desc(format.pixelformat) {
+        Some(desc) if desc.contiguous || desc.planes.len() == format.plane_fmt.len() => desc,
+        _ => {
+            return format
+                .plane_fmt
+                .iter()
+                .enumerate()
+                .map(|(i, plane)| PlaneGeometry {
+                    buffer_plane: i,
+                    offset: 0,
+                    stride: plane.bytesperline as usize,
+                    row_size: plane.bytesperline as usize,
+                    num_rows: height,
+                    tile_height: None,
+                })
+                .collect();
+        }
+    };
+
+    let first_stride = match format.plane_fmt.first() {
+        Some(plane) => plane.bytesperline as usize,
+        None => return Vec::new(),
+    };
+    let first_desc = desc.planes[0];
+    let mut offset = 0;
+
+    desc.planes
+        .iter()
+        .enumerate()
+        .map(|(i, plane)| {
+            let (buffer_plane, stride) = if desc.contiguous {
+                // Strides of the other planes are deduced from the one of the first plane.
+                let stride =
+                    first_stride * plane.bytes_per_group as usize * first_desc.h_sub as usize
+                        / (plane.h_sub as usize * first_desc.bytes_per_group as usize);
+                (0, stride)
+            } else {
+                offset = 0;
+                (i, format.plane_fmt[i].bytesperline as usize)
+            };
+            let plane_width = format.width.div_ceil(plane.h_sub) as usize;
+            let plane_height = height.div_ceil(plane.v_sub as usize);
+
+            let geometry = match plane.tile_height {
+                Some(tile_height) => {
+                    let tile_height = tile_height as usize;
+                    PlaneGeometry {
+                        buffer_plane,
+                        offset,
+                        stride: stride * tile_height,
+                        row_size: stride * tile_height,
+                        num_rows: plane_height.div_ceil(tile_height),
+                        tile_height: Some(tile_height),
+                    }
+                }
+                None => PlaneGeometry {
+                    buffer_plane,
+                    offset,
+                    stride,
+                    row_size: std::cmp::min(plane_width * plane.bytes_per_group as usize, stride),
+                    num_rows: plane_height,
+                    tile_height: None,
+                },
+            };
+            offset += geometry.stride * geometry.num_rows;
+
+            geometry
+        })
+        .collect()
+}
+
+/// A frame produced by the decoder.
+///
+/// The CAPTURE buffer is returned to the decoder once this object is dropped.
+pub struct DecodedFrame<P: HandlesProvider> {
+    buffer: DqBuffer<Capture, P::HandleType>,
+    format: Arc<FrameFormat>,
+}
+
+impl<P: HandlesProvider> DecodedFrame<P> {
+    pub(crate) fn new(buffer: DqBuffer<Capture, P::HandleType>, format: Arc<FrameFormat>) -> Self {
+        DecodedFrame { buffer, format }
+    }
+
+    /// Returns the dequeued buffer containing the frame.
+    pub fn buffer(&self) -> &DqBuffer<Capture, P::HandleType> {
+        &self.buffer
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut DqBuffer<Capture, P::HandleType> {
+        &mut self.buffer
+    }
+
+    pub fn into_buffer(self) -> DqBuffer<Capture, P::HandleType> {
+        self.buffer
+    }
+
+    /// Returns the format of the CAPTURE queue this frame has been decoded into.
+    pub fn format(&self) -> &Format {
+        &self.format.format
+    }
+
+    /// Returns the part of the frame that contains the picture. Data outside of this rectangle
+    /// is padding and should not be displayed.
+    pub fn visible_rect(&self) -> &Rect {
+        &self.format.visible_rect
+    }
+
+    pub fn colorimetry(&self) -> &Colorimetry {
+        &self.format.colorimetry
+    }
+
+    /// Returns the timestamp of the OUTPUT buffer this frame has been decoded from.
+    pub fn timestamp(&self) -> TimeVal {
+        TimeVal::from(self.buffer.data.timestamp())
+    }
+
+    /// Whether the driver reported an error while decoding this frame. Its content may be
+    /// corrupted.
+    pub fn has_error(&self) -> bool {
+        self.buffer.data.flags().contains(BufferFlags::ERROR)
+    }
+
+    /// Returns the number of planes of the frame, e.g. 2 for `NV12` even though both planes are
+    /// stored in the same buffer.
+    pub fn num_planes(&self) -> usize {
+        plane_geometries(self.format()).len()
+    }
+}
+
+impl<P> DecodedFrame<P>
+where
+    P: HandlesProvider,
+    P::HandleType: PrimitiveBufferHandles,
+    <P::HandleType as PrimitiveBufferHandles>::HandleType: Mappable,
+{
+    /// Map plane `plane` of the frame for reading, or return `None` if the frame has no such
+    /// plane or its buffer cannot be mapped.
+    pub fn map_plane(&self, plane: usize) -> Option<FramePlane<'_>> {
+        let geometry = *plane_geometries(self.format()).get(plane)?;
+        let mapping = self.buffer.get_plane_mapping(geometry.buffer_plane)?;
+
+        Some(FramePlane { mapping, geometry })
+    }
+}
+
+/// A plane of a decoded frame, mapped for CPU access.
+///
+/// Rows take the stride of the plane into account, and only contain the bytes of the frame
+/// width. For tiled formats, each row is a full row of tiles.
+pub struct FramePlane<'a> {
+    mapping: PlaneReadMapping<'a>,
+    geometry: PlaneGeometry,
+}
+
+impl<'a> FramePlane<'a> {
+    /// Returns the number of bytes between the start of two consecutive rows.
+    pub fn stride(&self) -> usize {
+        self.geometry.stride
+    }
+
+    /// Returns the number of meaningful bytes in a row.
+    pub fn row_size(&self) -> usize {
+        self.geometry.row_size
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.geometry.num_rows
+    }
+
+    /// Returns the height of a row in lines for tiled formats, or `None` if rows are single
+    /// lines.
+    pub fn tile_height(&self) -> Option<usize> {
+        self.geometry.tile_height
+    }
+
+    /// Returns row `index` of the plane, or `None` if it is out of the plane or the buffer.
+    pub fn row(&self, index: usize) -> Option<&[u8]> {
+        if index >= self.geometry.num_rows {
+            return None;
+        }
+
+        let start = self.geometry.offset + index * self.geometry.stride;
+        self.mapping.get(start..start + self.geometry.row_size)
+    }
+
+    /// Returns the samples of row `index` for formats with 16-bit little-endian samples such as
+    /// `P010`, or `None` if it is out of the plane or the buffer.
+    pub fn row_u16(&self, index: usize) -> Option<impl Iterator<Item = u16> + '_> {
+        Some(
+            self.row(index)?
+                .chunks_exact(2)
+                .map(|sample| u16::from_le_bytes([sample[0], sample[1]])),
+        )
+    }
+
+    /// Returns an iterator over the rows of the plane. The iteration stops early if the buffer
+    /// is too small to contain all the rows.
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        (0..self.geometry.num_rows).map_while(move |i| self.row(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PlaneLayout;
+
+    fn format(pixelformat: &[u8; 4], width: u32, height: u32, strides: &[u32]) -> Format {
+        Format {
+            width,
+            height,
+            pixelformat: pixelformat.into(),
+            plane_fmt: strides
+                .iter()
+                .map(|&bytesperline| PlaneLayout {
+                    sizeimage: 0,
+                    bytesperline,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_plane_geometries() {
+        // Contiguous NV12 with padding: the chroma plane follows the luma one.
+        let geometries = plane_geometries(&format(b"NV12", 318, 239, &[320]));
+        assert_eq!(geometries.len(), 2);
+        assert_eq!((geometries[0].offset, geometries[0].stride), (0, 320));
+        assert_eq!((geometries[0].row_size, geometries[0].num_rows), (318, 239));
+        assert_eq!(geometries[1].buffer_plane, 0);
+        assert_eq!(
+            (geometries[1].offset, geometries[1].stride),
+            (320 * 239, 320)
+        );
+        assert_eq!((geometries[1].row_size, geometries[1].num_rows), (318, 120));
+
+        // Contiguous YUV 4:2:0: chroma planes have half the stride.
+        let geometries = plane_geometries(&format(b"YU12", 64, 32, &[64]));
+        assert_eq!(geometries.len(), 3);
+        assert_eq!((geometries[1].offset, geometries[1].stride), (64 * 32, 32));
+        assert_eq!(
+            (geometries[2].offset, geometries[2].stride),
+            (64 * 32 + 32 * 16, 32)
+        );
+
+        // Tiled MM21 with one buffer per plane.
+        let geometries = plane_geometries(&format(b"MM21", 64, 48, &[64, 64]));
+        assert_eq!(geometries[0].buffer_plane, 0);
+        assert_eq!((geometries[0].stride, geometries[0].num_rows), (64 * 32, 2));
+        assert_eq!(geometries[1].buffer_plane, 1);
+        assert_eq!(geometries[1].offset, 0);
+        assert_eq!((geometries[1].stride, geometries[1].num_rows), (64 * 16, 2));
+
+        // Unknown formats get one plane per buffer plane.
+        let geometries = plane_geometries(&format(b"ABCD", 16, 16, &[32, 16]));
+        assert_eq!(geometries.len(), 2);
+        assert_eq!(geometries[1].buffer_plane, 1);
+        assert_eq!((geometries[1].row_size, geometries[1].num_rows), (16, 16));
+    }
+}
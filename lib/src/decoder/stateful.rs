@@ -1,4 +1,5 @@
 mod capture_thread;
+pub mod sync;
 
 use crate::{
     device::{
@@ -9,17 +10,17 @@ use crate::{
             handles_provider::HandlesProvider,
             qbuf::{
                 get_free::{GetFreeBufferError, GetFreeCaptureBuffer, GetFreeOutputBuffer},
-                get_indexed::GetCaptureBufferByIndex,
+                get_indexed::{GetCaptureBufferByIndex, GetOutputBufferByIndex, TryGetBufferError},
                 OutputQueueableProvider,
             },
-            BuffersAllocated, CreateQueueError, FormatBuilder, Queue, QueueInit,
-            RequestBuffersError,
+            BuffersAllocated, CreateBuffersError, CreateQueueError, FormatBuilder, Queue,
+            QueueInit, RequestBuffersError,
         },
         AllocatedQueue, Device, DeviceConfig, DeviceOpenError, Stream, TryDequeue,
     },
     ioctl::{self, subscribe_event, BufferCapabilities, Fmt, FormatFlags, StreamOnError},
     memory::{BufferHandles, PrimitiveBufferHandles},
-    FormatConversionError,
+    Format, FormatConversionError,
 };
 
 use capture_thread::CaptureThread;
@@ -178,6 +179,20 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
         self
     }
 
+    /// Free the OUTPUT buffers and return the decoder to the state where the
+    /// OUTPUT format can be set, e.g. to decode a stream using another codec.
+    pub fn free_output_buffers(self) -> Result<Decoder<AwaitingOutputFormat>, ioctl::ReqbufsError> {
+        let output_queue = self.state.output_queue.free_buffers()?.queue;
+
+        Ok(Decoder {
+            device: self.device,
+            state: AwaitingOutputFormat {
+                output_queue,
+                capture_queue: self.state.capture_queue,
+            },
+        })
+    }
+
     #[allow(clippy::type_complexity)]
     pub fn start<P, InputDoneCb, DecoderEventCb, FormatChangedCb>(
         self,
@@ -246,6 +261,7 @@ impl<OP: BufferHandles> Decoder<ReadyToDecode<OP>> {
                 command_sender,
                 response_receiver,
                 handle,
+                poll_wakeups_counter: self.state.poll_wakeups_counter,
             },
         })
     }
@@ -281,6 +297,8 @@ where
     response_receiver: mpsc::Receiver<CaptureThreadResponse>,
 
     handle: JoinHandle<CaptureThread<P, DecoderEventCb, FormatChangedCb>>,
+    /// Kept so it can be set on the pollers again if the decoder is restarted.
+    poll_wakeups_counter: Option<Arc<AtomicUsize>>,
 }
 impl<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb> DecoderState
     for Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
@@ -307,6 +325,8 @@ pub enum StopError {
     Join,
     #[error("Error while stopping the OUTPUT queue")]
     Streamoff(#[from] ioctl::StreamOffError),
+    #[error("CAPTURE queue has been lost following a decoder error")]
+    CaptureQueueLost,
 }
 
 #[derive(Debug, Error)]
@@ -335,11 +355,20 @@ pub enum FlushError {
     StreamonError(#[from] ioctl::StreamOnError),
 }
 
+#[derive(Debug, Error)]
+pub enum ResizeOutputBuffersError {
+    #[error("Error while getting the OUTPUT format")]
+    GFmtError(#[from] ioctl::GFmtError),
+    #[error("Error while trying the OUTPUT format")]
+    TryFmtError(#[from] ioctl::TryFmtError),
+    #[error("Driver cannot provide OUTPUT buffers of {0} bytes")]
+    SizeNotSupported(usize),
+    #[error("Error while allocating the OUTPUT buffers")]
+    CreateBuffersError(#[from] CreateBuffersError),
+}
+
 #[allow(type_alias_bounds)]
 type DequeueOutputBufferError<OP: BufferHandles> = ioctl::DqBufError<DqBuffer<Output, OP>>;
-#[allow(type_alias_bounds)]
-type CanceledBuffers<OP: BufferHandles> =
-    Vec<<Queue<Output, BuffersAllocated<OP>> as Stream>::Canceled>;
 
 impl<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
     Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>>
@@ -373,26 +402,46 @@ where
         self.state.output_queue.get_format()
     }
 
-    /// Stop the decoder.
+    /// Stop the decoder, and returns the decoder ready to be started again.
     ///
-    /// This will stop any pending operation consume the decoder, which cannot
-    /// be used anymore. To make sure all submitted encoded buffers have been
-    /// processed, call the [`Decoder::drain`] method and wait for the output buffer with
-    /// the LAST flag before calling this method.
+    /// This will stop any pending operation. The input buffers that were still
+    /// queued are returned as `CompletedInputBuffer::Canceled` through the
+    /// input done callback, and the CAPTURE buffers are freed. The OUTPUT
+    /// buffers are kept, so the returned decoder can be started again right
+    /// away, e.g. after a seek, or go back to setting the OUTPUT format with
+    /// [`Decoder::free_output_buffers`] to decode another codec.
+    ///
+    /// To make sure all submitted encoded buffers have been processed, call the
+    /// [`Decoder::drain`] method and wait for the output buffer with the LAST
+    /// flag before calling this method.
     ///
     /// TODO potential bug: the LAST buffer could also be the one signaling a
     /// DRC. We need another way to manage this? Probably a good idea to split
     /// into two properties of DQBuf.
-    pub fn stop(self) -> Result<CanceledBuffers<OP>, StopError> {
+    pub fn stop(self) -> Result<Decoder<ReadyToDecode<OP>>, StopError> {
         debug!("Stop requested");
         self.send_command(DecoderCommand::Stop)?;
 
-        match self.state.handle.join() {
-            Ok(_) => (),
+        let capture_thread = match self.state.handle.join() {
+            Ok(capture_thread) => capture_thread,
             Err(_) => return Err(StopError::Join),
+        };
+
+        let canceled_buffers = self.state.output_queue.stream_off()?;
+        for buffer in canceled_buffers {
+            (self.state.input_done_cb)(CompletedInputBuffer::Canceled(buffer));
         }
 
-        Ok(self.state.output_queue.stream_off()?)
+        Ok(Decoder {
+            device: self.device,
+            state: ReadyToDecode {
+                output_queue: self.state.output_queue,
+                capture_queue: capture_thread
+                    .into_capture_queue()
+                    .ok_or(StopError::CaptureQueueLost)?,
+                poll_wakeups_counter: self.state.poll_wakeups_counter,
+            },
+        })
     }
 
     /// Drain the decoder, i.e. make sure all its pending work is processed.
@@ -490,6 +539,48 @@ where
         Ok(())
     }
 
+    /// Make sure some OUTPUT buffers can hold at least `min_size` bytes, by
+    /// allocating `num_buffers` additional buffers of a larger size if
+    /// needed.
+    ///
+    /// The new buffers are added using `VIDIOC_CREATE_BUFS`, so the OUTPUT
+    /// queue keeps streaming and the decoding position is preserved. The
+    /// existing buffers remain usable for smaller frames; use
+    /// [`Decoder::get_buffer_for_size`] to obtain a buffer large enough for a
+    /// given frame.
+    ///
+    /// Returns the size of the largest OUTPUT buffers. The decoder is left
+    /// unchanged if an error is returned.
+    pub fn resize_output_buffers(
+        &mut self,
+        min_size: usize,
+        num_buffers: usize,
+    ) -> Result<usize, ResizeOutputBuffersError> {
+        let output_queue = &mut self.state.output_queue;
+        let max_size = output_queue.max_buffer_size();
+        if max_size >= min_size {
+            return Ok(max_size);
+        }
+
+        debug!("Allocating OUTPUT buffers of {} bytes", min_size);
+
+        // Negotiate the larger size before allocating anything.
+        let mut format: Format = output_queue.get_format()?;
+        match format.plane_fmt.first_mut() {
+            Some(plane) => plane.sizeimage = min_size as u32,
+            None => return Err(ResizeOutputBuffersError::SizeNotSupported(min_size)),
+        }
+        let format = output_queue.try_format(format)?;
+        let size = match format.plane_fmt.first() {
+            Some(plane) if plane.sizeimage as usize >= min_size => plane.sizeimage as usize,
+            _ => return Err(ResizeOutputBuffersError::SizeNotSupported(min_size)),
+        };
+
+        output_queue.create_buffers(num_buffers as u32, format)?;
+
+        Ok(std::cmp::max(size, output_queue.max_buffer_size()))
+    }
+
     /// Attempts to dequeue and release output buffers that the driver is done with.
     fn dequeue_output_buffers(&self) -> Result<(), DequeueOutputBufferError<OP>> {
         let output_queue = &self.state.output_queue;
@@ -547,6 +638,8 @@ pub enum GetBufferError<OP: BufferHandles> {
     PollError(#[from] PollError),
     #[error("Error while obtaining buffer")]
     GetFreeBufferError(#[from] GetFreeBufferError),
+    #[error("Error while obtaining buffer")]
+    TryGetBufferError(#[from] TryGetBufferError),
 }
 
 /// Let the decoder provide the buffers from the OUTPUT queue.
@@ -632,3 +725,62 @@ where
         self.dequeue_output_buffers()
     }
 }
+
+impl<'a, OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>
+    Decoder<Decoding<OP, P, InputDoneCb, DecoderEventCb, FormatChangedCb>>
+where
+    Queue<Output, BuffersAllocated<OP>>: GetOutputBufferByIndex<'a, OP>,
+    OP: BufferHandles,
+    P: HandlesProvider,
+    InputDoneCb: InputDoneCallback<OP>,
+    DecoderEventCb: DecoderEventCallback<P>,
+    FormatChangedCb: FormatChangedCallback<P>,
+{
+    /// Returns a V4L2 buffer able to hold a frame of `min_size` bytes if one is
+    /// available.
+    ///
+    /// Contrary to [`Decoder::get_buffer_for_size`], this method returns a
+    /// `NoFreeBuffer` error immediately if all the large enough buffers are
+    /// currently queued.
+    pub fn try_get_buffer_for_size(
+        &'a self,
+        min_size: usize,
+    ) -> Result<<Self as OutputQueueableProvider<'a, OP>>::Queueable, GetBufferError<OP>> {
+        self.dequeue_output_buffers()?;
+
+        let output_queue = &self.state.output_queue;
+        let index = output_queue
+            .find_free_buffer(min_size)
+            .ok_or(GetFreeBufferError::NoFreeBuffer)?;
+
+        Ok(output_queue.try_get_buffer(index)?)
+    }
+
+    /// Returns a V4L2 buffer able to hold a frame of `min_size` bytes, waiting
+    /// for one to be available if needed.
+    ///
+    /// If no OUTPUT buffer is large enough, [`Decoder::resize_output_buffers`]
+    /// must be called first, otherwise a `NoFreeBuffer` error is returned.
+    pub fn get_buffer_for_size(
+        &'a mut self,
+        min_size: usize,
+    ) -> Result<<Self as OutputQueueableProvider<'a, OP>>::Queueable, GetBufferError<OP>> {
+        let index = loop {
+            self.dequeue_output_buffers()?;
+
+            let output_queue = &self.state.output_queue;
+            if let Some(index) = output_queue.find_free_buffer(min_size) {
+                break index;
+            }
+            // Only wait if a large enough buffer may be returned by the
+            // driver.
+            if output_queue.max_buffer_size() < min_size || output_queue.num_queued_buffers() == 0 {
+                return Err(GetFreeBufferError::NoFreeBuffer.into());
+            }
+
+            self.wait_for_output_buffer()?;
+        };
+
+        Ok(self.state.output_queue.try_get_buffer(index)?)
+    }
+}
@@ -1,7 +1,8 @@
 use crate::{
     decoder::{
+        frame::{DecodedFrame, FrameFormat},
         stateful::{CaptureThreadResponse, DecoderCommand, DecoderEvent, DrainError},
-        DecoderEventCallback, FormatChangedCallback, FormatChangedReply,
+        DecoderError, DecoderEventCallback, FormatChangedCallback, FormatChangedReply,
     },
     device::{
         poller::{DeviceEvent, PollEvent, Poller, Waker},
@@ -17,7 +18,8 @@ use crate::{
         },
         AllocatedQueue, Device, Stream, TryDequeue,
     },
-    ioctl::{self, SelectionTarget},
+    ioctl::{self, BufferFlags, DqBufError, SelectionTarget},
+    Colorimetry, Format,
 };
 
 use std::{
@@ -66,9 +68,13 @@ enum CaptureQueue<P: HandlesProvider> {
         capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
         provider: P,
         cap_buffer_waker: Arc<Waker>,
+        /// Format of the frames decoded into `capture_queue`.
+        frame_format: Arc<FrameFormat>,
         // TODO not super elegant...
         blocking_drain_in_progress: bool,
     },
+    /// The CAPTURE queue has been lost following an unrecoverable error.
+    Lost,
 }
 
 pub(super) struct CaptureThread<P, DecoderEventCb, FormatChangedCb>
@@ -91,10 +97,16 @@ where
     // Sender we use to send status messages after receiving commands from the
     // main thread.
     response_sender: mpsc::Sender<CaptureThreadResponse>,
+    // Set once an unrecoverable error has been reported to the client.
+    died: bool,
 }
 
 #[derive(Debug, Error)]
 enum UpdateCaptureError {
+    #[error("Stream not supported by the decoder")]
+    UnsupportedStream,
+    #[error("CAPTURE queue has been lost")]
+    QueueLost,
     #[error("Error while enabling poller events: {0}")]
     PollerEvents(io::Error),
     #[error("Error while removing CAPTURE waker: {0}")]
@@ -120,14 +132,23 @@ enum UpdateCaptureError {
 const CAPTURE_READY: u32 = 1;
 const COMMAND_WAITING: u32 = 2;
 
-#[derive(Debug, Error)]
-enum ProcessEventsError {
-    #[error("Error while dequeueing event")]
-    DqEvent(#[from] ioctl::DqEventError),
-    #[error("Error while requesting buffers")]
-    RequestBuffers(#[from] queue::RequestBuffersError),
-    #[error("Error while updating CAPTURE format")]
-    UpdateCapture(#[from] UpdateCaptureError),
+impl<P, DecoderEventCb, FormatChangedCb> CaptureThread<P, DecoderEventCb, FormatChangedCb>
+where
+    P: HandlesProvider,
+    DecoderEventCb: DecoderEventCallback<P>,
+    FormatChangedCb: FormatChangedCallback<P>,
+{
+    /// Returns the CAPTURE queue once the thread has returned from `run`, or
+    /// `None` if it has been lost due to an error.
+    pub(super) fn into_capture_queue(self) -> Option<Queue<Capture, QueueInit>> {
+        match self.capture_queue {
+            CaptureQueue::AwaitingResolution { capture_queue } => Some(capture_queue),
+            // `run` returns the queue to the `AwaitingResolution` state, so
+            // buffers that are still allocated can only come from a thread
+            // that did not complete its teardown.
+            CaptureQueue::Decoding { .. } | CaptureQueue::Lost => None,
+        }
+    }
 }
 
 impl<P, DecoderEventCb, FormatChangedCb> CaptureThread<P, DecoderEventCb, FormatChangedCb>
@@ -162,11 +183,57 @@ where
             command_waker,
             command_receiver,
             response_sender,
+            died: false,
         };
 
         Ok(decoder_thread)
     }
 
+    /// Report an unrecoverable error to the client. The thread stops
+    /// processing events after this.
+    fn die(&mut self, error: DecoderError) {
+        error!("Unrecoverable decoder error: {}", error);
+        (self.event_cb)(DecoderEvent::Died(error));
+        self.died = true;
+    }
+
+    /// Answer the commands of the main thread with an error until we are
+    /// requested to stop.
+    fn wait_for_stop(&mut self) {
+        // The LAST buffer of a blocking drain in progress will never come.
+        if let CaptureQueue::Decoding {
+            blocking_drain_in_progress: blocking_drain_in_progress @ true,
+            ..
+        } = &mut self.capture_queue
+        {
+            *blocking_drain_in_progress = false;
+            self.send_response(CaptureThreadResponse::DrainDone(Err(
+                DrainError::CaptureThreadError(anyhow::anyhow!("Decoder has died")),
+            )));
+        }
+
+        loop {
+            let command = match self.command_receiver.recv() {
+                Ok(command) => command,
+                Err(e) => {
+                    error!("Error while reading decoder command: {}", e);
+                    return;
+                }
+            };
+            match command {
+                DecoderCommand::Drain(_) => {
+                    self.send_response(CaptureThreadResponse::DrainDone(Err(
+                        DrainError::CaptureThreadError(anyhow::anyhow!("Decoder has died")),
+                    )))
+                }
+                DecoderCommand::Flush => self.send_response(CaptureThreadResponse::FlushDone(Err(
+                    anyhow::anyhow!("Decoder has died"),
+                ))),
+                DecoderCommand::Stop => return,
+            }
+        }
+    }
+
     fn send_response(&self, response: CaptureThreadResponse) {
         trace!("Sending response: {:?}", response);
 
@@ -181,21 +248,34 @@ where
             CaptureQueue::AwaitingResolution { .. } => {
                 Some(CaptureThreadResponse::DrainDone(Err(DrainError::TryAgain)))
             }
+            CaptureQueue::Lost => Some(CaptureThreadResponse::DrainDone(Err(
+                DrainError::CaptureThreadError(anyhow::anyhow!("CAPTURE queue has been lost")),
+            ))),
             CaptureQueue::Decoding {
                 blocking_drain_in_progress,
                 ..
             } => {
                 // We can receive the LAST buffer, send the STOP command
                 // and exit the loop once the buffer with the LAST tag is received.
-                ioctl::decoder_cmd(&*self.device, ioctl::DecoderCommand::Stop).unwrap();
-                if blocking {
+                match ioctl::decoder_cmd(&*self.device, ioctl::DecoderCommand::Stop) {
                     // If we are blocking, we will send the answer when the drain
                     // is completed.
-                    *blocking_drain_in_progress = true;
-                    None
-                } else {
+                    Ok(()) if blocking => {
+                        *blocking_drain_in_progress = true;
+                        None
+                    }
                     // If not blocking, send the response now so the client can keep going.
-                    Some(CaptureThreadResponse::DrainDone(Ok(false)))
+                    Ok(()) => Some(CaptureThreadResponse::DrainDone(Ok(false))),
+                    Err(e) => {
+                        self.send_response(CaptureThreadResponse::DrainDone(Err(
+                            DrainError::CaptureThreadError(anyhow::anyhow!(
+                                "Error while sending STOP command: {}",
+                                e
+                            )),
+                        )));
+                        self.die(DecoderError::DeviceError(e.into()));
+                        return;
+                    }
                 }
             }
         };
@@ -208,7 +288,7 @@ where
     fn flush(&mut self) {
         trace!("Processing flush command");
         match &mut self.capture_queue {
-            CaptureQueue::AwaitingResolution { .. } => {}
+            CaptureQueue::AwaitingResolution { .. } | CaptureQueue::Lost => {}
             CaptureQueue::Decoding {
                 capture_queue,
                 blocking_drain_in_progress,
@@ -217,8 +297,18 @@ where
                 // Stream the capture queue off and back on, dropping any queued
                 // buffer, and making the decoder ready to work again if it was
                 // halted.
-                capture_queue.stream_off().unwrap();
-                capture_queue.stream_on().unwrap();
+                if let Err(e) = capture_queue
+                    .stream_off()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| capture_queue.stream_on().map_err(anyhow::Error::from))
+                {
+                    self.send_response(CaptureThreadResponse::FlushDone(Err(anyhow::anyhow!(
+                        "Error while restarting the CAPTURE queue: {}",
+                        e
+                    ))));
+                    self.die(DecoderError::DeviceError(e));
+                    return;
+                }
                 *blocking_drain_in_progress = false;
             }
         }
@@ -231,7 +321,7 @@ where
         trace!("Queueing available CAPTURE buffers");
         let (capture_queue, provider, cap_buffer_waker) = match &mut self.capture_queue {
             // Capture queue is not set up yet, no buffers to queue.
-            CaptureQueue::AwaitingResolution { .. } => return,
+            CaptureQueue::AwaitingResolution { .. } | CaptureQueue::Lost => return,
             CaptureQueue::Decoding {
                 capture_queue,
                 provider,
@@ -272,21 +362,39 @@ where
     fn process_v4l2_event(mut self) -> Self {
         trace!("Processing V4L2 event");
         match self.capture_queue {
-            CaptureQueue::AwaitingResolution { .. } => {
-                if is_drc_event_pending(&*self.device).unwrap() {
-                    self = self.update_capture_format().unwrap()
-                }
-            }
-            CaptureQueue::Decoding { .. } => unreachable!(),
+            CaptureQueue::AwaitingResolution { .. } => match is_drc_event_pending(&self.device) {
+                Ok(true) => self.update_capture_format(),
+                Ok(false) => (),
+                Err(e) => self.die(DecoderError::DeviceError(e.into())),
+            },
+            CaptureQueue::Decoding { .. } | CaptureQueue::Lost => unreachable!(),
         }
 
         self
     }
 
-    fn update_capture_format(mut self) -> Result<Self, UpdateCaptureError> {
+    /// Update the CAPTURE format and buffers after a resolution change,
+    /// reporting any error to the client.
+    fn update_capture_format(&mut self) {
+        if let Err(e) = self.try_update_capture_format() {
+            let error = match e {
+                UpdateCaptureError::UnsupportedStream => DecoderError::UnsupportedStream,
+                UpdateCaptureError::Callback(e) => DecoderError::CaptureAllocationFailed(e),
+                e @ UpdateCaptureError::RequestBuffers(_) => {
+                    DecoderError::CaptureAllocationFailed(e.into())
+                }
+                e => DecoderError::DeviceError(e.into()),
+            };
+            self.die(error);
+        }
+    }
+
+    fn try_update_capture_format(&mut self) -> Result<(), UpdateCaptureError> {
         debug!("Updating CAPTURE format");
-        // First reset the capture queue to the `Init` state if needed.
-        let mut capture_queue = match self.capture_queue {
+        // First reset the capture queue to the `Init` state if needed. The
+        // queue is considered lost until we successfully set it up again.
+        let capture_queue = std::mem::replace(&mut self.capture_queue, CaptureQueue::Lost);
+        let mut capture_queue = match capture_queue {
             // Initial resolution
             CaptureQueue::AwaitingResolution { capture_queue } => {
                 // Stop listening to V4L2 events. We will check them when we get
@@ -315,8 +423,17 @@ where
                 capture_queue.stream_off()?;
                 capture_queue.free_buffers()?.queue
             }
+            CaptureQueue::Lost => return Err(UpdateCaptureError::QueueLost),
         };
 
+        // Let the client know if the decoder could not make sense of the
+        // stream.
+        let format: Format = capture_queue.get_format()?;
+        if format.width == 0 || format.height == 0 {
+            self.capture_queue = CaptureQueue::AwaitingResolution { capture_queue };
+            return Err(UpdateCaptureError::UnsupportedStream);
+        }
+
         // Now get the parameters of the new format and build our new CAPTURE
         // queue.
 
@@ -347,6 +464,15 @@ where
         // returning buffers.
         let capture_queue =
             capture_queue.request_buffers_generic::<P::HandleType>(mem_type, num_buffers as u32)?;
+
+        // The client may have changed the format, so get the one actually in
+        // use for the frames we will produce.
+        let frame_format = Arc::new(FrameFormat {
+            format: capture_queue.get_format()?,
+            visible_rect: capture_queue.get_selection(SelectionTarget::Compose)?,
+            colorimetry: capture_queue.get_format::<_, Colorimetry>()?,
+        });
+        provider.queue_format_changed(&frame_format.format, capture_queue.num_buffers());
         let cap_buffer_waker = self
             .poller
             .add_waker(CAPTURE_READY)
@@ -357,22 +483,23 @@ where
         cap_buffer_waker.wake_by_ref();
         capture_queue.stream_on()?;
 
-        Ok(Self {
-            capture_queue: CaptureQueue::Decoding {
-                capture_queue,
-                provider,
-                cap_buffer_waker,
-                blocking_drain_in_progress: false,
-            },
-            ..self
-        })
+        self.capture_queue = CaptureQueue::Decoding {
+            capture_queue,
+            provider,
+            cap_buffer_waker,
+            frame_format,
+            blocking_drain_in_progress: false,
+        };
+
+        Ok(())
     }
 
     /// Attempt to dequeue and process a single CAPTURE buffer.
     ///
     /// If a buffer can be dequeued, then the following processing takes place:
     /// * Invoke the event callback with a `FrameDecoded` event containing the
-    ///   dequeued buffer,
+    ///   dequeued buffer, or a `CorruptedFrame` event if the buffer has the
+    ///   ERROR flag set,
     /// * If the buffer has the LAST flag set:
     ///   * If a resolution change event is pending, start the resolution change
     ///     procedure,
@@ -381,19 +508,29 @@ where
     ///   * If a blocking drain was in progress, complete it.
     fn dequeue_capture_buffer(mut self) -> Self {
         trace!("Dequeueing decoded CAPTURE buffers");
-        let (capture_queue, cap_buffer_waker, blocking_drain_in_progress) =
+        let (capture_queue, cap_buffer_waker, frame_format, blocking_drain_in_progress) =
             match &mut self.capture_queue {
-                CaptureQueue::AwaitingResolution { .. } => unreachable!(),
+                CaptureQueue::AwaitingResolution { .. } | CaptureQueue::Lost => unreachable!(),
                 CaptureQueue::Decoding {
                     capture_queue,
                     cap_buffer_waker,
+                    frame_format,
                     blocking_drain_in_progress,
                     ..
-                } => (capture_queue, cap_buffer_waker, blocking_drain_in_progress),
+                } => (
+                    capture_queue,
+                    cap_buffer_waker,
+                    frame_format,
+                    blocking_drain_in_progress,
+                ),
             };
 
-        let mut cap_buf = match capture_queue.try_dequeue() {
-            Ok(cap_buf) => cap_buf,
+        let (mut cap_buf, is_corrupted) = match capture_queue.try_dequeue() {
+            Ok(cap_buf) => {
+                let is_corrupted = cap_buf.data.flags().contains(BufferFlags::ERROR);
+                (cap_buf, is_corrupted)
+            }
+            Err(DqBufError::CorruptedBuffer(cap_buf)) => (cap_buf, true),
             Err(e) => {
                 warn!(
                     "Expected a CAPTURE buffer but none available, possible driver bug: {}",
@@ -409,18 +546,30 @@ where
         // re-queue it as soon as it is dropped.
         let cap_waker = Arc::clone(cap_buffer_waker);
         cap_buf.add_drop_callback(move |_dqbuf| {
-            // Intentionally ignore the result here.
-            let _ = cap_waker.wake();
+            cap_waker.wake();
         });
 
         // Pass buffers to the client
-        (self.event_cb)(DecoderEvent::FrameDecoded(cap_buf));
+        let frame = DecodedFrame::new(cap_buf, Arc::clone(frame_format));
+        if is_corrupted {
+            warn!("CAPTURE buffer marked with ERROR flag");
+            (self.event_cb)(DecoderEvent::CorruptedFrame(frame));
+        } else {
+            (self.event_cb)(DecoderEvent::FrameDecoded(frame));
+        }
 
         if is_last {
             debug!("CAPTURE buffer marked with LAST flag");
-            if is_drc_event_pending(&*self.device).unwrap() {
+            let drc_pending = match is_drc_event_pending(&self.device) {
+                Ok(drc_pending) => drc_pending,
+                Err(e) => {
+                    self.die(DecoderError::DeviceError(e.into()));
+                    return self;
+                }
+            };
+            if drc_pending {
                 debug!("DRC event pending, updating CAPTURE format");
-                self = self.update_capture_format().unwrap()
+                self.update_capture_format();
             }
             // No DRC event pending, this is the end of the stream.
             // We need to stop and restart the CAPTURE queue, otherwise
@@ -432,8 +581,14 @@ where
                 // instead, but with vicodec the CAPTURE queue reports
                 // as ready in subsequent polls() and DQBUF returns
                 // -EPIPE...
-                capture_queue.stream_off().unwrap();
-                capture_queue.stream_on().unwrap();
+                if let Err(e) = capture_queue
+                    .stream_off()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| capture_queue.stream_on().map_err(anyhow::Error::from))
+                {
+                    self.die(DecoderError::DeviceError(e));
+                    return self;
+                }
                 (self.event_cb)(DecoderEvent::EndOfStream);
                 if *blocking_drain_in_progress {
                     debug!("Signaling end of blocking drain");
@@ -448,23 +603,27 @@ where
 
     pub(super) fn run(mut self) -> Self {
         'mainloop: loop {
+            // Do not process anything anymore if we hit an unrecoverable error.
+            if self.died {
+                self.wait_for_stop();
+                break 'mainloop;
+            }
+
             if let CaptureQueue::Decoding { capture_queue, .. } = &self.capture_queue {
-                match capture_queue.num_queued_buffers() {
+                let res = match capture_queue.num_queued_buffers() {
                     // If there are no buffers on the CAPTURE queue, poll() will return
                     // immediately with EPOLLERR and we would loop indefinitely.
                     // Prevent this by temporarily disabling polling the CAPTURE queue
                     // in such cases.
-                    0 => {
-                        self.poller
-                            .disable_event(DeviceEvent::CaptureReady)
-                            .unwrap();
-                    }
+                    0 => self.poller.disable_event(DeviceEvent::CaptureReady),
                     // If device polling was disabled and we have buffers queued, we
                     // can reenable it as poll will now wait for a CAPTURE buffer to
                     // be ready for dequeue.
-                    _ => {
-                        self.poller.enable_event(DeviceEvent::CaptureReady).unwrap();
-                    }
+                    _ => self.poller.enable_event(DeviceEvent::CaptureReady),
+                };
+                if let Err(e) = res {
+                    self.die(DecoderError::DeviceError(e.into()));
+                    continue 'mainloop;
                 }
             }
 
@@ -472,8 +631,8 @@ where
             let events = match self.poller.poll(None) {
                 Ok(events) => events,
                 Err(e) => {
-                    error!("Polling failure, exiting capture thread: {}", e);
-                    break 'mainloop;
+                    self.die(DecoderError::DeviceError(e.into()));
+                    continue 'mainloop;
                 }
             };
             for event in events {
@@ -485,7 +644,9 @@ where
                         self
                     }
                     PollEvent::Waker(COMMAND_WAITING) => {
-                        loop {
+                        // Remaining commands are answered by `wait_for_stop` if
+                        // one of them makes us die.
+                        while !self.died {
                             let command =
                                 match self.command_receiver.recv_timeout(Default::default()) {
                                     Ok(command) => command,
@@ -506,30 +667,53 @@ where
                         }
                         self
                     }
-                    _ => panic!("Unexpected event!"),
+                    event => {
+                        self.die(DecoderError::DeviceError(anyhow::anyhow!(
+                            "Unexpected poll event: {:?}",
+                            event
+                        )));
+                        self
+                    }
+                };
+
+                if self.died {
+                    continue 'mainloop;
                 }
             }
         }
 
         // Return the decoder to the awaiting resolution state.
-        match self.capture_queue {
-            CaptureQueue::AwaitingResolution { .. } => self,
-            CaptureQueue::Decoding { capture_queue, .. } => Self {
-                capture_queue: CaptureQueue::AwaitingResolution {
-                    capture_queue: {
-                        capture_queue.stream_off().unwrap();
-                        capture_queue.free_buffers().unwrap().queue
-                    },
-                },
-                poller: {
-                    let mut poller = self.poller;
-                    poller.disable_event(DeviceEvent::CaptureReady).unwrap();
-                    poller.enable_event(DeviceEvent::V4L2Event).unwrap();
-                    poller.remove_waker(CAPTURE_READY).unwrap();
-                    poller
-                },
-                ..self
-            },
+        match std::mem::replace(&mut self.capture_queue, CaptureQueue::Lost) {
+            CaptureQueue::AwaitingResolution { capture_queue } => {
+                self.capture_queue = CaptureQueue::AwaitingResolution { capture_queue };
+            }
+            CaptureQueue::Lost => (),
+            CaptureQueue::Decoding { capture_queue, .. } => {
+                match capture_queue
+                    .stream_off()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| capture_queue.free_buffers().map_err(anyhow::Error::from))
+                {
+                    Ok(res) => {
+                        self.capture_queue = CaptureQueue::AwaitingResolution {
+                            capture_queue: res.queue,
+                        }
+                    }
+                    Err(e) => error!("Error while releasing the CAPTURE queue: {}", e),
+                }
+
+                let poller = &mut self.poller;
+                if let Err(e) = poller
+                    .disable_event(DeviceEvent::CaptureReady)
+                    .and_then(|_| poller.enable_event(DeviceEvent::V4L2Event))
+                    .map_err(io::Error::from)
+                    .and_then(|_| poller.remove_waker(CAPTURE_READY))
+                {
+                    self.die(DecoderError::DeviceError(e.into()));
+                }
+            }
         }
+
+        self
     }
 }
@@ -0,0 +1,996 @@
+//! Pull-based wrapper around the stateful [`Decoder`].
+//!
+//! The stateful decoder is driven by callbacks invoked from its capture
+//! thread. [`SyncDecoder`] hides these callbacks behind a simpler interface:
+//! encoded frames are submitted with [`SyncDecoder::decode`], and decoded
+//! frames are retrieved with [`SyncDecoder::next_frame`]. Resolution changes
+//! are handled internally.
+//!
+//! Data can also be submitted as an unframed byte stream using
+//! [`SyncDecoder::write`], e.g. when streaming from a socket. This is only
+//! supported by [`SyncDecoder`]: users of the callback-based [`Decoder`] need
+//! to split the stream into frames themselves, e.g. using the splitters of
+//! [`crate::decoder::format`].
+use std::{
+    io::{self, Read},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use nix::sys::time::{TimeVal, TimeValLike};
+use thiserror::Error;
+
+use super::*;
+use crate::{
+    decoder::{
+        format::{fwht::FwhtFrameParser, h264::H264FrameSplitter},
+        frame::DecodedFrame,
+    },
+    device::queue::{handles_provider::MmapProvider, qbuf::QBuffer},
+    memory::{MemoryType, MmapHandle},
+    Format, PixelFormat, PlaneLayout, Rect,
+};
+
+type SyncDecoderInner<P> = Decoder<
+    Decoding<
+        Vec<MmapHandle>,
+        P,
+        fn(CompletedInputBuffer<Vec<MmapHandle>>),
+        Box<dyn DecoderEventCallback<P>>,
+        Box<dyn FormatChangedCallback<P>>,
+    >,
+>;
+
+/// Time to wait before retrying a drain request while the decoder has not
+/// determined the stream resolution yet.
+const DRAIN_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Time to wait before trying again to queue written data into OUTPUT buffers
+/// while all of them are busy.
+const INPUT_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Time given to the decoder to determine the stream resolution after it has
+/// consumed all the OUTPUT buffers, before concluding that the stream does not
+/// contain any frame.
+const NO_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Progress of the decoder, as reported by the callbacks run on its capture
+/// thread.
+#[derive(Default)]
+struct DecoderStatus {
+    /// Set once the decoder has determined the stream resolution.
+    resolution_known: AtomicBool,
+    /// Set once the decoder has reported an unrecoverable error.
+    died: AtomicBool,
+}
+
+#[derive(Debug, Error)]
+pub enum SyncDecoderOpenError {
+    #[error("Error while opening decoder")]
+    OpenError(#[from] DecoderOpenError),
+    #[error("Error while setting OUTPUT format")]
+    SetOutputFormatError(anyhow::Error),
+    #[error("Error while allocating OUTPUT buffers")]
+    RequestBuffersError(#[from] RequestBuffersError),
+    #[error("Error while starting decoder")]
+    StartError(#[from] StartDecoderError),
+}
+
+#[derive(Debug, Error)]
+pub enum SyncDecoderError {
+    #[error("Error while obtaining an OUTPUT buffer")]
+    GetBufferError(#[source] Box<GetBufferError<Vec<MmapHandle>>>),
+    #[error("Cannot map OUTPUT buffer")]
+    MappingError,
+    #[error("Frame of {size} bytes does not fit into OUTPUT buffer of {capacity} bytes")]
+    FrameTooLarge { size: usize, capacity: usize },
+    #[error("Error while queueing OUTPUT buffer")]
+    QueueError(#[from] ioctl::QBufError),
+    #[error("Error while dequeueing OUTPUT buffers")]
+    DequeueError(#[source] Box<DequeueOutputBufferError<Vec<MmapHandle>>>),
+    #[error("Error while draining decoder")]
+    DrainError(#[from] DrainError),
+    #[error("Decoder died: {0}")]
+    DecoderDied(DecoderError),
+    #[error("Decoder has been disconnected")]
+    Disconnected,
+    #[error("Error while allocating larger OUTPUT buffers")]
+    ResizeError(#[from] ResizeOutputBuffersError),
+    #[error("No frame splitter available for {0} byte streams")]
+    UnsupportedByteStream(PixelFormat),
+    #[error("Byte stream could not be split into frames")]
+    InvalidByteStream,
+    #[error("Error while starting the frame splitter thread")]
+    SplitterThreadError(io::Error),
+}
+
+// The OUTPUT buffer errors are boxed as they can carry a whole dequeued buffer,
+// which would make every `Result` returned by `SyncDecoder` needlessly large.
+impl From<GetBufferError<Vec<MmapHandle>>> for SyncDecoderError {
+    fn from(e: GetBufferError<Vec<MmapHandle>>) -> Self {
+        SyncDecoderError::GetBufferError(Box::new(e))
+    }
+}
+
+impl From<DequeueOutputBufferError<Vec<MmapHandle>>> for SyncDecoderError {
+    fn from(e: DequeueOutputBufferError<Vec<MmapHandle>>) -> Self {
+        SyncDecoderError::DequeueError(Box::new(e))
+    }
+}
+
+/// Reader returning the chunks of data received through a channel, blocking
+/// until more data arrives. The end of the stream is reached when the sender
+/// is dropped.
+struct ChunkReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let len = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+/// Thread splitting a byte stream into frames using one of the splitters of
+/// [`crate::decoder::format`].
+///
+/// The splitters work on readers, so they are run on their own thread which
+/// blocks until more data is written.
+struct SplitterThread {
+    /// Dropped to signal the end of the stream.
+    data_sender: Option<mpsc::Sender<Vec<u8>>>,
+    frames: mpsc::Receiver<Vec<u8>>,
+    handle: std::thread::JoinHandle<()>,
+}
+
+impl SplitterThread {
+    fn new(pixelformat: PixelFormat) -> Result<Self, SyncDecoderError> {
+        let (data_sender, chunks) = mpsc::channel();
+        let (frame_sender, frames) = mpsc::channel();
+        let reader = ChunkReader {
+            chunks,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+
+        // Dropping `frame_sender` before the splitter is created signals an
+        // invalid stream.
+        let fourcc: [u8; 4] = pixelformat.into();
+        let run: fn(ChunkReader, mpsc::Sender<Vec<u8>>) = match &fourcc {
+            b"FWHT" => |reader, frame_sender| {
+                for frame in FwhtFrameParser::new(reader).into_iter().flatten() {
+                    if frame_sender.send(frame).is_err() {
+                        break;
+                    }
+                }
+            },
+            b"H264" => |reader, frame_sender| {
+                for frame in H264FrameSplitter::new(reader).into_iter().flatten() {
+                    if frame_sender.send(frame).is_err() {
+                        break;
+                    }
+                }
+            },
+            _ => return Err(SyncDecoderError::UnsupportedByteStream(pixelformat)),
+        };
+
+        let handle = std::thread::Builder::new()
+            .name("V4L2 Frame Splitter".into())
+            .spawn(move || run(reader, frame_sender))
+            .map_err(SyncDecoderError::SplitterThreadError)?;
+
+        Ok(SplitterThread {
+            data_sender: Some(data_sender),
+            frames,
+            handle,
+        })
+    }
+}
+
+/// How data submitted with [`SyncDecoder::write`] is turned into OUTPUT
+/// buffers.
+enum ByteStreamPacker {
+    /// The driver accepts OUTPUT buffers containing arbitrary parts of the
+    /// stream (`V4L2_FMT_FLAG_CONTINUOUS_BYTESTREAM`), so we just fill them up.
+    Continuous,
+    /// The driver requires one frame per OUTPUT buffer, so the stream is split
+    /// into frames first. The splitter thread is started on the first write.
+    Split(Option<SplitterThread>),
+}
+
+/// Data submitted with [`SyncDecoder::write`] that has not made it into an
+/// OUTPUT buffer yet.
+///
+/// Chunks of the stream are handed to a `queue` closure, which returns `false`
+/// if no OUTPUT buffer is free. The chunk is then kept until the next call to
+/// `pump`, and no more data is accepted until it has been queued.
+struct ByteStreamInput {
+    pixelformat: PixelFormat,
+    packer: ByteStreamPacker,
+    /// Chunk waiting to be queued. In continuous mode, data is accumulated
+    /// here until it fills an OUTPUT buffer. In split mode, this is the next
+    /// frame produced by the splitter thread.
+    pending: Vec<u8>,
+    /// Whether the end of the stream has been signaled with `finish`.
+    finished: bool,
+}
+
+impl ByteStreamInput {
+    fn new(pixelformat: PixelFormat, packer: ByteStreamPacker) -> Self {
+        ByteStreamInput {
+            pixelformat,
+            packer,
+            pending: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Queue as much of the data received so far as possible using `queue`.
+    ///
+    /// In continuous mode, partially filled chunks of `buffer_size` bytes are
+    /// only queued once the end of the stream has been signaled.
+    ///
+    /// Returns `false` if some data is ready but could not be queued because
+    /// no OUTPUT buffer is free.
+    fn pump<Q>(&mut self, buffer_size: usize, queue: &mut Q) -> Result<bool, SyncDecoderError>
+    where
+        Q: FnMut(&[u8]) -> Result<bool, SyncDecoderError>,
+    {
+        loop {
+            if self.pending.is_empty() {
+                let splitter = match &mut self.packer {
+                    ByteStreamPacker::Split(splitter) => splitter,
+                    ByteStreamPacker::Continuous => return Ok(true),
+                };
+                let frame = match splitter {
+                    Some(splitter) => splitter.frames.try_recv(),
+                    None => return Ok(true),
+                };
+
+                match frame {
+                    Ok(frame) => self.pending = frame,
+                    Err(mpsc::TryRecvError::Empty) => return Ok(true),
+                    Err(mpsc::TryRecvError::Disconnected) if self.finished => {
+                        if let Some(splitter) = splitter.take() {
+                            splitter
+                                .handle
+                                .join()
+                                .map_err(|_| SyncDecoderError::InvalidByteStream)?;
+                        }
+                        return Ok(true);
+                    }
+                    // The splitter only stops early if the stream is invalid.
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        return Err(SyncDecoderError::InvalidByteStream)
+                    }
+                }
+                continue;
+            }
+
+            if matches!(self.packer, ByteStreamPacker::Continuous)
+                && self.pending.len() < buffer_size
+                && !self.finished
+            {
+                return Ok(true);
+            }
+
+            if !queue(&self.pending)? {
+                return Ok(false);
+            }
+            // Keep the allocation around for the next chunk.
+            self.pending.clear();
+        }
+    }
+
+    /// Submit `data`, queueing the chunks that are ready using `queue`.
+    ///
+    /// Returns the number of bytes consumed, which is smaller than the length
+    /// of `data` if no OUTPUT buffer is free to queue the pending data. Data
+    /// written after the end of the stream is only accepted once the whole
+    /// stream has been queued, and starts a new stream.
+    fn write<Q>(
+        &mut self,
+        data: &[u8],
+        buffer_size: usize,
+        queue: &mut Q,
+    ) -> Result<usize, SyncDecoderError>
+    where
+        Q: FnMut(&[u8]) -> Result<bool, SyncDecoderError>,
+    {
+        if !self.pump(buffer_size, queue)? {
+            return Ok(0);
+        }
+        if self.finished {
+            if !self.is_done() {
+                return Ok(0);
+            }
+            self.finished = false;
+        }
+
+        let pixelformat = self.pixelformat;
+        let splitter = match &mut self.packer {
+            ByteStreamPacker::Split(splitter) => splitter,
+            ByteStreamPacker::Continuous => {
+                let mut consumed = 0;
+                while consumed < data.len() {
+                    let len = std::cmp::min(
+                        data.len() - consumed,
+                        buffer_size.saturating_sub(self.pending.len()),
+                    );
+                    self.pending
+                        .extend_from_slice(&data[consumed..consumed + len]);
+                    consumed += len;
+
+                    if !self.pump(buffer_size, queue)? {
+                        break;
+                    }
+                }
+
+                return Ok(consumed);
+            }
+        };
+
+        let splitter = match splitter {
+            Some(splitter) => splitter,
+            None => splitter.insert(SplitterThread::new(pixelformat)?),
+        };
+        splitter
+            .data_sender
+            .as_ref()
+            .ok_or(SyncDecoderError::InvalidByteStream)?
+            .send(data.to_vec())
+            .map_err(|_| SyncDecoderError::InvalidByteStream)?;
+
+        // The frames are split asynchronously, so most of them will only be
+        // queued by later calls.
+        self.pump(buffer_size, queue)?;
+
+        Ok(data.len())
+    }
+
+    /// Signal the end of the stream, so the remaining data is queued by the
+    /// next calls to `pump`.
+    fn finish(&mut self) {
+        self.finished = true;
+        if let ByteStreamPacker::Split(Some(splitter)) = &mut self.packer {
+            // Let the splitter return the last frame and stop.
+            splitter.data_sender = None;
+        }
+    }
+
+    /// Returns `true` once the end of the stream has been signaled and all the
+    /// data has been queued.
+    fn is_done(&self) -> bool {
+        self.finished
+            && self.pending.is_empty()
+            && !matches!(self.packer, ByteStreamPacker::Split(Some(_)))
+    }
+}
+
+/// Allocate OUTPUT buffers that can hold at least `min_size` bytes and update
+/// `buffer_size` accordingly.
+fn resize_output_buffers<P: HandlesProvider>(
+    decoder: &mut SyncDecoderInner<P>,
+    buffer_size: &mut usize,
+    num_buffers: usize,
+    min_size: usize,
+) -> Result<(), SyncDecoderError> {
+    // Leave some room so we don't need to reallocate for every frame that is
+    // slightly larger than the previous one.
+    let new_size = std::cmp::max(min_size, *buffer_size * 2);
+    *buffer_size = decoder.resize_output_buffers(new_size, num_buffers)?;
+
+    Ok(())
+}
+
+/// Copy `data` into `buffer` and queue it with `timestamp`.
+fn queue_buffer(
+    mut buffer: QBuffer<'_, Output, Vec<MmapHandle>, Vec<MmapHandle>>,
+    data: &[u8],
+    timestamp: TimeVal,
+) -> Result<(), SyncDecoderError> {
+    let mut mapping = buffer
+        .get_plane_mapping(0)
+        .ok_or(SyncDecoderError::MappingError)?;
+    if data.len() > mapping.len() {
+        return Err(SyncDecoderError::FrameTooLarge {
+            size: data.len(),
+            capacity: mapping.len(),
+        });
+    }
+    mapping.as_mut()[0..data.len()].copy_from_slice(data);
+    drop(mapping);
+
+    buffer.set_timestamp(timestamp).queue(&[data.len()])?;
+
+    Ok(())
+}
+
+/// Synchronous decoder returning decoded frames on demand.
+///
+/// Decoded frames are kept in an internal queue until they are retrieved with
+/// [`SyncDecoder::next_frame`]. Their CAPTURE buffers only become available to
+/// the decoder again once they are dropped, so the client must retrieve (and
+/// eventually drop) frames regularly, otherwise [`SyncDecoder::decode`] may
+/// block forever waiting for a free OUTPUT buffer, and [`SyncDecoder::write`]
+/// stops accepting data.
+pub struct SyncDecoder<P: HandlesProvider> {
+    decoder: SyncDecoderInner<P>,
+    events: mpsc::Receiver<DecoderEvent<P>>,
+    status: Arc<DecoderStatus>,
+    /// Whether the end of stream has been signaled since the last call to
+    /// `drain`.
+    eos: bool,
+    /// Whether we are waiting for the end of stream following a call to
+    /// `drain`. Other end of stream events are ignored.
+    drain_pending: bool,
+    /// Whether `drain` has been called but the decoder cannot be drained yet
+    /// because some written data is still waiting for a free OUTPUT buffer.
+    drain_requested: bool,
+    /// Size of the largest OUTPUT buffers, as set by the driver.
+    output_buffer_size: usize,
+    /// Number of OUTPUT buffers to allocate when larger ones are needed.
+    num_output_buffers: usize,
+    input: ByteStreamInput,
+    /// Timestamp given to the next OUTPUT buffer queued by `write`.
+    bytestream_timestamp: i64,
+}
+
+impl SyncDecoder<MmapProvider> {
+    /// Open the decoder at `path` to decode `pixelformat` streams, using MMAP
+    /// buffers for decoded frames.
+    ///
+    /// `num_output_buffers` OUTPUT buffers of `output_buffer_size` bytes each
+    /// are allocated to hold the encoded frames.
+    pub fn open(
+        path: &Path,
+        pixelformat: PixelFormat,
+        num_output_buffers: usize,
+        output_buffer_size: usize,
+    ) -> Result<Self, SyncDecoderOpenError> {
+        Self::open_with_provider(
+            path,
+            pixelformat,
+            num_output_buffers,
+            output_buffer_size,
+            |f: FormatBuilder, _visible_rect: Rect, min_num_buffers: usize| {
+                let format = f.format();
+                Ok(FormatChangedReply {
+                    provider: MmapProvider::new(format),
+                    mem_type: MemoryType::Mmap,
+                    num_buffers: min_num_buffers,
+                })
+            },
+        )
+    }
+}
+
+impl<P> SyncDecoder<P>
+where
+    P: HandlesProvider,
+    for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
+        GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
+{
+    /// Open the decoder at `path` to decode `pixelformat` streams, using
+    /// `set_capture_format_cb` to set the CAPTURE format and provide the
+    /// buffers for decoded frames every time the resolution changes.
+    pub fn open_with_provider<F>(
+        path: &Path,
+        pixelformat: PixelFormat,
+        num_output_buffers: usize,
+        output_buffer_size: usize,
+        set_capture_format_cb: F,
+    ) -> Result<Self, SyncDecoderOpenError>
+    where
+        F: FormatChangedCallback<P>,
+    {
+        let (event_sender, events) = mpsc::channel();
+        let status = Arc::new(DecoderStatus::default());
+        let event_status = Arc::clone(&status);
+        let format_status = Arc::clone(&status);
+
+        let decoder = Decoder::open(path)?;
+        let continuous_bytestream = decoder
+            .state
+            .output_queue
+            .format_iter()
+            .find(|fmt| fmt.pixelformat == pixelformat)
+            .map(|fmt| fmt.flags.contains(FormatFlags::CONTINUOUS_BYTESTREAM))
+            .unwrap_or(false);
+
+        let mut sizeimage = output_buffer_size;
+        let decoder = decoder
+            .set_output_format(|f| {
+                let format: Format = f
+                    .set_pixelformat(pixelformat)
+                    .set_planes_layout(vec![PlaneLayout {
+                        sizeimage: output_buffer_size as u32,
+                        ..Default::default()
+                    }])
+                    .apply()?;
+
+                anyhow::ensure!(
+                    format.pixelformat == pixelformat,
+                    "{} format not supported by device",
+                    pixelformat
+                );
+                sizeimage = format.plane_fmt[0].sizeimage as usize;
+
+                Ok(())
+            })
+            .map_err(SyncDecoderOpenError::SetOutputFormatError)?
+            .allocate_output_buffers::<Vec<MmapHandle>>(num_output_buffers)?
+            .start(
+                (|_| ()) as fn(CompletedInputBuffer<Vec<MmapHandle>>),
+                Box::new(move |event: DecoderEvent<P>| {
+                    if let DecoderEvent::Died(_) = event {
+                        event_status.died.store(true, Ordering::SeqCst);
+                    }
+                    // The receiver is only dropped along with the decoder, so
+                    // there is nobody left to care about the event.
+                    let _ = event_sender.send(event);
+                }) as Box<dyn DecoderEventCallback<P>>,
+                Box::new(
+                    move |f: FormatBuilder, visible_rect: Rect, min_num_buffers: usize| {
+                        format_status.resolution_known.store(true, Ordering::SeqCst);
+                        set_capture_format_cb(f, visible_rect, min_num_buffers)
+                    },
+                ) as Box<dyn FormatChangedCallback<P>>,
+            )?;
+
+        let packer = if continuous_bytestream {
+            ByteStreamPacker::Continuous
+        } else {
+            ByteStreamPacker::Split(None)
+        };
+
+        Ok(SyncDecoder {
+            decoder,
+            events,
+            status,
+            eos: false,
+            drain_pending: false,
+            drain_requested: false,
+            output_buffer_size: sizeimage,
+            num_output_buffers,
+            input: ByteStreamInput::new(pixelformat, packer),
+            bytestream_timestamp: 0,
+        })
+    }
+}
+
+impl<P: HandlesProvider> SyncDecoder<P> {
+    /// Copy `data` into a free OUTPUT buffer and queue it for decoding with
+    /// `timestamp`, waiting for a buffer to become free if needed.
+    ///
+    /// If `data` does not fit into the OUTPUT buffers, larger buffers are
+    /// transparently added to the OUTPUT queue without interrupting decoding.
+    pub fn decode(&mut self, data: &[u8], timestamp: TimeVal) -> Result<(), SyncDecoderError> {
+        if data.len() > self.output_buffer_size {
+            resize_output_buffers(
+                &mut self.decoder,
+                &mut self.output_buffer_size,
+                self.num_output_buffers,
+                data.len(),
+            )?;
+        }
+
+        let buffer = self.decoder.get_buffer_for_size(data.len())?;
+        queue_buffer(buffer, data, timestamp)?;
+        self.eos = false;
+        self.drain_pending = false;
+
+        Ok(())
+    }
+
+    /// Returns `true` if the driver accepts OUTPUT buffers containing arbitrary
+    /// parts of the stream, in which case [`SyncDecoder::write`] does not need
+    /// to split the stream into frames.
+    pub fn is_continuous_bytestream(&self) -> bool {
+        matches!(self.input.packer, ByteStreamPacker::Continuous)
+    }
+
+    /// Submit an arbitrary chunk of an unframed stream for decoding.
+    ///
+    /// If the driver supports continuous byte streams, the data is packed into
+    /// OUTPUT buffers as-is. Otherwise it is first split into frames by one of
+    /// the splitters of [`crate::decoder::format`]; only FWHT and H.264 streams
+    /// are supported in that case.
+    ///
+    /// This method never waits for an OUTPUT buffer to become free. It returns
+    /// the number of bytes consumed, which is smaller than the length of
+    /// `data`, possibly zero, if all the OUTPUT buffers are busy. The client
+    /// should then retrieve frames with [`SyncDecoder::next_frame`], which
+    /// queues the pending data as OUTPUT buffers become free, before writing
+    /// the rest. After a call to [`SyncDecoder::drain`], no data is accepted
+    /// until all the data written before it has been queued.
+    ///
+    /// Buffered data is only guaranteed to be submitted to the decoder after a
+    /// call to [`SyncDecoder::drain`]. This method should not be mixed with
+    /// [`SyncDecoder::decode`].
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, SyncDecoderError> {
+        self.pump_input()?;
+        if self.drain_requested {
+            return Ok(0);
+        }
+
+        let SyncDecoder {
+            decoder,
+            input,
+            output_buffer_size,
+            num_output_buffers,
+            bytestream_timestamp,
+            ..
+        } = self;
+        let buffer_size = *output_buffer_size;
+        let consumed = input.write(data, buffer_size, &mut |chunk: &[u8]| {
+            try_queue_chunk(
+                decoder,
+                output_buffer_size,
+                *num_output_buffers,
+                bytestream_timestamp,
+                chunk,
+            )
+        })?;
+
+        if consumed > 0 {
+            self.eos = false;
+            self.drain_pending = false;
+        }
+
+        Ok(consumed)
+    }
+
+    /// Queue the written data that is waiting for an OUTPUT buffer into the
+    /// ones that are free, and drain the decoder if [`SyncDecoder::drain`] has
+    /// been called and all the data has been queued.
+    ///
+    /// Returns `false` if some data is still waiting for a free OUTPUT buffer.
+    fn pump_input(&mut self) -> Result<bool, SyncDecoderError> {
+        let SyncDecoder {
+            decoder,
+            input,
+            output_buffer_size,
+            num_output_buffers,
+            bytestream_timestamp,
+            ..
+        } = self;
+        let buffer_size = *output_buffer_size;
+        let all_queued = input.pump(buffer_size, &mut |chunk: &[u8]| {
+            try_queue_chunk(
+                decoder,
+                output_buffer_size,
+                *num_output_buffers,
+                bytestream_timestamp,
+                chunk,
+            )
+        })?;
+
+        if self.drain_requested && self.input.is_done() {
+            self.drain_requested = false;
+            self.drain_decoder()?;
+        }
+
+        Ok(all_queued)
+    }
+
+    /// Return the next decoded frame, waiting up to `timeout` for it to be
+    /// available, or indefinitely if `timeout` is `None`.
+    ///
+    /// Returns `Ok(None)` if no frame has been produced before `timeout`
+    /// expired, or if all the frames preceding a call to
+    /// [`SyncDecoder::drain`] have already been returned. Use
+    /// [`SyncDecoder::is_drained`] to distinguish between the two cases.
+    ///
+    /// Data written with [`SyncDecoder::write`] that is waiting for an OUTPUT
+    /// buffer is queued while waiting.
+    ///
+    /// Frames the driver reported errors for are returned as well, see
+    /// [`DecodedFrame::has_error`].
+    pub fn next_frame(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<DecodedFrame<P>>, SyncDecoderError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            // If some input is still waiting, wake up regularly to queue it as
+            // OUTPUT buffers become free.
+            let input_waiting = !self.pump_input()? || self.drain_requested;
+            let retry_delay = if input_waiting {
+                Some(INPUT_RETRY_DELAY)
+            } else {
+                None
+            };
+            let wait = deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                .into_iter()
+                .chain(retry_delay)
+                .min();
+
+            // Once the end of stream is reached, only return the frames still
+            // pending without waiting for new ones.
+            let event = match (self.eos, wait) {
+                (true, _) => self.events.try_recv().map_err(|e| match e {
+                    mpsc::TryRecvError::Empty => RecvTimeoutError::Timeout,
+                    mpsc::TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+                }),
+                (false, Some(wait)) => self.events.recv_timeout(wait),
+                (false, None) => self
+                    .events
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            // Give the input buffers processed so far back to the decoder.
+            self.decoder.kick()?;
+
+            match event {
+                Ok(DecoderEvent::FrameDecoded(frame)) | Ok(DecoderEvent::CorruptedFrame(frame)) => {
+                    // Empty buffers are used to signal events such as the end
+                    // of a drain sequence and do not carry a frame.
+                    if frame.buffer().data.get_first_plane().payload_size() == 0 {
+                        continue;
+                    }
+                    return Ok(Some(frame));
+                }
+                Ok(DecoderEvent::EndOfStream) if self.drain_pending => {
+                    self.drain_pending = false;
+                    self.eos = true;
+                }
+                Ok(DecoderEvent::EndOfStream) => (),
+                Ok(DecoderEvent::Died(e)) => return Err(SyncDecoderError::DecoderDied(e)),
+                // Woken up to queue the waiting input.
+                Err(RecvTimeoutError::Timeout)
+                    if !self.eos && deadline.is_none_or(|deadline| Instant::now() < deadline) => {}
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => return Err(SyncDecoderError::Disconnected),
+            }
+        }
+    }
+
+    /// Signal that no more data will be submitted until the frames of all the
+    /// data queued so far are produced.
+    ///
+    /// The remaining frames are retrieved with [`SyncDecoder::next_frame`],
+    /// which will return `Ok(None)` once all of them have been returned. If
+    /// some data written with [`SyncDecoder::write`] is still waiting for an
+    /// OUTPUT buffer, the decoder is only drained once `next_frame` has queued
+    /// it.
+    ///
+    /// Draining only blocks if the decoder has not determined the stream
+    /// resolution yet, until it does or gives up on the stream.
+    pub fn drain(&mut self) -> Result<(), SyncDecoderError> {
+        self.input.finish();
+        self.eos = false;
+        self.drain_requested = true;
+        self.pump_input()?;
+
+        Ok(())
+    }
+
+    /// Send a drain request to the decoder once all the data has been queued.
+    fn drain_decoder(&mut self) -> Result<(), SyncDecoderError> {
+        // Time at which the decoder was found to have consumed all the OUTPUT
+        // buffers without determining the stream resolution.
+        let mut consumed_since = None;
+        loop {
+            match self.decoder.drain(false) {
+                // Drain is done synchronously, or will be signaled by the
+                // end-of-stream event.
+                Ok(true) => {
+                    self.eos = true;
+                    return Ok(());
+                }
+                Ok(false) => {
+                    self.drain_pending = true;
+                    return Ok(());
+                }
+                // The decoder has not determined the stream resolution yet.
+                // Wait until it does, or until it gives up on the stream.
+                Err(DrainError::TryAgain) => {
+                    self.decoder.kick()?;
+
+                    // The error is returned by `next_frame`.
+                    if self.status.died.load(Ordering::SeqCst) {
+                        self.eos = true;
+                        return Ok(());
+                    }
+
+                    // Once the resolution is known, the capture thread will
+                    // accept the drain request shortly. Otherwise the driver
+                    // may still be parsing the last OUTPUT buffer it consumed,
+                    // so give it some time before concluding that the stream
+                    // contains no frame.
+                    if self.status.resolution_known.load(Ordering::SeqCst)
+                        || self.decoder.num_queued_buffers() > 0
+                    {
+                        consumed_since = None;
+                    } else if consumed_since.get_or_insert_with(Instant::now).elapsed()
+                        >= NO_FRAME_TIMEOUT
+                    {
+                        warn!("Drain requested but no frame has been found in the stream");
+                        self.eos = true;
+                        return Ok(());
+                    }
+
+                    std::thread::sleep(DRAIN_RETRY_DELAY);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Returns `true` if all the frames preceding the last call to
+    /// [`SyncDecoder::drain`] have been produced.
+    pub fn is_drained(&self) -> bool {
+        self.eos
+    }
+
+    /// Stop the decoder and release its resources.
+    ///
+    /// Frames that have not been retrieved yet are discarded.
+    pub fn stop(self) -> Result<(), StopError> {
+        // Make sure the capture thread does not get stuck sending events.
+        drop(self.events);
+        self.decoder.stop().map(|_| ())
+    }
+}
+
+/// Queue `chunk` of the byte stream into a free OUTPUT buffer of `decoder`,
+/// giving it the next `timestamp`.
+///
+/// Returns `false` if no large enough OUTPUT buffer is free.
+fn try_queue_chunk<P: HandlesProvider>(
+    decoder: &mut SyncDecoderInner<P>,
+    output_buffer_size: &mut usize,
+    num_output_buffers: usize,
+    timestamp: &mut i64,
+    chunk: &[u8],
+) -> Result<bool, SyncDecoderError> {
+    if chunk.len() > *output_buffer_size {
+        resize_output_buffers(decoder, output_buffer_size, num_output_buffers, chunk.len())?;
+    }
+
+    let buffer = match decoder.try_get_buffer_for_size(chunk.len()) {
+        Ok(buffer) => buffer,
+        Err(GetBufferError::GetFreeBufferError(GetFreeBufferError::NoFreeBuffer)) => {
+            return Ok(false)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    queue_buffer(buffer, chunk, TimeVal::microseconds(*timestamp))?;
+    *timestamp += 1;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    const HEADER: [u8; 8] = [0x4f, 0x4f, 0x4f, 0x4f, 0xff, 0xff, 0xff, 0xff];
+
+    #[test]
+    fn test_splitter_thread() {
+        let frames = [
+            [&HEADER[..], &[1, 2, 3]].concat(),
+            [&HEADER[..], &[4, 5]].concat(),
+            [&HEADER[..], &[6]].concat(),
+        ];
+        let stream = frames.concat();
+
+        let mut splitter = SplitterThread::new(b"FWHT".into()).unwrap();
+        // Write the stream in chunks that do not match frame boundaries.
+        let data_sender = splitter.data_sender.take().unwrap();
+        for chunk in stream.chunks(5) {
+            data_sender.send(chunk.to_vec()).unwrap();
+        }
+        drop(data_sender);
+
+        assert_eq!(splitter.frames.iter().collect::<Vec<_>>(), frames);
+        splitter.handle.join().unwrap();
+
+        assert!(matches!(
+            SplitterThread::new(b"VP80".into()),
+            Err(SyncDecoderError::UnsupportedByteStream(_))
+        ));
+    }
+
+    #[test]
+    fn test_bytestream_input_does_not_block() {
+        const NUM_BUFFERS: usize = 2;
+        const BUFFER_SIZE: usize = 4;
+
+        // Pretend to queue chunks into OUTPUT buffers, `free` of which are
+        // not queued yet.
+        let free = Cell::new(NUM_BUFFERS);
+        let queued = RefCell::new(Vec::new());
+        let mut queue = |chunk: &[u8]| {
+            if free.get() == 0 {
+                return Ok(false);
+            }
+            free.set(free.get() - 1);
+            queued.borrow_mut().push(chunk.to_vec());
+            Ok(true)
+        };
+
+        // Continuous mode: write more than `NUM_BUFFERS` buffers worth of data
+        // at once. One more chunk than there are free buffers is accepted and
+        // kept until a buffer becomes free.
+        let stream: Vec<u8> = (0..(BUFFER_SIZE * NUM_BUFFERS * 2 + 1) as u8).collect();
+        let mut input = ByteStreamInput::new(b"FWHT".into(), ByteStreamPacker::Continuous);
+        let consumed = input.write(&stream, BUFFER_SIZE, &mut queue).unwrap();
+        assert_eq!(consumed, BUFFER_SIZE * (NUM_BUFFERS + 1));
+        assert_eq!(
+            input
+                .write(&stream[consumed..], BUFFER_SIZE, &mut queue)
+                .unwrap(),
+            0
+        );
+
+        let mut data = &stream[consumed..];
+        while !data.is_empty() {
+            free.set(NUM_BUFFERS);
+            let consumed = input.write(data, BUFFER_SIZE, &mut queue).unwrap();
+            assert!(consumed > 0);
+            data = &data[consumed..];
+        }
+        input.finish();
+        free.set(NUM_BUFFERS);
+        assert!(input.pump(BUFFER_SIZE, &mut queue).unwrap());
+        assert!(input.is_done());
+        assert_eq!(
+            queued.take(),
+            stream
+                .chunks(BUFFER_SIZE)
+                .map(<[u8]>::to_vec)
+                .collect::<Vec<_>>()
+        );
+
+        // Split mode: frames that cannot be queued yet stay in the splitter
+        // until buffers become free.
+        let frames: Vec<_> = (0..(NUM_BUFFERS * 2 + 1) as u8)
+            .map(|i| [&HEADER[..], &[i]].concat())
+            .collect();
+        let stream = frames.concat();
+        let mut input = ByteStreamInput::new(b"FWHT".into(), ByteStreamPacker::Split(None));
+        assert_eq!(
+            input.write(&stream, BUFFER_SIZE, &mut queue).unwrap(),
+            stream.len()
+        );
+        input.finish();
+
+        let start = Instant::now();
+        while !input.is_done() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            free.set(NUM_BUFFERS);
+            input.pump(BUFFER_SIZE, &mut queue).unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(queued.take(), frames);
+    }
+}
@@ -1,6 +1,7 @@
 pub mod buffer;
 pub mod direction;
 pub mod dqbuf;
+pub mod export;
 pub mod generic;
 pub mod handles_provider;
 pub mod qbuf;
@@ -207,6 +208,14 @@ pub enum RequestBuffersError {
     QueryBufferError(#[from] ioctl::QueryBufError),
 }
 
+#[derive(Debug, Error)]
+pub enum CreateBuffersError {
+    #[error("Error while creating buffers")]
+    CreateBufsError(#[from] ioctl::CreateBufsError),
+    #[error("Error while querying buffer")]
+    QueryBufferError(#[from] ioctl::QueryBufError),
+}
+
 impl<D: Direction> Queue<D, QueueInit> {
     /// Create a queue for type `queue_type` on `device`. A queue of a specific type
     /// can be requested only once.
@@ -288,6 +297,7 @@ impl<D: Direction> Queue<D, QueueInit> {
                 memory_type,
                 buffer_info,
                 buffer_stats,
+                userptr_alignment: None,
             },
         })
     }
@@ -346,10 +356,98 @@ pub struct BuffersAllocated<P: BufferHandles> {
     /// deallocated alone (V4L2 currently does not allow this, but might in the future).
     buffer_info: Vec<Arc<BufferInfo<P>>>,
     buffer_stats: Arc<BufferStats>,
+    /// Alignment required for the memory of USERPTR planes, if any.
+    userptr_alignment: Option<usize>,
 }
 impl<P: BufferHandles> QueueState for BuffersAllocated<P> {}
 
 impl<D: Direction, P: BufferHandles> Queue<D, BuffersAllocated<P>> {
+    /// Returns the memory type the buffers of this queue have been allocated
+    /// with.
+    pub fn memory_type(&self) -> P::SupportedMemoryType {
+        self.state.memory_type
+    }
+
+    /// Require the memory of the USERPTR planes queued from now on to start at
+    /// an address aligned to `align` bytes, which must be a power of two.
+    ///
+    /// V4L2 has no way to report the alignment a driver needs, but some of
+    /// them reject or silently mishandle misaligned USERPTR memory. Once this
+    /// is set, queuing such a plane fails with `QBufError::UserPtrMisaligned`.
+    /// [`page_size`] is a safe choice for most drivers.
+    pub fn set_userptr_alignment(&mut self, align: usize) -> Result<(), UserPtrError> {
+        if !align.is_power_of_two() {
+            return Err(UserPtrError::InvalidAlignment(align));
+        }
+        self.state.userptr_alignment = Some(align);
+
+        Ok(())
+    }
+
+    /// Allocate `count` additional buffers large enough to hold frames of
+    /// `format`, which may differ from the current format of the queue. This
+    /// can be done while the queue is streaming, e.g. to make room for frames
+    /// larger than the current buffers.
+    ///
+    /// Returns the number of buffers actually created.
+    pub fn create_buffers(
+        &mut self,
+        count: u32,
+        format: Format,
+    ) -> Result<usize, CreateBuffersError> {
+        let type_ = self.inner.type_;
+        let created = ioctl::create_bufs(
+            &self.inner,
+            type_,
+            self.state.memory_type.into(),
+            count,
+            format,
+        )?;
+
+        debug!(
+            "Created {} additional buffers on {} queue, obtained {}",
+            count, type_, created.count
+        );
+
+        // New buffers are always given the indices following the existing
+        // ones.
+        let first = created.index as usize;
+        for index in first..first + created.count as usize {
+            let features: QueryBuffer = ioctl::querybuf(&self.inner, type_, index)?;
+            self.state.buffer_info.push(Arc::new(BufferInfo::new(
+                features,
+                Arc::clone(&self.state.buffer_stats),
+            )));
+        }
+
+        Ok(created.count as usize)
+    }
+
+    /// Returns the size of the first plane of the largest buffer of the queue.
+    pub fn max_buffer_size(&self) -> usize {
+        self.state
+            .buffer_info
+            .iter()
+            .filter_map(|buffer| buffer.features.planes.first())
+            .map(|plane| plane.length as usize)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the index of a free buffer whose first plane can hold at least
+    /// `min_size` bytes, if there is one.
+    pub fn find_free_buffer(&self, min_size: usize) -> Option<usize> {
+        self.state.buffer_info.iter().position(|buffer| {
+            buffer
+                .features
+                .planes
+                .first()
+                .map(|plane| plane.length as usize >= min_size)
+                .unwrap_or(false)
+                && buffer.do_with_state(|s| matches!(s, BufferState::Free))
+        })
+    }
+
     /// Return all the currently queued buffers as CanceledBuffers. This can
     /// be called after a explicit or implicit streamoff to inform the client
     /// of which buffers have been canceled and return their handles.
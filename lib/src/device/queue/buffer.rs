@@ -1,9 +1,12 @@
 use super::BufferHandles;
-use crate::ioctl;
-
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, Mutex,
+use crate::{ioctl, memory::Mappable};
+
+use std::{
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 /// Represents the current state of an allocated buffer.
@@ -53,6 +56,9 @@ pub(super) struct BufferInfo<P: BufferHandles> {
     state: Mutex<BufferState<P>>,
     /// Link to the queue's buffer stats, so we can update them as the buffer state changes.
     stats: Arc<BufferStats>,
+    /// Mappings of the planes of the buffer, created the first time they are requested and
+    /// kept until the buffer is freed.
+    mappings: Mutex<Vec<Option<Arc<ioctl::MmapRegion>>>>,
 }
 
 impl<P: BufferHandles> Drop for BufferInfo<P> {
@@ -66,11 +72,28 @@ impl<P: BufferHandles> BufferInfo<P> {
         stats.num_free.fetch_add(1, Ordering::Relaxed);
         Self {
             state: Mutex::new(BufferState::Free),
+            mappings: Mutex::new(vec![None; features.planes.len()]),
             features,
             stats: Arc::clone(&stats),
         }
     }
 
+    /// Returns the mapping of plane `plane` of the buffer, mapping it using `M` if this is the
+    /// first time it is requested.
+    pub(super) fn get_mapping<M: Mappable, D: AsRawFd>(
+        &self,
+        device: &D,
+        plane: usize,
+    ) -> Option<Arc<ioctl::MmapRegion>> {
+        let mut mappings = self.mappings.lock().unwrap();
+        let mapping = mappings.get_mut(plane)?;
+        if mapping.is_none() {
+            *mapping = Some(Arc::new(M::map(device, self.features.planes.get(plane)?)?));
+        }
+
+        mapping.clone()
+    }
+
     /// Do something with the buffer's state. The state is provided read-only and thus cannot be
     /// modified.
     pub(super) fn do_with_state<R, F: FnOnce(&BufferState<P>) -> R>(&self, f: F) -> R {
@@ -4,7 +4,7 @@ use super::{
     direction::{Capture, Direction},
     BufferStateFuse, BuffersAllocated, Queue,
 };
-use crate::ioctl::{self, PlaneMapping};
+use crate::ioctl::{self, PlaneReadMapping};
 use crate::{
     device::Device,
     memory::{BufferHandles, Mappable, PrimitiveBufferHandles},
@@ -72,6 +72,10 @@ impl<D: Direction, P: BufferHandles> DqBuffer<D, P> {
     pub fn take_handles(&mut self) -> Option<P> {
         self.plane_handles.take()
     }
+    /// Returns the allocated buffer this buffer has been dequeued from.
+    pub(super) fn buffer_info(&self) -> &Weak<BufferInfo<P>> {
+        &self.buffer_info
+    }
 }
 
 impl<P> DqBuffer<Capture, P>
@@ -79,19 +83,26 @@ where
     P: PrimitiveBufferHandles,
     P::HandleType: Mappable,
 {
-    // TODO returned mapping should be read-only!
-    pub fn get_plane_mapping(&self, plane_index: usize) -> Option<PlaneMapping> {
+    /// Map the data of plane `plane_index` for reading, i.e. its payload starting at its
+    /// `data_offset`.
+    ///
+    /// The plane is only mapped the first time it is requested, and the mapping is then reused
+    /// for as long as the buffer exists. The returned mapping borrows this object, which
+    /// guarantees that the buffer cannot be queued again while it is being read.
+    pub fn get_plane_mapping(&self, plane_index: usize) -> Option<PlaneReadMapping<'_>> {
         // We can only obtain a mapping if this buffer has not been deleted.
         let buffer_info = self.buffer_info.upgrade()?;
-        let plane = buffer_info.features.planes.get(plane_index)?;
         let plane_data = self.data.get_plane(plane_index)?;
         // If the buffer info was alive, then the device must also be.
         let device = self.device.upgrade()?;
+        let region = buffer_info.get_mapping::<P::HandleType, _>(device.as_ref(), plane_index)?;
 
         let start = plane_data.data_offset() as usize;
-        let end = start + plane_data.bytesused() as usize;
+        let end = plane_data.bytesused() as usize;
 
-        Some(P::HandleType::map(device.as_ref(), plane)?.restrict(start, end))
+        // Safe because the buffer remains dequeued for as long as we are borrowed, so neither
+        // the device nor the owner of a `QBuffer` can write into it.
+        Some(unsafe { PlaneReadMapping::new(region, start, end) })
     }
 }
 
@@ -0,0 +1,180 @@
+//! Export of the MMAP buffers of a queue as DMA-BUFs, so they can be passed to
+//! another device without copying their content.
+//!
+//! The buffers of a queue are exported once using `Queue::export_buffers`.
+//! After that, every CAPTURE buffer dequeued from that queue can be turned
+//! into DMA-BUF handles using `ExportedBuffers::handles_for`, and the handles
+//! queued into the OUTPUT queue of another device, e.g. to feed the frames
+//! produced by a decoder into an encoder.
+//!
+//! The source buffer remains dequeued for as long as any of its handles is
+//! alive. Once the sink queue has processed it and its handles are dropped,
+//! the buffer becomes free again and can be queued into the source queue.
+use super::{
+    buffer::BufferInfo, direction::Capture, dqbuf::DqBuffer, BuffersAllocated, Direction, Queue,
+};
+use crate::{
+    ioctl::{self, ExpbufFlags},
+    memory::{DmaBufHandle, DmaBufSource, MmapHandle},
+};
+use std::{
+    fs::File,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{Arc, Mutex, Weak},
+};
+
+/// The dequeued source buffer of an `ExportedDmaBuf`, shared by all its planes.
+type SourceBuffer = DqBuffer<Capture, Vec<MmapHandle>>;
+
+/// DMA-BUFs exported from all the buffers of a queue.
+#[derive(Debug)]
+pub struct ExportedBuffers {
+    /// Exported buffers, indexed by buffer index.
+    buffers: Vec<ExportedBuffer>,
+}
+
+/// DMA-BUFs exported from the planes of a single buffer.
+struct ExportedBuffer {
+    /// Buffer the DMA-BUFs have been exported from, so we can tell whether a
+    /// dequeued buffer belongs to the same allocation.
+    info: Weak<BufferInfo<Vec<MmapHandle>>>,
+    dmabufs: Vec<Arc<File>>,
+}
+
+impl std::fmt::Debug for ExportedBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.dmabufs.fmt(f)
+    }
+}
+
+impl<D: Direction> Queue<D, BuffersAllocated<Vec<MmapHandle>>> {
+    /// Export all the buffers of the queue as DMA-BUFs.
+    ///
+    /// This only needs to be done once after the buffers are allocated. The
+    /// exported DMA-BUFs remain valid even after the buffers are freed, but
+    /// then do not correspond to any buffer of the queue anymore, and buffers
+    /// dequeued from a new allocation are not matched by
+    /// `ExportedBuffers::handles_for`.
+    pub fn export_buffers(&self) -> Result<ExportedBuffers, ioctl::ExpbufError> {
+        let buffers = self
+            .state
+            .buffer_info
+            .iter()
+            .map(|buffer| -> Result<_, ioctl::ExpbufError> {
+                let dmabufs = (0..buffer.features.planes.len())
+                    .map(|plane| {
+                        ioctl::expbuf::<_, File>(
+                            &self.inner,
+                            self.inner.type_,
+                            buffer.features.index,
+                            plane,
+                            ExpbufFlags::CLOEXEC | ExpbufFlags::RDWR,
+                        )
+                        .map(Arc::new)
+                    })
+                    .collect::<Result<_, _>>()?;
+
+                Ok(ExportedBuffer {
+                    info: Arc::downgrade(buffer),
+                    dmabufs,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(ExportedBuffers { buffers })
+    }
+}
+
+impl ExportedBuffers {
+    pub fn num_buffers(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Returns the DMA-BUFs of the planes of buffer `index`.
+    pub fn get(&self, index: usize) -> Option<&[Arc<File>]> {
+        self.buffers
+            .get(index)
+            .map(|buffer| buffer.dmabufs.as_slice())
+    }
+
+    /// Turn `dqbuf`, which must have been dequeued from the queue these
+    /// buffers have been exported from, into DMA-BUF handles that can be
+    /// queued into another queue.
+    ///
+    /// `dqbuf` is kept alive, and thus its buffer cannot be reused by its
+    /// queue, until all the returned handles are dropped.
+    ///
+    /// `None` is returned if `dqbuf` does not match any exported buffer, e.g.
+    /// because the buffers of its queue have been reallocated since they were
+    /// exported.
+    pub fn handles_for(&self, dqbuf: SourceBuffer) -> Option<ExportedBufferHandles> {
+        let buffer = self.buffers.get(dqbuf.data.index() as usize)?;
+        if !Weak::ptr_eq(&buffer.info, dqbuf.buffer_info()) {
+            return None;
+        }
+        let dmabufs = &buffer.dmabufs;
+        if dmabufs.len() != dqbuf.data.num_planes() {
+            return None;
+        }
+
+        let planes = (0..dmabufs.len())
+            .map(|i| {
+                let plane = dqbuf.data.get_plane(i)?;
+                Some((
+                    plane.data_offset(),
+                    plane.length().saturating_sub(plane.data_offset()),
+                    plane.payload_size() as usize,
+                ))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let source = Arc::new(Mutex::new(dqbuf));
+
+        let mut handles = Vec::with_capacity(planes.len());
+        let mut bytes_used = Vec::with_capacity(planes.len());
+        for (dmabuf, (offset, length, payload_size)) in dmabufs.iter().zip(planes) {
+            let dmabuf = ExportedDmaBuf {
+                dmabuf: Arc::clone(dmabuf),
+                _source: Arc::clone(&source),
+            };
+            handles.push(DmaBufHandle::new(dmabuf, offset, length));
+            bytes_used.push(payload_size);
+        }
+
+        Some(ExportedBufferHandles {
+            handles,
+            bytes_used,
+        })
+    }
+}
+
+/// A plane of a buffer exported as a DMA-BUF, which keeps the buffer dequeued
+/// from its source queue for as long as it is alive.
+#[derive(Debug)]
+pub struct ExportedDmaBuf {
+    dmabuf: Arc<File>,
+    // Never locked: the `Mutex` only makes the buffer `Sync`, which handles
+    // need to be, while `DqBuffer` is only `Send`.
+    _source: Arc<Mutex<SourceBuffer>>,
+}
+
+impl AsRawFd for ExportedDmaBuf {
+    fn as_raw_fd(&self) -> RawFd {
+        self.dmabuf.as_raw_fd()
+    }
+}
+
+impl DmaBufSource for ExportedDmaBuf {
+    fn len(&self) -> u64 {
+        self.dmabuf.as_ref().len()
+    }
+}
+
+/// Handles of a dequeued buffer exported as DMA-BUFs, returned by
+/// `ExportedBuffers::handles_for`.
+#[derive(Debug)]
+pub struct ExportedBufferHandles {
+    pub handles: Vec<DmaBufHandle<ExportedDmaBuf>>,
+    /// Number of bytes of data in each plane, to be passed when queuing
+    /// `handles` into an OUTPUT queue.
+    pub bytes_used: Vec<usize>,
+}
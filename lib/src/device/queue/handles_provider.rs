@@ -7,12 +7,16 @@ use std::{
 
 use log::error;
 
+#[cfg(feature = "dma-heap")]
+use crate::memory::{DmaBufAllocator, DmaBufHandle, DmaHeapError, MemoryType};
 use crate::{
     bindings,
     device::poller::Waker,
     memory::{BufferHandles, MmapHandle, PrimitiveBufferHandles},
     Format,
 };
+#[cfg(feature = "dma-heap")]
+use std::fs::File;
 
 use thiserror::Error;
 
@@ -38,6 +42,11 @@ pub trait HandlesProvider: Send + 'static {
     /// are available again.
     fn get_handles(&self, waker: &Arc<Waker>) -> Option<Self::HandleType>;
 
+    /// Called when the queue handles are provided for has been given `num_buffers`
+    /// buffers of `format`, e.g. after a resolution change, so the provider can
+    /// adapt the handles it provides. Does nothing by default.
+    fn queue_format_changed(&self, _format: &Format, _num_buffers: usize) {}
+
     fn get_suitable_buffer_for<'a, Q>(
         &self,
         _handles: &Self::HandleType,
@@ -62,6 +71,10 @@ impl<P: HandlesProvider> HandlesProvider for Box<P> {
         self.as_ref().get_handles(waker)
     }
 
+    fn queue_format_changed(&self, format: &Format, num_buffers: usize) {
+        self.as_ref().queue_format_changed(format, num_buffers)
+    }
+
     fn get_suitable_buffer_for<'a, Q>(
         &self,
         handles: &Self::HandleType,
@@ -85,6 +98,10 @@ impl<P: HandlesProvider + Sync> HandlesProvider for Arc<P> {
         self.as_ref().get_handles(waker)
     }
 
+    fn queue_format_changed(&self, format: &Format, num_buffers: usize) {
+        self.as_ref().queue_format_changed(format, num_buffers)
+    }
+
     fn get_suitable_buffer_for<'a, Q>(
         &self,
         handles: &Self::HandleType,
@@ -223,3 +240,288 @@ impl<H: PrimitiveBufferHandles> PrimitiveBufferHandles for PooledHandles<H> {
     type HandleType = H::HandleType;
     const MEMORY_TYPE: Self::SupportedMemoryType = H::MEMORY_TYPE;
 }
+
+/// How the planes of the frames allocated by `DmaHeapProvider` are laid out in
+/// memory.
+#[cfg(feature = "dma-heap")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaBufLayout {
+    /// Each plane has its own DMA-BUF.
+    PerPlane,
+    /// All the planes share a single DMA-BUF, in which they are placed one
+    /// after the other. The offsets of the planes are only honored by OUTPUT
+    /// queues, and CAPTURE queues reject such frames.
+    Single,
+}
+
+#[cfg(feature = "dma-heap")]
+struct DmaHeapProviderInternal {
+    allocator: DmaBufAllocator,
+    layout: DmaBufLayout,
+    format: Format,
+    /// Maximum number of frames to allocate for `format`.
+    num_buffers: usize,
+    /// Number of frames currently allocated for `format`.
+    num_allocated: usize,
+    /// Incremented every time the format changes, so frames allocated for a
+    /// previous format can be recognized and freed.
+    generation: u64,
+    buffers: VecDeque<Vec<DmaBufHandle<File>>>,
+    waker: Option<Arc<Waker>>,
+}
+
+#[cfg(feature = "dma-heap")]
+impl DmaHeapProviderInternal {
+    fn allocate_frame(&self) -> Result<Vec<DmaBufHandle<File>>, DmaHeapError> {
+        let sizes = self
+            .format
+            .plane_fmt
+            .iter()
+            .map(|plane| plane.sizeimage as usize);
+
+        match self.layout {
+            DmaBufLayout::PerPlane => sizes
+                .map(|size| self.allocator.allocate(size).map(DmaBufHandle::from))
+                .collect(),
+            DmaBufLayout::Single => {
+                let dmabuf = self.allocator.allocate(sizes.sum())?;
+                DmaBufHandle::from_format(dmabuf, &self.format).map_err(DmaHeapError::DupError)
+            }
+        }
+    }
+}
+
+/// A handles provider that allocates DMA-BUFs for frames of a given format,
+/// up to a maximum number of frames. Provided `DmaHeapHandles` return to the
+/// provider when they are dropped, so they can be reused.
+///
+/// When the format is changed using `set_format`, the frames allocated for the
+/// previous format are freed as they are returned and new ones are allocated
+/// on demand.
+///
+/// Decoders report the new CAPTURE format after each resolution change, upon
+/// which the provider switches to it automatically. When used with other
+/// queues, `set_format` must be called with the new format before handles are
+/// requested again. Otherwise frames of the previous format keep being
+/// provided, which the queue may reject as too small.
+#[cfg(feature = "dma-heap")]
+pub struct DmaHeapProvider {
+    d: Arc<Mutex<DmaHeapProviderInternal>>,
+}
+
+#[cfg(feature = "dma-heap")]
+impl DmaHeapProvider {
+    /// Create a new provider allocating up to `num_buffers` frames of
+    /// `format` using `allocator`.
+    pub fn new(
+        allocator: DmaBufAllocator,
+        layout: DmaBufLayout,
+        format: Format,
+        num_buffers: usize,
+    ) -> Self {
+        Self {
+            d: Arc::new(Mutex::new(DmaHeapProviderInternal {
+                allocator,
+                layout,
+                format,
+                num_buffers,
+                num_allocated: 0,
+                generation: 0,
+                buffers: VecDeque::new(),
+                waker: None,
+            })),
+        }
+    }
+
+    /// Allocate up to `num_buffers` frames of `format` from now on. Frames of
+    /// the previous format are freed.
+    pub fn set_format(&self, format: Format, num_buffers: usize) {
+        let mut d = self.d.lock().unwrap();
+        d.format = format;
+        d.num_buffers = num_buffers;
+        d.num_allocated = 0;
+        d.generation += 1;
+        d.buffers.clear();
+        // We can allocate new frames right away.
+        if let Some(waker) = d.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.d.lock().unwrap().format.clone()
+    }
+}
+
+#[cfg(feature = "dma-heap")]
+impl HandlesProvider for DmaHeapProvider {
+    type HandleType = DmaHeapHandles;
+
+    fn get_handles(&self, waker: &Arc<Waker>) -> Option<DmaHeapHandles> {
+        let mut d = self.d.lock().unwrap();
+        let handles = match d.buffers.pop_front() {
+            Some(handles) => handles,
+            None if d.num_allocated < d.num_buffers => match d.allocate_frame() {
+                Ok(handles) => {
+                    d.num_allocated += 1;
+                    handles
+                }
+                // Try again once a frame is returned or the format changes.
+                Err(e) => {
+                    error!("Failed to allocate frame: {}", e);
+                    d.waker = Some(Arc::clone(waker));
+                    return None;
+                }
+            },
+            None => {
+                d.waker = Some(Arc::clone(waker));
+                return None;
+            }
+        };
+
+        Some(DmaHeapHandles {
+            handles: Some(handles),
+            generation: d.generation,
+            provider: Arc::downgrade(&self.d),
+        })
+    }
+
+    fn queue_format_changed(&self, format: &Format, num_buffers: usize) {
+        let changed = {
+            let d = self.d.lock().unwrap();
+            d.format != *format || d.num_buffers != num_buffers
+        };
+        if changed {
+            self.set_format(format.clone(), num_buffers);
+        }
+    }
+}
+
+/// A set of DMA-BUF handles provided by `DmaHeapProvider`. The handles return
+/// to the provider when this instance is dropped, unless the format of the
+/// provider has changed in the meantime.
+#[cfg(feature = "dma-heap")]
+pub struct DmaHeapHandles {
+    // Use of Option is necessary here because of Drop implementation, but the
+    // Option will always be Some()
+    handles: Option<Vec<DmaBufHandle<File>>>,
+    generation: u64,
+    provider: Weak<Mutex<DmaHeapProviderInternal>>,
+}
+
+#[cfg(feature = "dma-heap")]
+impl DmaHeapHandles {
+    pub fn handles(&self) -> &Vec<DmaBufHandle<File>> {
+        self.handles.as_ref().unwrap()
+    }
+}
+
+#[cfg(feature = "dma-heap")]
+impl Debug for DmaHeapHandles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.handles.fmt(f)
+    }
+}
+
+#[cfg(feature = "dma-heap")]
+impl Drop for DmaHeapHandles {
+    /// Return the handles to the provider if it still exists and the format
+    /// has not changed, otherwise the handles themselves are destroyed.
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.upgrade() {
+            let mut provider = provider.lock().unwrap();
+            if provider.generation == self.generation {
+                provider.buffers.push_back(self.handles.take().unwrap());
+                if let Some(waker) = provider.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "dma-heap")]
+impl BufferHandles for DmaHeapHandles {
+    type SupportedMemoryType = MemoryType;
+
+    fn len(&self) -> usize {
+        self.handles().len()
+    }
+
+    fn fill_v4l2_plane(&self, index: usize, plane: &mut bindings::v4l2_plane) {
+        self.handles().fill_v4l2_plane(index, plane);
+    }
+}
+
+#[cfg(feature = "dma-heap")]
+impl PrimitiveBufferHandles for DmaHeapHandles {
+    type HandleType = DmaBufHandle<File>;
+    const MEMORY_TYPE: Self::SupportedMemoryType = MemoryType::DmaBuf;
+}
+
+#[cfg(all(test, feature = "dma-heap"))]
+mod tests {
+    use super::*;
+    use crate::{memory::DmaBufSource, PixelFormat, PlaneLayout};
+
+    fn test_format(sizes: &[u32]) -> Format {
+        Format {
+            width: 64,
+            height: 64,
+            pixelformat: PixelFormat::from(b"NM12"),
+            plane_fmt: sizes
+                .iter()
+                .map(|&sizeimage| PlaneLayout {
+                    sizeimage,
+                    bytesperline: 64,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_dma_heap_provider() {
+        let waker = Arc::new(Waker::new().unwrap());
+        let provider = DmaHeapProvider::new(
+            DmaBufAllocator::Memfd,
+            DmaBufLayout::PerPlane,
+            test_format(&[4096, 2048]),
+            2,
+        );
+
+        let frame = provider.get_handles(&waker).unwrap();
+        let planes = frame
+            .handles()
+            .iter()
+            .map(|h| (h.dmabuf.len(), h.offset, h.length))
+            .collect::<Vec<_>>();
+        assert_eq!(planes, vec![(4096, 0, 4096), (2048, 0, 2048)]);
+        let _frame2 = provider.get_handles(&waker).unwrap();
+        // We cannot allocate more than 2 frames...
+        assert!(provider.get_handles(&waker).is_none());
+        // ... but dropped frames are reused.
+        drop(frame);
+        assert!(provider.get_handles(&waker).is_some());
+
+        // Frames of the new format reported by the queue use a single
+        // allocation.
+        provider.d.lock().unwrap().layout = DmaBufLayout::Single;
+        provider.queue_format_changed(&test_format(&[8192, 4096]), 1);
+        let frame = provider.get_handles(&waker).unwrap();
+        let planes = frame
+            .handles()
+            .iter()
+            .map(|h| (h.dmabuf.len(), h.offset, h.length))
+            .collect::<Vec<_>>();
+        assert_eq!(planes, vec![(12288, 0, 8192), (12288, 8192, 4096)]);
+        assert!(provider.get_handles(&waker).is_none());
+        // Reporting the same format again keeps the current frames.
+        provider.queue_format_changed(&test_format(&[8192, 4096]), 1);
+
+        // Frames of the previous format are not reused.
+        drop(_frame2);
+        assert!(provider.get_handles(&waker).is_none());
+        drop(frame);
+        assert!(provider.get_handles(&waker).is_some());
+    }
+}
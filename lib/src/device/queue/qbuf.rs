@@ -5,6 +5,7 @@ use crate::ioctl;
 use crate::memory::*;
 use std::{
     fmt::{self, Debug},
+    os::unix::io::AsRawFd,
     sync::Arc,
 };
 
@@ -65,6 +66,7 @@ pub struct QBuffer<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles
     index: usize,
     num_planes: usize,
     timestamp: TimeVal,
+    request: Option<&'a ioctl::Request>,
     fuse: BufferStateFuse<Q>,
     _p: std::marker::PhantomData<P>,
 }
@@ -82,6 +84,7 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
             index: buffer.index,
             num_planes: buffer.planes.len(),
             timestamp: TimeVal::zero(),
+            request: None,
             fuse,
             _p: std::marker::PhantomData,
         }
@@ -103,6 +106,35 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
         self
     }
 
+    /// Queue the buffer as part of `request`. The buffer will only be processed
+    /// once the request is queued, along with the controls set into it.
+    pub fn set_request(mut self, request: &'a ioctl::Request) -> Self {
+        self.request = Some(request);
+        self
+    }
+
+    /// Check that the memory of `planes` satisfies the USERPTR alignment
+    /// required by the queue, if any.
+    fn check_userptr_alignment(&self, planes: &[ioctl::QBufPlane]) -> Result<(), ioctl::QBufError> {
+        let align = match (
+            self.queue.state.userptr_alignment,
+            self.queue.state.memory_type.into(),
+        ) {
+            (Some(align), MemoryType::UserPtr) => align,
+            _ => return Ok(()),
+        };
+
+        for plane in planes {
+            // Safe because the planes of USERPTR buffers are filled with their address.
+            let addr = unsafe { plane.0.m.userptr } as usize;
+            if addr & (align - 1) != 0 {
+                return Err(ioctl::QBufError::UserPtrMisaligned { addr, align });
+            }
+        }
+
+        Ok(())
+    }
+
     // R is meant to mean "either P or Q".
     // Caller is responsible for making sure that the number of planes and
     // plane_handles is the same as the number of expected planes for this
@@ -115,6 +147,7 @@ impl<'a, D: Direction, P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> QB
         let qbuffer = ioctl::QBuffer::<P::HandleType> {
             planes,
             timestamp: self.timestamp,
+            request: self.request.map(|request| request.as_raw_fd()),
             ..Default::default()
         };
 
@@ -155,10 +188,20 @@ where
     P::HandleType: Mappable,
     Q: BufferHandles + From<P>,
 {
-    pub fn get_plane_mapping(&self, plane: usize) -> Option<ioctl::PlaneMapping> {
+    /// Map plane `plane` of the buffer for writing the data to be queued.
+    ///
+    /// The plane is only mapped the first time it is requested, and the mapping is then reused
+    /// for as long as the buffer exists. The returned mapping borrows this object, and thus must
+    /// be dropped before the buffer can be queued.
+    pub fn get_plane_mapping(&mut self, plane: usize) -> Option<ioctl::PlaneWriteMapping<'_>> {
         let buffer_info = self.queue.state.buffer_info.get(self.index)?;
-        let plane_info = buffer_info.features.planes.get(plane)?;
-        P::HandleType::map(self.queue.inner.device.as_ref(), plane_info)
+        let region =
+            buffer_info.get_mapping::<P::HandleType, _>(self.queue.inner.device.as_ref(), plane)?;
+        let len = region.len();
+
+        // Safe because the buffer is not queued and we are borrowed mutably, so nothing else can
+        // access its memory for as long as the mapping lives.
+        Some(unsafe { ioctl::PlaneWriteMapping::new(region, 0, len) })
     }
 }
 
@@ -176,7 +219,9 @@ pub trait OutputQueueable<Q: BufferHandles> {
     /// Queue the buffer after binding `handles`, consuming the object.
     /// The number of handles must match the buffer's expected number of planes.
     /// `bytes_used` must be a slice with as many slices as there are handles,
-    /// describing the amount of useful data in each of them.
+    /// describing the amount of useful data in each of them. The data of a
+    /// plane starts at the data offset set by its handle, if any, which is not
+    /// included in `bytes_used`.
     fn queue_with_handles(self, handles: Q, bytes_used: &[usize]) -> QueueResult<(), Q>;
 }
 
@@ -219,6 +264,22 @@ impl<P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> CaptureQueueable<Q>
             handles.fill_v4l2_plane(index, &mut plane.0);
         }
 
+        // V4L2 zeroes the data offset of CAPTURE planes, so planes sharing a
+        // DMA-BUF at different offsets would all be written at its start.
+        if planes.iter().any(|plane| plane.0.data_offset != 0) {
+            return Err(QueueError {
+                error: ioctl::QBufError::CaptureDataOffsetNotSupported,
+                plane_handles: handles,
+            });
+        }
+
+        if let Err(error) = self.check_userptr_alignment(&planes) {
+            return Err(QueueError {
+                error,
+                plane_handles: handles,
+            });
+        }
+
         self.queue_bound_planes(planes, handles)
     }
 }
@@ -259,6 +320,15 @@ impl<P: PrimitiveBufferHandles, Q: BufferHandles + From<P>> OutputQueueable<Q>
         for (index, plane) in planes.iter_mut().enumerate() {
             // TODO take the QBufPlane as argument if possible?
             handles.fill_v4l2_plane(index, &mut plane.0);
+            // V4L2 expects the data offset to be included in the number of bytes used.
+            plane.0.bytesused += plane.0.data_offset;
+        }
+
+        if let Err(error) = self.check_userptr_alignment(&planes) {
+            return Err(QueueError {
+                error,
+                plane_handles: handles,
+            });
         }
 
         self.queue_bound_planes(planes, handles)
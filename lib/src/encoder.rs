@@ -20,22 +20,53 @@ use crate::{
     },
     ioctl::{self, DqBufError, EncoderCommand, FormatFlags, GFmtError},
     memory::{BufferHandles, PrimitiveBufferHandles},
-    Format,
+    Format, QueueType,
 };
 
-use log::warn;
+use log::{debug, error, trace, warn};
 use std::{
     any::Any,
     io,
+    os::unix::io::AsRawFd,
     path::Path,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{atomic::AtomicUsize, mpsc, Arc},
     task::Wake,
     thread::JoinHandle,
 };
 use thiserror::Error;
 
+pub mod format;
+pub mod packet;
+pub mod params;
+
+use packet::EncodedPacket;
+use params::{DynamicParams, EncoderParams, EncoderParamsError};
+
 /// Trait implemented by all states of the encoder.
-pub trait EncoderState {}
+pub trait EncoderState {
+    /// Returns the type of the OUTPUT queue, i.e. whether it uses the multi-planar API.
+    fn output_queue_type(&self) -> QueueType;
+}
+
+/// Trait implemented by the states in which the encoding parameters can be set, i.e. once the
+/// CAPTURE format is known and until encoding is started.
+pub trait ConfigurableEncoderState: EncoderState {
+    /// Returns the type of the CAPTURE queue, i.e. whether it uses the multi-planar API.
+    fn capture_queue_type(&self) -> QueueType;
+}
+
+impl<S: ConfigurableEncoderState> Encoder<S> {
+    /// Validate `params` against the controls supported by the encoder and apply them.
+    ///
+    /// Nothing is applied if any of the parameters is not supported.
+    pub fn set_params(&self, params: &EncoderParams) -> Result<(), EncoderParamsError> {
+        let format: Format = ioctl::g_fmt(&*self.device, self.state.capture_queue_type())?;
+        let codec = params::Codec::from_pixelformat(format.pixelformat);
+        let controls = params.to_controls(codec)?;
+
+        params::apply_controls(&self.device, &controls, ioctl::CtrlWhich::Current)
+    }
+}
 
 pub struct Encoder<S: EncoderState> {
     // Make sure to keep the device alive as long as we are.
@@ -43,11 +74,75 @@ pub struct Encoder<S: EncoderState> {
     state: S,
 }
 
+/// Events emitted by the encoder through the callback passed to `start`.
+pub enum EncoderEvent<P: HandlesProvider> {
+    /// Emitted when an encoded buffer is available.
+    ///
+    /// The parameter contains the dequeued CAPTURE buffer with the encoded data, along with its
+    /// metadata. The buffer is returned to the encoder once dropped.
+    FrameEncoded(EncodedPacket<P>),
+    /// Emitted when a previously requested `drain` completes, after the last encoded buffer of
+    /// the stream has been emitted.
+    EndOfStream,
+    /// Emitted when the encoder encountered an unrecoverable error. No further event will be
+    /// emitted, and the encoder should be stopped.
+    ///
+    /// After this event, drain and resume requests fail immediately.
+    Died(EncoderError),
+}
+
+/// Unrecoverable errors reported by an `EncoderEvent::Died` event.
+#[derive(Debug, Error)]
+pub enum EncoderError {
+    #[error("Device error: {0}")]
+    DeviceError(anyhow::Error),
+}
+
+pub trait EncoderEventCallback<P: HandlesProvider>:
+    FnMut(EncoderEvent<P>) + Send + 'static
+{
+}
+impl<P, F> EncoderEventCallback<P> for F
+where
+    P: HandlesProvider,
+    F: FnMut(EncoderEvent<P>) + Send + 'static,
+{
+}
+
+impl<S: EncoderState> Encoder<S> {
+    /// Set the frame rate of the stream to encode to `numerator / denominator` frames per
+    /// second. This can be done at any time, including while encoding.
+    ///
+    /// Returns the frame rate actually applied by the driver, as a `(numerator, denominator)`
+    /// tuple.
+    pub fn set_frame_rate(
+        &self,
+        numerator: u32,
+        denominator: u32,
+    ) -> Result<(u32, u32), ioctl::ParmError> {
+        // The frame rate is set as the time per frame of the OUTPUT queue, i.e. its inverse.
+        let time_per_frame = ioctl::s_parm(
+            &*self.device,
+            self.state.output_queue_type(),
+            ioctl::TimePerFrame {
+                numerator: denominator,
+                denominator: numerator,
+            },
+        )?;
+
+        Ok((time_per_frame.denominator, time_per_frame.numerator))
+    }
+}
+
 pub struct AwaitingCaptureFormat {
     output_queue: Queue<Output, QueueInit>,
     capture_queue: Queue<Capture, QueueInit>,
 }
-impl EncoderState for AwaitingCaptureFormat {}
+impl EncoderState for AwaitingCaptureFormat {
+    fn output_queue_type(&self) -> QueueType {
+        self.output_queue.get_type()
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum EncoderOpenError {
@@ -111,7 +206,16 @@ pub struct AwaitingOutputFormat {
     output_queue: Queue<Output, QueueInit>,
     capture_queue: Queue<Capture, QueueInit>,
 }
-impl EncoderState for AwaitingOutputFormat {}
+impl EncoderState for AwaitingOutputFormat {
+    fn output_queue_type(&self) -> QueueType {
+        self.output_queue.get_type()
+    }
+}
+impl ConfigurableEncoderState for AwaitingOutputFormat {
+    fn capture_queue_type(&self) -> QueueType {
+        self.capture_queue.get_type()
+    }
+}
 
 impl Encoder<AwaitingOutputFormat> {
     pub fn set_output_format<F>(mut self, f: F) -> anyhow::Result<Encoder<AwaitingOutputBuffers>>
@@ -135,7 +239,16 @@ pub struct AwaitingOutputBuffers {
     output_queue: Queue<Output, QueueInit>,
     capture_queue: Queue<Capture, QueueInit>,
 }
-impl EncoderState for AwaitingOutputBuffers {}
+impl EncoderState for AwaitingOutputBuffers {
+    fn output_queue_type(&self) -> QueueType {
+        self.output_queue.get_type()
+    }
+}
+impl ConfigurableEncoderState for AwaitingOutputBuffers {
+    fn capture_queue_type(&self) -> QueueType {
+        self.capture_queue.get_type()
+    }
+}
 
 impl Encoder<AwaitingOutputBuffers> {
     pub fn allocate_output_buffers_generic<OP: BufferHandles>(
@@ -175,7 +288,16 @@ pub struct AwaitingCaptureBuffers<OP: BufferHandles> {
     output_queue: Queue<Output, BuffersAllocated<OP>>,
     capture_queue: Queue<Capture, QueueInit>,
 }
-impl<OP: BufferHandles> EncoderState for AwaitingCaptureBuffers<OP> {}
+impl<OP: BufferHandles> EncoderState for AwaitingCaptureBuffers<OP> {
+    fn output_queue_type(&self) -> QueueType {
+        self.output_queue.get_type()
+    }
+}
+impl<OP: BufferHandles> ConfigurableEncoderState for AwaitingCaptureBuffers<OP> {
+    fn capture_queue_type(&self) -> QueueType {
+        self.capture_queue.get_type()
+    }
+}
 
 impl<OP: BufferHandles> Encoder<AwaitingCaptureBuffers<OP>> {
     pub fn allocate_capture_buffers_generic<P: HandlesProvider>(
@@ -226,7 +348,16 @@ pub struct ReadyToEncode<OP: BufferHandles, P: HandlesProvider> {
     capture_memory_provider: P,
     poll_wakeups_counter: Option<Arc<AtomicUsize>>,
 }
-impl<OP: BufferHandles, P: HandlesProvider> EncoderState for ReadyToEncode<OP, P> {}
+impl<OP: BufferHandles, P: HandlesProvider> EncoderState for ReadyToEncode<OP, P> {
+    fn output_queue_type(&self) -> QueueType {
+        self.output_queue.get_type()
+    }
+}
+impl<OP: BufferHandles, P: HandlesProvider> ConfigurableEncoderState for ReadyToEncode<OP, P> {
+    fn capture_queue_type(&self) -> QueueType {
+        self.capture_queue.get_type()
+    }
+}
 
 impl<OP: BufferHandles, P: HandlesProvider> Encoder<ReadyToEncode<OP, P>>
 where
@@ -238,14 +369,14 @@ where
         self
     }
 
-    pub fn start<InputDoneCb, OutputReadyCb>(
+    pub fn start<InputDoneCb, EncoderEventCb>(
         self,
         input_done_cb: InputDoneCb,
-        output_ready_cb: OutputReadyCb,
-    ) -> io::Result<Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>>
+        event_cb: EncoderEventCb,
+    ) -> io::Result<Encoder<Encoding<OP, P, InputDoneCb, EncoderEventCb>>>
     where
         InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-        OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send + 'static,
+        EncoderEventCb: EncoderEventCallback<P>,
     {
         self.state.output_queue.stream_on().unwrap();
         self.state.capture_queue.stream_on().unwrap();
@@ -253,12 +384,27 @@ where
         let mut output_poller = Poller::new(Arc::clone(&self.device))?;
         output_poller.enable_event(DeviceEvent::OutputReady)?;
 
+        // Codec produced by the encoder, used to interpret the encoded packets.
+        let codec = self
+            .state
+            .capture_queue
+            .get_format()
+            .ok()
+            .and_then(|format: Format| params::Codec::from_pixelformat(format.pixelformat));
+
+        let (command_sender, command_receiver) = mpsc::channel::<EncoderThreadCommand>();
+        let (response_sender, response_receiver) = mpsc::channel::<EncoderThreadResponse>();
+
         let mut encoder_thread = EncoderThread::new(
             &self.device,
             self.state.capture_queue,
             self.state.capture_memory_provider,
-            output_ready_cb,
+            event_cb,
+            codec,
+            command_receiver,
+            response_sender,
         )?;
+        let command_waker = Arc::clone(&encoder_thread.command_waker);
 
         if let Some(counter) = &self.state.poll_wakeups_counter {
             output_poller.set_poll_counter(Arc::clone(counter));
@@ -275,31 +421,55 @@ where
                 output_queue: self.state.output_queue,
                 input_done_cb,
                 output_poller,
+                command_waker,
+                command_sender,
+                response_receiver,
                 handle,
             },
         })
     }
 }
 
-pub struct Encoding<OP: BufferHandles, P, InputDoneCb, OutputReadyCb>
+pub struct Encoding<OP: BufferHandles, P, InputDoneCb, EncoderEventCb>
 where
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    EncoderEventCb: EncoderEventCallback<P>,
 {
     output_queue: Queue<Output, BuffersAllocated<OP>>,
     input_done_cb: InputDoneCb,
     output_poller: Poller,
 
-    handle: JoinHandle<EncoderThread<P, OutputReadyCb>>,
+    command_waker: Arc<Waker>,
+    command_sender: mpsc::Sender<EncoderThreadCommand>,
+    response_receiver: mpsc::Receiver<EncoderThreadResponse>,
+
+    handle: JoinHandle<EncoderThread<P, EncoderEventCb>>,
 }
-impl<OP, P, InputDoneCb, OutputReadyCb> EncoderState for Encoding<OP, P, InputDoneCb, OutputReadyCb>
+
+#[derive(Debug)]
+enum EncoderThreadCommand {
+    Drain(bool),
+    Resume,
+    Stop,
+}
+
+#[derive(Debug)]
+enum EncoderThreadResponse {
+    DrainDone(Result<bool, DrainError>),
+    ResumeDone(Result<(), ResumeError>),
+}
+impl<OP, P, InputDoneCb, EncoderEventCb> EncoderState
+    for Encoding<OP, P, InputDoneCb, EncoderEventCb>
 where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    EncoderEventCb: EncoderEventCallback<P>,
 {
+    fn output_queue_type(&self) -> QueueType {
+        self.output_queue.get_type()
+    }
 }
 
 // Safe because all Rcs are internal and never leaked outside of the struct.
@@ -323,10 +493,42 @@ pub enum GetBufferError<OP: BufferHandles> {
     GetFreeBufferError(#[from] GetFreeBufferError),
 }
 
+#[derive(Debug, Error)]
+pub enum SendCommandError {
+    #[error("Error while sending the command to the encoder thread")]
+    SendError,
+    #[error("Error while waiting for the encoder thread to respond")]
+    RecvError(#[from] mpsc::RecvError),
+    #[error("Unexpected response from the encoder thread")]
+    UnexpectedResponse,
+}
+
+#[derive(Debug, Error)]
+pub enum DrainError {
+    #[error("Error while sending STOP command")]
+    EncoderCmdError(#[from] ioctl::EncoderCmdError),
+    #[error("Encoder has died")]
+    EncoderDied,
+    #[error("Error while communicating with the encoder thread")]
+    SendCommandError(#[from] SendCommandError),
+}
+
+#[derive(Debug, Error)]
+pub enum ResumeError {
+    #[error("Error while sending START command")]
+    EncoderCmdError(#[from] ioctl::EncoderCmdError),
+    #[error("Encoder has died")]
+    EncoderDied,
+    #[error("Error while communicating with the encoder thread")]
+    SendCommandError(#[from] SendCommandError),
+}
+
 #[derive(Debug, Error)]
 pub enum EncoderStopError {
     #[error("Error while sending STOP command")]
     EncoderCmdError(#[from] ioctl::EncoderCmdError),
+    #[error("Error while communicating with the encoder thread")]
+    SendCommandError(#[from] SendCommandError),
     #[error("Thread has panicked")]
     ThreadPanickedError(Box<dyn Any + Send + 'static>),
     #[error("Cannot streamoff capture queue")]
@@ -335,23 +537,95 @@ pub enum EncoderStopError {
     OutputQueueStreamoffError(ioctl::StreamOffError),
 }
 
-impl<OP, P, InputDoneCb, OutputReadyCb> Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
+impl<OP, P, InputDoneCb, EncoderEventCb> Encoder<Encoding<OP, P, InputDoneCb, EncoderEventCb>>
 where
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    EncoderEventCb: EncoderEventCallback<P>,
 {
+    fn send_command(&self, command: EncoderThreadCommand) -> Result<(), SendCommandError> {
+        trace!("Sending command: {:?}", command);
+
+        self.state
+            .command_sender
+            .send(command)
+            .map_err(|_| SendCommandError::SendError)?;
+        self.state.command_waker.wake_by_ref();
+
+        Ok(())
+    }
+
+    /// Drain the encoder, i.e. make sure all the frames queued so far are encoded and emitted.
+    ///
+    /// Completion of the drain is signaled by an `EncoderEvent::EndOfStream` event, emitted
+    /// after the last encoded buffer. If `blocking` is `true`, this method only returns once
+    /// this event has been emitted, and always returns `true`. Otherwise it may return `false`
+    /// to signal that the drain is still in progress.
+    ///
+    /// Once drained the encoder does not process new frames until `resume` is called.
+    ///
+    /// Note that requesting a blocking drain can be hazardous if the current thread is
+    /// responsible for e.g. releasing the encoded buffers, as the encoder may be starved of
+    /// CAPTURE buffers.
+    pub fn drain(&self, blocking: bool) -> Result<bool, DrainError> {
+        debug!("Drain requested");
+        self.send_command(EncoderThreadCommand::Drain(blocking))?;
+
+        match self
+            .state
+            .response_receiver
+            .recv()
+            .map_err(SendCommandError::from)?
+        {
+            EncoderThreadResponse::DrainDone(response) => response,
+            r => {
+                error!(
+                    "Unexpected encoder thread response received while draining: {:?}",
+                    r
+                );
+                Err(SendCommandError::UnexpectedResponse.into())
+            }
+        }
+    }
+
+    /// Resume encoding after a drain has completed.
+    pub fn resume(&self) -> Result<(), ResumeError> {
+        debug!("Resume requested");
+        self.send_command(EncoderThreadCommand::Resume)?;
+
+        match self
+            .state
+            .response_receiver
+            .recv()
+            .map_err(SendCommandError::from)?
+        {
+            EncoderThreadResponse::ResumeDone(response) => response,
+            r => {
+                error!(
+                    "Unexpected encoder thread response received while resuming: {:?}",
+                    r
+                );
+                Err(SendCommandError::UnexpectedResponse.into())
+            }
+        }
+    }
+
     /// Stop the encoder, and returns the encoder ready to be started again.
+    ///
+    /// This will stop any pending operation. The frames that were still queued are returned as
+    /// `CompletedOutputBuffer::Canceled` through the input done callback.
+    ///
+    /// To make sure all the frames queued so far are encoded, call the [`Encoder::drain`]
+    /// method and wait for the `EncoderEvent::EndOfStream` event before calling this method.
     pub fn stop(self) -> Result<Encoder<ReadyToEncode<OP, P>>, EncoderStopError> {
-        ioctl::encoder_cmd(&*self.device, EncoderCommand::Stop(false))?;
+        self.send_command(EncoderThreadCommand::Stop)?;
 
-        // The encoder thread should receive the LAST buffer and exit on its own.
         let encoding_thread = self
             .state
             .handle
             .join()
-            .map_err(|e| EncoderStopError::ThreadPanickedError(e))?;
+            .map_err(EncoderStopError::ThreadPanickedError)?;
 
         encoding_thread
             .capture_queue
@@ -378,6 +652,45 @@ where
         })
     }
 
+    /// Returns whether the OUTPUT queue supports requests, i.e. whether parameters can be tied
+    /// to a specific frame with `set_dynamic_params`.
+    pub fn supports_requests(&self) -> bool {
+        self.state
+            .output_queue
+            .get_capabilities()
+            .contains(ioctl::BufferCapabilities::SUPPORTS_REQUESTS)
+    }
+
+    /// Change encoding parameters while encoding.
+    ///
+    /// If `request` is `None`, the parameters are applied right away and take effect from the
+    /// next OUTPUT buffer to be queued. Otherwise they are stored into `request`, and only
+    /// applied to the OUTPUT buffer queued with it (see `QBuffer::set_request`) when the request
+    /// itself is queued.
+    pub fn set_dynamic_params(
+        &self,
+        params: &DynamicParams,
+        request: Option<&ioctl::Request>,
+    ) -> Result<(), EncoderParamsError> {
+        let which = match request {
+            Some(request) => ioctl::CtrlWhich::Request(request.as_raw_fd()),
+            None => ioctl::CtrlWhich::Current,
+        };
+
+        params::apply_controls(&self.device, &params.to_controls(), which)
+    }
+
+    /// Encode the next queued OUTPUT buffer as a key frame.
+    pub fn force_key_frame(&self) -> Result<(), EncoderParamsError> {
+        self.set_dynamic_params(
+            &DynamicParams {
+                force_key_frame: true,
+                ..Default::default()
+            },
+            None,
+        )
+    }
+
     /// Attempts to dequeue and release output buffers that the driver is done with.
     fn dequeue_output_buffers(&self) -> Result<(), DequeueOutputBufferError<OP>> {
         let output_queue = &self.state.output_queue;
@@ -397,6 +710,18 @@ where
         Ok(())
     }
 
+    /// Dequeue the OUTPUT buffers the driver is done with and pass them to the input done
+    /// callback.
+    ///
+    /// Completed OUTPUT buffers are otherwise only dequeued when obtaining a new buffer, so a
+    /// client that has queued all its frames can call this method after receiving an encoded
+    /// packet to recycle them without having to queue a new frame.
+    // Returns the same error as the other OUTPUT dequeue paths, which is kept unboxed.
+    #[allow(clippy::result_large_err)]
+    pub fn kick(&self) -> Result<(), DequeueOutputBufferError<OP>> {
+        self.dequeue_output_buffers()
+    }
+
     // Make this thread sleep until at least one OUTPUT buffer is ready to be
     // obtained through `try_get_buffer()`, dequeuing buffers if necessary.
     fn wait_for_output_buffer(&mut self) -> Result<(), GetBufferError<OP>> {
@@ -413,28 +738,28 @@ where
     }
 }
 
-impl<'a, OP, P, InputDoneCb, OutputReadyCb> OutputQueueableProvider<'a, OP>
-    for Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
+impl<'a, OP, P, InputDoneCb, EncoderEventCb> OutputQueueableProvider<'a, OP>
+    for Encoder<Encoding<OP, P, InputDoneCb, EncoderEventCb>>
 where
     Queue<Output, BuffersAllocated<OP>>: OutputQueueableProvider<'a, OP>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    EncoderEventCb: EncoderEventCallback<P>,
 {
     type Queueable =
         <Queue<Output, BuffersAllocated<OP>> as OutputQueueableProvider<'a, OP>>::Queueable;
 }
 
 /// Let the encoder provide the buffers from the OUTPUT queue.
-impl<'a, OP, P, InputDoneCb, OutputReadyCb> GetFreeOutputBuffer<'a, OP, GetBufferError<OP>>
-    for Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
+impl<'a, OP, P, InputDoneCb, EncoderEventCb> GetFreeOutputBuffer<'a, OP, GetBufferError<OP>>
+    for Encoder<Encoding<OP, P, InputDoneCb, EncoderEventCb>>
 where
     Queue<Output, BuffersAllocated<OP>>: GetFreeOutputBuffer<'a, OP>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    EncoderEventCb: EncoderEventCallback<P>,
 {
     /// Returns a V4L2 buffer to be filled with a frame to encode if one
     /// is available.
@@ -449,13 +774,13 @@ where
 
 // If `GetFreeBuffer` is implemented, we can also provide a blocking `get_buffer`
 // method.
-impl<'a, OP, P, InputDoneCb, OutputReadyCb> Encoder<Encoding<OP, P, InputDoneCb, OutputReadyCb>>
+impl<'a, OP, P, InputDoneCb, EncoderEventCb> Encoder<Encoding<OP, P, InputDoneCb, EncoderEventCb>>
 where
     Self: GetFreeOutputBuffer<'a, OP, GetBufferError<OP>>,
     OP: BufferHandles,
     P: HandlesProvider,
     InputDoneCb: Fn(CompletedOutputBuffer<OP>),
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    EncoderEventCb: EncoderEventCallback<P>,
 {
     /// Returns a V4L2 buffer to be filled with a frame to encode, waiting for
     /// one to be available if needed.
@@ -476,22 +801,36 @@ where
     }
 }
 
-struct EncoderThread<P, OutputReadyCb>
+struct EncoderThread<P, EncoderEventCb>
 where
     P: HandlesProvider,
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    EncoderEventCb: EncoderEventCallback<P>,
 {
     capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
     capture_memory_provider: P,
+    device: Arc<Device>,
     poller: Poller,
     waker: Arc<Waker>,
-    output_ready_cb: OutputReadyCb,
+    event_cb: EncoderEventCb,
+    codec: Option<params::Codec>,
+    // Waker signaled when the main thread has commands pending for us.
+    command_waker: Arc<Waker>,
+    command_receiver: mpsc::Receiver<EncoderThreadCommand>,
+    response_sender: mpsc::Sender<EncoderThreadResponse>,
+    // Whether the encoder has been drained and is waiting for the START command.
+    stopped: bool,
+    blocking_drain_in_progress: bool,
+    // Whether we encountered an unrecoverable error and are waiting to be stopped.
+    died: bool,
 }
 
-impl<P, OutputReadyCb> EncoderThread<P, OutputReadyCb>
+const CAPTURE_READY: u32 = 1;
+const COMMAND_WAITING: u32 = 2;
+
+impl<P, EncoderEventCb> EncoderThread<P, EncoderEventCb>
 where
     P: HandlesProvider,
-    OutputReadyCb: FnMut(DqBuffer<Capture, P::HandleType>) + Send,
+    EncoderEventCb: EncoderEventCallback<P>,
     for<'a> Queue<Capture, BuffersAllocated<P::HandleType>>:
         GetFreeCaptureBuffer<'a, P::HandleType> + GetCaptureBufferByIndex<'a, P::HandleType>,
 {
@@ -499,87 +838,200 @@ where
         device: &Arc<Device>,
         capture_queue: Queue<Capture, BuffersAllocated<P::HandleType>>,
         capture_memory_provider: P,
-        output_ready_cb: OutputReadyCb,
+        event_cb: EncoderEventCb,
+        codec: Option<params::Codec>,
+        command_receiver: mpsc::Receiver<EncoderThreadCommand>,
+        response_sender: mpsc::Sender<EncoderThreadResponse>,
     ) -> io::Result<Self> {
         let mut poller = Poller::new(Arc::clone(device))?;
 
         poller.enable_event(DeviceEvent::CaptureReady)?;
-        let waker = poller.add_waker(0)?;
+        let waker = poller.add_waker(CAPTURE_READY)?;
+        let command_waker = poller.add_waker(COMMAND_WAITING)?;
 
         Ok(EncoderThread {
             capture_queue,
             capture_memory_provider,
+            device: Arc::clone(device),
             poller,
             waker,
-            output_ready_cb,
+            event_cb,
+            codec,
+            command_waker,
+            command_receiver,
+            response_sender,
+            stopped: false,
+            blocking_drain_in_progress: false,
+            died: false,
         })
     }
 
+    fn send_response(&self, response: EncoderThreadResponse) {
+        trace!("Sending response: {:?}", response);
+
+        self.response_sender.send(response).unwrap();
+    }
+
+    fn drain(&mut self, blocking: bool) {
+        trace!("Processing Drain({}) command", blocking);
+
+        // Nothing to do if we are already drained.
+        if self.stopped {
+            self.send_response(EncoderThreadResponse::DrainDone(Ok(true)));
+            return;
+        }
+
+        // A blocking drain may be requested while a non-blocking one is in progress, in which
+        // case the STOP command has already been sent and we just need to wait for the LAST
+        // buffer.
+        let response = match ioctl::encoder_cmd(&*self.device, EncoderCommand::Stop(false)) {
+            Ok(()) | Err(ioctl::EncoderCmdError::DrainInProgress) if blocking => {
+                self.blocking_drain_in_progress = true;
+                None
+            }
+            Ok(()) => Some(Ok(false)),
+            Err(e) => Some(Err(e.into())),
+        };
+
+        if let Some(response) = response {
+            self.send_response(EncoderThreadResponse::DrainDone(response));
+        }
+    }
+
+    fn resume(&mut self) {
+        trace!("Processing Resume command");
+
+        let response =
+            ioctl::encoder_cmd(&*self.device, EncoderCommand::Start).map_err(ResumeError::from);
+        if response.is_ok() {
+            self.stopped = false;
+        }
+
+        self.send_response(EncoderThreadResponse::ResumeDone(response));
+    }
+
+    /// Process all pending commands. Returns `false` if the thread should exit.
+    fn process_commands(&mut self) -> bool {
+        loop {
+            let command = match self.command_receiver.try_recv() {
+                Ok(command) => command,
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(e) => {
+                    error!("Error while reading encoder command: {}", e);
+                    return false;
+                }
+            };
+
+            match command {
+                EncoderThreadCommand::Drain(blocking) => self.drain(blocking),
+                EncoderThreadCommand::Resume => self.resume(),
+                EncoderThreadCommand::Stop => {
+                    trace!("Processing stop command");
+                    return false;
+                }
+            }
+        }
+    }
+
     fn set_poll_counter(&mut self, poll_wakeups_counter: Arc<AtomicUsize>) {
         self.poller.set_poll_counter(poll_wakeups_counter);
     }
 
+    /// Report an unrecoverable error to the client. The thread then only waits to be stopped.
+    fn die(&mut self, error: EncoderError) {
+        error!("Unrecoverable encoder error: {}", error);
+        (self.event_cb)(EncoderEvent::Died(error));
+        self.died = true;
+    }
+
+    /// Answer the commands of the main thread with an error until we are requested to stop.
+    fn wait_for_stop(&mut self) {
+        // The LAST buffer will never come.
+        if self.blocking_drain_in_progress {
+            self.blocking_drain_in_progress = false;
+            self.send_response(EncoderThreadResponse::DrainDone(Err(
+                DrainError::EncoderDied,
+            )));
+        }
+
+        loop {
+            let command = match self.command_receiver.recv() {
+                Ok(command) => command,
+                Err(e) => {
+                    error!("Error while reading encoder command: {}", e);
+                    return;
+                }
+            };
+            match command {
+                EncoderThreadCommand::Drain(_) => self.send_response(
+                    EncoderThreadResponse::DrainDone(Err(DrainError::EncoderDied)),
+                ),
+                EncoderThreadCommand::Resume => self.send_response(
+                    EncoderThreadResponse::ResumeDone(Err(ResumeError::EncoderDied)),
+                ),
+                EncoderThreadCommand::Stop => return,
+            }
+        }
+    }
+
     fn run(mut self) -> Self {
         self.enqueue_capture_buffers();
 
         'polling: loop {
-            match self.capture_queue.num_queued_buffers() {
+            // Do not process anything anymore if we hit an unrecoverable error.
+            if self.died {
+                self.wait_for_stop();
+                break 'polling;
+            }
+
+            let res = match self.capture_queue.num_queued_buffers() {
                 // If there are no buffers on the CAPTURE queue, poll() will return
                 // immediately with EPOLLERR and we would loop indefinitely.
                 // Prevent this by temporarily disabling polling the device in such
-                // cases.
-                0 => {
-                    self.poller
-                        .disable_event(DeviceEvent::CaptureReady)
-                        .unwrap();
-                }
+                // cases. The same happens after the LAST buffer has been dequeued,
+                // until the encoder is resumed.
+                _ if self.stopped => self.poller.disable_event(DeviceEvent::CaptureReady),
+                0 => self.poller.disable_event(DeviceEvent::CaptureReady),
                 // If device polling was disabled and we have buffers queued, we
                 // can reenable it as poll will now wait for a CAPTURE buffer to
                 // be ready for dequeue.
-                _ => {
-                    self.poller.enable_event(DeviceEvent::CaptureReady).unwrap();
-                }
+                _ => self.poller.enable_event(DeviceEvent::CaptureReady),
+            };
+            if let Err(e) = res {
+                self.die(EncoderError::DeviceError(e.into()));
+                continue 'polling;
             }
 
-            // TODO handle errors - this system call can be interrupted and we
-            // should leave in this case.
-            for event in self.poller.poll(None).unwrap() {
+            let events = match self.poller.poll(None) {
+                Ok(events) => events,
+                Err(e) => {
+                    self.die(EncoderError::DeviceError(e.into()));
+                    continue 'polling;
+                }
+            };
+            for event in events {
                 match event {
                     // A CAPTURE buffer has been released by the client.
-                    PollEvent::Waker(0) => {
+                    PollEvent::Waker(CAPTURE_READY) => {
                         // Requeue all available CAPTURE buffers.
                         self.enqueue_capture_buffers();
                     }
-                    // A CAPTURE buffer is ready to be dequeued.
-                    PollEvent::Device(DeviceEvent::CaptureReady) => {
-                        // Get the encoded buffer
-                        // TODO Manage errors here, including corrupted buffers!
-                        if let Ok(mut cap_buf) = self.capture_queue.try_dequeue() {
-                            let is_last = cap_buf.data.is_last();
-                            let is_empty = cap_buf.data.get_first_plane().bytesused() == 0;
-
-                            // Add a drop callback to the dequeued buffer so we
-                            // re-queue it as soon as it is dropped.
-                            let cap_waker = Arc::clone(&self.waker);
-                            cap_buf.add_drop_callback(move |_dqbuf| {
-                                cap_waker.wake();
-                            });
-
-                            // Empty buffers do not need to be passed to the client.
-                            if !is_empty {
-                                (self.output_ready_cb)(cap_buf);
-                            }
-
-                            // Last buffer of the stream? Time for us to terminate.
-                            if is_last {
-                                break 'polling;
-                            }
-                        } else {
-                            // TODO we should not crash here.
-                            panic!("Expected a CAPTURE buffer but none available!");
+                    // The main thread has sent us commands.
+                    PollEvent::Waker(COMMAND_WAITING) => {
+                        if !self.process_commands() {
+                            break 'polling;
                         }
                     }
-                    _ => panic!("Unexpected return from CAPTURE queue poll!"),
+                    // A CAPTURE buffer is ready to be dequeued.
+                    PollEvent::Device(DeviceEvent::CaptureReady) => self.dequeue_capture_buffer(),
+                    event => self.die(EncoderError::DeviceError(anyhow::anyhow!(
+                        "Unexpected event from CAPTURE queue poll: {:?}",
+                        event
+                    ))),
+                }
+
+                if self.died {
+                    continue 'polling;
                 }
             }
         }
@@ -587,15 +1039,69 @@ where
         self
     }
 
+    fn dequeue_capture_buffer(&mut self) {
+        // Buffers with the ERROR flag are still passed to the client, which can check
+        // `EncodedPacket::has_error`.
+        let mut cap_buf = match self.capture_queue.try_dequeue() {
+            Ok(cap_buf) | Err(DqBufError::CorruptedBuffer(cap_buf)) => cap_buf,
+            Err(DqBufError::NotReady) => {
+                warn!("Expected a CAPTURE buffer but none available, possible driver bug");
+                return;
+            }
+            Err(e) => {
+                self.die(EncoderError::DeviceError(anyhow::anyhow!(
+                    "Error while dequeueing CAPTURE buffer: {}",
+                    e
+                )));
+                return;
+            }
+        };
+
+        let is_last = cap_buf.data.is_last();
+        let is_empty = cap_buf.data.get_first_plane().payload_size() == 0;
+
+        // Add a drop callback to the dequeued buffer so we
+        // re-queue it as soon as it is dropped.
+        let cap_waker = Arc::clone(&self.waker);
+        cap_buf.add_drop_callback(move |_dqbuf| {
+            cap_waker.wake();
+        });
+
+        // Empty buffers do not need to be passed to the client.
+        if !is_empty {
+            (self.event_cb)(EncoderEvent::FrameEncoded(EncodedPacket::new(
+                cap_buf, self.codec,
+            )));
+        }
+
+        // Last buffer of the stream? The drain is completed and
+        // the encoder waits for the START command.
+        if is_last {
+            debug!("CAPTURE buffer marked with LAST flag");
+            self.stopped = true;
+            (self.event_cb)(EncoderEvent::EndOfStream);
+            if self.blocking_drain_in_progress {
+                debug!("Signaling end of blocking drain");
+                self.blocking_drain_in_progress = false;
+                self.send_response(EncoderThreadResponse::DrainDone(Ok(true)));
+            }
+        }
+    }
+
     fn enqueue_capture_buffers(&mut self) {
         'enqueue: while let Some(handles) = self.capture_memory_provider.get_handles(&self.waker) {
-            if let Ok(buffer) = self
+            let res = match self
                 .capture_memory_provider
                 .get_suitable_buffer_for(&handles, &self.capture_queue)
             {
-                buffer.queue_with_handles(handles).unwrap();
-            } else {
-                warn!("Handles potentially lost due to no V4L2 buffer being available");
+                Ok(buffer) => buffer.queue_with_handles(handles),
+                Err(_) => {
+                    warn!("Handles potentially lost due to no V4L2 buffer being available");
+                    break 'enqueue;
+                }
+            };
+            if let Err(e) = res {
+                self.die(EncoderError::DeviceError(e.error.into()));
                 break 'enqueue;
             }
         }
@@ -0,0 +1,41 @@
+//! Writers producing playable files from the encoded buffers returned by an
+//! [`Encoder`](super::Encoder).
+use crate::{
+    device::queue::{direction::Capture, dqbuf::DqBuffer},
+    ioctl::PlaneReadMapping,
+    memory::{Mappable, PrimitiveBufferHandles},
+    PixelFormat,
+};
+use nix::sys::time::TimeVal;
+use std::io;
+use thiserror::Error;
+
+pub mod annexb;
+pub mod ivf;
+
+#[derive(Debug, Error)]
+pub enum WriteError {
+    #[error("I/O error while writing stream: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Pixel format {0} is not supported by this writer")]
+    UnsupportedFormat(PixelFormat),
+    #[error("Failed to map encoded buffer")]
+    MappingFailed,
+    #[error("Timestamp {0} cannot be represented in the stream")]
+    InvalidTimestamp(TimeVal),
+}
+
+/// Map the encoded data of `buffer`, i.e. the used part of its first plane.
+fn map_encoded_buffer<P>(buffer: &DqBuffer<Capture, P>) -> Result<PlaneReadMapping<'_>, WriteError>
+where
+    P: PrimitiveBufferHandles,
+    P::HandleType: Mappable,
+{
+    buffer.get_plane_mapping(0).ok_or(WriteError::MappingFailed)
+}
+
+/// Returns the timestamp of `buffer`, which the encoder copies from the OUTPUT buffer the frame
+/// has been encoded from.
+fn buffer_timestamp<P: PrimitiveBufferHandles>(buffer: &DqBuffer<Capture, P>) -> TimeVal {
+    TimeVal::from(buffer.data.timestamp())
+}
@@ -0,0 +1,86 @@
+//! Writing of H.264 and HEVC encoded buffers as Annex B elementary streams.
+use super::{map_encoded_buffer, WriteError};
+use crate::{
+    device::queue::{direction::Capture, dqbuf::DqBuffer},
+    memory::{Mappable, PrimitiveBufferHandles},
+    PixelFormat,
+};
+use log::warn;
+use std::io::Write;
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Writes the buffers produced by a H.264 or HEVC encoder into an Annex B elementary stream.
+///
+/// Annex B streams carry no timestamps: buffers must be written in the order they are produced
+/// by the encoder, which is the decoding order.
+pub struct AnnexBWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> AnnexBWriter<W> {
+    /// Create a new writer for the encoded `pixelformat`, which must be H.264 or HEVC.
+    pub fn new(writer: W, pixelformat: PixelFormat) -> Result<Self, WriteError> {
+        if pixelformat != b"H264".into() && pixelformat != b"HEVC".into() {
+            return Err(WriteError::UnsupportedFormat(pixelformat));
+        }
+
+        Ok(AnnexBWriter { writer })
+    }
+
+    /// Write the encoded `data` to the stream. A start code is added if `data` does not begin
+    /// with one. Empty frames are ignored.
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<(), WriteError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        if !data.starts_with(&START_CODE) && !data.starts_with(&START_CODE[1..]) {
+            warn!("Encoded frame does not start with a start code, adding one");
+            self.writer.write_all(&START_CODE)?;
+        }
+        self.writer.write_all(data)?;
+
+        Ok(())
+    }
+
+    /// Write the content of an encoded buffer dequeued from the encoder.
+    pub fn write_buffer<P>(&mut self, buffer: &DqBuffer<Capture, P>) -> Result<(), WriteError>
+    where
+        P: PrimitiveBufferHandles,
+        P::HandleType: Mappable,
+    {
+        if buffer.data.get_first_plane().payload_size() == 0 {
+            return Ok(());
+        }
+
+        self.write_frame(&map_encoded_buffer(buffer)?)
+    }
+
+    /// Flush the stream and return the underlying writer.
+    pub fn into_inner(mut self) -> Result<W, WriteError> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annexb_writer() {
+        assert!(AnnexBWriter::new(Vec::new(), b"VP90".into()).is_err());
+
+        let mut writer = AnnexBWriter::new(Vec::new(), b"H264".into()).unwrap();
+        writer.write_frame(&[0, 0, 0, 1, 0x65, 0xaa]).unwrap();
+        writer.write_frame(&[]).unwrap();
+        writer.write_frame(&[0, 0, 1, 0x41, 0xbb]).unwrap();
+        writer.write_frame(&[0x41, 0xcc]).unwrap();
+
+        assert_eq!(
+            writer.into_inner().unwrap(),
+            vec![0, 0, 0, 1, 0x65, 0xaa, 0, 0, 1, 0x41, 0xbb, 0, 0, 0, 1, 0x41, 0xcc]
+        );
+    }
+}
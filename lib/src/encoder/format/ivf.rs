@@ -0,0 +1,162 @@
+//! Writing of VP8, VP9 and AV1 encoded buffers into IVF files.
+use super::{buffer_timestamp, map_encoded_buffer, WriteError};
+use crate::{
+    device::queue::{direction::Capture, dqbuf::DqBuffer},
+    memory::{Mappable, PrimitiveBufferHandles},
+    PixelFormat,
+};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::convert::TryFrom;
+use std::io::{Seek, SeekFrom, Write};
+
+const IVF_HEADER_SIZE: u16 = 32;
+/// Offset of the frame count in the IVF header.
+const FRAME_COUNT_OFFSET: u64 = 24;
+
+/// Writes the buffers produced by a VP8, VP9 or AV1 encoder into an IVF file.
+///
+/// Frame timestamps are expressed in units of the time base passed to [`IvfWriter::new`], and
+/// computed from the timestamps of the encoded buffers.
+pub struct IvfWriter<W: Write> {
+    writer: W,
+    /// Time base as (numerator, denominator), i.e. the duration of one unit in seconds.
+    timebase: (u32, u32),
+    frame_count: u32,
+}
+
+impl<W: Write> IvfWriter<W> {
+    /// Create a new writer for the encoded `pixelformat` and write the IVF header.
+    ///
+    /// `timebase` is the duration in seconds of one timestamp unit, as a (numerator,
+    /// denominator) pair, e.g. `(1, 30)` for a 30 fps stream or `(1, 1_000_000)` to keep the
+    /// microsecond precision of buffer timestamps.
+    pub fn new(
+        mut writer: W,
+        pixelformat: PixelFormat,
+        width: u16,
+        height: u16,
+        timebase: (u32, u32),
+    ) -> Result<Self, WriteError> {
+        let fourcc: &[u8; 4] = match &<[u8; 4]>::from(pixelformat) {
+            b"VP80" => b"VP80",
+            b"VP90" => b"VP90",
+            b"AV1F" => b"AV01",
+            _ => return Err(WriteError::UnsupportedFormat(pixelformat)),
+        };
+
+        let mut header = Vec::with_capacity(IVF_HEADER_SIZE as usize);
+        header.extend_from_slice(b"DKIF");
+        // Version.
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&IVF_HEADER_SIZE.to_le_bytes());
+        header.extend_from_slice(fourcc);
+        header.extend_from_slice(&width.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        // The header stores the time base as a rate, i.e. denominator first.
+        header.extend_from_slice(&timebase.1.to_le_bytes());
+        header.extend_from_slice(&timebase.0.to_le_bytes());
+        // Frame count, updated by `finish` if possible.
+        header.extend_from_slice(&0u32.to_le_bytes());
+        // Unused.
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&header)?;
+
+        Ok(IvfWriter {
+            writer,
+            timebase,
+            frame_count: 0,
+        })
+    }
+
+    /// Write an encoded frame with presentation time `timestamp`. Empty frames are ignored.
+    ///
+    /// IVF timestamps are unsigned, so a negative `timestamp` is rejected.
+    pub fn write_frame(&mut self, data: &[u8], timestamp: TimeVal) -> Result<(), WriteError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let (num, den) = self.timebase;
+        let pts = u64::try_from(
+            timestamp.num_microseconds() as i128 * den as i128
+                / (std::cmp::max(num, 1) as i128 * 1_000_000),
+        )
+        .map_err(|_| WriteError::InvalidTimestamp(timestamp))?;
+
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&pts.to_le_bytes())?;
+        self.writer.write_all(data)?;
+        self.frame_count += 1;
+
+        Ok(())
+    }
+
+    /// Write the content of an encoded buffer dequeued from the encoder, using its timestamp.
+    pub fn write_buffer<P>(&mut self, buffer: &DqBuffer<Capture, P>) -> Result<(), WriteError>
+    where
+        P: PrimitiveBufferHandles,
+        P::HandleType: Mappable,
+    {
+        if buffer.data.get_first_plane().payload_size() == 0 {
+            return Ok(());
+        }
+
+        self.write_frame(&map_encoded_buffer(buffer)?, buffer_timestamp(buffer))
+    }
+
+    /// Returns the number of frames written so far.
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+}
+
+impl<W: Write + Seek> IvfWriter<W> {
+    /// Write the final frame count into the IVF header, flush the stream and return the
+    /// underlying writer.
+    pub fn finish(mut self) -> Result<W, WriteError> {
+        let pos = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+        self.writer.write_all(&self.frame_count.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(pos))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_ivf_writer() {
+        assert!(IvfWriter::new(Vec::new(), b"H264".into(), 320, 240, (1, 30)).is_err());
+
+        let mut writer =
+            IvfWriter::new(Cursor::new(Vec::new()), b"VP90".into(), 320, 240, (1, 30)).unwrap();
+        writer
+            .write_frame(&[0xaa, 0xbb], TimeVal::microseconds(0))
+            .unwrap();
+        writer.write_frame(&[], TimeVal::microseconds(1)).unwrap();
+        writer
+            .write_frame(&[0xcc], TimeVal::microseconds(66_667))
+            .unwrap();
+        assert!(writer
+            .write_frame(&[0xdd], TimeVal::microseconds(-66_667))
+            .is_err());
+        let ivf = writer.finish().unwrap().into_inner();
+
+        assert_eq!(&ivf[0..4], b"DKIF");
+        assert_eq!(&ivf[8..12], b"VP90");
+        assert_eq!(&ivf[12..16], &[64, 1, 240, 0]);
+        assert_eq!(&ivf[16..24], &[30, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&ivf[24..28], &[2, 0, 0, 0]);
+        assert_eq!(&ivf[32..36], &[2, 0, 0, 0]);
+        assert_eq!(&ivf[36..44], &0u64.to_le_bytes());
+        assert_eq!(&ivf[44..46], &[0xaa, 0xbb]);
+        assert_eq!(&ivf[46..50], &[1, 0, 0, 0]);
+        assert_eq!(&ivf[50..58], &2u64.to_le_bytes());
+        assert_eq!(&ivf[58..], &[0xcc]);
+    }
+}
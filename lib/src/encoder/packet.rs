@@ -0,0 +1,217 @@
+//! Encoded buffers returned by the [`Encoder`](super::Encoder), along with the metadata needed
+//! to mux or stream them.
+use super::params::Codec;
+use crate::{
+    codec::h264::find_start_code,
+    device::queue::{direction::Capture, dqbuf::DqBuffer, handles_provider::HandlesProvider},
+    ioctl::{BufferFlags, PlaneReadMapping},
+    memory::{Mappable, PrimitiveBufferHandles},
+};
+use nix::sys::time::TimeVal;
+
+/// Type of an encoded frame, as reported by the driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Key,
+    P,
+    B,
+    /// The driver did not report the frame type.
+    Unknown,
+}
+
+/// Location and type of a NAL unit inside an encoded H.264 or HEVC buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NalUnit {
+    /// Offset of the NAL unit header, i.e. right after the start code.
+    pub offset: usize,
+    /// Size of the NAL unit, header included.
+    pub size: usize,
+    pub nal_type: u8,
+    codec: Codec,
+}
+
+impl NalUnit {
+    pub fn is_sps(&self) -> bool {
+        match self.codec {
+            Codec::Hevc => self.nal_type == 33,
+            _ => self.nal_type == 7,
+        }
+    }
+
+    pub fn is_pps(&self) -> bool {
+        match self.codec {
+            Codec::Hevc => self.nal_type == 34,
+            _ => self.nal_type == 8,
+        }
+    }
+
+    /// Whether this NAL unit is a parameter set (VPS for HEVC, SPS or PPS).
+    pub fn is_parameter_set(&self) -> bool {
+        self.is_sps() || self.is_pps() || (self.codec == Codec::Hevc && self.nal_type == 32)
+    }
+}
+
+/// Returns the NAL units of the Annex B `data` produced by an encoder for `codec`.
+///
+/// Returns an empty list for codecs that are not NAL-based.
+pub fn parse_nal_units(data: &[u8], codec: Codec) -> Vec<NalUnit> {
+    if !matches!(codec, Codec::H264 | Codec::Hevc) {
+        return Vec::new();
+    }
+
+    let mut nal_units = Vec::new();
+    let mut next = find_start_code(data).map(|pos| pos + 3);
+    while let Some(offset) = next {
+        let (end, following) = match find_start_code(&data[offset..]) {
+            Some(pos) => (offset + pos, Some(offset + pos + 3)),
+            None => (data.len(), None),
+        };
+        // Trailing zeroes belong to the next start code.
+        let size = data[offset..end]
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |pos| pos + 1);
+        if size > 0 {
+            let nal_type = match codec {
+                Codec::Hevc => (data[offset] >> 1) & 0x3f,
+                _ => data[offset] & 0x1f,
+            };
+            nal_units.push(NalUnit {
+                offset,
+                size,
+                nal_type,
+                codec,
+            });
+        }
+        next = following;
+    }
+
+    nal_units
+}
+
+/// An encoded buffer dequeued from the encoder.
+///
+/// The buffer is returned to the encoder once this object is dropped.
+pub struct EncodedPacket<P: HandlesProvider> {
+    buffer: DqBuffer<Capture, P::HandleType>,
+    codec: Option<Codec>,
+}
+
+impl<P: HandlesProvider> EncodedPacket<P> {
+    pub(crate) fn new(buffer: DqBuffer<Capture, P::HandleType>, codec: Option<Codec>) -> Self {
+        EncodedPacket { buffer, codec }
+    }
+
+    /// Returns the dequeued buffer containing the encoded data.
+    pub fn buffer(&self) -> &DqBuffer<Capture, P::HandleType> {
+        &self.buffer
+    }
+
+    pub fn into_buffer(self) -> DqBuffer<Capture, P::HandleType> {
+        self.buffer
+    }
+
+    /// Returns the codec of the encoded data, if known.
+    pub fn codec(&self) -> Option<Codec> {
+        self.codec
+    }
+
+    /// Returns the number of bytes of encoded data.
+    pub fn bytes_used(&self) -> usize {
+        self.buffer.data.get_first_plane().payload_size() as usize
+    }
+
+    pub fn frame_type(&self) -> FrameType {
+        let flags = self.buffer.data.flags();
+        if flags.contains(BufferFlags::KEYFRAME) {
+            FrameType::Key
+        } else if flags.contains(BufferFlags::PFRAME) {
+            FrameType::P
+        } else if flags.contains(BufferFlags::BFRAME) {
+            FrameType::B
+        } else {
+            FrameType::Unknown
+        }
+    }
+
+    /// Whether this packet can be used as a sync point, i.e. decoding can start from it.
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type() == FrameType::Key
+    }
+
+    /// Whether the driver reported an error while encoding this packet. The data may be
+    /// corrupted.
+    pub fn has_error(&self) -> bool {
+        self.buffer.data.flags().contains(BufferFlags::ERROR)
+    }
+
+    /// Returns the presentation timestamp of the packet, i.e. the timestamp of the OUTPUT buffer
+    /// containing the frame it has been encoded from.
+    pub fn pts(&self) -> TimeVal {
+        TimeVal::from(self.buffer.data.timestamp())
+    }
+}
+
+impl<P> EncodedPacket<P>
+where
+    P: HandlesProvider,
+    P::HandleType: PrimitiveBufferHandles,
+    <P::HandleType as PrimitiveBufferHandles>::HandleType: Mappable,
+{
+    /// Map the encoded data of the packet.
+    pub fn get_mapping(&self) -> Option<PlaneReadMapping<'_>> {
+        self.buffer.get_plane_mapping(0)
+    }
+
+    /// Returns the NAL units of the packet for H.264 and HEVC streams, or `None` if the codec
+    /// is not NAL-based or the buffer cannot be mapped.
+    pub fn nal_units(&self) -> Option<Vec<NalUnit>> {
+        let codec = self
+            .codec
+            .filter(|c| matches!(c, Codec::H264 | Codec::Hevc))?;
+        let mapping = self.get_mapping()?;
+        let data = &mapping.as_ref()[..std::cmp::min(self.bytes_used(), mapping.len())];
+
+        Some(parse_nal_units(data, codec))
+    }
+
+    /// Whether the packet contains stream parameter sets (SPS/PPS, and VPS for HEVC).
+    pub fn has_parameter_sets(&self) -> bool {
+        self.nal_units()
+            .map(|nalus| nalus.iter().any(NalUnit::is_parameter_set))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nal_units() {
+        let data = [
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce, 0, 0, 0, 1, 0x65, 0x88, 0x84,
+        ];
+        let nalus = parse_nal_units(&data, Codec::H264);
+
+        assert_eq!(
+            nalus
+                .iter()
+                .map(|n| (n.offset, n.size, n.nal_type))
+                .collect::<Vec<_>>(),
+            vec![(4, 2, 7), (9, 2, 8), (15, 3, 5)]
+        );
+        assert!(nalus[0].is_sps() && nalus[0].is_parameter_set());
+        assert!(nalus[1].is_pps());
+        assert!(!nalus[2].is_parameter_set());
+
+        // HEVC VPS, then IDR slice.
+        let data = [0, 0, 1, 0x40, 0x01, 0x0c, 0, 0, 1, 0x26, 0x01, 0xaf];
+        let nalus = parse_nal_units(&data, Codec::Hevc);
+        assert_eq!(nalus[0].nal_type, 32);
+        assert!(nalus[0].is_parameter_set() && !nalus[0].is_sps());
+        assert_eq!(nalus[1].nal_type, 19);
+
+        assert!(parse_nal_units(&data, Codec::Vp9).is_empty());
+    }
+}
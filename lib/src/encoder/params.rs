@@ -0,0 +1,656 @@
+//! Typed encoding parameters, applied to the encoder through the V4L2 codec controls.
+//!
+//! Parameters are validated against the ranges and menu items reported by the driver before
+//! being set, so unsupported settings are reported as a typed error instead of being silently
+//! clamped or ignored.
+use crate::{
+    bindings,
+    device::Device,
+    ioctl::{
+        self, ControlFlags, CtrlWhich, ExtControl, ExtControlError, GFmtError, QueryCtrlError,
+        QueryExtCtrl,
+    },
+    PixelFormat,
+};
+use thiserror::Error;
+
+/// Bitrate control modes, for `V4L2_CID_MPEG_VIDEO_BITRATE_MODE`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitrateMode {
+    Vbr = bindings::v4l2_mpeg_video_bitrate_mode_V4L2_MPEG_VIDEO_BITRATE_MODE_VBR,
+    Cbr = bindings::v4l2_mpeg_video_bitrate_mode_V4L2_MPEG_VIDEO_BITRATE_MODE_CBR,
+    ConstantQuality = bindings::v4l2_mpeg_video_bitrate_mode_V4L2_MPEG_VIDEO_BITRATE_MODE_CQ,
+}
+
+/// How the stream headers (e.g. SPS/PPS) are produced, for `V4L2_CID_MPEG_VIDEO_HEADER_MODE`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderMode {
+    /// Headers are returned in their own CAPTURE buffer.
+    Separate = bindings::v4l2_mpeg_video_header_mode_V4L2_MPEG_VIDEO_HEADER_MODE_SEPARATE,
+    /// Headers are returned together with the first encoded frame.
+    JoinedWithFirstFrame =
+        bindings::v4l2_mpeg_video_header_mode_V4L2_MPEG_VIDEO_HEADER_MODE_JOINED_WITH_1ST_FRAME,
+}
+
+/// H.264 entropy coding modes, for `V4L2_CID_MPEG_VIDEO_H264_ENTROPY_MODE`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H264EntropyMode {
+    Cavlc = bindings::v4l2_mpeg_video_h264_entropy_mode_V4L2_MPEG_VIDEO_H264_ENTROPY_MODE_CAVLC,
+    Cabac = bindings::v4l2_mpeg_video_h264_entropy_mode_V4L2_MPEG_VIDEO_H264_ENTROPY_MODE_CABAC,
+}
+
+/// H.264 profiles, for `V4L2_CID_MPEG_VIDEO_H264_PROFILE`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H264Profile {
+    Baseline = bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_BASELINE,
+    ConstrainedBaseline =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_CONSTRAINED_BASELINE,
+    Main = bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_MAIN,
+    Extended = bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_EXTENDED,
+    High = bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_HIGH,
+    High10 = bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_HIGH_10,
+    High422 = bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_HIGH_422,
+    High444Predictive =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_HIGH_444_PREDICTIVE,
+    High10Intra = bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_HIGH_10_INTRA,
+    High422Intra =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_HIGH_422_INTRA,
+    High444Intra =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_HIGH_444_INTRA,
+    Cavlc444Intra =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_CAVLC_444_INTRA,
+    ScalableBaseline =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_SCALABLE_BASELINE,
+    ScalableHigh =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_SCALABLE_HIGH,
+    ScalableHighIntra =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_SCALABLE_HIGH_INTRA,
+    StereoHigh = bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_STEREO_HIGH,
+    MultiviewHigh =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_MULTIVIEW_HIGH,
+    ConstrainedHigh =
+        bindings::v4l2_mpeg_video_h264_profile_V4L2_MPEG_VIDEO_H264_PROFILE_CONSTRAINED_HIGH,
+}
+
+/// H.264 levels, for `V4L2_CID_MPEG_VIDEO_H264_LEVEL`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H264Level {
+    L1_0 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_1_0,
+    L1B = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_1B,
+    L1_1 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_1_1,
+    L1_2 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_1_2,
+    L1_3 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_1_3,
+    L2_0 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_2_0,
+    L2_1 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_2_1,
+    L2_2 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_2_2,
+    L3_0 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_3_0,
+    L3_1 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_3_1,
+    L3_2 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_3_2,
+    L4_0 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_4_0,
+    L4_1 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_4_1,
+    L4_2 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_4_2,
+    L5_0 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_5_0,
+    L5_1 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_5_1,
+    L5_2 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_5_2,
+    L6_0 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_6_0,
+    L6_1 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_6_1,
+    L6_2 = bindings::v4l2_mpeg_video_h264_level_V4L2_MPEG_VIDEO_H264_LEVEL_6_2,
+}
+
+/// HEVC profiles, for `V4L2_CID_MPEG_VIDEO_HEVC_PROFILE`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HevcProfile {
+    Main = bindings::v4l2_mpeg_video_hevc_profile_V4L2_MPEG_VIDEO_HEVC_PROFILE_MAIN,
+    MainStillPicture =
+        bindings::v4l2_mpeg_video_hevc_profile_V4L2_MPEG_VIDEO_HEVC_PROFILE_MAIN_STILL_PICTURE,
+    Main10 = bindings::v4l2_mpeg_video_hevc_profile_V4L2_MPEG_VIDEO_HEVC_PROFILE_MAIN_10,
+}
+
+/// HEVC levels, for `V4L2_CID_MPEG_VIDEO_HEVC_LEVEL`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HevcLevel {
+    L1 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_1,
+    L2 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_2,
+    L2_1 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_2_1,
+    L3 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_3,
+    L3_1 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_3_1,
+    L4 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_4,
+    L4_1 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_4_1,
+    L5 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_5,
+    L5_1 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_5_1,
+    L5_2 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_5_2,
+    L6 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_6,
+    L6_1 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_6_1,
+    L6_2 = bindings::v4l2_mpeg_video_hevc_level_V4L2_MPEG_VIDEO_HEVC_LEVEL_6_2,
+}
+
+/// VP8 profiles, for `V4L2_CID_MPEG_VIDEO_VP8_PROFILE`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vp8Profile {
+    P0 = bindings::v4l2_mpeg_video_vp8_profile_V4L2_MPEG_VIDEO_VP8_PROFILE_0,
+    P1 = bindings::v4l2_mpeg_video_vp8_profile_V4L2_MPEG_VIDEO_VP8_PROFILE_1,
+    P2 = bindings::v4l2_mpeg_video_vp8_profile_V4L2_MPEG_VIDEO_VP8_PROFILE_2,
+    P3 = bindings::v4l2_mpeg_video_vp8_profile_V4L2_MPEG_VIDEO_VP8_PROFILE_3,
+}
+
+/// VP9 profiles, for `V4L2_CID_MPEG_VIDEO_VP9_PROFILE`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vp9Profile {
+    P0 = bindings::v4l2_mpeg_video_vp9_profile_V4L2_MPEG_VIDEO_VP9_PROFILE_0,
+    P1 = bindings::v4l2_mpeg_video_vp9_profile_V4L2_MPEG_VIDEO_VP9_PROFILE_1,
+    P2 = bindings::v4l2_mpeg_video_vp9_profile_V4L2_MPEG_VIDEO_VP9_PROFILE_2,
+    P3 = bindings::v4l2_mpeg_video_vp9_profile_V4L2_MPEG_VIDEO_VP9_PROFILE_3,
+}
+
+/// VP9 levels, for `V4L2_CID_MPEG_VIDEO_VP9_LEVEL`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vp9Level {
+    L1_0 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_1_0,
+    L1_1 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_1_1,
+    L2_0 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_2_0,
+    L2_1 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_2_1,
+    L3_0 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_3_0,
+    L3_1 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_3_1,
+    L4_0 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_4_0,
+    L4_1 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_4_1,
+    L5_0 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_5_0,
+    L5_1 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_5_1,
+    L5_2 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_5_2,
+    L6_0 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_6_0,
+    L6_1 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_6_1,
+    L6_2 = bindings::v4l2_mpeg_video_vp9_level_V4L2_MPEG_VIDEO_VP9_LEVEL_6_2,
+}
+
+/// Codecs for which codec-specific parameters can be set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    Hevc,
+    Vp8,
+    Vp9,
+}
+
+impl Codec {
+    /// Returns the codec matching the compressed `pixelformat`, if it is one we know about.
+    pub fn from_pixelformat(pixelformat: PixelFormat) -> Option<Self> {
+        let fourcc: [u8; 4] = pixelformat.into();
+        match &fourcc {
+            b"H264" => Some(Codec::H264),
+            b"HEVC" => Some(Codec::Hevc),
+            b"VP80" => Some(Codec::Vp8),
+            b"VP90" => Some(Codec::Vp9),
+            _ => None,
+        }
+    }
+}
+
+/// Codec profile to encode with. The codec must match the CAPTURE format of the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    H264(H264Profile),
+    Hevc(HevcProfile),
+    Vp8(Vp8Profile),
+    Vp9(Vp9Profile),
+}
+
+impl Profile {
+    fn codec(&self) -> Codec {
+        match self {
+            Profile::H264(_) => Codec::H264,
+            Profile::Hevc(_) => Codec::Hevc,
+            Profile::Vp8(_) => Codec::Vp8,
+            Profile::Vp9(_) => Codec::Vp9,
+        }
+    }
+}
+
+/// Codec level to encode with. The codec must match the CAPTURE format of the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    H264(H264Level),
+    Hevc(HevcLevel),
+    Vp9(Vp9Level),
+}
+
+impl Level {
+    fn codec(&self) -> Codec {
+        match self {
+            Level::H264(_) => Codec::H264,
+            Level::Hevc(_) => Codec::Hevc,
+            Level::Vp9(_) => Codec::Vp9,
+        }
+    }
+}
+
+/// Range of quantization parameters the encoder is allowed to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QpRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Encoding parameters. Only the parameters that are set are applied, the others keep the
+/// current value of the driver.
+#[derive(Debug, Clone, Default)]
+pub struct EncoderParams {
+    /// Average bitrate, in bits per second.
+    pub bitrate: Option<u32>,
+    /// Peak bitrate in bits per second, used in VBR mode.
+    pub peak_bitrate: Option<u32>,
+    pub bitrate_mode: Option<BitrateMode>,
+    /// Quality to target in constant quality mode, from 1 (smallest output) to 100 (best
+    /// quality).
+    pub constant_quality: Option<u32>,
+    /// Distance between two key frames.
+    pub gop_size: Option<u32>,
+    /// Number of B-frames between two reference frames.
+    pub b_frames: Option<u32>,
+    pub profile: Option<Profile>,
+    pub level: Option<Level>,
+    pub qp_range: Option<QpRange>,
+    pub h264_entropy_mode: Option<H264EntropyMode>,
+    pub header_mode: Option<HeaderMode>,
+}
+
+/// Parameters that can be changed while the encoder is running. When used with a request, they
+/// only apply from the frame queued with that request.
+#[derive(Debug, Clone, Default)]
+pub struct DynamicParams {
+    /// Average bitrate, in bits per second.
+    pub bitrate: Option<u32>,
+    /// Peak bitrate in bits per second, used in VBR mode.
+    pub peak_bitrate: Option<u32>,
+    /// Encode the next frame as a key frame.
+    pub force_key_frame: bool,
+}
+
+impl DynamicParams {
+    pub(crate) fn to_controls(&self) -> Vec<ControlValue> {
+        let mut controls = Vec::new();
+
+        if let Some(bitrate) = self.bitrate {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_BITRATE,
+                "bitrate",
+                bitrate as i64,
+            ));
+        }
+        if let Some(peak_bitrate) = self.peak_bitrate {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_BITRATE_PEAK,
+                "peak bitrate",
+                peak_bitrate as i64,
+            ));
+        }
+        if self.force_key_frame {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_FORCE_KEY_FRAME,
+                "force key frame",
+                1,
+            ));
+        }
+
+        controls
+    }
+}
+
+/// A control to set along with the value it should take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ControlValue {
+    pub id: u32,
+    /// Name of the control, for error reporting.
+    pub name: &'static str,
+    pub value: i64,
+}
+
+impl ControlValue {
+    fn new(id: u32, name: &'static str, value: i64) -> Self {
+        ControlValue { id, name, value }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EncoderParamsError {
+    #[error("Encoder does not support the {0} control")]
+    UnsupportedControl(&'static str),
+    #[error("Value {value} of {control} is out of range (min: {min}, max: {max}, step: {step})")]
+    OutOfRange {
+        control: &'static str,
+        value: i64,
+        min: i64,
+        max: i64,
+        step: u64,
+    },
+    #[error("Value {value} of {control} is not supported by the encoder")]
+    UnsupportedValue { control: &'static str, value: i64 },
+    #[error("{param} parameter does not apply to the {codec:?} codec")]
+    CodecMismatch {
+        param: &'static str,
+        codec: Option<Codec>,
+    },
+    #[error("Error while querying control: {0}")]
+    QueryCtrlError(#[from] QueryCtrlError),
+    #[error("Error while setting controls: {0}")]
+    ExtControlError(#[from] ExtControlError),
+    #[error("Error while getting the CAPTURE format: {0}")]
+    GFmtError(#[from] GFmtError),
+}
+
+impl EncoderParams {
+    /// Returns the list of controls to set in order to apply these parameters to an encoder
+    /// producing `codec`.
+    pub(crate) fn to_controls(
+        &self,
+        codec: Option<Codec>,
+    ) -> Result<Vec<ControlValue>, EncoderParamsError> {
+        let mut controls = Vec::new();
+
+        // The bitrate mode goes first, as it may change which of the following controls are
+        // active.
+        if let Some(mode) = self.bitrate_mode {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_BITRATE_MODE,
+                "bitrate mode",
+                mode as i64,
+            ));
+        }
+        if let Some(bitrate) = self.bitrate {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_BITRATE,
+                "bitrate",
+                bitrate as i64,
+            ));
+        }
+        if let Some(peak_bitrate) = self.peak_bitrate {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_BITRATE_PEAK,
+                "peak bitrate",
+                peak_bitrate as i64,
+            ));
+        }
+        if let Some(quality) = self.constant_quality {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_CONSTANT_QUALITY,
+                "constant quality",
+                quality as i64,
+            ));
+        }
+        if let Some(gop_size) = self.gop_size {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_GOP_SIZE,
+                "GOP size",
+                gop_size as i64,
+            ));
+        }
+        if let Some(b_frames) = self.b_frames {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_B_FRAMES,
+                "B-frames",
+                b_frames as i64,
+            ));
+        }
+        if let Some(header_mode) = self.header_mode {
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_HEADER_MODE,
+                "header mode",
+                header_mode as i64,
+            ));
+        }
+
+        if let Some(profile) = self.profile {
+            if codec != Some(profile.codec()) {
+                return Err(EncoderParamsError::CodecMismatch {
+                    param: "profile",
+                    codec,
+                });
+            }
+            controls.push(match profile {
+                Profile::H264(p) => ControlValue::new(
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_PROFILE,
+                    "H.264 profile",
+                    p as i64,
+                ),
+                Profile::Hevc(p) => ControlValue::new(
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_PROFILE,
+                    "HEVC profile",
+                    p as i64,
+                ),
+                Profile::Vp8(p) => ControlValue::new(
+                    bindings::V4L2_CID_MPEG_VIDEO_VP8_PROFILE,
+                    "VP8 profile",
+                    p as i64,
+                ),
+                Profile::Vp9(p) => ControlValue::new(
+                    bindings::V4L2_CID_MPEG_VIDEO_VP9_PROFILE,
+                    "VP9 profile",
+                    p as i64,
+                ),
+            });
+        }
+        if let Some(level) = self.level {
+            if codec != Some(level.codec()) {
+                return Err(EncoderParamsError::CodecMismatch {
+                    param: "level",
+                    codec,
+                });
+            }
+            controls.push(match level {
+                Level::H264(l) => ControlValue::new(
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_LEVEL,
+                    "H.264 level",
+                    l as i64,
+                ),
+                Level::Hevc(l) => ControlValue::new(
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_LEVEL,
+                    "HEVC level",
+                    l as i64,
+                ),
+                Level::Vp9(l) => ControlValue::new(
+                    bindings::V4L2_CID_MPEG_VIDEO_VP9_LEVEL,
+                    "VP9 level",
+                    l as i64,
+                ),
+            });
+        }
+        if let Some(entropy_mode) = self.h264_entropy_mode {
+            if codec != Some(Codec::H264) {
+                return Err(EncoderParamsError::CodecMismatch {
+                    param: "entropy mode",
+                    codec,
+                });
+            }
+            controls.push(ControlValue::new(
+                bindings::V4L2_CID_MPEG_VIDEO_H264_ENTROPY_MODE,
+                "H.264 entropy mode",
+                entropy_mode as i64,
+            ));
+        }
+        if let Some(qp_range) = self.qp_range {
+            let (min_id, max_id) = match codec {
+                Some(Codec::H264) => (
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_H264_MAX_QP,
+                ),
+                Some(Codec::Hevc) => (
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_HEVC_MAX_QP,
+                ),
+                Some(Codec::Vp8) | Some(Codec::Vp9) => (
+                    bindings::V4L2_CID_MPEG_VIDEO_VPX_MIN_QP,
+                    bindings::V4L2_CID_MPEG_VIDEO_VPX_MAX_QP,
+                ),
+                None => {
+                    return Err(EncoderParamsError::CodecMismatch {
+                        param: "QP range",
+                        codec,
+                    })
+                }
+            };
+            if qp_range.min > qp_range.max {
+                return Err(EncoderParamsError::OutOfRange {
+                    control: "minimum QP",
+                    value: qp_range.min as i64,
+                    min: 0,
+                    max: qp_range.max as i64,
+                    step: 1,
+                });
+            }
+            controls.push(ControlValue::new(min_id, "minimum QP", qp_range.min as i64));
+            controls.push(ControlValue::new(max_id, "maximum QP", qp_range.max as i64));
+        }
+
+        Ok(controls)
+    }
+}
+
+/// Check that `control` can be set to its value according to the information returned by the
+/// driver.
+fn validate_control(
+    device: &Device,
+    control: &ControlValue,
+    qctrl: &QueryExtCtrl,
+) -> Result<(), EncoderParamsError> {
+    if qctrl
+        .flags
+        .intersects(ControlFlags::DISABLED | ControlFlags::READ_ONLY)
+    {
+        return Err(EncoderParamsError::UnsupportedControl(control.name));
+    }
+
+    match qctrl.ctrl_type {
+        // Buttons have no value to validate.
+        bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_BUTTON => (),
+        bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_MENU
+        | bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER_MENU => {
+            let supported = control.value >= qctrl.minimum
+                && control.value <= qctrl.maximum
+                && ioctl::querymenu(device, control.id, control.value as u32).is_ok();
+            if !supported {
+                return Err(EncoderParamsError::UnsupportedValue {
+                    control: control.name,
+                    value: control.value,
+                });
+            }
+        }
+        _ => {
+            let step = std::cmp::max(qctrl.step, 1);
+            // The value is known to be above the minimum when checking the step, so `abs_diff`
+            // gives its offset from the minimum without risk of overflow.
+            if control.value < qctrl.minimum
+                || control.value > qctrl.maximum
+                || !control.value.abs_diff(qctrl.minimum).is_multiple_of(step)
+            {
+                return Err(EncoderParamsError::OutOfRange {
+                    control: control.name,
+                    value: control.value,
+                    min: qctrl.minimum,
+                    max: qctrl.maximum,
+                    step: qctrl.step,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validate `controls` against the capabilities of `device`, and set them all at once with
+/// `which`.
+pub(crate) fn apply_controls(
+    device: &Device,
+    controls: &[ControlValue],
+    which: CtrlWhich,
+) -> Result<(), EncoderParamsError> {
+    let mut ext_controls = Vec::with_capacity(controls.len());
+    for control in controls {
+        let qctrl = match ioctl::query_ext_ctrl(device, control.id) {
+            Ok(qctrl) => qctrl,
+            Err(QueryCtrlError::InvalidControl) => {
+                return Err(EncoderParamsError::UnsupportedControl(control.name))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        validate_control(device, control, &qctrl)?;
+        ext_controls.push(
+            if qctrl.ctrl_type == bindings::v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER64 {
+                ExtControl::new64(control.id, control.value)
+            } else {
+                ExtControl::new(control.id, control.value as i32)
+            },
+        );
+    }
+
+    if ext_controls.is_empty() {
+        return Ok(());
+    }
+
+    ioctl::s_ext_ctrls(device, which, &mut ext_controls)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qp_range_controls() {
+        let params = EncoderParams {
+            qp_range: Some(QpRange { min: 10, max: 40 }),
+            ..Default::default()
+        };
+
+        let controls = params.to_controls(Some(Codec::Vp9)).unwrap();
+        assert_eq!(
+            controls.iter().map(|c| (c.id, c.value)).collect::<Vec<_>>(),
+            vec![
+                (bindings::V4L2_CID_MPEG_VIDEO_VPX_MIN_QP, 10),
+                (bindings::V4L2_CID_MPEG_VIDEO_VPX_MAX_QP, 40)
+            ]
+        );
+
+        let controls = params.to_controls(Some(Codec::H264)).unwrap();
+        assert_eq!(controls[0].id, bindings::V4L2_CID_MPEG_VIDEO_H264_MIN_QP);
+
+        let inverted = EncoderParams {
+            qp_range: Some(QpRange { min: 40, max: 10 }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            inverted.to_controls(Some(Codec::H264)),
+            Err(EncoderParamsError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn codec_mismatch() {
+        let params = EncoderParams {
+            bitrate: Some(1_000_000),
+            profile: Some(Profile::H264(H264Profile::High)),
+            ..Default::default()
+        };
+
+        assert!(params.to_controls(Some(Codec::H264)).is_ok());
+        assert!(matches!(
+            params.to_controls(Some(Codec::Vp8)),
+            Err(EncoderParamsError::CodecMismatch {
+                param: "profile",
+                ..
+            })
+        ));
+        assert!(matches!(
+            params.to_controls(None),
+            Err(EncoderParamsError::CodecMismatch { .. })
+        ));
+    }
+}
@@ -7,33 +7,47 @@
 //! argument, and only return the values written by the kernel. Therefore,
 //! although the return types look similar to the kernel structures, they are
 //! not strictly identical.
+mod create_bufs;
 mod decoder_cmd;
+mod dma_buf_sync;
 mod dqbuf;
 mod encoder_cmd;
 mod enum_fmt;
+mod enum_framesizes;
 mod expbuf;
+mod ext_ctrls;
 mod g_fmt;
 mod g_selection;
 mod mmap;
+mod parm;
 mod qbuf;
 mod querybuf;
 mod querycap;
+mod queryctrl;
 mod reqbufs;
+mod request;
 mod streamon;
 mod subscribe_event;
 
+pub use create_bufs::*;
 pub use decoder_cmd::*;
+pub use dma_buf_sync::*;
 pub use dqbuf::*;
 pub use encoder_cmd::*;
 pub use enum_fmt::*;
+pub use enum_framesizes::*;
 pub use expbuf::*;
+pub use ext_ctrls::*;
 pub use g_fmt::*;
 pub use g_selection::*;
 pub use mmap::*;
+pub use parm::*;
 pub use qbuf::*;
 pub use querybuf::*;
 pub use querycap::*;
+pub use queryctrl::*;
 pub use reqbufs::*;
+pub use request::*;
 pub use streamon::*;
 pub use subscribe_event::*;
 
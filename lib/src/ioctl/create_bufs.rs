@@ -0,0 +1,69 @@
+//! Safe wrapper for the `VIDIOC_CREATE_BUFS` ioctl.
+use super::BufferCapabilities;
+use crate::bindings;
+use crate::memory::MemoryType;
+use crate::{Format, FormatConversionError, QueueType};
+use nix::{self, errno::Errno};
+use std::convert::TryInto;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use thiserror::Error;
+
+/// Result of the `create_bufs` ioctl.
+#[derive(Debug)]
+pub struct CreateBuffers {
+    /// Index of the first created buffer.
+    pub index: u32,
+    /// Number of buffers actually created.
+    pub count: u32,
+    pub capabilities: BufferCapabilities,
+}
+
+#[doc(hidden)]
+mod ioctl {
+    use crate::bindings::v4l2_create_buffers;
+    nix::ioctl_readwrite!(vidioc_create_bufs, b'V', 92, v4l2_create_buffers);
+}
+
+#[derive(Debug, Error)]
+pub enum CreateBufsError {
+    #[error("Error while converting to V4L2 format")]
+    ToV4L2FormatConversionError(#[from] FormatConversionError),
+    #[error("No memory to allocate the buffers")]
+    NoMemory,
+    #[error("Invalid buffer type, memory type or format requested")]
+    InvalidArgument,
+    #[error("Unexpected ioctl error: {0}")]
+    IoctlError(nix::Error),
+}
+
+/// Safe wrapper around the `VIDIOC_CREATE_BUFS` ioctl.
+///
+/// Allocates `count` additional buffers of `memory` type for `queue`, large
+/// enough to hold frames of `format`. Contrary to `reqbufs`, the buffers
+/// already allocated are preserved and the queue can keep streaming.
+pub fn create_bufs<F: AsRawFd>(
+    fd: &F,
+    queue: QueueType,
+    memory: MemoryType,
+    count: u32,
+    format: Format,
+) -> Result<CreateBuffers, CreateBufsError> {
+    let mut create_bufs = bindings::v4l2_create_buffers {
+        count,
+        memory: memory as u32,
+        format: (format, queue).try_into()?,
+        ..unsafe { mem::zeroed() }
+    };
+
+    match unsafe { ioctl::vidioc_create_bufs(fd.as_raw_fd(), &mut create_bufs) } {
+        Ok(_) => Ok(CreateBuffers {
+            index: create_bufs.index,
+            count: create_bufs.count,
+            capabilities: BufferCapabilities::from_bits_truncate(create_bufs.capabilities),
+        }),
+        Err(Errno::ENOMEM) => Err(CreateBufsError::NoMemory),
+        Err(Errno::EINVAL) => Err(CreateBufsError::InvalidArgument),
+        Err(e) => Err(CreateBufsError::IoctlError(e)),
+    }
+}
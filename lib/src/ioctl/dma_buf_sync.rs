@@ -0,0 +1,74 @@
+//! Safe wrapper for the `DMA_BUF_IOCTL_SYNC` ioctl of DMA-BUFs.
+//!
+//! This ioctl is not part of V4L2, but is required to access the memory of
+//! DMA-BUFs from the CPU on platforms without cache coherency.
+use nix::errno::Errno;
+use std::os::unix::io::AsRawFd;
+use thiserror::Error;
+
+const DMA_BUF_SYNC_READ: u64 = 1 << 0;
+const DMA_BUF_SYNC_WRITE: u64 = 1 << 1;
+const DMA_BUF_SYNC_START: u64 = 0 << 2;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+/// Kind of CPU access to the memory of a DMA-BUF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaBufAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Whether a CPU access to a DMA-BUF begins or ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaBufSyncPhase {
+    Start,
+    End,
+}
+
+#[doc(hidden)]
+mod ioctl {
+    #[repr(C)]
+    #[allow(non_camel_case_types)]
+    pub struct dma_buf_sync {
+        pub flags: u64,
+    }
+    nix::ioctl_write_ptr!(dma_buf_ioctl_sync, b'b', 0, dma_buf_sync);
+}
+
+#[derive(Debug, Error)]
+pub enum DmaBufSyncError {
+    #[error("Unexpected ioctl error: {0}")]
+    IoctlError(#[from] nix::Error),
+}
+
+/// Safe wrapper around the `DMA_BUF_IOCTL_SYNC` ioctl.
+///
+/// Every `DmaBufSyncPhase::Start` call must be followed by a
+/// `DmaBufSyncPhase::End` call with the same `access` once the CPU is done
+/// accessing the memory of the DMA-BUF.
+pub fn dma_buf_sync<F: AsRawFd>(
+    fd: &F,
+    phase: DmaBufSyncPhase,
+    access: DmaBufAccess,
+) -> Result<(), DmaBufSyncError> {
+    let sync = ioctl::dma_buf_sync {
+        flags: match phase {
+            DmaBufSyncPhase::Start => DMA_BUF_SYNC_START,
+            DmaBufSyncPhase::End => DMA_BUF_SYNC_END,
+        } | match access {
+            DmaBufAccess::Read => DMA_BUF_SYNC_READ,
+            DmaBufAccess::Write => DMA_BUF_SYNC_WRITE,
+            DmaBufAccess::ReadWrite => DMA_BUF_SYNC_READ | DMA_BUF_SYNC_WRITE,
+        },
+    };
+
+    // The kernel may ask us to try again if it has been interrupted.
+    loop {
+        match unsafe { ioctl::dma_buf_ioctl_sync(fd.as_raw_fd(), &sync) } {
+            Ok(_) => return Ok(()),
+            Err(Errno::EINTR) | Err(Errno::EAGAIN) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
@@ -2,7 +2,7 @@ use super::{is_multi_planar, BufferFlags, PlaneData};
 use crate::bindings;
 use crate::QueueType;
 
-use nix::{self, errno::Errno, Error};
+use nix::{self, errno::Errno, sys::time::TimeVal, Error};
 use std::mem;
 use std::os::unix::io::AsRawFd;
 use std::{fmt::Debug, pin::Pin};
@@ -57,6 +57,12 @@ impl<'a> DqBufPlane<'a> {
     pub fn data_offset(&self) -> u32 {
         self.plane.data_offset
     }
+
+    /// Returns the number of bytes of data in the plane, starting at `data_offset`. Contrary to
+    /// `bytesused`, this does not include the data offset.
+    pub fn payload_size(&self) -> u32 {
+        self.plane.bytesused.saturating_sub(self.plane.data_offset)
+    }
 }
 
 /// Information for a dequeued buffer. Safe variant of `struct v4l2_buffer`.
@@ -95,6 +101,16 @@ impl Debug for DqBuffer {
     }
 }
 
+/// Converts the timestamp of a V4L2 buffer, as returned by [`DqBuffer::timestamp`].
+impl From<bindings::timeval> for TimeVal {
+    fn from(timestamp: bindings::timeval) -> Self {
+        TimeVal::from(nix::libc::timeval {
+            tv_sec: timestamp.tv_sec,
+            tv_usec: timestamp.tv_usec,
+        })
+    }
+}
+
 impl DqBuffer {
     pub fn index(&self) -> u32 {
         self.v4l2_buffer.index
@@ -21,6 +21,8 @@ bitflags! {
     pub struct FormatFlags: u32 {
         const COMPRESSED = bindings::V4L2_FMT_FLAG_COMPRESSED;
         const EMULATED = bindings::V4L2_FMT_FLAG_EMULATED;
+        const CONTINUOUS_BYTESTREAM = bindings::V4L2_FMT_FLAG_CONTINUOUS_BYTESTREAM;
+        const DYN_RESOLUTION = bindings::V4L2_FMT_FLAG_DYN_RESOLUTION;
     }
 }
 /// Quickly get the Fourcc code of a format.
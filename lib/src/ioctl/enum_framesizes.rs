@@ -0,0 +1,145 @@
+//! Safe wrapper for the `VIDIOC_ENUM_FRAMESIZES` ioctl.
+use crate::bindings;
+use crate::PixelFormat;
+use log::error;
+use nix::errno::Errno;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use thiserror::Error;
+
+/// Range of frame sizes supported for a pixel format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameSizeRange {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub step_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub step_height: u32,
+}
+
+impl From<bindings::v4l2_frmsize_stepwise> for FrameSizeRange {
+    fn from(stepwise: bindings::v4l2_frmsize_stepwise) -> Self {
+        FrameSizeRange {
+            min_width: stepwise.min_width,
+            max_width: stepwise.max_width,
+            step_width: stepwise.step_width,
+            min_height: stepwise.min_height,
+            max_height: stepwise.max_height,
+            step_height: stepwise.step_height,
+        }
+    }
+}
+
+/// Safe variant of the `v4l2_frmsizeenum` struct, to be used with `enum_frame_sizes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameSize {
+    /// A single supported frame size. Other discrete sizes can be obtained by increasing the
+    /// index passed to `enum_frame_sizes`.
+    Discrete { width: u32, height: u32 },
+    /// All the sizes of the range are supported, with a step of 1 pixel.
+    Continuous(FrameSizeRange),
+    /// All the sizes of the range that are a multiple of the step are supported.
+    Stepwise(FrameSizeRange),
+}
+
+impl FrameSize {
+    /// Returns the range of sizes covered, which is a single size for `FrameSize::Discrete`.
+    pub fn range(&self) -> FrameSizeRange {
+        match *self {
+            FrameSize::Discrete { width, height } => FrameSizeRange {
+                min_width: width,
+                max_width: width,
+                step_width: 1,
+                min_height: height,
+                max_height: height,
+                step_height: 1,
+            },
+            FrameSize::Continuous(range) | FrameSize::Stepwise(range) => range,
+        }
+    }
+}
+
+#[doc(hidden)]
+mod ioctl {
+    use crate::bindings::v4l2_frmsizeenum;
+    nix::ioctl_readwrite!(vidioc_enum_framesizes, b'V', 74, v4l2_frmsizeenum);
+}
+
+#[derive(Debug, Error)]
+pub enum EnumFrameSizesError {
+    #[error("Unknown frame size type {0}")]
+    UnknownType(u32),
+    #[error("Unexpected ioctl error: {0}")]
+    IoctlError(#[from] nix::Error),
+}
+
+/// Safe wrapper around the `VIDIOC_ENUM_FRAMESIZES` ioctl.
+pub fn enum_frame_sizes<F: AsRawFd>(
+    fd: &F,
+    pixelformat: PixelFormat,
+    index: u32,
+) -> Result<FrameSize, EnumFrameSizesError> {
+    let mut frmsize = bindings::v4l2_frmsizeenum {
+        index,
+        pixel_format: pixelformat.into(),
+        ..unsafe { mem::zeroed() }
+    };
+    unsafe { ioctl::vidioc_enum_framesizes(fd.as_raw_fd(), &mut frmsize) }?;
+
+    // Safe because the type tells us which member of the union is valid.
+    match frmsize.type_ {
+        bindings::v4l2_frmsizetypes_V4L2_FRMSIZE_TYPE_DISCRETE => {
+            let discrete = unsafe { frmsize.__bindgen_anon_1.discrete };
+            Ok(FrameSize::Discrete {
+                width: discrete.width,
+                height: discrete.height,
+            })
+        }
+        bindings::v4l2_frmsizetypes_V4L2_FRMSIZE_TYPE_CONTINUOUS => Ok(FrameSize::Continuous(
+            unsafe { frmsize.__bindgen_anon_1.stepwise }.into(),
+        )),
+        bindings::v4l2_frmsizetypes_V4L2_FRMSIZE_TYPE_STEPWISE => Ok(FrameSize::Stepwise(
+            unsafe { frmsize.__bindgen_anon_1.stepwise }.into(),
+        )),
+        t => Err(EnumFrameSizesError::UnknownType(t)),
+    }
+}
+
+/// Iterator over the frame sizes supported for a pixel format. Only discrete frame sizes can
+/// produce more than one item.
+pub struct FrameSizeIterator<'a, F: AsRawFd> {
+    fd: &'a F,
+    pixelformat: PixelFormat,
+    index: u32,
+}
+
+impl<'a, F: AsRawFd> FrameSizeIterator<'a, F> {
+    /// Create a new iterator listing all the frame sizes supported for `pixelformat`.
+    pub fn new(fd: &'a F, pixelformat: PixelFormat) -> Self {
+        FrameSizeIterator {
+            fd,
+            pixelformat,
+            index: 0,
+        }
+    }
+}
+
+impl<'a, F: AsRawFd> Iterator for FrameSizeIterator<'a, F> {
+    type Item = FrameSize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match enum_frame_sizes(self.fd, self.pixelformat, self.index) {
+            Ok(frame_size) => {
+                self.index += 1;
+                Some(frame_size)
+            }
+            // EINVAL means we have reached the last frame size.
+            Err(EnumFrameSizesError::IoctlError(Errno::EINVAL)) => None,
+            Err(e) => {
+                error!("Unexpected return value for VIDIOC_ENUM_FRAMESIZES: {}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Safe wrappers for the `VIDIOC_G_EXT_CTRLS`, `VIDIOC_S_EXT_CTRLS` and `VIDIOC_TRY_EXT_CTRLS`
+//! ioctls.
+use crate::bindings;
+use nix::errno::Errno;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use thiserror::Error;
+
+/// Value of a control. Controls of type `V4L2_CTRL_TYPE_INTEGER64` use `Value64`, other
+/// non-compound controls use `Value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtrlValue {
+    Value(i32),
+    Value64(i64),
+}
+
+/// A control to get or set with the extended controls ioctls.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtControl {
+    pub id: u32,
+    pub value: CtrlValue,
+}
+
+impl ExtControl {
+    pub fn new(id: u32, value: i32) -> Self {
+        ExtControl {
+            id,
+            value: CtrlValue::Value(value),
+        }
+    }
+
+    pub fn new64(id: u32, value: i64) -> Self {
+        ExtControl {
+            id,
+            value: CtrlValue::Value64(value),
+        }
+    }
+}
+
+impl From<&ExtControl> for bindings::v4l2_ext_control {
+    fn from(control: &ExtControl) -> Self {
+        bindings::v4l2_ext_control {
+            id: control.id,
+            __bindgen_anon_1: match control.value {
+                CtrlValue::Value(value) => bindings::v4l2_ext_control__bindgen_ty_1 { value },
+                CtrlValue::Value64(value64) => bindings::v4l2_ext_control__bindgen_ty_1 { value64 },
+            },
+            ..unsafe { mem::zeroed() }
+        }
+    }
+}
+
+/// Which value of the controls the ioctls should operate on.
+#[derive(Debug, Clone, Copy)]
+pub enum CtrlWhich {
+    Current,
+    Default,
+    /// Values stored in (or to be applied by) the request with the given FD.
+    Request(RawFd),
+}
+
+impl From<CtrlWhich> for u32 {
+    fn from(which: CtrlWhich) -> Self {
+        match which {
+            CtrlWhich::Current => bindings::V4L2_CTRL_WHICH_CUR_VAL,
+            CtrlWhich::Default => bindings::V4L2_CTRL_WHICH_DEF_VAL,
+            CtrlWhich::Request(_) => bindings::V4L2_CTRL_WHICH_REQUEST_VAL,
+        }
+    }
+}
+
+#[doc(hidden)]
+mod ioctl {
+    use crate::bindings::v4l2_ext_controls;
+    nix::ioctl_readwrite!(vidioc_g_ext_ctrls, b'V', 71, v4l2_ext_controls);
+    nix::ioctl_readwrite!(vidioc_s_ext_ctrls, b'V', 72, v4l2_ext_controls);
+    nix::ioctl_readwrite!(vidioc_try_ext_ctrls, b'V', 73, v4l2_ext_controls);
+}
+
+/// Errors of the extended controls ioctls. The index of the faulty control is provided when the
+/// driver reports it, or `None` if the error could not be attributed to a single control.
+#[derive(Debug, Error)]
+pub enum ExtControlError {
+    #[error("Invalid control or value (control index: {0:?})")]
+    InvalidControl(Option<usize>),
+    #[error("Control value out of range (control index: {0:?})")]
+    OutOfRange(Option<usize>),
+    #[error("Control cannot be changed right now (control index: {0:?})")]
+    Busy(Option<usize>),
+    #[error("Control is read-only or write-only (control index: {0:?})")]
+    PermissionDenied(Option<usize>),
+    #[error("Unexpected ioctl error: {0}")]
+    IoctlError(nix::Error),
+}
+
+type ExtCtrlsIoctl =
+    unsafe fn(nix::libc::c_int, *mut bindings::v4l2_ext_controls) -> nix::Result<nix::libc::c_int>;
+
+fn ext_ctrls<F: AsRawFd>(
+    fd: &F,
+    which: CtrlWhich,
+    controls: &mut [ExtControl],
+    ioctl: ExtCtrlsIoctl,
+) -> Result<(), ExtControlError> {
+    let mut v4l2_controls = controls
+        .iter()
+        .map(bindings::v4l2_ext_control::from)
+        .collect::<Vec<_>>();
+    let mut ext_ctrls = bindings::v4l2_ext_controls {
+        __bindgen_anon_1: bindings::v4l2_ext_controls__bindgen_ty_1 {
+            which: which.into(),
+        },
+        count: v4l2_controls.len() as u32,
+        controls: v4l2_controls.as_mut_ptr(),
+        ..unsafe { mem::zeroed() }
+    };
+    if let CtrlWhich::Request(request_fd) = which {
+        ext_ctrls.request_fd = request_fd;
+    }
+
+    let res = unsafe { ioctl(fd.as_raw_fd(), &mut ext_ctrls) };
+
+    // Report the values written by the driver.
+    for (control, v4l2_control) in controls.iter_mut().zip(v4l2_controls.iter()) {
+        // Safe because we only read the member of the union we have set.
+        control.value = match control.value {
+            CtrlValue::Value(_) => CtrlValue::Value(unsafe { v4l2_control.__bindgen_anon_1.value }),
+            CtrlValue::Value64(_) => {
+                CtrlValue::Value64(unsafe { v4l2_control.__bindgen_anon_1.value64 })
+            }
+        };
+    }
+
+    let error_idx = if (ext_ctrls.error_idx as usize) < controls.len() {
+        Some(ext_ctrls.error_idx as usize)
+    } else {
+        None
+    };
+    match res {
+        Ok(_) => Ok(()),
+        Err(Errno::EINVAL) => Err(ExtControlError::InvalidControl(error_idx)),
+        Err(Errno::ERANGE) => Err(ExtControlError::OutOfRange(error_idx)),
+        Err(Errno::EBUSY) => Err(ExtControlError::Busy(error_idx)),
+        Err(Errno::EACCES) => Err(ExtControlError::PermissionDenied(error_idx)),
+        Err(e) => Err(ExtControlError::IoctlError(e)),
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_G_EXT_CTRLS` ioctl. The values of `controls` are updated
+/// with the values returned by the driver.
+pub fn g_ext_ctrls<F: AsRawFd>(
+    fd: &F,
+    which: CtrlWhich,
+    controls: &mut [ExtControl],
+) -> Result<(), ExtControlError> {
+    ext_ctrls(fd, which, controls, ioctl::vidioc_g_ext_ctrls)
+}
+
+/// Safe wrapper around the `VIDIOC_S_EXT_CTRLS` ioctl. The values of `controls` are updated
+/// with the values actually applied by the driver.
+pub fn s_ext_ctrls<F: AsRawFd>(
+    fd: &F,
+    which: CtrlWhich,
+    controls: &mut [ExtControl],
+) -> Result<(), ExtControlError> {
+    ext_ctrls(fd, which, controls, ioctl::vidioc_s_ext_ctrls)
+}
+
+/// Safe wrapper around the `VIDIOC_TRY_EXT_CTRLS` ioctl. The values of `controls` are updated
+/// with the values the driver would apply.
+pub fn try_ext_ctrls<F: AsRawFd>(
+    fd: &F,
+    which: CtrlWhich,
+    controls: &mut [ExtControl],
+) -> Result<(), ExtControlError> {
+    ext_ctrls(fd, which, controls, ioctl::vidioc_try_ext_ctrls)
+}
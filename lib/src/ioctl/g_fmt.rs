@@ -1,6 +1,6 @@
 //! Safe wrapper for the `VIDIOC_(G|S|TRY)_FMT` ioctls.
 use crate::{bindings, FormatConversionError};
-use crate::{Format, PlaneLayout, QueueType};
+use crate::{Colorimetry, Format, PlaneLayout, QueueType};
 use nix::errno::Errno;
 use std::convert::{From, Into, TryFrom, TryInto};
 use std::default::Default;
@@ -87,6 +87,8 @@ impl TryFrom<(Format, QueueType)> for bindings::v4l2_format {
 
 impl Fmt<FormatConversionError> for Format {}
 
+impl Fmt<FormatConversionError> for Colorimetry {}
+
 // We cannot derive from the bindings since they are generated.
 #[allow(clippy::derivable_impls)]
 impl Default for bindings::v4l2_plane_pix_format {
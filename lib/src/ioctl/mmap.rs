@@ -1,9 +1,11 @@
 use std::{
-    cmp::{max, min},
-    ops::Deref,
+    cmp::min,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    os::unix::io::AsRawFd,
     slice,
+    sync::Arc,
 };
-use std::{ops::DerefMut, os::unix::io::AsRawFd};
 
 use log::error;
 use nix::{
@@ -12,62 +14,168 @@ use nix::{
 };
 use thiserror::Error;
 
-pub struct PlaneMapping {
-    // A mapping remains valid until we munmap it, that is, until the
-    // PlaneMapping object is deleted. Hence the static lifetime.
-    pub data: &'static mut [u8],
+/// A memory area mapped using `mmap`, which is unmapped when dropped.
+///
+/// The memory may be written by the device or other mappings at any time, so
+/// it is only exposed as raw pointers. Safe access is provided by
+/// `PlaneReadMapping` and `PlaneWriteMapping`, which are borrowed from an
+/// object guaranteeing exclusive CPU ownership of the memory, like a dequeued
+/// buffer.
+#[derive(Debug)]
+pub struct MmapRegion {
+    addr: *mut u8,
+    len: usize,
+}
+
+// The region is just a range of addresses that remains valid until dropped.
+unsafe impl Send for MmapRegion {}
+unsafe impl Sync for MmapRegion {}
+
+impl MmapRegion {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.addr
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+}
 
+impl Drop for MmapRegion {
+    fn drop(&mut self) {
+        // Safe because the pointer and length were constructed in mmap() and
+        // are always valid.
+        unsafe { mman::munmap(self.addr as *mut c_void, self.len) }.unwrap_or_else(|e| {
+            error!("Error while unmapping plane: {}", e);
+        });
+    }
+}
+
+/// Read-only access to a range of an `MmapRegion`, which cannot outlive the
+/// object it has been obtained from.
+pub struct PlaneReadMapping<'a> {
+    region: Arc<MmapRegion>,
     start: usize,
     end: usize,
+    _owner: PhantomData<&'a ()>,
 }
 
-impl PlaneMapping {
-    pub fn size(&self) -> usize {
-        self.end - self.start
+impl<'a> PlaneReadMapping<'a> {
+    /// Give read access to the `start..end` range of `region`, clamped to the
+    /// size of the region, for the lifetime `'a`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing must be able to write into the range for as long as `'a` lasts.
+    pub unsafe fn new(region: Arc<MmapRegion>, start: usize, end: usize) -> Self {
+        let end = min(end, region.len());
+        PlaneReadMapping {
+            start: min(start, end),
+            end,
+            region,
+            _owner: PhantomData,
+        }
     }
 
-    pub fn restrict(mut self, start: usize, end: usize) -> Self {
-        self.start = max(self.start, start);
-        self.end = min(self.end, end);
+    /// Returns the whole mapped region this mapping is a part of.
+    pub fn region(&self) -> &Arc<MmapRegion> {
+        &self.region
+    }
+}
 
-        self
+impl<'a> Deref for PlaneReadMapping<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // Safe because the range is within the region, and nothing can write
+        // into it for as long as we live.
+        unsafe {
+            slice::from_raw_parts(self.region.as_ptr().add(self.start), self.end - self.start)
+        }
     }
 }
 
-impl AsRef<[u8]> for PlaneMapping {
+impl<'a> AsRef<[u8]> for PlaneReadMapping<'a> {
     fn as_ref(&self) -> &[u8] {
-        &self.data[self.start..self.end]
+        self
     }
 }
 
-impl AsMut<[u8]> for PlaneMapping {
-    fn as_mut(&mut self) -> &mut [u8] {
-        &mut self.data[self.start..self.end]
+/// Read and write access to a range of an `MmapRegion`, which cannot outlive
+/// the object it has been obtained from.
+pub struct PlaneWriteMapping<'a> {
+    region: Arc<MmapRegion>,
+    start: usize,
+    end: usize,
+    _owner: PhantomData<&'a mut ()>,
+}
+
+impl<'a> PlaneWriteMapping<'a> {
+    /// Give read and write access to the `start..end` range of `region`,
+    /// clamped to the size of the region, for the lifetime `'a`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else must be able to access the range for as long as `'a`
+    /// lasts.
+    pub unsafe fn new(region: Arc<MmapRegion>, start: usize, end: usize) -> Self {
+        let end = min(end, region.len());
+        PlaneWriteMapping {
+            start: min(start, end),
+            end,
+            region,
+            _owner: PhantomData,
+        }
+    }
+
+    /// Returns the whole mapped region this mapping is a part of.
+    pub fn region(&self) -> &Arc<MmapRegion> {
+        &self.region
     }
 }
 
-impl Deref for PlaneMapping {
+impl<'a> Deref for PlaneWriteMapping<'a> {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.data[self.start..self.end]
+        // Safe because the range is within the region, and we have exclusive
+        // access to it.
+        unsafe {
+            slice::from_raw_parts(self.region.as_ptr().add(self.start), self.end - self.start)
+        }
     }
 }
 
-impl DerefMut for PlaneMapping {
+impl<'a> DerefMut for PlaneWriteMapping<'a> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.data[self.start..self.end]
+        // Safe because the range is within the region, and we have exclusive
+        // access to it.
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.region.as_mut_ptr().add(self.start),
+                self.end - self.start,
+            )
+        }
     }
 }
 
-impl Drop for PlaneMapping {
-    fn drop(&mut self) {
-        // Safe because the pointer and length were constructed in mmap() and
-        // are always valid.
-        unsafe { mman::munmap(self.data.as_mut_ptr() as *mut c_void, self.data.len()) }
-            .unwrap_or_else(|e| {
-                error!("Error while unmapping plane: {}", e);
-            });
+impl<'a> AsRef<[u8]> for PlaneWriteMapping<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<'a> AsMut<[u8]> for PlaneWriteMapping<'a> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
     }
 }
 
@@ -77,10 +185,10 @@ pub enum MmapError {
     IoctlError(#[from] nix::Error),
 }
 
-// TODO should be unsafe because the mapping can be used after a buffer is queued?
-// Or not, since this cannot cause a crash...
-pub fn mmap<F: AsRawFd>(fd: &F, mem_offset: u32, length: u32) -> Result<PlaneMapping, MmapError> {
-    let data = unsafe {
+/// Map `length` bytes of `fd` starting at `mem_offset` for reading and
+/// writing.
+pub fn mmap<F: AsRawFd>(fd: &F, mem_offset: u32, length: u32) -> Result<MmapRegion, MmapError> {
+    let addr = unsafe {
         mman::mmap(
             std::ptr::null_mut::<c_void>(),
             length as size_t,
@@ -91,11 +199,8 @@ pub fn mmap<F: AsRawFd>(fd: &F, mem_offset: u32, length: u32) -> Result<PlaneMap
         )
     }?;
 
-    Ok(PlaneMapping {
-        // Safe because we know the pointer is valid and has enough data mapped
-        // to cover the length.
-        data: unsafe { slice::from_raw_parts_mut(data as *mut u8, length as usize) },
-        start: 0,
-        end: length as usize,
+    Ok(MmapRegion {
+        addr: addr as *mut u8,
+        len: length as usize,
     })
 }
@@ -0,0 +1,113 @@
+//! Safe wrappers for the `VIDIOC_G_PARM` and `VIDIOC_S_PARM` ioctls.
+use crate::bindings;
+use crate::QueueType;
+use nix::errno::Errno;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use thiserror::Error;
+
+/// Time per frame, expressed as a fraction of seconds (i.e. the inverse of the frame rate).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimePerFrame {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl From<bindings::v4l2_fract> for TimePerFrame {
+    fn from(fract: bindings::v4l2_fract) -> Self {
+        TimePerFrame {
+            numerator: fract.numerator,
+            denominator: fract.denominator,
+        }
+    }
+}
+
+impl From<TimePerFrame> for bindings::v4l2_fract {
+    fn from(tpf: TimePerFrame) -> Self {
+        bindings::v4l2_fract {
+            numerator: tpf.numerator,
+            denominator: tpf.denominator,
+        }
+    }
+}
+
+#[doc(hidden)]
+mod ioctl {
+    use crate::bindings::v4l2_streamparm;
+    nix::ioctl_readwrite!(vidioc_g_parm, b'V', 21, v4l2_streamparm);
+    nix::ioctl_readwrite!(vidioc_s_parm, b'V', 22, v4l2_streamparm);
+}
+
+#[derive(Debug, Error)]
+pub enum ParmError {
+    #[error("Queue does not support setting the frame interval")]
+    NotSupported,
+    #[error("Unexpected ioctl error: {0}")]
+    IoctlError(nix::Error),
+}
+
+/// Returns the time per frame from a `v4l2_streamparm`, if the queue supports it.
+fn time_per_frame(queue: QueueType, parm: &bindings::v4l2_streamparm) -> Option<TimePerFrame> {
+    // Safe because we read the member of the union matching the queue direction.
+    let (capability, timeperframe) = match queue {
+        QueueType::VideoCapture | QueueType::VideoCaptureMplane => unsafe {
+            (parm.parm.capture.capability, parm.parm.capture.timeperframe)
+        },
+        QueueType::VideoOutput | QueueType::VideoOutputMplane => unsafe {
+            (parm.parm.output.capability, parm.parm.output.timeperframe)
+        },
+    };
+
+    if capability & bindings::V4L2_CAP_TIMEPERFRAME != 0 {
+        Some(timeperframe.into())
+    } else {
+        None
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_G_PARM` ioctl. Returns the time per frame of `queue`.
+pub fn g_parm<F: AsRawFd>(fd: &F, queue: QueueType) -> Result<TimePerFrame, ParmError> {
+    let mut parm = bindings::v4l2_streamparm {
+        type_: queue as u32,
+        ..unsafe { mem::zeroed() }
+    };
+
+    match unsafe { ioctl::vidioc_g_parm(fd.as_raw_fd(), &mut parm) } {
+        Ok(_) => time_per_frame(queue, &parm).ok_or(ParmError::NotSupported),
+        Err(Errno::EINVAL) | Err(Errno::ENOTTY) => Err(ParmError::NotSupported),
+        Err(e) => Err(ParmError::IoctlError(e)),
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_S_PARM` ioctl. Sets the time per frame of `queue` and
+/// returns the value actually applied by the driver.
+pub fn s_parm<F: AsRawFd>(
+    fd: &F,
+    queue: QueueType,
+    time_per_frame: TimePerFrame,
+) -> Result<TimePerFrame, ParmError> {
+    let mut parm = bindings::v4l2_streamparm {
+        type_: queue as u32,
+        ..unsafe { mem::zeroed() }
+    };
+    match queue {
+        QueueType::VideoCapture | QueueType::VideoCaptureMplane => {
+            parm.parm.capture = bindings::v4l2_captureparm {
+                timeperframe: time_per_frame.into(),
+                ..unsafe { mem::zeroed() }
+            }
+        }
+        QueueType::VideoOutput | QueueType::VideoOutputMplane => {
+            parm.parm.output = bindings::v4l2_outputparm {
+                timeperframe: time_per_frame.into(),
+                ..unsafe { mem::zeroed() }
+            }
+        }
+    }
+
+    match unsafe { ioctl::vidioc_s_parm(fd.as_raw_fd(), &mut parm) } {
+        Ok(_) => self::time_per_frame(queue, &parm).ok_or(ParmError::NotSupported),
+        Err(Errno::EINVAL) | Err(Errno::ENOTTY) => Err(ParmError::NotSupported),
+        Err(e) => Err(ParmError::IoctlError(e)),
+    }
+}
@@ -10,7 +10,7 @@ use nix::{
 };
 use std::fmt::Debug;
 use std::mem;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use thiserror::Error;
 
 bitflags! {
@@ -22,9 +22,13 @@ bitflags! {
         const MAPPED = bindings::V4L2_BUF_FLAG_MAPPED;
         const QUEUED = bindings::V4L2_BUF_FLAG_QUEUED;
         const DONE = bindings::V4L2_BUF_FLAG_DONE;
+        const KEYFRAME = bindings::V4L2_BUF_FLAG_KEYFRAME;
+        const PFRAME = bindings::V4L2_BUF_FLAG_PFRAME;
+        const BFRAME = bindings::V4L2_BUF_FLAG_BFRAME;
         const ERROR = bindings::V4L2_BUF_FLAG_ERROR;
 
         const LAST = bindings::V4L2_BUF_FLAG_LAST;
+        const REQUEST_FD = bindings::V4L2_BUF_FLAG_REQUEST_FD;
     }
 }
 
@@ -34,6 +38,10 @@ pub enum QBufError {
     NumPlanesMismatch(usize, usize),
     #[error("Data offset specified while using the single-planar API")]
     DataOffsetNotSupported,
+    #[error("Data offset specified for a CAPTURE buffer, which V4L2 would ignore")]
+    CaptureDataOffsetNotSupported,
+    #[error("USERPTR plane at {addr:#x} is not aligned to {align} bytes")]
+    UserPtrMisaligned { addr: usize, align: usize },
     #[error("Unexpected ioctl error: {0}")]
     IoctlError(Error),
 }
@@ -73,9 +81,13 @@ impl QBufPlane {
         })
     }
 
+    /// Create a plane backed by `handle`, containing `bytes_used` bytes of data
+    /// starting at the data offset set by `handle`.
     pub fn new_from_handle<H: PlaneHandle>(handle: &H, bytes_used: usize) -> Self {
         let mut plane = Self::new(bytes_used);
         handle.fill_v4l2_plane(&mut plane.0);
+        // V4L2 expects the data offset to be included in the number of bytes used.
+        plane.0.bytesused += plane.0.data_offset;
         plane
     }
 }
@@ -101,6 +113,8 @@ pub struct QBuffer<H: PlaneHandle> {
     pub sequence: u32,
     pub timestamp: TimeVal,
     pub planes: Vec<QBufPlane>,
+    /// Request to queue the buffer into, if any.
+    pub request: Option<RawFd>,
     pub _h: std::marker::PhantomData<H>,
 }
 
@@ -112,6 +126,7 @@ impl<H: PlaneHandle> Default for QBuffer<H> {
             sequence: Default::default(),
             timestamp: TimeVal::zero(),
             planes: Vec::new(),
+            request: None,
             _h: std::marker::PhantomData,
         }
     }
@@ -125,6 +140,10 @@ impl<H: PlaneHandle> QBuffer<H> {
         v4l2_buf.sequence = self.sequence;
         v4l2_buf.timestamp.tv_sec = self.timestamp.tv_sec();
         v4l2_buf.timestamp.tv_usec = self.timestamp.tv_usec();
+        if let Some(request_fd) = self.request {
+            v4l2_buf.flags |= bindings::V4L2_BUF_FLAG_REQUEST_FD;
+            v4l2_buf.__bindgen_anon_1.request_fd = request_fd;
+        }
     }
 }
 
@@ -0,0 +1,127 @@
+//! Safe wrappers for the `VIDIOC_QUERY_EXT_CTRL` and `VIDIOC_QUERYMENU` ioctls.
+use super::string_from_cstr;
+use crate::bindings;
+use bitflags::bitflags;
+use nix::errno::Errno;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use thiserror::Error;
+
+bitflags! {
+    /// Flags returned by the `VIDIOC_QUERY_EXT_CTRL` ioctl into the `flags` field of
+    /// `struct v4l2_query_ext_ctrl`.
+    pub struct ControlFlags: u32 {
+        const DISABLED = bindings::V4L2_CTRL_FLAG_DISABLED;
+        const GRABBED = bindings::V4L2_CTRL_FLAG_GRABBED;
+        const READ_ONLY = bindings::V4L2_CTRL_FLAG_READ_ONLY;
+        const UPDATE = bindings::V4L2_CTRL_FLAG_UPDATE;
+        const INACTIVE = bindings::V4L2_CTRL_FLAG_INACTIVE;
+        const SLIDER = bindings::V4L2_CTRL_FLAG_SLIDER;
+        const WRITE_ONLY = bindings::V4L2_CTRL_FLAG_WRITE_ONLY;
+        const VOLATILE = bindings::V4L2_CTRL_FLAG_VOLATILE;
+        const HAS_PAYLOAD = bindings::V4L2_CTRL_FLAG_HAS_PAYLOAD;
+        const EXECUTE_ON_WRITE = bindings::V4L2_CTRL_FLAG_EXECUTE_ON_WRITE;
+        const MODIFY_LAYOUT = bindings::V4L2_CTRL_FLAG_MODIFY_LAYOUT;
+    }
+}
+
+/// Safe variant of the `v4l2_query_ext_ctrl` struct, to be used with `query_ext_ctrl`.
+#[derive(Debug, Clone)]
+pub struct QueryExtCtrl {
+    pub id: u32,
+    /// One of the `V4L2_CTRL_TYPE_*` values.
+    pub ctrl_type: u32,
+    pub name: String,
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: u64,
+    pub default_value: i64,
+    pub flags: ControlFlags,
+}
+
+impl From<bindings::v4l2_query_ext_ctrl> for QueryExtCtrl {
+    fn from(qctrl: bindings::v4l2_query_ext_ctrl) -> Self {
+        let name = qctrl.name.iter().map(|&c| c as u8).collect::<Vec<_>>();
+
+        QueryExtCtrl {
+            id: qctrl.id,
+            ctrl_type: qctrl.type_,
+            name: string_from_cstr(&name).unwrap_or_else(|_| "".into()),
+            minimum: qctrl.minimum,
+            maximum: qctrl.maximum,
+            step: qctrl.step,
+            default_value: qctrl.default_value,
+            flags: ControlFlags::from_bits_truncate(qctrl.flags),
+        }
+    }
+}
+
+/// Menu item returned by `querymenu`.
+pub struct QueryMenu(bindings::v4l2_querymenu);
+
+impl QueryMenu {
+    pub fn index(&self) -> u32 {
+        self.0.index
+    }
+
+    /// Returns the name of the menu item, for controls of type `V4L2_CTRL_TYPE_MENU`.
+    pub fn name(&self) -> String {
+        // Safe because the name is always valid data for the union, whatever the control type.
+        string_from_cstr(unsafe { &self.0.__bindgen_anon_1.name }).unwrap_or_else(|_| "".into())
+    }
+
+    /// Returns the value of the menu item, for controls of type `V4L2_CTRL_TYPE_INTEGER_MENU`.
+    pub fn value(&self) -> i64 {
+        // Safe because the value is always valid data for the union, whatever the control type.
+        unsafe { self.0.__bindgen_anon_1.value }
+    }
+}
+
+#[doc(hidden)]
+mod ioctl {
+    use crate::bindings::{v4l2_query_ext_ctrl, v4l2_querymenu};
+    nix::ioctl_readwrite!(vidioc_querymenu, b'V', 37, v4l2_querymenu);
+    nix::ioctl_readwrite!(vidioc_query_ext_ctrl, b'V', 103, v4l2_query_ext_ctrl);
+}
+
+#[derive(Debug, Error)]
+pub enum QueryCtrlError {
+    #[error("Control or menu item not supported by device")]
+    InvalidControl,
+    #[error("Unexpected ioctl error: {0}")]
+    IoctlError(nix::Error),
+}
+
+/// Safe wrapper around the `VIDIOC_QUERY_EXT_CTRL` ioctl.
+///
+/// `id` can be OR'd with `V4L2_CTRL_FLAG_NEXT_CTRL` to enumerate the controls of the device.
+pub fn query_ext_ctrl<F: AsRawFd>(fd: &F, id: u32) -> Result<QueryExtCtrl, QueryCtrlError> {
+    let mut qctrl = bindings::v4l2_query_ext_ctrl {
+        id,
+        ..unsafe { mem::zeroed() }
+    };
+
+    match unsafe { ioctl::vidioc_query_ext_ctrl(fd.as_raw_fd(), &mut qctrl) } {
+        Ok(_) => Ok(QueryExtCtrl::from(qctrl)),
+        Err(Errno::EINVAL) => Err(QueryCtrlError::InvalidControl),
+        Err(e) => Err(QueryCtrlError::IoctlError(e)),
+    }
+}
+
+/// Safe wrapper around the `VIDIOC_QUERYMENU` ioctl.
+///
+/// Returns `QueryCtrlError::InvalidControl` if `index` is not a supported item of menu control
+/// `id`.
+pub fn querymenu<F: AsRawFd>(fd: &F, id: u32, index: u32) -> Result<QueryMenu, QueryCtrlError> {
+    let mut qmenu = bindings::v4l2_querymenu {
+        id,
+        index,
+        ..unsafe { mem::zeroed() }
+    };
+
+    match unsafe { ioctl::vidioc_querymenu(fd.as_raw_fd(), &mut qmenu) } {
+        Ok(_) => Ok(QueryMenu(qmenu)),
+        Err(Errno::EINVAL) => Err(QueryCtrlError::InvalidControl),
+        Err(e) => Err(QueryCtrlError::IoctlError(e)),
+    }
+}
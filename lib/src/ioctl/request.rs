@@ -0,0 +1,77 @@
+//! Safe wrappers for the media request API (`MEDIA_IOC_REQUEST_ALLOC`,
+//! `MEDIA_REQUEST_IOC_QUEUE` and `MEDIA_REQUEST_IOC_REINIT`).
+//!
+//! Requests allow controls to be tied to a given buffer: controls set with
+//! `CtrlWhich::Request` and buffers queued with a request are only applied once
+//! the request itself is queued.
+use nix::errno::Errno;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use thiserror::Error;
+
+#[doc(hidden)]
+mod ioctl {
+    nix::ioctl_read!(media_ioc_request_alloc, b'|', 0x05, nix::libc::c_int);
+    nix::ioctl_none!(media_request_ioc_queue, b'|', 0x80);
+    nix::ioctl_none!(media_request_ioc_reinit, b'|', 0x81);
+}
+
+/// A media request, allocated from a media device. The request is freed when
+/// this object is dropped.
+#[derive(Debug)]
+pub struct Request(File);
+
+impl AsRawFd for Request {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RequestError {
+    #[error("Media device does not support requests")]
+    NotSupported,
+    #[error("Request is already queued or being processed")]
+    Busy,
+    #[error("Request contains no buffer, or invalid data")]
+    InvalidRequest,
+    #[error("Unexpected ioctl error: {0}")]
+    IoctlError(nix::Error),
+}
+
+impl From<nix::Error> for RequestError {
+    fn from(error: nix::Error) -> Self {
+        match error {
+            Errno::ENOTTY => RequestError::NotSupported,
+            Errno::EBUSY => RequestError::Busy,
+            Errno::EINVAL | Errno::ENOENT => RequestError::InvalidRequest,
+            e => RequestError::IoctlError(e),
+        }
+    }
+}
+
+/// Safe wrapper around the `MEDIA_IOC_REQUEST_ALLOC` ioctl. `fd` must be the
+/// media device associated to the video device we want to use requests with.
+pub fn request_alloc<F: AsRawFd>(fd: &F) -> Result<Request, RequestError> {
+    let mut request_fd: nix::libc::c_int = -1;
+
+    unsafe { ioctl::media_ioc_request_alloc(fd.as_raw_fd(), &mut request_fd) }?;
+
+    // Safe because the kernel just gave us ownership of this FD.
+    Ok(Request(unsafe { File::from_raw_fd(request_fd) }))
+}
+
+impl Request {
+    /// Queue the request, applying its controls and buffers. The request
+    /// completes once all its buffers have been processed.
+    pub fn queue(&self) -> Result<(), RequestError> {
+        unsafe { ioctl::media_request_ioc_queue(self.as_raw_fd()) }?;
+        Ok(())
+    }
+
+    /// Reinitialize a completed request so it can be used again.
+    pub fn reinit(&self) -> Result<(), RequestError> {
+        unsafe { ioctl::media_request_ioc_reinit(self.as_raw_fd()) }?;
+        Ok(())
+    }
+}
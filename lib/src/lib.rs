@@ -19,6 +19,7 @@
 //!
 #[doc(hidden)]
 pub mod bindings;
+mod codec;
 pub mod decoder;
 pub mod device;
 pub mod encoder;
@@ -247,6 +248,49 @@ impl TryFrom<bindings::v4l2_format> for Format {
     }
 }
 
+/// Colorimetry parameters of a format, i.e. how its pixel values map to colors.
+///
+/// The fields hold the raw values of the `v4l2_colorspace`, `v4l2_xfer_func`,
+/// `v4l2_ycbcr_encoding` and `v4l2_quantization` enums. A value of `0` means
+/// that the default for the colorspace is used.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Colorimetry {
+    pub colorspace: u32,
+    pub xfer_func: u32,
+    pub ycbcr_enc: u32,
+    pub quantization: u32,
+}
+
+impl TryFrom<bindings::v4l2_format> for Colorimetry {
+    type Error = FormatConversionError;
+
+    fn try_from(fmt: bindings::v4l2_format) -> std::result::Result<Self, Self::Error> {
+        match fmt.type_ {
+            bindings::v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE
+            | bindings::v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_OUTPUT => {
+                let pix = unsafe { &fmt.fmt.pix };
+                Ok(Colorimetry {
+                    colorspace: pix.colorspace,
+                    xfer_func: pix.xfer_func,
+                    ycbcr_enc: unsafe { pix.__bindgen_anon_1.ycbcr_enc },
+                    quantization: pix.quantization,
+                })
+            }
+            bindings::v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE
+            | bindings::v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_OUTPUT_MPLANE => {
+                let pix_mp = unsafe { &fmt.fmt.pix_mp };
+                Ok(Colorimetry {
+                    colorspace: pix_mp.colorspace,
+                    xfer_func: pix_mp.xfer_func as u32,
+                    ycbcr_enc: unsafe { pix_mp.__bindgen_anon_1.ycbcr_enc } as u32,
+                    quantization: pix_mp.quantization as u32,
+                })
+            }
+            t => Err(Self::Error::InvalidBufferType(t)),
+        }
+    }
+}
+
 /// Quickly build a usable `Format` from a pixel format and resolution.
 ///
 /// # Examples
@@ -271,7 +315,7 @@ impl<T: Into<PixelFormat>> From<(T, (usize, usize))> for Format {
 }
 
 /// A more elegant representation for `v4l2_rect`.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rect {
     pub left: i32,
     pub top: i32,
@@ -28,17 +28,24 @@
 //! it. `PrimitiveBufferHandles` is used to represent plane handles which memory
 //! type is known at compilation time, and thus includes a reference to a
 //! `PlaneHandle` type and by transition its `Memory` type.
+//!
+//! With the `dma-heap` feature, DMA-BUFs can also be allocated from the DMA
+//! heaps of the system using `DmaBufAllocator`.
+#[cfg(feature = "dma-heap")]
+mod dma_heap;
 mod dmabuf;
 mod mmap;
 mod userptr;
 
+#[cfg(feature = "dma-heap")]
+pub use dma_heap::*;
 pub use dmabuf::*;
 pub use mmap::*;
 pub use userptr::*;
 
 use crate::{
     bindings,
-    ioctl::{PlaneMapping, QueryBufPlane},
+    ioctl::{MmapRegion, QueryBufPlane},
 };
 use std::fmt::Debug;
 use std::os::unix::io::AsRawFd;
@@ -85,8 +92,12 @@ pub trait PlaneHandle: Debug + Send + 'static {
 // Trait for plane handles that provide access to their content through a map()
 // method (typically, MMAP buffers).
 pub trait Mappable: PlaneHandle {
-    /// Return a `PlaneMapping` enabling access to the memory of this handle.
-    fn map<D: AsRawFd>(device: &D, plane_info: &QueryBufPlane) -> Option<PlaneMapping>;
+    /// Map the memory of the plane described by `plane_info`.
+    ///
+    /// Queues cache the returned region for as long as the buffer exists, and
+    /// give access to it through `PlaneReadMapping` or `PlaneWriteMapping`
+    /// while the buffer is owned by the user.
+    fn map<D: AsRawFd>(device: &D, plane_info: &QueryBufPlane) -> Option<MmapRegion>;
 }
 
 /// Trait for structures providing all the handles of a single buffer.
@@ -0,0 +1,141 @@
+//! Allocation of DMA-BUFs from the DMA heaps exposed by the kernel.
+//!
+//! DMA heaps are character devices under `/dev/dma_heap`, each of which
+//! allocates memory with different properties (e.g. `system` or a contiguous
+//! `reserved` heap). The buffers they return are regular DMA-BUFs that can be
+//! used with `DmaBufHandle`.
+//!
+//! For systems without DMA heaps, e.g. when running tests, `DmaBufAllocator`
+//! can fall back to memfd-backed buffers. These can be mapped and passed
+//! around like DMA-BUFs, but will be rejected by devices.
+use log::warn;
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::Path,
+};
+use thiserror::Error;
+
+/// Directory in which the kernel exposes DMA heaps.
+pub const DMA_HEAP_DIR: &str = "/dev/dma_heap";
+
+#[doc(hidden)]
+mod ioctl {
+    #[repr(C)]
+    #[allow(non_camel_case_types)]
+    pub struct dma_heap_allocation_data {
+        pub len: u64,
+        pub fd: u32,
+        pub fd_flags: u32,
+        pub heap_flags: u64,
+    }
+    nix::ioctl_readwrite!(dma_heap_ioctl_alloc, b'H', 0x0, dma_heap_allocation_data);
+}
+
+#[derive(Debug, Error)]
+pub enum DmaHeapError {
+    #[error("Cannot open DMA heap: {0}")]
+    OpenError(io::Error),
+    #[error("Error while allocating from DMA heap: {0}")]
+    AllocationError(#[from] nix::Error),
+    #[error("Error while allocating memfd buffer: {0}")]
+    MemfdError(io::Error),
+    #[error("Error while duplicating DMA-BUF: {0}")]
+    DupError(io::Error),
+}
+
+/// Returns the names of the DMA heaps available on the system, in alphabetical
+/// order.
+pub fn dma_heaps() -> io::Result<Vec<String>> {
+    let mut heaps = fs::read_dir(DMA_HEAP_DIR)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    heaps.sort();
+
+    Ok(heaps)
+}
+
+/// A DMA heap from which DMA-BUFs can be allocated.
+#[derive(Debug)]
+pub struct DmaHeap {
+    name: String,
+    device: File,
+}
+
+impl DmaHeap {
+    /// Open the heap named `name`, e.g. `system`.
+    pub fn open(name: &str) -> Result<Self, DmaHeapError> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(Path::new(DMA_HEAP_DIR).join(name))
+            .map_err(DmaHeapError::OpenError)?;
+
+        Ok(DmaHeap {
+            name: name.to_owned(),
+            device,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Allocate a DMA-BUF of `len` bytes from this heap.
+    pub fn allocate(&self, len: usize) -> Result<File, DmaHeapError> {
+        let mut data = ioctl::dma_heap_allocation_data {
+            len: len as u64,
+            fd: 0,
+            fd_flags: (nix::libc::O_RDWR | nix::libc::O_CLOEXEC) as u32,
+            heap_flags: 0,
+        };
+        unsafe { ioctl::dma_heap_ioctl_alloc(self.device.as_raw_fd(), &mut data) }?;
+
+        // Safe because the fd has just been created for us.
+        Ok(unsafe { File::from_raw_fd(data.fd as i32) })
+    }
+}
+
+/// Allocator of buffers that can be used as DMA-BUFs.
+#[derive(Debug)]
+pub enum DmaBufAllocator {
+    /// Allocate from a DMA heap.
+    Heap(DmaHeap),
+    /// Allocate memfd-backed buffers, which cannot be imported by devices.
+    Memfd,
+}
+
+impl DmaBufAllocator {
+    /// Returns an allocator using the DMA heap named `name`, or falling back to
+    /// memfd-backed buffers if that heap cannot be opened.
+    pub fn with_fallback(name: &str) -> Self {
+        match DmaHeap::open(name) {
+            Ok(heap) => DmaBufAllocator::Heap(heap),
+            Err(e) => {
+                warn!("Cannot use DMA heap {}, falling back to memfd: {}", name, e);
+                DmaBufAllocator::Memfd
+            }
+        }
+    }
+
+    /// Allocate a buffer of `len` bytes.
+    pub fn allocate(&self, len: usize) -> Result<File, DmaHeapError> {
+        match self {
+            DmaBufAllocator::Heap(heap) => heap.allocate(len),
+            DmaBufAllocator::Memfd => {
+                let name = CString::new("v4l2r").unwrap();
+                let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)
+                    .map_err(|e| DmaHeapError::MemfdError(e.into()))?;
+                // Safe because the fd has just been created for us.
+                let file = unsafe { File::from_raw_fd(fd) };
+                file.set_len(len as u64).map_err(DmaHeapError::MemfdError)?;
+
+                Ok(file)
+            }
+        }
+    }
+}
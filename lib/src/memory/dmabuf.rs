@@ -1,9 +1,16 @@
 //! Operations specific to DMABuf-type buffers.
-use log::warn;
+use log::{error, warn};
+use thiserror::Error;
 
 use super::*;
-use crate::{bindings, ioctl};
-use std::os::unix::io::AsRawFd;
+use crate::{bindings, ioctl, Format};
+use std::{
+    fs::File,
+    io,
+    ops::{Deref, DerefMut},
+    os::unix::io::AsRawFd,
+    sync::Arc,
+};
 
 pub struct DmaBuf;
 
@@ -38,12 +45,80 @@ impl DmaBufSource for std::fs::File {
 
 /// Handle for a DMABUF plane. Any type that can provide a file descriptor is
 /// valid.
+///
+/// The plane occupies `length` bytes of `dmabuf` starting at `offset`, which
+/// allows several planes to share the same DMA-BUF. The offset is only honored
+/// on OUTPUT queues: V4L2 ignores the data offset of CAPTURE planes, so
+/// CAPTURE buffers with a non-zero offset are rejected when queued.
 #[derive(Debug)]
-pub struct DmaBufHandle<T: DmaBufSource>(pub T);
+pub struct DmaBufHandle<T: DmaBufSource> {
+    pub dmabuf: T,
+    /// Offset of the plane in `dmabuf`, passed as the `data_offset` of the
+    /// plane. Must be zero for CAPTURE buffers.
+    pub offset: u32,
+    /// Length in bytes of the plane, not including `offset`.
+    pub length: u32,
+}
 
+impl<T: DmaBufSource> DmaBufHandle<T> {
+    /// Create a handle for the plane of `length` bytes starting at `offset` in
+    /// `dmabuf`.
+    pub fn new(dmabuf: T, offset: u32, length: u32) -> Self {
+        DmaBufHandle {
+            dmabuf,
+            offset,
+            length,
+        }
+    }
+}
+
+/// Use the whole DMA-BUF as a plane.
 impl<T: DmaBufSource> From<T> for DmaBufHandle<T> {
     fn from(dmabuf: T) -> Self {
-        DmaBufHandle(dmabuf)
+        let length = dmabuf.len() as u32;
+        DmaBufHandle::new(dmabuf, 0, length)
+    }
+}
+
+impl DmaBufHandle<File> {
+    /// Build the handles of a buffer which planes are all stored in `dmabuf`,
+    /// one after the other, as described by the `plane_fmt` of `format`.
+    ///
+    /// The file descriptor of `dmabuf` is duplicated for every plane. An error
+    /// of kind `InvalidInput` is returned if `dmabuf` is too small to contain
+    /// all the planes.
+    ///
+    /// Since the planes after the first one have a non-zero offset, the
+    /// returned handles can only be queued into OUTPUT queues.
+    pub fn from_format(dmabuf: File, format: &Format) -> io::Result<Vec<Self>> {
+        let total_size = format
+            .plane_fmt
+            .iter()
+            .map(|plane| plane.sizeimage as u64)
+            .sum::<u64>();
+        let size = dmabuf.len();
+        if total_size > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "DMA-BUF is too small for format: {} < {} bytes",
+                    size, total_size
+                ),
+            ));
+        }
+
+        let mut offset = 0;
+        let mut handles = Vec::with_capacity(format.plane_fmt.len());
+        for plane in &format.plane_fmt {
+            handles.push(DmaBufHandle::new(
+                dmabuf.try_clone()?,
+                offset,
+                plane.sizeimage,
+            ));
+            offset += plane.sizeimage;
+        }
+
+        Ok(handles)
     }
 }
 
@@ -51,15 +126,157 @@ impl<T: DmaBufSource + 'static> PlaneHandle for DmaBufHandle<T> {
     type Memory = DmaBuf;
 
     fn fill_v4l2_plane(&self, plane: &mut bindings::v4l2_plane) {
-        plane.m.fd = self.0.as_raw_fd();
-        plane.length = self.0.len() as u32;
+        plane.m.fd = self.dmabuf.as_raw_fd();
+        // The length of a plane includes its data offset.
+        plane.length = self.offset + self.length;
+        plane.data_offset = self.offset;
     }
 }
 
+#[derive(Debug, Error)]
+pub enum DmaBufMapError {
+    #[error("Error while mapping DMA-BUF: {0}")]
+    MmapError(#[from] ioctl::MmapError),
+    #[error("Error while synchronizing DMA-BUF: {0}")]
+    SyncError(#[from] ioctl::DmaBufSyncError),
+}
+
 impl<T: DmaBufSource> DmaBufHandle<T> {
-    pub fn map(&self) -> Result<PlaneMapping, ioctl::MmapError> {
-        let len = self.0.len();
+    /// Map the DMA-BUF up to the end of the plane, and return the region along with the range
+    /// of the plane in it.
+    fn map_region(&self) -> Result<(Arc<ioctl::MmapRegion>, usize, usize), ioctl::MmapError> {
+        // The offset of the plane may not be page-aligned, so map the DMA-BUF from its start.
+        let end = self.offset + self.length;
+        let region = ioctl::mmap(&self.dmabuf, 0, end)?;
+
+        Ok((Arc::new(region), self.offset as usize, end as usize))
+    }
+
+    /// Map the plane's part of the DMA-BUF for reading.
+    ///
+    /// The CPU caches are not synchronized with device accesses, so the
+    /// `map_read`, `map_write` and `map_read_write` methods should be preferred
+    /// on platforms without cache coherency.
+    pub fn map(&self) -> Result<ioctl::PlaneReadMapping<'_>, ioctl::MmapError> {
+        let (region, start, end) = self.map_region()?;
+
+        // Safe because the mapping borrows the handle, which therefore cannot be mapped for
+        // writing or queued while it is alive.
+        Ok(unsafe { ioctl::PlaneReadMapping::new(region, start, end) })
+    }
+
+    /// Map the plane's part of the DMA-BUF for writing.
+    ///
+    /// Other handles sharing the DMA-BUF can still be mapped, so their planes
+    /// must not overlap with this one. The same cache synchronization caveat
+    /// as [`DmaBufHandle::map`] applies.
+    pub fn map_mut(&mut self) -> Result<ioctl::PlaneWriteMapping<'_>, ioctl::MmapError> {
+        let (region, start, end) = self.map_region()?;
+
+        // Safe because the mapping borrows the handle exclusively, so it cannot be mapped again
+        // or queued while it is alive.
+        Ok(unsafe { ioctl::PlaneWriteMapping::new(region, start, end) })
+    }
+
+    /// Signal the start of a CPU `access` to the DMA-BUF, which ends when the
+    /// returned object is dropped.
+    fn sync<M>(
+        &self,
+        mapping: M,
+        access: ioctl::DmaBufAccess,
+    ) -> Result<SyncedMapping<'_, T, M>, DmaBufMapError> {
+        ioctl::dma_buf_sync(&self.dmabuf, ioctl::DmaBufSyncPhase::Start, access)?;
+
+        Ok(SyncedMapping {
+            mapping,
+            dmabuf: &self.dmabuf,
+            access,
+        })
+    }
+
+    fn map_synced_mut(
+        &mut self,
+        access: ioctl::DmaBufAccess,
+    ) -> Result<DmaBufWriteMapping<'_, T>, DmaBufMapError> {
+        // Keep the exclusive borrow for as long as the mapping lives.
+        let this = &*self;
+        let (region, start, end) = this.map_region()?;
+        // Safe because the handle is borrowed exclusively by the mapping, so it cannot be mapped
+        // again or queued while it is alive.
+        let mapping = unsafe { ioctl::PlaneWriteMapping::new(region, start, end) };
+
+        Ok(DmaBufWriteMapping(this.sync(mapping, access)?))
+    }
+
+    /// Map the DMA-BUF for reading from the CPU. The CPU caches are kept
+    /// synchronized until the returned mapping is dropped.
+    pub fn map_read(&self) -> Result<DmaBufReadMapping<'_, T>, DmaBufMapError> {
+        let mapping = self.map()?;
+
+        Ok(DmaBufReadMapping(
+            self.sync(mapping, ioctl::DmaBufAccess::Read)?,
+        ))
+    }
+
+    /// Map the DMA-BUF for writing from the CPU. The CPU caches are kept
+    /// synchronized until the returned mapping is dropped.
+    pub fn map_write(&mut self) -> Result<DmaBufWriteMapping<'_, T>, DmaBufMapError> {
+        self.map_synced_mut(ioctl::DmaBufAccess::Write)
+    }
+
+    /// Map the DMA-BUF for reading and writing from the CPU. The CPU caches
+    /// are kept synchronized until the returned mapping is dropped.
+    pub fn map_read_write(&mut self) -> Result<DmaBufWriteMapping<'_, T>, DmaBufMapError> {
+        self.map_synced_mut(ioctl::DmaBufAccess::ReadWrite)
+    }
+}
+
+/// Mapping of a DMA-BUF during which CPU access has been signaled to the
+/// kernel using `DMA_BUF_IOCTL_SYNC`. The end of the access is signaled when
+/// it is dropped, before the memory is unmapped.
+struct SyncedMapping<'a, T: DmaBufSource, M> {
+    mapping: M,
+    dmabuf: &'a T,
+    access: ioctl::DmaBufAccess,
+}
+
+impl<'a, T: DmaBufSource, M> Drop for SyncedMapping<'a, T, M> {
+    fn drop(&mut self) {
+        if let Err(e) = ioctl::dma_buf_sync(self.dmabuf, ioctl::DmaBufSyncPhase::End, self.access) {
+            error!("Error while ending CPU access to DMA-BUF: {}", e);
+        }
+    }
+}
+
+/// Read-only mapping of a DMA-BUF, returned by `DmaBufHandle::map_read`.
+pub struct DmaBufReadMapping<'a, T: DmaBufSource>(
+    SyncedMapping<'a, T, ioctl::PlaneReadMapping<'a>>,
+);
+
+impl<'a, T: DmaBufSource> Deref for DmaBufReadMapping<'a, T> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.mapping
+    }
+}
+
+/// Writable mapping of a DMA-BUF, returned by `DmaBufHandle::map_write` and
+/// `DmaBufHandle::map_read_write`.
+pub struct DmaBufWriteMapping<'a, T: DmaBufSource>(
+    SyncedMapping<'a, T, ioctl::PlaneWriteMapping<'a>>,
+);
+
+impl<'a, T: DmaBufSource> Deref for DmaBufWriteMapping<'a, T> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.mapping
+    }
+}
 
-        ioctl::mmap(&self.0, 0, len as u32)
+impl<'a, T: DmaBufSource> DerefMut for DmaBufWriteMapping<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0.mapping
     }
 }
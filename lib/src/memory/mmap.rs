@@ -26,7 +26,7 @@ impl PlaneHandle for MmapHandle {
 }
 
 impl Mappable for MmapHandle {
-    fn map<D: AsRawFd>(device: &D, plane_info: &QueryBufPlane) -> Option<PlaneMapping> {
+    fn map<D: AsRawFd>(device: &D, plane_info: &QueryBufPlane) -> Option<ioctl::MmapRegion> {
         ioctl::mmap(device, plane_info.mem_offset, plane_info.length).ok()
     }
 }
@@ -1,7 +1,15 @@
 //! Operations specific to UserPtr-type buffers.
 
 use super::*;
-use crate::bindings;
+use crate::{bindings, PlaneLayout};
+use nix::unistd::{sysconf, SysconfVar};
+use std::{
+    alloc::{self, Layout},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    slice,
+};
+use thiserror::Error;
 
 pub struct UserPtr;
 
@@ -11,36 +19,83 @@ impl Memory for UserPtr {
 
 impl Imported for UserPtr {}
 
+#[derive(Debug, Error)]
+pub enum UserPtrError {
+    #[error("Alignment {0} is not a power of two")]
+    InvalidAlignment(usize),
+    #[error("Buffer at {addr:#x} is not aligned to {align} bytes")]
+    Misaligned { addr: usize, align: usize },
+    #[error("Buffer of {0} bytes is too large to be allocated")]
+    TooLarge(usize),
+}
+
 /// Handle for a USERPTR plane. These buffers are backed by userspace-allocated
 /// memory, which translates well into Rust's slice of `u8`s. Since slices also
 /// carry size information, we know that we are not passing unallocated areas
 /// of the address-space to the kernel.
 ///
+/// Any owner of memory can be used, e.g. a `Vec<u8>`, an `AlignedBuffer`, or
+/// a memory-mapped file. Since the device may write into the memory, the owner
+/// must be able to provide mutable access to it.
+///
 /// USERPTR buffers have the particularity that the `length` field of `struct
 /// v4l2_buffer` must be set before doing a `QBUF` ioctl. This handle struct
 /// also takes care of that.
-#[derive(Debug)]
-pub struct UserPtrHandle<T: AsRef<[u8]> + Debug + Send + 'static>(pub T);
+pub struct UserPtrHandle<T: AsRef<[u8]> + AsMut<[u8]> + Send + 'static>(pub T);
+
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send> UserPtrHandle<T> {
+    /// Check that the memory of this handle starts at an address aligned to
+    /// `align` bytes, which drivers may require. `Queue::set_userptr_alignment`
+    /// makes the queue perform this check on every USERPTR buffer it queues.
+    pub fn check_alignment(&self, align: usize) -> Result<(), UserPtrError> {
+        if !align.is_power_of_two() {
+            return Err(UserPtrError::InvalidAlignment(align));
+        }
+
+        let addr = self.0.as_ref().as_ptr() as usize;
+        if addr & (align - 1) != 0 {
+            return Err(UserPtrError::Misaligned { addr, align });
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send> Debug for UserPtrHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let slice = self.0.as_ref();
+        f.debug_struct("UserPtrHandle")
+            .field("addr", &slice.as_ptr())
+            .field("len", &slice.len())
+            .finish()
+    }
+}
 
-impl<T: AsRef<[u8]> + Debug + Send + Clone> Clone for UserPtrHandle<T> {
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send + Clone> Clone for UserPtrHandle<T> {
     fn clone(&self) -> Self {
         UserPtrHandle(self.0.clone())
     }
 }
 
-impl<T: AsRef<[u8]> + Debug + Send + 'static> AsRef<[u8]> for UserPtrHandle<T> {
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send + 'static> AsRef<[u8]> for UserPtrHandle<T> {
     fn as_ref(&self) -> &[u8] {
         self.0.as_ref()
     }
 }
 
-impl<T: AsRef<[u8]> + Debug + Send> From<T> for UserPtrHandle<T> {
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send + 'static> AsMut<[u8]> for UserPtrHandle<T> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut()
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send> From<T> for UserPtrHandle<T> {
     fn from(buffer: T) -> Self {
         UserPtrHandle(buffer)
     }
 }
 
-impl<T: AsRef<[u8]> + Debug + Send + 'static> PlaneHandle for UserPtrHandle<T> {
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send + 'static> PlaneHandle for UserPtrHandle<T> {
     type Memory = UserPtr;
 
     fn fill_v4l2_plane(&self, plane: &mut bindings::v4l2_plane) {
@@ -50,3 +105,147 @@ impl<T: AsRef<[u8]> + Debug + Send + 'static> PlaneHandle for UserPtrHandle<T> {
         plane.length = slice.len() as u32;
     }
 }
+
+/// Returns the size of a memory page, which is the alignment most drivers
+/// require for USERPTR buffers.
+pub fn page_size() -> usize {
+    match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(size)) if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+/// Zero-initialized heap memory starting at an aligned address, to be used as
+/// the backing memory of USERPTR buffers.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// The buffer owns its memory exclusively, just like a `Vec<u8>`.
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocate `len` bytes starting at an address aligned to `align` bytes,
+    /// which must be a power of two.
+    pub fn new(len: usize, align: usize) -> Result<Self, UserPtrError> {
+        if !align.is_power_of_two() {
+            return Err(UserPtrError::InvalidAlignment(align));
+        }
+        // Zero-sized allocations are not allowed, so always allocate at least
+        // one byte.
+        let layout = Layout::from_size_align(std::cmp::max(len, 1), align)
+            .map_err(|_| UserPtrError::TooLarge(len))?;
+        // Safe because the size of the layout is not zero.
+        let ptr = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+            Some(ptr) => ptr,
+            None => alloc::handle_alloc_error(layout),
+        };
+
+        Ok(AlignedBuffer { ptr, len, layout })
+    }
+
+    /// Allocate a page-aligned buffer large enough for a plane of `height`
+    /// lines with the given `layout`, as returned by the driver. The size is
+    /// rounded up to a whole number of pages.
+    pub fn for_plane(layout: &PlaneLayout, height: u32) -> Result<Self, UserPtrError> {
+        let page_size = page_size();
+        let lines_size = (layout.bytesperline as usize)
+            .checked_mul(height as usize)
+            .ok_or(UserPtrError::TooLarge(usize::MAX))?;
+        let len = std::cmp::max(layout.sizeimage as usize, lines_size);
+        let len = len
+            .checked_next_multiple_of(page_size)
+            .ok_or(UserPtrError::TooLarge(len))?;
+
+        Self::new(len, page_size)
+    }
+}
+
+impl Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlignedBuffer")
+            .field("addr", &self.ptr)
+            .field("len", &self.len)
+            .field("align", &self.layout.align())
+            .finish()
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // Safe because we own `len` initialized bytes starting at `ptr`.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safe because we own `len` initialized bytes starting at `ptr`.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for AlignedBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // Safe because the memory has been allocated with this layout in
+        // `new`.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_buffer() {
+        let page_size = page_size();
+        let layout = PlaneLayout {
+            sizeimage: 1000,
+            bytesperline: 320,
+        };
+
+        // The lines of the plane are larger than `sizeimage`.
+        let mut buffer = AlignedBuffer::for_plane(&layout, 16).unwrap();
+        assert_eq!(buffer.len() % page_size, 0);
+        assert!(buffer.len() >= 320 * 16);
+        assert!(buffer.iter().all(|&b| b == 0));
+        buffer[0] = 0xff;
+
+        let handle = UserPtrHandle::from(buffer);
+        assert!(handle.check_alignment(page_size).is_ok());
+        assert!(matches!(
+            handle.check_alignment(3),
+            Err(UserPtrError::InvalidAlignment(3))
+        ));
+        assert_eq!(handle.as_ref()[0], 0xff);
+
+        assert!(matches!(
+            AlignedBuffer::new(16, 3),
+            Err(UserPtrError::InvalidAlignment(3))
+        ));
+        assert!(matches!(
+            AlignedBuffer::new(usize::MAX, 64),
+            Err(UserPtrError::TooLarge(usize::MAX))
+        ));
+        let buffer = AlignedBuffer::new(0, 64).unwrap();
+        assert!(buffer.is_empty());
+    }
+}
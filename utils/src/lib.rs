@@ -1,2 +1 @@
-pub mod dmabuf_exporter;
 pub mod framegen;